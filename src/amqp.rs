@@ -1,10 +1,462 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use lapin::{
+    options::{BasicPublishOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    types::FieldTable,
+    BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind,
+};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::types::PublishedEvent;
 
-pub struct AmqpClient;
+/// Exchange events are published to when AMQP_EXCHANGE is not set
+pub const DEFAULT_EXCHANGE: &str = "anypay";
+
+/// Key the queue is bound with when AMQP_BINDING_KEY is not set, matching every routing key
+pub const DEFAULT_BINDING_KEY: &str = "#";
+
+/// How often the connection is checked, and reconnected if it has dropped
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a publish may take before it is reported as failed
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The kind of exchange to declare, which decides how routing keys are matched against bindings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExchangeType {
+    Direct,
+    Fanout,
+    Topic,
+    Headers,
+}
+
+impl FromStr for ExchangeType {
+    type Err = anyhow::Error;
+
+    fn from_str(exchange_type: &str) -> Result<Self> {
+        match exchange_type.trim().to_lowercase().as_str() {
+            "direct" => Ok(ExchangeType::Direct),
+            "fanout" => Ok(ExchangeType::Fanout),
+            "topic" => Ok(ExchangeType::Topic),
+            "headers" => Ok(ExchangeType::Headers),
+            _ => Err(anyhow!("Invalid exchange type: {} (expected direct, fanout, topic or headers)", exchange_type)),
+        }
+    }
+}
+
+impl From<ExchangeType> for ExchangeKind {
+    fn from(exchange_type: ExchangeType) -> Self {
+        match exchange_type {
+            ExchangeType::Direct => ExchangeKind::Direct,
+            ExchangeType::Fanout => ExchangeKind::Fanout,
+            ExchangeType::Topic => ExchangeKind::Topic,
+            ExchangeType::Headers => ExchangeKind::Headers,
+        }
+    }
+}
+
+/// The exchange messages are published to, and optionally a queue bound to it, declared on
+/// every (re)connection so they fit into an existing broker setup
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AmqpTopology {
+    pub exchange: String,
+    pub exchange_type: ExchangeType,
+    /// Queue to declare and bind to the exchange; none is declared when unset
+    pub queue: Option<String>,
+    pub binding_key: String,
+}
+
+impl Default for AmqpTopology {
+    fn default() -> Self {
+        AmqpTopology {
+            exchange: DEFAULT_EXCHANGE.to_string(),
+            exchange_type: ExchangeType::Topic,
+            queue: None,
+            binding_key: DEFAULT_BINDING_KEY.to_string(),
+        }
+    }
+}
+
+/// An open connection to the broker, behind a trait so tests can stand in for the broker
+#[async_trait]
+trait Link: Send + Sync {
+    fn is_open(&self) -> bool;
+
+    async fn publish(&self, exchange: &str, routing_key: &str, payload: &[u8]) -> Result<()>;
+}
+
+/// Opens links to the broker, declaring the topology on each
+#[async_trait]
+trait Connector: Send + Sync {
+    async fn connect(&self, topology: &AmqpTopology) -> Result<Arc<dyn Link>>;
+}
+
+/// An open connection and the channel publishing on it
+struct LapinLink {
+    connection: Connection,
+    channel: Channel,
+}
+
+#[async_trait]
+impl Link for LapinLink {
+    fn is_open(&self) -> bool {
+        self.connection.status().connected() && self.channel.status().connected()
+    }
+
+    async fn publish(&self, exchange: &str, routing_key: &str, payload: &[u8]) -> Result<()> {
+        self.channel.basic_publish(
+            exchange,
+            routing_key,
+            BasicPublishOptions::default(),
+            payload,
+            BasicProperties::default(),
+        ).await?.await?;
+        Ok(())
+    }
+}
+
+/// Connects to the broker at `url`
+struct LapinConnector {
+    url: String,
+}
+
+#[async_trait]
+impl Connector for LapinConnector {
+    async fn connect(&self, topology: &AmqpTopology) -> Result<Arc<dyn Link>> {
+        let connection = Connection::connect(&self.url, ConnectionProperties::default()).await
+            .map_err(|e| anyhow!("Failed to connect to AMQP: {}", e))?;
+        let channel = connection.create_channel().await
+            .map_err(|e| anyhow!("Failed to open AMQP channel: {}", e))?;
+        channel.exchange_declare(
+            &topology.exchange,
+            topology.exchange_type.into(),
+            ExchangeDeclareOptions { durable: true, ..Default::default() },
+            FieldTable::default(),
+        ).await
+            .map_err(|e| anyhow!("Failed to declare AMQP exchange {}: {}", topology.exchange, e))?;
+
+        if let Some(queue) = &topology.queue {
+            channel.queue_declare(
+                queue,
+                QueueDeclareOptions { durable: true, ..Default::default() },
+                FieldTable::default(),
+            ).await
+                .map_err(|e| anyhow!("Failed to declare AMQP queue {}: {}", queue, e))?;
+            channel.queue_bind(
+                queue,
+                &topology.exchange,
+                &topology.binding_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            ).await
+                .map_err(|e| anyhow!("Failed to bind AMQP queue {} to {}: {}", queue, topology.exchange, e))?;
+        }
+
+        Ok(Arc::new(LapinLink { connection, channel }))
+    }
+}
+
+/// State shared between the client and its background tasks, which stop once the client is dropped
+struct Shared {
+    topology: AmqpTopology,
+    connector: Box<dyn Connector>,
+    link: RwLock<Option<Arc<dyn Link>>>,
+}
+
+impl Shared {
+    /// Open a new link, replacing the current one
+    async fn reconnect(&self) -> Result<()> {
+        let link = self.connector.connect(&self.topology).await?;
+        *self.link.write().unwrap() = Some(link);
+        Ok(())
+    }
+
+    /// The current link, if it is open
+    fn open_link(&self) -> Option<Arc<dyn Link>> {
+        self.link.read().unwrap().as_ref()
+            .filter(|link| link.is_open())
+            .cloned()
+    }
+
+    async fn publish(&self, routing_key: &str, payload: &[u8]) -> Result<()> {
+        let link = self.open_link()
+            .ok_or_else(|| anyhow!("Not connected to AMQP; not publishing {}", routing_key))?;
+
+        tokio::time::timeout(PUBLISH_TIMEOUT, link.publish(&self.topology.exchange, routing_key, payload)).await
+            .map_err(|_| anyhow!("Timed out publishing {} to AMQP", routing_key))?
+            .map_err(|e| anyhow!("Failed to publish {} to AMQP: {}", routing_key, e))
+    }
+}
+
+/// Publishes to the broker, reconnecting in the background whenever the connection drops
+pub struct AmqpClient {
+    shared: Arc<Shared>,
+}
 
 impl AmqpClient {
-    pub async fn new(url: &str) -> Result<Self> {
-        // TODO: Implement AMQP connection
-        Ok(Self)
+    /// Connect to the broker at `url` with the default topology
+    pub async fn new(url: &str) -> Self {
+        Self::connect(url, AmqpTopology::default()).await
+    }
+
+    /// Connect to the broker at `url` and declare `topology`. A broker that can't be reached now
+    /// doesn't fail startup: the health check keeps retrying, and publishes fail until it connects.
+    pub async fn connect(url: &str, topology: AmqpTopology) -> Self {
+        Self::with_connector(Box::new(LapinConnector { url: url.to_string() }), topology, HEALTH_CHECK_INTERVAL).await
+    }
+
+    async fn with_connector(connector: Box<dyn Connector>, topology: AmqpTopology, health_check_interval: Duration) -> Self {
+        let client = AmqpClient {
+            shared: Arc::new(Shared { topology, connector, link: RwLock::new(None) }),
+        };
+        match client.shared.reconnect().await {
+            Ok(()) => tracing::info!("✅ AMQP Connected"),
+            Err(e) => tracing::warn!("AMQP unavailable, retrying in the background: {}", e),
+        }
+        client.start_health_check(health_check_interval);
+        client
+    }
+
+    /// The exchange messages are published to
+    pub fn exchange(&self) -> &str {
+        &self.shared.topology.exchange
+    }
+
+    /// Every `interval`, replace a dropped connection with a new one, redeclaring the topology.
+    /// Stops once the client is dropped.
+    fn start_health_check(&self, interval: Duration) {
+        let shared = Arc::downgrade(&self.shared);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(shared) = shared.upgrade() else {
+                    break;
+                };
+                if shared.open_link().is_some() {
+                    continue;
+                }
+
+                tracing::warn!("AMQP connection lost, reconnecting...");
+                match shared.reconnect().await {
+                    Ok(()) => tracing::info!("✅ AMQP Reconnected"),
+                    Err(e) => tracing::error!("AMQP reconnect failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Whether the connection to the broker is currently open
+    pub fn is_connected(&self) -> bool {
+        self.shared.open_link().is_some()
+    }
+
+    /// Publish `payload` to the exchange under `routing_key`. Fails immediately while
+    /// disconnected rather than waiting for the connection to come back.
+    pub async fn publish(&self, routing_key: &str, payload: &[u8]) -> Result<()> {
+        self.shared.publish(routing_key, payload).await
+    }
+
+    /// Publish each of `events` as JSON under its topic, until the client or the sender is dropped.
+    /// Events that can't be published, while disconnected, are logged and skipped.
+    pub fn forward_events(&self, mut events: broadcast::Receiver<PublishedEvent>) {
+        let shared = Arc::downgrade(&self.shared);
+
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("AMQP publisher fell behind and skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Some(shared) = shared.upgrade() else {
+                    break;
+                };
+                if let Err(e) = shared.publish(&event.topic, event.payload.to_string().as_bytes()).await {
+                    tracing::error!("{}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    /// What a fake link was asked to publish: exchange, routing key and payload
+    type Published = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+    struct FakeLink {
+        open: AtomicBool,
+        published: Published,
+    }
+
+    #[async_trait]
+    impl Link for FakeLink {
+        fn is_open(&self) -> bool {
+            self.open.load(Ordering::SeqCst)
+        }
+
+        async fn publish(&self, exchange: &str, routing_key: &str, payload: &[u8]) -> Result<()> {
+            self.published.lock().unwrap().push((exchange.to_string(), routing_key.to_string(), payload.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// Stands in for the broker, handing out links that tests can drop
+    #[derive(Clone, Default)]
+    struct FakeBroker {
+        reachable: Arc<AtomicBool>,
+        links: Arc<Mutex<Vec<Arc<FakeLink>>>>,
+        /// The topology declared on each connection
+        topologies: Arc<Mutex<Vec<AmqpTopology>>>,
+        published: Published,
+    }
+
+    impl FakeBroker {
+        fn reachable() -> Self {
+            let broker = FakeBroker::default();
+            broker.reachable.store(true, Ordering::SeqCst);
+            broker
+        }
+
+        /// Close the current connection, as a broker restart would
+        fn drop_connection(&self) {
+            if let Some(link) = self.links.lock().unwrap().last() {
+                link.open.store(false, Ordering::SeqCst);
+            }
+        }
+
+        fn connections(&self) -> usize {
+            self.links.lock().unwrap().len()
+        }
+    }
+
+    #[async_trait]
+    impl Connector for FakeBroker {
+        async fn connect(&self, topology: &AmqpTopology) -> Result<Arc<dyn Link>> {
+            if !self.reachable.load(Ordering::SeqCst) {
+                return Err(anyhow!("Connection refused"));
+            }
+            self.topologies.lock().unwrap().push(topology.clone());
+            let link = Arc::new(FakeLink { open: AtomicBool::new(true), published: self.published.clone() });
+            self.links.lock().unwrap().push(link.clone());
+            Ok(link)
+        }
+    }
+
+    async fn wait_until_connected(client: &AmqpClient) {
+        for _ in 0..100 {
+            if client.is_connected() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the client never reconnected");
+    }
+
+    #[tokio::test]
+    async fn test_publish_fails_fast_while_disconnected() {
+        // Nothing listens on the port, so every connection attempt fails
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let client = AmqpClient::connect(&format!("amqp://127.0.0.1:{}/%2f", port), AmqpTopology::default()).await;
+
+        assert!(!client.is_connected());
+        let published = tokio::time::timeout(Duration::from_secs(1), client.publish("invoice.paid", b"{}")).await
+            .expect("publish should not wait for a reconnection");
+        assert!(published.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_connection_drops() {
+        let broker = FakeBroker::reachable();
+        let client = AmqpClient::with_connector(Box::new(broker.clone()), AmqpTopology::default(), Duration::from_millis(10)).await;
+        client.publish("invoice.paid", b"{}").await.unwrap();
+
+        broker.drop_connection();
+        assert!(!client.is_connected());
+        assert!(client.publish("invoice.paid", b"{}").await.is_err());
+
+        wait_until_connected(&client).await;
+        client.publish("invoice.paid", b"{}").await.unwrap();
+        assert_eq!(broker.connections(), 2);
+        assert_eq!(broker.published.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_broker_at_startup_is_retried() {
+        let broker = FakeBroker::default();
+        let client = AmqpClient::with_connector(Box::new(broker.clone()), AmqpTopology::default(), Duration::from_millis(10)).await;
+        assert!(!client.is_connected());
+
+        broker.reachable.store(true, Ordering::SeqCst);
+        wait_until_connected(&client).await;
+        client.publish("invoice.paid", b"{}").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forwards_dispatched_events() {
+        use crate::event_dispatcher::EventDispatcher;
+        use crate::types::{InvoiceEvent, InvoiceStatus};
+
+        let broker = FakeBroker::reachable();
+        let client = AmqpClient::with_connector(Box::new(broker.clone()), AmqpTopology::default(), Duration::from_secs(60)).await;
+        let event_dispatcher = EventDispatcher::new();
+        client.forward_events(event_dispatcher.subscribe_events());
+
+        event_dispatcher.publish_invoice_event(InvoiceEvent { uid: "inv_123".to_string(), status: InvoiceStatus::Paid });
+
+        for _ in 0..100 {
+            if !broker.published.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let published = broker.published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        let (exchange, routing_key, payload) = &published[0];
+        assert_eq!(exchange, DEFAULT_EXCHANGE);
+        assert_eq!(routing_key, "invoice.paid");
+        let payload: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        assert_eq!(payload["uid"], "inv_123");
+    }
+
+    #[test]
+    fn test_exchange_type_parses_case_insensitively() {
+        assert_eq!("Topic".parse::<ExchangeType>().unwrap(), ExchangeType::Topic);
+        assert_eq!(" fanout ".parse::<ExchangeType>().unwrap(), ExchangeType::Fanout);
+        assert!("queue".parse::<ExchangeType>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publishes_to_configured_exchange() {
+        let broker = FakeBroker::reachable();
+        let topology = AmqpTopology {
+            exchange: "anypay-test-events".to_string(),
+            exchange_type: ExchangeType::Direct,
+            queue: Some("anypay-test-invoices".to_string()),
+            binding_key: "invoice.paid".to_string(),
+        };
+        let client = AmqpClient::with_connector(Box::new(broker.clone()), topology.clone(), Duration::from_secs(60)).await;
+        assert_eq!(client.exchange(), "anypay-test-events");
+        assert_eq!(broker.topologies.lock().unwrap().as_slice(), &[topology]);
+
+        client.publish("invoice.paid", b"{\"uid\":\"inv_123\"}").await.unwrap();
+
+        let published = broker.published.lock().unwrap();
+        assert_eq!(
+            published.as_slice(),
+            &[("anypay-test-events".to_string(), "invoice.paid".to_string(), b"{\"uid\":\"inv_123\"}".to_vec())]
+        );
     }
-} 
\ No newline at end of file
+}
@@ -1,14 +1,21 @@
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use axum::Server;
 use tracing::info;
 use anyhow::Result;
 use crate::server::AnypayEventsServer;
+use crate::event_dispatcher::EventDispatcher;
 use crate::supabase::SupabaseClient;
 use crate::http::HttpServer;
+use crate::blockbook::BlockbookClient;
 use crate::amqp::AmqpClient;
 use crate::xrpl::XRPLClient;
 use crate::ethereum::EthereumClient;
+use crate::config::Config;
+use crate::status::start_block_watchdog;
+
+/// How often connected chains are checked for stalled block subscriptions
+const BLOCK_WATCHDOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 
 pub struct AnypayServer {
     ws_server: AnypayEventsServer,
@@ -18,125 +25,80 @@ pub struct AnypayServer {
     polygon_client: Option<EthereumClient>,
     avax_client: Option<EthereumClient>,
     bnb_client: Option<EthereumClient>,
+    /// Carries every event the servers publish
+    event_dispatcher: Arc<EventDispatcher>,
+    /// Publishes the dispatcher's events while the server runs
+    amqp: Option<AmqpClient>,
+    http_host: String,
     http_port: u16,
     xrpl_url: Option<String>,
 }
 
 impl AnypayServer {
-    pub async fn new(
-        host: &str,
-        port: u16,
-        http_port: u16,
-        supabase_url: &str,
-        supabase_anon_key: &str,
-        supabase_service_role_key: &str,
-        amqp_url: Option<String>,
-        xrpl_wss_url: Option<String>,
-        eth_wss_url: Option<String>,
-        polygon_wss_url: Option<String>,
-        avax_wss_url: Option<String>,
-        bnb_wss_url: Option<String>,
-    ) -> Result<(Self)> {
+    pub async fn new(config: &Config) -> Result<(Self)> {
+        // One dispatcher carries every event, from the HTTP and WebSocket servers alike
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+
         // Initialize Supabase client
         let supabase = Arc::new(SupabaseClient::new(
-            supabase_url,
-            supabase_anon_key,
-            supabase_service_role_key
-        ));
-
-        // Initialize AMQP if configured
-        if let Some(amqp_url) = &amqp_url {
-            info!("Connecting to AMQP...");
-            AmqpClient::new(amqp_url).await?;
-            info!("✅ AMQP Connected");
-        }
+            &config.supabase_url,
+            &config.supabase_anon_key,
+            &config.supabase_service_role_key
+        )
+        .with_price_sources(config.price_sources())
+        .with_event_dispatcher(event_dispatcher.clone()));
+
+        // Initialize AMQP if configured; an unreachable broker is retried by the health check
+        let amqp = match &config.amqp_url {
+            Some(amqp_url) => {
+                info!("Connecting to AMQP...");
+                let amqp = AmqpClient::connect(amqp_url, config.amqp_topology.clone()).await;
+                amqp.forward_events(event_dispatcher.subscribe_events());
+                Some(amqp)
+            }
+            None => None,
+        };
 
         // Initial price load and start updater
         supabase.refresh_prices().await?;
         SupabaseClient::start_price_updater(supabase.clone());
+        SupabaseClient::start_invoice_expiry_sweeper(supabase.clone());
+        start_block_watchdog(BLOCK_WATCHDOG_INTERVAL);
 
         // Initialize WebSocket server
-        let ws_addr = format!("{}:{}", host, port);
+        let ws_addr = format!("{}:{}", config.websocket_host, config.websocket_port);
         let ws_server = AnypayEventsServer::new(
             &ws_addr,
-            supabase_url,
-            supabase_anon_key,
-            supabase_service_role_key,
-        );
+            &config.supabase_url,
+            &config.supabase_anon_key,
+            &config.supabase_service_role_key,
+        )
+        .with_event_dispatcher(event_dispatcher.clone())
+        .with_backpressure(config.websocket_send_buffer, config.websocket_backpressure)
+        .with_max_subscriptions(config.websocket_max_subscriptions)
+        .with_rate_limit(config.rate_limit)
+        .with_price_sources(config.price_sources());
 
         // Initialize HTTP server
-        let http_server = HttpServer::new(supabase);
-
-        // Initialize blockchain clients
-        let eth_client = if let Some(ws_url) = eth_wss_url {
-            info!("Connecting to Ethereum node...");
-            match EthereumClient::new("ETH", &ws_url).await {
-                Ok(client) => {
-                    info!("Connected to Ethereum node");
-                    client.subscribe_blocks().await?;
-                    Some(client)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to connect to Ethereum node: {}", e);
-                    None
-                }
-            }
-        } else {
-            None
-        };
-
-        let polygon_client = if let Some(ws_url) = polygon_wss_url {
-            info!("Connecting to Polygon node...");
-            match EthereumClient::new("POLYGON", &ws_url).await {
-                Ok(client) => {
-                    info!("Connected to Polygon node");
-                    client.subscribe_blocks().await?;
-                    Some(client)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to connect to Polygon node: {}", e);
-                    None
-                }
-            }
-        } else {
-            None
-        };
-
-        let avax_client = if let Some(ws_url) = avax_wss_url {
-            info!("Connecting to Avalanche node...");
-            match EthereumClient::new("AVAX", &ws_url).await {
-                Ok(client) => {
-                    info!("Connected to Avalanche node");
-                    client.subscribe_blocks().await?;
-                    Some(client)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to connect to Avalanche node: {}", e);
-                    None
-                }
-            }
-        } else {
-            None
-        };
+        let mut http_server = HttpServer::new(supabase.clone())
+            .with_cors_allowed_origins(config.cors_allowed_origins.clone())
+            .with_max_body_bytes(config.http_max_body_bytes)
+            .with_network(config.payment_network.clone())
+            .with_admin_api_key(config.admin_api_key.clone())
+            .with_rate_limit(config.rate_limit);
+        if let (Some(blockbook_url), Some(api_key)) = (&config.blockbook_url, &config.blockbook_api_key) {
+            http_server = http_server.with_blockbook(
+                BlockbookClient::new(blockbook_url.clone(), api_key.clone(), supabase.as_ref().clone())
+            );
+        }
 
-        let bnb_client = if let Some(ws_url) = bnb_wss_url {
-            info!("Connecting to Binance Smart Chain node...");
-            match EthereumClient::new("BNB", &ws_url).await {
-                Ok(client) => {
-                    info!("Connected to BSC node");
-                    client.subscribe_blocks().await?;
-                    Some(client)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to connect to BSC node: {}", e);
-                    None
-                }
-            }
-        } else {
-            None
-        };
+        // Initialize blockchain clients; an unreachable node disables that chain rather than the server
+        let eth_client = connect_evm_client("ETH", "Ethereum", config.eth_wss_url.as_deref()).await;
+        let polygon_client = connect_evm_client("POLYGON", "Polygon", config.polygon_wss_url.as_deref()).await;
+        let avax_client = connect_evm_client("AVAX", "Avalanche", config.avax_wss_url.as_deref()).await;
+        let bnb_client = connect_evm_client("BNB", "Binance Smart Chain", config.bnb_wss_url.as_deref()).await;
 
-        let xrpl_client = xrpl_wss_url.as_ref().map(|_| XRPLClient::new());
+        let xrpl_client = config.xrpl_wss_url.as_ref().map(|_| XRPLClient::new());
 
         Ok(Self {
             ws_server,
@@ -146,32 +108,50 @@ impl AnypayServer {
             polygon_client,
             avax_client,
             bnb_client,
-            http_port,
-            xrpl_url: xrpl_wss_url,
+            event_dispatcher,
+            amqp,
+            http_host: config.http_host.clone(),
+            http_port: config.http_port,
+            xrpl_url: config.xrpl_wss_url.clone(),
         })
     }
 
+    /// The dispatcher the servers publish through, for clients started alongside them
+    pub fn event_dispatcher(&self) -> Arc<EventDispatcher> {
+        self.event_dispatcher.clone()
+    }
+
     pub async fn run(self) -> Result<()> {
         let http_app = self.http_server.router();
-        let http_addr = SocketAddr::from(([127, 0, 0, 1], self.http_port));
+        let http_addr = (self.http_host.as_str(), self.http_port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("HTTP_HOST {} has no addresses", self.http_host))?;
 
+        if let Some(amqp) = &self.amqp {
+            info!("Publishing events to AMQP exchange {}", amqp.exchange());
+        }
         info!("Starting WebSocket server...");
-        info!("Starting HTTP server on http://127.0.0.1:{}", self.http_port);
+        info!("Starting HTTP server on http://{}", http_addr);
 
         match self.xrpl_client {
             Some(mut xrpl) => {
                 if let Some(url) = self.xrpl_url {
                     tokio::join!(
                         self.ws_server.run(),
-                        Server::bind(&http_addr).serve(http_app.into_make_service()),
-                        async move { xrpl.run_with_url(&url).await }
+                        Server::bind(&http_addr).serve(http_app.into_make_service_with_connect_info::<SocketAddr>()),
+                        async move {
+                            // The XRPL listener is optional, so a failure leaves the servers running
+                            if let Err(e) = xrpl.run_with_url(&url).await {
+                                tracing::error!("XRPL client stopped: {}", e);
+                            }
+                        }
                     );
                 }
             }
             None => {
                 tokio::join!(
                     self.ws_server.run(),
-                    Server::bind(&http_addr).serve(http_app.into_make_service())
+                    Server::bind(&http_addr).serve(http_app.into_make_service_with_connect_info::<SocketAddr>())
                 );
             }
         }
@@ -179,3 +159,72 @@ impl AnypayServer {
         Ok(())
     }
 }
+
+/// Connect to an EVM node and subscribe to its blocks, logging and returning None on failure
+async fn connect_evm_client(chain: &str, name: &str, ws_url: Option<&str>) -> Option<EthereumClient> {
+    let ws_url = ws_url?;
+    info!("Connecting to {} node...", name);
+
+    let client = match EthereumClient::new(chain, ws_url).await {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to connect to {} node: {}", name, e);
+            return None;
+        }
+    };
+    info!("Connected to {} node", name);
+
+    match client.subscribe_blocks().await {
+        Ok(()) => Some(client),
+        Err(e) => {
+            tracing::error!("Failed to subscribe to {} blocks: {}", name, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Json, Router};
+    use serde_json::json;
+    use crate::supabase::tests::spawn_mock_supabase;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_xrpl_node_does_not_stop_server() {
+        let supabase_url = spawn_mock_supabase(Router::new()
+            .route("/rest/v1/prices", get(|| async { Json(json!([])) })));
+        let http_port = free_port();
+        let config = Config {
+            supabase_url,
+            // Nothing listens on port 1
+            xrpl_wss_url: Some("ws://127.0.0.1:1".to_string()),
+            websocket_port: free_port(),
+            http_port,
+            ..Config::for_tests()
+        };
+
+        let server = AnypayServer::new(&config).await.unwrap();
+
+        let serving = async {
+            let client = reqwest::Client::new();
+            let url = format!("http://127.0.0.1:{}/api/v1/prices", http_port);
+            for _ in 0..50 {
+                if let Ok(response) = client.get(&url).send().await {
+                    return response.status();
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            panic!("HTTP server never came up");
+        };
+
+        tokio::select! {
+            _ = server.run() => panic!("server stopped when the XRPL node was unreachable"),
+            status = serving => assert!(status.is_success()),
+        }
+    }
+}
@@ -1,19 +1,127 @@
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
 use tokio_tungstenite::tungstenite::http::HeaderMap;
 use crate::supabase::SupabaseClient;
 
+/// The account resolved from a request's API token, available to handlers as an `Extension`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthenticatedAccount(pub i32);
+
+/// Extract the API token from a `Bearer <token>` or `Basic base64(<token>:)` authorization header
+pub fn token_from_authorization(value: &str) -> Option<String> {
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        let token = token.trim();
+        return (!token.is_empty()).then(|| token.to_string());
+    }
+
+    if let Some(encoded) = value.strip_prefix("Basic ") {
+        let credentials = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+        // The API key is sent as the username with an empty password
+        let token = credentials.split(':').next().unwrap_or_default();
+        return (!token.is_empty()).then(|| token.to_string());
+    }
+
+    None
+}
+
 pub async fn validate_connection(headers: &HeaderMap, supabase: &SupabaseClient) -> bool {
-    if let Some(auth_header) = headers.get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if let Some(token) = auth_str.strip_prefix("Bearer ") {
-                match supabase.validate_api_key(token).await {
-                    Ok(valid) => return valid,
-                    Err(e) => {
-                        tracing::error!("Error validating API key: {}", e);
-                        return false;
-                    }
-                }
+    let token = headers.get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(token_from_authorization);
+
+    match token {
+        Some(token) => match supabase.validate_api_key(&token).await {
+            Ok(account_id) => account_id.is_some(),
+            Err(e) => {
+                tracing::error!("Error validating API key: {}", e);
+                false
             }
+        },
+        None => false,
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({
+        "status": "error",
+        "message": message
+    }))).into_response()
+}
+
+/// Middleware rejecting requests without a valid API token, and injecting the
+/// authenticated account into the request extensions for those with one
+pub async fn require_account(supabase: Arc<SupabaseClient>, mut req: Request<Body>, next: Next<Body>) -> Response {
+    let token = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(token_from_authorization);
+
+    let Some(token) = token else {
+        return error_response(StatusCode::UNAUTHORIZED, "Missing API token");
+    };
+
+    match supabase.validate_api_key(&token).await {
+        Ok(Some(account_id)) => {
+            req.extensions_mut().insert(AuthenticatedAccount(account_id));
+            next.run(req).await
+        }
+        Ok(None) => error_response(StatusCode::UNAUTHORIZED, "Invalid API token"),
+        Err(e) => {
+            tracing::error!("Error validating API key: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to validate API token")
         }
     }
-    false
-} 
\ No newline at end of file
+}
+
+/// Compare tokens without short-circuiting, so response times don't reveal how much of a guess matched.
+/// Both are hashed first, so the comparison always covers 32 bytes whatever the tokens' lengths.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    given.iter().zip(expected.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Middleware rejecting requests that don't carry the configured admin API key
+pub async fn require_admin(admin_key: Arc<String>, req: Request<Body>, next: Next<Body>) -> Response {
+    let token = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(token_from_authorization);
+
+    match token {
+        Some(token) if tokens_match(&token, &admin_key) => next.run(req).await,
+        Some(_) => error_response(StatusCode::UNAUTHORIZED, "Invalid admin token"),
+        None => error_response(StatusCode::UNAUTHORIZED, "Missing admin token"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_from_authorization() {
+        assert_eq!(token_from_authorization("Bearer abc123"), Some("abc123".to_string()));
+        // base64("abc123:")
+        assert_eq!(token_from_authorization("Basic YWJjMTIzOg=="), Some("abc123".to_string()));
+        assert_eq!(token_from_authorization("Bearer "), None);
+        assert_eq!(token_from_authorization("Token abc123"), None);
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("s3cret", "s3cret"));
+        assert!(!tokens_match("s3cres", "s3cret"));
+        assert!(!tokens_match("s3cret-longer", "s3cret"));
+        assert!(!tokens_match("", "s3cret"));
+    }
+}
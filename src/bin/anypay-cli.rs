@@ -126,6 +126,12 @@ enum Commands {
         #[arg(short, long)]
         uid: String,
     },
+
+    /// Decode a payment URI (pay:?r=..., https://anypayx.com/i/{uid}, anypay:... or a uid) and show its invoice without paying
+    Decode {
+        #[arg(help = "Payment URI or invoice uid")]
+        uri: String,
+    },
 }
 
 async fn create_invoice(
@@ -204,6 +210,23 @@ async fn cancel_invoice(client: &reqwest::Client, uid: &str, api_url: &str) -> R
     Ok(body)
 }
 
+/// One line per output of each payment option in a payment-options invoice response
+fn describe_payment_options(invoice: &Value) -> Vec<String> {
+    let options = invoice["payment_options"].as_array().cloned().unwrap_or_default();
+
+    options.iter()
+        .flat_map(|option| {
+            let chain = option["chain"].as_str().unwrap_or("?").to_string();
+            let currency = option["currency"].as_str().unwrap_or("?").to_string();
+            option["instructions"].as_array().cloned().unwrap_or_default()
+                .into_iter()
+                .flat_map(|instruction| instruction["outputs"].as_array().cloned().unwrap_or_default())
+                .map(move |output| format!("{}/{}: {} to {}",
+                    chain, currency, output["amount"], output["address"].as_str().unwrap_or("?")))
+        })
+        .collect()
+}
+
 async fn get_prices(client: &reqwest::Client, api_url: &str) -> Result<Value, Box<dyn Error>> {
     let response = client
         .get(&format!("{}/api/v1/prices", api_url))
@@ -515,6 +538,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     }
                 },
                 
+                Commands::Decode { uri } => {
+                    let decoded = anypay::uri::parse_payment_uri(&uri)?;
+                    let api_url = decoded.base_url.as_deref().unwrap_or(&cli.api_url);
+                    let invoice = get_invoice(&client, &decoded.uid, api_url).await?;
+
+                    if cli.json {
+                        println!("{}", serde_json::json!({
+                            "uid": decoded.uid,
+                            "base_url": api_url,
+                            "invoice": invoice,
+                        }));
+                    } else {
+                        println!("Invoice: {}", decoded.uid);
+                        println!("Host: {}", api_url);
+                        for line in describe_payment_options(&invoice) {
+                            println!("  {}", line);
+                        }
+                    }
+                },
+
                 Commands::MonitorInvoice { uid } => {
                     // For monitoring, we still use WebSocket
                     let mut url = Url::parse(&cli.ws_url)?;
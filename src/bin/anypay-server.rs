@@ -1,4 +1,5 @@
 use clap::Parser;
+use std::collections::HashMap;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 use anypay::anypay_server::AnypayServer;
@@ -6,21 +7,26 @@ use anyhow::Result;
 use anypay::blockbook::BlockbookClient;
 use tokio::signal;
 use anypay::supabase::SupabaseClient;
+use anypay::config::Config;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Host address to bind to
-    #[arg(long, env = "HOST", default_value = "0.0.0.0")]
-    host: String,
+    /// Host address for the WebSocket server to bind to
+    #[arg(long, env = "HOST")]
+    host: Option<String>,
 
     /// Port to listen on
-    #[arg(long, env = "PORT", default_value = "8080")]
-    port: u16,
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+
+    /// Host address for the HTTP server to bind to
+    #[arg(long, env = "HTTP_HOST")]
+    http_host: Option<String>,
 
     /// HTTP port to listen on
-    #[arg(long, env = "HTTP_PORT", default_value = "3000")]
-    http_port: u16,
+    #[arg(long, env = "HTTP_PORT")]
+    http_port: Option<u16>,
 
     /// Supabase URL
     #[arg(long, env = "SUPABASE_URL")]
@@ -71,8 +77,39 @@ struct Args {
     blockbook_api_key: Option<String>,
 }
 
+impl Args {
+    /// The settings given as flags, or through their environment variables, by Config's variable names
+    fn vars(&self) -> HashMap<&'static str, String> {
+        let mut vars = HashMap::from([
+            ("SUPABASE_URL", self.supabase_url.clone()),
+            ("SUPABASE_ANON_KEY", self.supabase_anon_key.clone()),
+            ("SUPABASE_SERVICE_ROLE_KEY", self.supabase_service_role_key.clone()),
+        ]);
+        for (name, value) in [
+            ("WEBSOCKET_HOST", self.host.clone()),
+            ("WEBSOCKET_PORT", self.port.map(|port| port.to_string())),
+            ("HTTP_HOST", self.http_host.clone()),
+            ("HTTP_PORT", self.http_port.map(|port| port.to_string())),
+            ("AMQP_URL", self.amqp_url.clone()),
+            ("XRPL_WSS_URL", self.xrpl_wss_url.clone()),
+            ("ETH_WSS_URL", self.eth_wss_url.clone()),
+            ("POLYGON_WSS_URL", self.polygon_wss_url.clone()),
+            ("AVAX_WSS_URL", self.avax_wss_url.clone()),
+            ("BNB_WSS_URL", self.bnb_wss_url.clone()),
+            ("BLOCKBOOK_WS_URL", self.blockbook_url.clone()),
+            ("BLOCKBOOK_API_KEY", self.blockbook_api_key.clone()),
+        ] {
+            if let Some(value) = value {
+                vars.insert(name, value);
+            }
+        }
+        vars
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
     let args = Args::parse();
 
     // Setup logging
@@ -86,37 +123,38 @@ async fn main() -> Result<()> {
         .compact()
         .init();
 
+    // Flags take precedence; the remaining settings come from the environment
+    let vars = args.vars();
+    let config = Config::from_vars(|name| vars.get(name).cloned().or_else(|| std::env::var(name).ok()))?;
+    config.validate()?;
+
+    info!("Starting Anypay server...");
+
+    // Initialize server
+    let server = AnypayServer::new(&config).await?;
+
     // Initialize Blockbook client if configured
-    let blockbook_handle = if let Some(blockbook_url) = args.blockbook_url {
-        let api_key = args.blockbook_api_key.ok_or_else(|| {
-            anyhow::anyhow!("Blockbook API key is required when Blockbook URL is provided")
-        })?;
-
-        let supabase = SupabaseClient::new(&args.supabase_url, &args.supabase_anon_key, &args.supabase_service_role_key);
-        let blockbook = BlockbookClient::new(blockbook_url, api_key, supabase);
-        Some(blockbook.start_subscription().await?)
+    let blockbook_handle = if let Some(blockbook_url) = config.blockbook_url.clone() {
+        // validate() guarantees the API key is present alongside the URL
+        let api_key = config.blockbook_api_key.clone().unwrap_or_default();
+
+        let supabase = SupabaseClient::new(&config.supabase_url, &config.supabase_anon_key, &config.supabase_service_role_key);
+        // Payments seen in the mempool are published with the server's events
+        let blockbook = BlockbookClient::new(blockbook_url, api_key, supabase)
+            .with_zero_conf(config.zero_conf_policy)
+            .with_event_dispatcher(server.event_dispatcher());
+        // Blockbook only feeds confirmations, so the servers start without it
+        match blockbook.start_subscription().await {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                tracing::error!("Failed to subscribe to Blockbook: {}", e);
+                None
+            }
+        }
     } else {
         None
     };
 
-    info!("Starting Anypay server...");
-
-    // Initialize and run server
-    let server = AnypayServer::new(
-        &args.host,
-        args.port,
-        args.http_port,
-        &args.supabase_url,
-        &args.supabase_anon_key,
-        &args.supabase_service_role_key,
-        args.amqp_url,
-        args.xrpl_wss_url,
-        args.eth_wss_url,
-        args.polygon_wss_url,
-        args.avax_wss_url,
-        args.bnb_wss_url,
-    ).await?;
-    
     // Wait for shutdown signal
     tokio::select! {
         _ = server.run() => {},
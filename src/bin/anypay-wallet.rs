@@ -1,7 +1,9 @@
 use clap::{Parser, Subcommand};
-use bitcoin::Network;
+use anypay::cards::{parse_language, parse_network};
 use anyhow::{Result, anyhow};
 use std::fmt::{self, Display};
+use std::future::Future;
+use futures::stream::{FuturesUnordered, StreamExt};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -10,6 +12,10 @@ struct Args {
     #[arg(long, env = "ANYPAY_WALLET_SEED_PHRASE")]
     seed_phrase: Option<String>,
 
+    /// BIP39 wordlist language of the seed phrase, e.g. english, spanish, japanese (detected when omitted)
+    #[arg(long, env = "ANYPAY_WALLET_LANGUAGE")]
+    language: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -17,7 +23,11 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Generate a new wallet
-    Generate,
+    Generate {
+        /// Number of words in the seed phrase (12, 15, 18, 21 or 24)
+        #[arg(long, default_value = "24")]
+        words: usize,
+    },
 
     /// Create a new card for a specific chain
     CreateCard {
@@ -29,7 +39,7 @@ enum Commands {
         #[arg(long)]
         currency: String,
 
-        /// Network to use (mainnet or testnet)
+        /// Network to use (mainnet, testnet, signet or regtest)
         #[arg(long, default_value = "mainnet")]
         network: String,
 
@@ -51,7 +61,7 @@ enum Commands {
         #[arg(long)]
         currency: Option<String>,
 
-        /// Network to use (mainnet or testnet)
+        /// Network to use (mainnet, testnet, signet or regtest)
         #[arg(long, default_value = "mainnet")]
         network: String,
 
@@ -73,16 +83,80 @@ enum Commands {
         #[arg(long)]
         currency: String,
 
-        /// Network to use (mainnet or testnet)
+        /// Network to use (mainnet, testnet, signet or regtest)
         #[arg(long, default_value = "mainnet")]
         network: String,
 
         /// Account index to pay from
         #[arg(long, default_value = "0")]
         account: u32,
+
+        /// Further account indices whose UTXOs may fund the payment (repeatable)
+        #[arg(long = "also-account")]
+        also_accounts: Vec<u32>,
+
+        /// Signal replace-by-fee, so the payment can be sped up later with bump-fee
+        #[arg(long)]
+        rbf: bool,
+
+        /// Confirmations to wait for before reporting success, as CHAIN=N (repeatable, e.g. BTC=2);
+        /// chains not listed only wait for the invoice to be marked paid
+        #[arg(long = "confirmations", value_parser = parse_confirmations)]
+        confirmations: Vec<(String, u32)>,
+
+        /// Seconds to wait for the confirmations before giving up
+        #[arg(long, default_value = "3600")]
+        confirmation_timeout: u64,
+    },
+
+    /// Replace a stuck, unconfirmed payment with one paying a higher fee from the same inputs
+    BumpFee {
+        /// Transaction ID of the payment to replace
+        txid: String,
+
+        /// New fee rate in sat/vbyte
+        #[arg(long)]
+        fee_rate: u64,
+
+        /// Chain the payment was made on (BTC, FB)
+        #[arg(long)]
+        chain: String,
+
+        /// Currency the payment was made in (BTC, FB)
+        #[arg(long)]
+        currency: String,
+
+        /// Network to use (mainnet, testnet, signet or regtest)
+        #[arg(long, default_value = "mainnet")]
+        network: String,
+
+        /// Account index the payment was made from
+        #[arg(long, default_value = "0")]
+        account: u32,
+
+        /// Further account indices whose UTXOs funded the payment (repeatable)
+        #[arg(long = "also-account")]
+        also_accounts: Vec<u32>,
     },
 }
 
+/// Parse a CHAIN=N confirmations requirement
+fn parse_confirmations(value: &str) -> Result<(String, u32)> {
+    let (chain, count) = value.split_once('=')
+        .ok_or_else(|| anyhow!("Expected CHAIN=N, e.g. BTC=2, got {}", value))?;
+    let count = count.trim().parse::<u32>()
+        .map_err(|e| anyhow!("Invalid confirmation count in {}: {}", value, e))?;
+    Ok((anypay::symbols::normalize_chain(chain), count))
+}
+
+/// Confirmations required for payments on `chain`, zero when none were asked for
+fn required_confirmations(confirmations: &[(String, u32)], chain: &str) -> u32 {
+    confirmations.iter()
+        .rev()
+        .find(|(required_chain, _)| *required_chain == anypay::symbols::normalize_chain(chain))
+        .map_or(0, |(_, count)| *count)
+}
+
 #[derive(Debug)]
 struct Balance {
     sats: u64,
@@ -98,11 +172,38 @@ impl std::fmt::Display for Balance {
 }
 
 async fn get_balance(card: &Box<dyn anypay::cards::Card>) -> Result<Balance> {
-    let sats = card.get_balance().await?;
-    let btc = card.get_decimal_balance().await?;
-    let usd = card.get_usd_balance().await?;
-    
-    Ok(Balance { sats, btc, usd, currency: String::new() })
+    let balance = card.balance().await?;
+
+    Ok(Balance {
+        sats: balance.smallest_unit,
+        btc: balance.decimal,
+        usd: balance.usd,
+        currency: card.currency().to_string(),
+    })
+}
+
+/// Fetch balances for every item concurrently, reporting each one as it completes.
+/// Returns the USD total of the balances that were fetched successfully.
+async fn fetch_balances_concurrently<T, F, Fut>(
+    items: Vec<T>,
+    fetch: F,
+    mut on_result: impl FnMut(&str, &str, &Result<Balance>),
+) -> f64
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = (String, String, Result<Balance>)>,
+{
+    let mut pending = items.into_iter().map(fetch).collect::<FuturesUnordered<_>>();
+    let mut total_usd = 0.0;
+
+    while let Some((chain, currency, result)) = pending.next().await {
+        if let Ok(balance) = &result {
+            total_usd += balance.usd;
+        }
+        on_result(&chain, &currency, &result);
+    }
+
+    total_usd
 }
 
 #[tokio::main]
@@ -116,19 +217,17 @@ async fn main() -> Result<()> {
             .map_err(|_| anyhow!("No seed phrase provided. Use --seed-phrase or set ANYPAY_WALLET_SEED_PHRASE"))?
     };
 
+    let language = args.language.as_deref().map(parse_language).transpose()?;
+
     match args.command {
-        Commands::Generate => {
-            let wallet = anypay::wallet::Wallet::new()?;
+        Commands::Generate { words } => {
+            let wallet = anypay::wallet::Wallet::generate_in(language.unwrap_or(anypay::cards::Language::English), words)?;
             println!("New wallet generated!");
             println!("Seed phrase: {}", wallet.seed_phrase());
         },
         Commands::CreateCard { chain, currency, network, account } => {
-            let wallet = anypay::wallet::Wallet::from_seed_phrase(&seed_phrase)?;
-            let network = match network.as_str() {
-                "mainnet" => Network::Bitcoin,
-                "testnet" => Network::Testnet,
-                _ => return Err(anyhow!("Invalid network: {}", network))
-            };
+            let wallet = anypay::wallet::Wallet::from_seed_phrase_in(&seed_phrase, language)?;
+            let network = parse_network(&network)?;
             
             let card = wallet.create_card(&chain, &currency, network, account)?;
             println!("Card created successfully!");
@@ -143,12 +242,8 @@ async fn main() -> Result<()> {
             println!("Card listing not yet implemented");
         },
         Commands::Balance { chain, currency, network, account } => {
-            let wallet = anypay::wallet::Wallet::from_seed_phrase(&seed_phrase)?;
-            let network = match network.as_str() {
-                "mainnet" => Network::Bitcoin,
-                "testnet" => Network::Testnet,
-                _ => return Err(anyhow!("Invalid network: {}", network))
-            };
+            let wallet = anypay::wallet::Wallet::from_seed_phrase_in(&seed_phrase, language)?;
+            let network = parse_network(&network)?;
             
             if let (Some(chain), Some(currency)) = (chain, currency) {
                 // Check specific card balance
@@ -161,62 +256,153 @@ async fn main() -> Result<()> {
                 let supported_pairs = vec![
                     ("ETH", "ETH"),
                     ("POLYGON", "MATIC"),
-                    ("XRPL", "XRP"),
+                    ("AVAX", "AVAX"),
+                    ("BNB", "BNB"),
+                    ("XRP", "XRP"),
                     ("SOL", "SOL"),
                     ("DOGE", "DOGE"),
                     ("FB", "FB"),
                     ("BTC", "BTC"),
+                    ("BSV", "BSV"),
                 ];
                 
-                for (chain, currency) in supported_pairs {
-                    if let Ok(card) = wallet.create_card(chain, currency, network, account) {
-                        match get_balance(&card).await {
-                            Ok(balance) => {
-                                println!("Balance for {}/{} card:", chain, currency);
-                                println!("{}", balance);
-                            },
-                            Err(e) => println!("Error getting {}/{} balance: {}", chain, currency, e),
-                        }
-                    }
-                }
+                let cards = supported_pairs.into_iter()
+                    .filter_map(|(chain, currency)| wallet.create_card(chain, currency, network, account).ok())
+                    .collect::<Vec<_>>();
+
+                let total_usd = fetch_balances_concurrently(cards, |card| async move {
+                    let result = get_balance(&card).await;
+                    (card.chain().to_string(), card.currency().to_string(), result)
+                }, |chain, currency, result| match result {
+                    Ok(balance) => {
+                        println!("Balance for {}/{} card:", chain, currency);
+                        println!("{}", balance);
+                    },
+                    Err(e) => println!("Error getting {}/{} balance: {}", chain, currency, e),
+                }).await;
+
+                println!("Total: ${:.2} USD", total_usd);
             }
         },
-        Commands::Pay { invoice, chain, currency, network, account } => {
-            let wallet = anypay::wallet::Wallet::from_seed_phrase(&seed_phrase)?;
+        Commands::Pay { invoice, chain, currency, network, account, also_accounts, rbf, confirmations, confirmation_timeout } => {
+            let wallet = anypay::wallet::Wallet::from_seed_phrase_in(&seed_phrase, language)?;
             
             // Parse network
-            let network = match network.as_str() {
-                "mainnet" => Network::Bitcoin,
-                "testnet" => Network::Testnet,
-                _ => return Err(anyhow!("Invalid network: {}", network))
-            };
+            let network = parse_network(&network)?;
 
             // Get API key from environment
             let api_key = std::env::var("ANYPAY_API_KEY")
                 .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
 
-            // Parse invoice identifier
+            // Parse invoice identifier, talking to the API the invoice was issued by
             println!("Parsing invoice identifier...");
-            let invoice_uid = anypay::wallet::Wallet::parse_invoice_identifier(&invoice)?;
+            let payment_uri = anypay::uri::parse_payment_uri(&invoice)?;
+            let invoice_uid = payment_uri.uid;
+            let mut client = anypay::client::AnypayClient::new(&api_key);
+            if let Some(base_url) = &payment_uri.base_url {
+                client = client.with_api_url(base_url);
+            }
             
             // Fetch invoice details
             println!("Fetching invoice details...");
-            let invoice_details = anypay::wallet::Wallet::fetch_invoice_details(&invoice_uid, &api_key).await?;
+            let invoice_details = anypay::wallet::Wallet::fetch_invoice_details_with(&client, &invoice_uid).await?;
             
-            // Create card for payment
+            // Create cards for payment, the first receiving any change
             println!("Creating card for {}/{}", chain, currency);
-            let card = wallet.create_card(&chain, &currency, network, account)?;
+            let cards = std::iter::once(account).chain(also_accounts)
+                .map(|account| wallet.create_card(&chain, &currency, network, account))
+                .collect::<Result<Vec<_>>>()?;
 
-            // print the card to the console
-            println!("Card: {:?}", card);
-            
             // Execute payment
             println!("Executing payment...");
-            anypay::wallet::Wallet::pay_invoice(&card, &invoice_details).await?;
+            let txid = anypay::wallet::Wallet::pay_invoice_with(
+                &client,
+                &cards,
+                &invoice_details,
+                rbf,
+                &|stage: &anypay::wallet::PaymentStage| println!("{}", stage),
+            ).await?;
             
-            println!("Payment submitted successfully!");
+            println!("Payment submitted successfully: {}", txid);
+
+            // Confirm the server has seen the payment. The transaction is already broadcast, so
+            // an invoice that isn't marked paid in time leaves the payment pending, not failed.
+            println!("Waiting for invoice to be marked paid...");
+            match client.wait_for_payment(
+                &invoice_uid,
+                std::time::Duration::from_secs(60),
+                std::time::Duration::from_secs(2),
+            ).await {
+                Ok(status) => println!("Invoice status: {:?}", status),
+                Err(e) => println!("Payment {} is submitted and pending: {}", txid, e),
+            }
+
+            // Only report success once the payment is buried deep enough, when asked to
+            let required = required_confirmations(&confirmations, cards[0].chain());
+            if required > 0 {
+                println!("Waiting for {} confirmations...", required);
+                let confirmed = client.wait_for_confirmations(
+                    cards[0].chain(),
+                    &txid,
+                    required,
+                    std::time::Duration::from_secs(confirmation_timeout),
+                    std::time::Duration::from_secs(30),
+                ).await?;
+                println!("Payment confirmed with {} confirmations", confirmed);
+            }
+        }
+        Commands::BumpFee { txid, fee_rate, chain, currency, network, account, also_accounts } => {
+            let wallet = anypay::wallet::Wallet::from_seed_phrase_in(&seed_phrase, language)?;
+            let network = parse_network(&network)?;
+
+            let cards = std::iter::once(account).chain(also_accounts)
+                .map(|account| wallet.create_card(&chain, &currency, network, account))
+                .collect::<Result<Vec<_>>>()?;
+
+            let replacement = anypay::wallet::Wallet::bump_fee(
+                &cards,
+                &txid,
+                fee_rate,
+                &|stage: &anypay::wallet::FeeBumpStage| println!("{}", stage),
+            ).await?;
+            println!("Replacement transaction: {}", replacement);
         }
     }
 
     Ok(())
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_confirmations_are_configured_per_chain() {
+        let confirmations = ["btc=2", "FB=6"].into_iter()
+            .map(|value| parse_confirmations(value).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(required_confirmations(&confirmations, "BTC"), 2);
+        assert_eq!(required_confirmations(&confirmations, "FB"), 6);
+        assert_eq!(required_confirmations(&confirmations, "BSV"), 0);
+        assert!(parse_confirmations("BTC").is_err());
+        assert!(parse_confirmations("BTC=two").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_balances_concurrently_queries_all_chains() {
+        // Earlier pairs take longer, so results complete in reverse order
+        let pairs = vec![("ETH", "ETH", 30, 10.0), ("SOL", "SOL", 20, 5.5), ("BTC", "BTC", 10, 2.25)];
+        let mut seen = Vec::new();
+
+        let total = fetch_balances_concurrently(pairs, |(chain, currency, delay, usd)| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            let balance = Balance { sats: 1, btc: 1.0, usd, currency: currency.to_string() };
+            (chain.to_string(), currency.to_string(), Ok(balance))
+        }, |chain, _currency, _result| seen.push(chain.to_string())).await;
+
+        assert_eq!(seen, vec!["BTC", "SOL", "ETH"]);
+        assert_eq!(total, 17.75);
+    }
+}
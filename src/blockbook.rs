@@ -3,17 +3,31 @@ use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use tokio_tungstenite::{connect_async, tungstenite::{Message, http::{Uri, Request, HeaderValue}}};
 use tracing::{info, error};
-use tokio::sync::oneshot;
-use reqwest;
+use tokio::sync::{broadcast, oneshot};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use crate::supabase::SupabaseClient;
-use crate::confirmations;
+use crate::confirmations::{self, InvoiceInfo, PaymentInfo, PaymentSeenEvent, PaymentSeenPayload, ZeroConfPolicy};
+use crate::event_dispatcher::EventDispatcher;
+use crate::status::chain_status;
+use crate::types::{InvoiceStatus, Output, PaymentStatus};
 use chrono::{DateTime, Utc};
 
+/// Chain whose payments this Blockbook instance confirms
+const BLOCKBOOK_CHAIN: &str = "BTC";
+
+/// How often the watched address set is reloaded, picking up new and expired invoices
+const WATCHED_ADDRESS_REFRESH: Duration = Duration::from_secs(30);
+
+/// New blocks buffered for the confirmation service before the oldest are dropped
+const BLOCK_BUFFER: usize = 16;
+
 #[derive(Debug, Serialize)]
 struct SubscribeRequest {
     id: String,
     method: String,
-    params: Vec<String>,
+    params: serde_json::Value,
 }
 
 #[derive(Debug, Deserialize)]
@@ -39,64 +53,132 @@ struct TransactionInput {
 struct TransactionOutput {
     value: String,
     n: u32,
+    #[serde(default)]
     hex: String,
+    #[serde(default)]
     addresses: Vec<String>,
-    #[serde(rename = "isAddress")]
+    #[serde(rename = "isAddress", default)]
     is_address: bool,
 }
 
+// Mempool transactions have no block, which Blockbook reports as height -1 and zero confirmations
 #[derive(Debug, Deserialize)]
 struct TransactionNotification {
     txid: String,
+    #[serde(default)]
     version: u32,
     vin: Vec<TransactionInput>,
     vout: Vec<TransactionOutput>,
-    #[serde(rename = "blockHeight")]
-    block_height: u32,
+    #[serde(rename = "blockHeight", default)]
+    block_height: i64,
+    #[serde(default)]
     confirmations: u32,
-    #[serde(rename = "blockTime")]
+    #[serde(rename = "blockTime", default)]
     block_time: u64,
+    #[serde(default)]
     size: u32,
+    #[serde(default)]
     vsize: u32,
+    #[serde(rename = "blockHash", default)]
+    block_hash: Option<String>,
     value: String,
-    #[serde(rename = "valueIn")]
+    #[serde(rename = "valueIn", default)]
     value_in: String,
     fees: String,
+    #[serde(default)]
     hex: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct BlockbookMessage {
-    id: Option<String>,
-    data: Option<BlockbookData>,
-}
+impl TransactionNotification {
+    /// Base units paid to each output address
+    fn paid_addresses(&self) -> HashMap<String, i64> {
+        let mut paid = HashMap::new();
+        for output in &self.vout {
+            let Ok(value) = output.value.parse::<i64>() else { continue };
+            for address in &output.addresses {
+                *paid.entry(address.clone()).or_insert(0) += value;
+            }
+        }
+        paid
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum BlockbookData {
-    Block(BlockNotification),
-    Transaction(TransactionNotification),
-    Subscription { subscribed: bool },
+    /// Base units paid to each output script, by lowercase hex
+    fn paid_scripts(&self) -> HashMap<String, i64> {
+        let mut paid = HashMap::new();
+        for output in self.vout.iter().filter(|output| !output.hex.is_empty()) {
+            let Ok(value) = output.value.parse::<i64>() else { continue };
+            *paid.entry(output.hex.to_lowercase()).or_insert(0) += value;
+        }
+        paid
+    }
+
+    /// Whether the transaction pays every one of `outputs` in full, fee outputs included.
+    /// Donation outputs ask for nothing, so any positive amount pays them.
+    fn pays_outputs(&self, outputs: &[Output]) -> bool {
+        let mut required_addresses: HashMap<&str, i64> = HashMap::new();
+        let mut required_scripts: HashMap<String, i64> = HashMap::new();
+        for output in outputs {
+            match &output.script {
+                Some(script) => *required_scripts.entry(script.to_lowercase()).or_insert(0) += output.amount,
+                None => *required_addresses.entry(output.address.as_str()).or_insert(0) += output.amount,
+            }
+        }
+
+        let paid_addresses = self.paid_addresses();
+        let paid_scripts = self.paid_scripts();
+        let covers = |paid: Option<&i64>, amount: i64| paid.map_or(false, |&paid| paid > 0 && paid >= amount);
+
+        !outputs.is_empty()
+            && required_addresses.iter().all(|(address, &amount)| covers(paid_addresses.get(*address), amount))
+            && required_scripts.iter().all(|(script, &amount)| covers(paid_scripts.get(script), amount))
+    }
 }
 
+/// A page of a block from the Blockbook REST API
 #[derive(Debug, Deserialize)]
 struct BlockbookBlockResponse {
     hash: String,
     height: u32,
+    #[serde(default)]
     time: i64,
+    #[serde(rename = "totalPages", default)]
+    total_pages: u32,
+    #[serde(default)]
     txs: Vec<BlockbookTransaction>,
 }
 
 #[derive(Debug, Deserialize)]
 struct BlockbookTransaction {
     txid: String,
-    // We can add other fields if needed later
 }
 
+#[derive(Debug, Deserialize)]
+struct BlockbookMessage {
+    id: Option<String>,
+    data: Option<BlockbookData>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BlockbookData {
+    Block(BlockNotification),
+    /// A transaction touching a subscribed address, sent when it enters the mempool and again when mined
+    AddressTransaction { address: String, tx: TransactionNotification },
+    Subscription { subscribed: bool },
+}
+
+#[derive(Clone)]
 pub struct BlockbookClient {
     ws_url: String,
     api_key: String,
     supabase: SupabaseClient,
+    zero_conf: ZeroConfPolicy,
+    /// New blocks, with their txids, for `confirmations`
+    block_tx: broadcast::Sender<confirmations::BlockNotification>,
+    /// Confirms the payments in each new block, and in replayed ones
+    confirmations: Arc<confirmations::ConfirmationService>,
+    /// Addresses of open payment options and unconfirmed payments, shared by clones
+    watched: Arc<RwLock<HashSet<String>>>,
 }
 
 pub struct BlockbookHandle {
@@ -105,7 +187,30 @@ pub struct BlockbookHandle {
 
 impl BlockbookClient {
     pub fn new(ws_url: String, api_key: String, supabase: SupabaseClient) -> Self {
-        Self { ws_url, api_key, supabase }
+        let (block_tx, _) = broadcast::channel(BLOCK_BUFFER);
+        let confirmations = Arc::new(confirmations::ConfirmationService::new(supabase.clone(), block_tx.clone()));
+        Self {
+            ws_url,
+            api_key,
+            supabase,
+            zero_conf: ZeroConfPolicy::Disabled,
+            block_tx,
+            confirmations,
+            watched: Arc::new(RwLock::new(HashSet::new())),
+        }
+    }
+
+    /// Watch the mempool for payments according to `policy`
+    pub fn with_zero_conf(mut self, policy: ZeroConfPolicy) -> Self {
+        self.zero_conf = policy;
+        self
+    }
+
+    /// Publish payment.seen, and the invoice events of the status changes payments cause, to `event_dispatcher`
+    pub fn with_event_dispatcher(mut self, event_dispatcher: Arc<EventDispatcher>) -> Self {
+        self.supabase = self.supabase.with_event_dispatcher(event_dispatcher);
+        self.confirmations = Arc::new(confirmations::ConfirmationService::new(self.supabase.clone(), self.block_tx.clone()));
+        self
     }
 
     pub async fn start_subscription(&self) -> Result<BlockbookHandle> {
@@ -115,80 +220,62 @@ impl BlockbookClient {
         let (mut write, mut read) = ws_stream.split();
 
         // Create shutdown channel
-        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
 
-        // Subscribe to new blocks
+        // Subscribe to new blocks, which feed the chain status and confirm the payments mined in them
         let block_sub = SubscribeRequest {
             id: "1".to_string(),
             method: "subscribeNewBlock".to_string(),
-            params: vec![],
+            params: serde_json::json!({}),
         };
         write.send(Message::Text(serde_json::to_string(&block_sub)?)).await?;
 
-        // Subscribe to new transactions
-        /*let tx_sub = SubscribeRequest {
-            id: "2".to_string(),
-            method: "subscribeNewTransaction".to_string(),
-            params: vec![],
-        };
-        write.send(Message::Text(serde_json::to_string(&tx_sub)?)).await?;*/
+        info!("Subscribed to blocks from Blockbook");
+        chain_status().mark_connected("BLOCKBOOK");
+        self.confirmations.start_confirmation_monitoring();
 
-        info!("Subscribed to blocks and transactions from Blockbook");
-
-        let ws_url = self.ws_url.clone();
-        let api_key = self.api_key.clone();
-        let supabase = self.supabase.clone();
+        let client = self.clone();
 
         tokio::spawn(async move {
-            tokio::select! {
-                _ = shutdown_rx => {
-                    info!("Shutting down Blockbook subscription");
-                    let _ = write.close().await;
-                }
-                () = async {
-                    while let Some(msg) = read.next().await {
-                        match msg {
-                            Ok(Message::Text(text)) => {
-                                // Log raw message first
-                                info!("Raw Blockbook message: {}", text);
-
-                                match serde_json::from_str::<BlockbookMessage>(&text) {
-                                    Ok(block_msg) => {
-                                        if let Some(data) = block_msg.data {
-                                            match data {
-                                                BlockbookData::Block(block) => {
-                                                    info!("New block: hash={} height={}", block.hash, block.height);
-                                                    let client = BlockbookClient::new(ws_url.clone(), api_key.clone(), supabase.clone());
-                                                    if let Err(e) = client.process_block(&block).await {
-                                                        error!("Failed to process block {}: {}", block.hash, e);
-                                                    }
-                                                }
-                                                BlockbookData::Transaction(tx) => {
-                                                    info!(
-                                                        "New transaction: txid={} value={} fees={} inputs={} outputs={}",
-                                                        tx.txid,
-                                                        tx.value,
-                                                        tx.fees,
-                                                        tx.vin.len(),
-                                                        tx.vout.len()
-                                                    );
-                                                }
-                                                BlockbookData::Subscription { subscribed } => {
-                                                    info!("Subscription update: subscribed={}", subscribed);
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(e) => error!("Failed to parse blockbook message: {} (raw: {})", e, text),
+            // The first tick fires immediately, subscribing to the initial address set
+            let mut refresh = tokio::time::interval(WATCHED_ADDRESS_REFRESH);
+
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown_rx => {
+                        info!("Shutting down Blockbook subscription");
+                        let _ = write.close().await;
+                        break;
+                    }
+                    _ = refresh.tick() => {
+                        match client.refresh_watched_addresses().await {
+                            Ok(true) => {
+                                let addresses = client.watched_addresses();
+                                info!("Watching {} addresses on Blockbook", addresses.len());
+                                let address_sub = SubscribeRequest {
+                                    id: "2".to_string(),
+                                    method: "subscribeAddresses".to_string(),
+                                    params: serde_json::json!({ "addresses": addresses }),
+                                };
+                                let request = serde_json::to_string(&address_sub).unwrap_or_default();
+                                if let Err(e) = write.send(Message::Text(request)).await {
+                                    error!("Failed to subscribe to addresses: {}", e);
                                 }
                             }
-                            Err(e) => error!("WebSocket error: {}", e),
-                            _ => {}
+                            Ok(false) => {}
+                            Err(e) => error!("Failed to load watched addresses: {}", e),
                         }
                     }
-                } => {}
+                    msg = read.next() => match msg {
+                        Some(Ok(Message::Text(text))) => client.handle_message(&text).await,
+                        Some(Err(e)) => error!("WebSocket error: {}", e),
+                        Some(Ok(_)) => {}
+                        None => break,
+                    }
+                }
             }
             info!("WebSocket connection closed");
+            chain_status().mark_disconnected("BLOCKBOOK");
         });
 
         Ok(BlockbookHandle {
@@ -196,46 +283,186 @@ impl BlockbookClient {
         })
     }
 
-    async fn get_block_txids(&self, hash: &str) -> Result<Vec<String>> {
-        let url = format!("https://{}/{}/api/v2/block/{}", self.ws_url, self.api_key, hash);
-        let response = reqwest::Client::new()
-            .get(&url)
-            .header("api-key", &self.api_key)
-            .send()
-            .await?
-            .json::<BlockbookBlockResponse>()
-            .await?;
-
-        // Extract just the txids from transactions
-        Ok(response.txs.into_iter().map(|tx| tx.txid).collect())
-    }
-
-    async fn process_block(&self, block: &BlockNotification) -> Result<()> {
-        info!("Processing block {} at height {}", block.hash, block.height);
-        
-        let txids = self.get_block_txids(&block.hash).await?;
-        
-        for txid in txids {
-            if let Some(payment) = self.supabase.get_unconfirmed_payment_by_txid(&txid).await? {
-                let confirmation = confirmations::Confirmation {
-                    confirmation_hash: block.hash.clone(),
-                    confirmation_height: block.height as i32,
-                    confirmation_date: if block.timestamp > 0 {
-                        DateTime::from_timestamp(block.timestamp, 0)
-                            .unwrap_or_else(|| Utc::now())
-                    } else {
-                        Utc::now()
-                    },
-                    confirmations: Some(1),
-                };
+    async fn handle_message(&self, text: &str) {
+        // Log raw message first
+        info!("Raw Blockbook message: {}", text);
+
+        let data = match serde_json::from_str::<BlockbookMessage>(text) {
+            Ok(message) => message.data,
+            Err(e) => {
+                error!("Failed to parse blockbook message: {} (raw: {})", e, text);
+                return;
+            }
+        };
 
-                match self.supabase.confirm_payment(payment, confirmation).await {
-                    Ok(_) => info!("Confirmed payment for txid {}", txid),
-                    Err(e) => error!("Failed to confirm payment for txid {}: {}", txid, e),
+        match data {
+            Some(BlockbookData::Block(block)) => {
+                info!("New block: hash={} height={}", block.hash, block.height);
+                chain_status().record_block("BLOCKBOOK", block.height as u64);
+                // The notification carries no txids, so fetch the block for the confirmation service
+                match self.fetch_block(block.height).await {
+                    Ok(block) => {
+                        let _ = self.block_tx.send(block);
+                    }
+                    Err(e) => error!("Failed to fetch block {}: {}", block.height, e),
+                }
+            }
+            Some(BlockbookData::AddressTransaction { address, tx }) => {
+                info!(
+                    "New transaction for {}: txid={} value={} fees={} confirmations={}",
+                    address,
+                    tx.txid,
+                    tx.value,
+                    tx.fees,
+                    tx.confirmations
+                );
+                if let Err(e) = self.process_address_transaction(&address, &tx).await {
+                    error!("Failed to process transaction {}: {}", tx.txid, e);
                 }
             }
+            Some(BlockbookData::Subscription { subscribed }) => {
+                info!("Subscription update: subscribed={}", subscribed);
+            }
+            None => {}
+        }
+    }
+
+    /// Reload the watched addresses from the database, returning whether the set changed
+    async fn refresh_watched_addresses(&self) -> Result<bool> {
+        let addresses = self.supabase.get_watched_addresses(BLOCKBOOK_CHAIN).await?;
+        let mut watched = self.watched.write().unwrap();
+        if *watched == addresses {
+            return Ok(false);
+        }
+        *watched = addresses;
+        Ok(true)
+    }
+
+    /// The watched addresses, sorted so subscriptions are deterministic
+    fn watched_addresses(&self) -> Vec<String> {
+        let mut addresses = self.watched.read().unwrap().iter().cloned().collect::<Vec<_>>();
+        addresses.sort();
+        addresses
+    }
+
+    /// Fetch every txid in the block at `height`, following Blockbook's pagination
+    pub async fn fetch_block(&self, height: u32) -> Result<confirmations::BlockNotification> {
+        let client = reqwest::Client::new();
+        let mut page = 1;
+        let mut block = confirmations::BlockNotification { height, ..Default::default() };
+
+        loop {
+            // The key goes in a header only, so it stays out of proxy and access logs
+            let url = format!("https://{}/api/v2/block/{}?page={}", self.ws_url, height, page);
+            let response = client
+                .get(&url)
+                .header("api-key", &self.api_key)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<BlockbookBlockResponse>()
+                .await?;
+
+            block.hash = response.hash;
+            block.timestamp = response.time;
+            block.txids.extend(response.txs.into_iter().map(|tx| tx.txid));
+
+            if page >= response.total_pages {
+                return Ok(block);
+            }
+            page += 1;
+        }
+    }
+
+    /// Re-scan blocks `from_height` to `to_height` for payments missed while the subscription was down
+    pub async fn replay_blocks(&self, from_height: u32, to_height: u32) -> Result<confirmations::ReplaySummary> {
+        self.confirmations.replay_blocks(from_height, to_height, |height| self.fetch_block(height)).await
+    }
+
+    /// Handle a transaction paying a subscribed address: unconfirmed ones go through the zero-conf
+    /// policy, mined ones confirm the payment recorded for the txid. Returns the confirmed payment.
+    async fn process_address_transaction(&self, address: &str, tx: &TransactionNotification) -> Result<Option<confirmations::Payment>> {
+        // A stale notification for an address whose invoice has since closed
+        if !self.watched.read().unwrap().contains(address) {
+            return Ok(None);
+        }
+
+        if tx.confirmations == 0 {
+            self.process_transaction(address, tx).await?;
+            return Ok(None);
+        }
+
+        let Some(payment) = self.supabase.get_unconfirmed_payment_by_txid(&tx.txid).await? else {
+            return Ok(None);
+        };
+
+        let confirmation = confirmations::Confirmation {
+            confirmation_hash: tx.block_hash.clone().unwrap_or_default(),
+            confirmation_height: tx.block_height as i32,
+            confirmation_date: DateTime::from_timestamp(tx.block_time as i64, 0)
+                .filter(|_| tx.block_time > 0)
+                .unwrap_or_else(Utc::now),
+            confirmations: Some(tx.confirmations as i32),
+        };
+
+        let confirmed = self.supabase.confirm_payment(payment, confirmation).await?;
+        info!("Confirmed payment for txid {}", tx.txid);
+        Ok(Some(confirmed))
+    }
+
+    /// Publish payment.seen for the payment an unconfirmed transaction makes to `address`, recording
+    /// the payment as well when the zero-conf policy accepts it. Blockbook notifies once per subscribed
+    /// address a transaction touches, so the other addresses it pays are left to their own notifications.
+    async fn process_transaction(&self, address: &str, tx: &TransactionNotification) -> Result<Vec<PaymentSeenEvent>> {
+        if self.zero_conf == ZeroConfPolicy::Disabled || tx.confirmations > 0 {
+            return Ok(Vec::new());
+        }
+
+        let Some(amount) = tx.paid_addresses().get(address).copied() else {
+            return Ok(Vec::new());
+        };
+        let mut events = Vec::new();
+
+        for (invoice, option) in self.supabase.get_watched_payment_options(&[address.to_string()]).await? {
+            if option.address != address {
+                continue;
+            }
+            // Paying the merchant in full isn't enough when the option also has a fee output
+            let accepted = self.zero_conf == ZeroConfPolicy::Accept && tx.pays_outputs(&option.outputs);
+
+            if accepted {
+                self.supabase.create_payment(&invoice.uid, &option.chain, &option.currency, &tx.txid, amount).await?;
+                self.supabase.update_invoice_status(&invoice.uid, InvoiceStatus::Paid).await?;
+            }
+
+            let event = PaymentSeenEvent {
+                topic: "payment.seen".to_string(),
+                payload: PaymentSeenPayload {
+                    account_id: Some(invoice.account_id.to_string()),
+                    payment: PaymentInfo {
+                        chain: option.chain.clone(),
+                        currency: option.currency.clone(),
+                        txid: tx.txid.clone(),
+                        status: PaymentStatus::Unconfirmed,
+                    },
+                    invoice: InvoiceInfo {
+                        uid: invoice.uid.clone(),
+                        status: if accepted { InvoiceStatus::Paid } else { invoice.status },
+                    },
+                    address: option.address.clone(),
+                    amount,
+                    accepted,
+                },
+            };
+
+            info!("Payment seen for invoice {}: txid={} amount={}", invoice.uid, tx.txid, amount);
+            if let Some(event_dispatcher) = self.supabase.event_dispatcher() {
+                event_dispatcher.publish_event(&event.topic, &event.payload);
+            }
+            events.push(event);
         }
-        Ok(())
+
+        Ok(events)
     }
 }
 
@@ -243,4 +470,293 @@ impl BlockbookHandle {
     pub async fn shutdown(self) {
         let _ = self.shutdown.send(());
     }
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Query, http::StatusCode, routing::{get, post}, Json, Router};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use crate::supabase::tests::{invoice_transition_rows, spawn_mock_supabase};
+
+    const WATCHED_ADDRESS: &str = "bc1qp5wfcq48h6d63wyy9qz0awtpfqwwv4sma86mhz";
+    const OTHER_ADDRESS: &str = "bc1qrfxr69jqnhwufxgkqgcdep9prq4j4vuw2wyg0v";
+    const BLOCK_HASH: &str = "00000000000000000001d8f3c4a9b1e2f5a6c7d8e9f0a1b2c3d4e5f6a7b8c9d0";
+
+    const FEE_ADDRESS: &str = "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh";
+
+    fn watched_option_row() -> serde_json::Value {
+        json!({
+            "invoice_uid": "inv_1",
+            "currency": "BTC",
+            "chain": "BTC",
+            "amount": 20_000,
+            "address": WATCHED_ADDRESS,
+            "outputs": [{ "address": WATCHED_ADDRESS, "amount": 20_000 }],
+            "uri": "anypay:btc_inv_1",
+            "fee": 0,
+            "createdAt": "2024-01-01T00:00:00Z",
+            "updatedAt": "2024-01-01T00:00:00Z",
+            "expires": "2099-01-01T00:00:00Z"
+        })
+    }
+
+    /// An unpaid invoice inv_1 with a BTC option for 20,000 sats to WATCHED_ADDRESS, recording status updates
+    fn mock_watched_invoice(statuses: Arc<Mutex<Vec<serde_json::Value>>>) -> Router {
+        mock_watched_option(watched_option_row(), statuses)
+    }
+
+    /// Like `mock_watched_invoice`, with `option` as inv_1's payment option
+    fn mock_watched_option(option: serde_json::Value, statuses: Arc<Mutex<Vec<serde_json::Value>>>) -> Router {
+        mock_watched_options(vec![option], statuses)
+    }
+
+    /// An unpaid invoice for each of `options`, serving the options paying to the queried addresses
+    fn mock_watched_options(options: Vec<serde_json::Value>, statuses: Arc<Mutex<Vec<serde_json::Value>>>) -> Router {
+        let invoices = options.iter()
+            .map(|option| option["invoice_uid"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        Router::new()
+            .route("/rest/v1/payment_options", get(move |Query(params): Query<HashMap<String, String>>| async move {
+                let queried = params.get("address").cloned().unwrap_or_default();
+                let rows = options.into_iter()
+                    .filter(|option| queried.contains(option["address"].as_str().unwrap()))
+                    .collect::<Vec<_>>();
+                Json(rows)
+            }))
+            .route("/rest/v1/invoices", get(move || async move {
+                Json(invoices.iter().enumerate().map(|(i, uid)| json!({
+                    "id": i + 1,
+                    "uid": uid,
+                    "amount": 1000,
+                    "currency": "USD",
+                    "status": "unpaid",
+                    "account_id": 7,
+                    "complete": false,
+                    "webhook_url": null,
+                    "redirect_url": null,
+                    "memo": null,
+                    "uri": format!("anypay:btc_{}", uid),
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": "2024-01-01T00:00:00Z"
+                })).collect::<Vec<_>>())
+            }))
+            .route("/rest/v1/rpc/update_invoice_status", post(move |Json(params): Json<serde_json::Value>| async move {
+                statuses.lock().unwrap().push(params["p_status"].clone());
+                Json(invoice_transition_rows(&params))
+            }))
+            .route("/rest/v1/payments", get(|| async { Json(json!([])) }).post(|Json(rows): Json<serde_json::Value>| async move {
+                let mut row = rows[0].clone();
+                row["id"] = json!(1);
+                (StatusCode::CREATED, Json(json!([row])))
+            }))
+    }
+
+    /// A subscribeAddresses notification for a transaction paying `value` sats to WATCHED_ADDRESS,
+    /// in the mempool when `confirmations` is zero
+    fn address_transaction(value: i64, confirmations: u32) -> TransactionNotification {
+        let (block_height, block_hash) = if confirmations == 0 { (-1, None) } else { (840_000, Some(BLOCK_HASH)) };
+        let message: BlockbookMessage = serde_json::from_value(json!({
+            "id": "2",
+            "data": {
+                "address": WATCHED_ADDRESS,
+                "tx": {
+                    "txid": "f".repeat(64),
+                    "vin": [],
+                    "vout": [
+                        { "value": value.to_string(), "n": 0, "addresses": [WATCHED_ADDRESS], "isAddress": true },
+                        { "value": "5000", "n": 1, "addresses": [OTHER_ADDRESS], "isAddress": true }
+                    ],
+                    "blockHeight": block_height,
+                    "blockHash": block_hash,
+                    "confirmations": confirmations,
+                    "blockTime": 1700000000,
+                    "value": (value + 5000).to_string(),
+                    "fees": "300"
+                }
+            }
+        })).unwrap();
+
+        match message.data {
+            Some(BlockbookData::AddressTransaction { address, tx }) => {
+                assert_eq!(address, WATCHED_ADDRESS);
+                tx
+            }
+            other => panic!("expected an address transaction, got {:?}", other),
+        }
+    }
+
+    fn mempool_transaction(value: i64) -> TransactionNotification {
+        address_transaction(value, 0)
+    }
+
+    /// A watched BTC option with a pending payment for the transaction, recording confirmation patches
+    fn mock_unconfirmed_payment(patches: Arc<Mutex<Vec<serde_json::Value>>>) -> Router {
+        let payment = json!({
+            "id": 1,
+            "txid": "f".repeat(64),
+            "chain": "BTC",
+            "currency": "BTC",
+            "status": "pending",
+            "invoice_uid": "inv_1",
+            "amount": 20_000,
+            "confirmation_hash": null,
+            "confirmation_height": null,
+            "confirmation_date": null
+        });
+
+        Router::new()
+            .route("/rest/v1/payment_options", get(|| async { Json(json!([watched_option_row()])) }))
+            .route("/rest/v1/payments", get({
+                let payment = payment.clone();
+                move || async move { Json(json!([payment])) }
+            }).patch(move |Json(body): Json<serde_json::Value>| async move {
+                let mut confirmed = payment.clone();
+                for (key, value) in body.as_object().unwrap() {
+                    confirmed[key] = value.clone();
+                }
+                patches.lock().unwrap().push(body);
+                Json(confirmed)
+            }))
+    }
+
+    fn client(url: &str, policy: ZeroConfPolicy) -> BlockbookClient {
+        BlockbookClient::new("btc.example.com".to_string(), "key".to_string(), SupabaseClient::new(url, "anon", "service"))
+            .with_zero_conf(policy)
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_payment_to_watched_address_emits_payment_seen() {
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let blockbook = client(&spawn_mock_supabase(mock_watched_invoice(statuses.clone())), ZeroConfPolicy::Notify)
+            .with_event_dispatcher(event_dispatcher.clone());
+        let mut events = event_dispatcher.subscribe_events();
+
+        blockbook.process_transaction(WATCHED_ADDRESS, &mempool_transaction(20_000)).await.unwrap();
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.topic, "payment.seen");
+        assert_eq!(event.payload["invoice"]["uid"], "inv_1");
+        assert_eq!(event.payload["invoice"]["status"], "unpaid");
+        assert_eq!(event.payload["payment"]["txid"], "f".repeat(64));
+        assert_eq!(event.payload["address"], WATCHED_ADDRESS);
+        assert_eq!(event.payload["amount"], 20_000);
+        assert_eq!(event.payload["accepted"], false);
+        assert!(events.try_recv().is_err());
+        // Notify leaves the invoice for the block processor to mark paid
+        assert!(statuses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_accept_policy_marks_invoice_paid() {
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let blockbook = client(&spawn_mock_supabase(mock_watched_invoice(statuses.clone())), ZeroConfPolicy::Accept);
+
+        let events = blockbook.process_transaction(WATCHED_ADDRESS, &mempool_transaction(20_000)).await.unwrap();
+
+        assert!(events[0].payload.accepted);
+        assert_eq!(events[0].payload.invoice.status, InvoiceStatus::Paid);
+        assert_eq!(*statuses.lock().unwrap(), vec![json!("paid")]);
+
+        // An underpayment is still reported, but not accepted
+        statuses.lock().unwrap().clear();
+        let events = blockbook.process_transaction(WATCHED_ADDRESS, &mempool_transaction(10_000)).await.unwrap();
+        assert!(!events[0].payload.accepted);
+        assert!(statuses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_accept_policy_requires_the_fee_output() {
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let mut option = watched_option_row();
+        option["outputs"] = json!([
+            { "address": WATCHED_ADDRESS, "amount": 19_000 },
+            { "address": FEE_ADDRESS, "amount": 1_000 }
+        ]);
+        let blockbook = client(&spawn_mock_supabase(mock_watched_option(option, statuses.clone())), ZeroConfPolicy::Accept);
+
+        // The whole amount to the merchant leaves the fee unpaid
+        let events = blockbook.process_transaction(WATCHED_ADDRESS, &mempool_transaction(20_000)).await.unwrap();
+
+        assert!(!events[0].payload.accepted);
+        assert_eq!(events[0].payload.invoice.status, InvoiceStatus::Unpaid);
+        assert!(statuses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_only_watched_addresses_trigger_confirmation() {
+        let patches = Arc::new(Mutex::new(Vec::new()));
+        let blockbook = client(&spawn_mock_supabase(mock_unconfirmed_payment(patches.clone())), ZeroConfPolicy::Notify);
+
+        assert!(blockbook.refresh_watched_addresses().await.unwrap());
+        assert_eq!(blockbook.watched_addresses(), vec![WATCHED_ADDRESS]);
+        // Nothing changed, so there is nothing to resubscribe
+        assert!(!blockbook.refresh_watched_addresses().await.unwrap());
+
+        let tx = address_transaction(20_000, 1);
+        assert!(blockbook.process_address_transaction(OTHER_ADDRESS, &tx).await.unwrap().is_none());
+        assert!(patches.lock().unwrap().is_empty());
+
+        let confirmed = blockbook.process_address_transaction(WATCHED_ADDRESS, &tx).await.unwrap().unwrap();
+        assert_eq!(confirmed.confirmation_hash.as_deref(), Some(BLOCK_HASH));
+        assert_eq!(confirmed.confirmation_height, Some(840_000));
+        let patches = patches.lock().unwrap();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0]["status"], "confirmed");
+    }
+
+    #[tokio::test]
+    async fn test_transaction_paying_two_watched_addresses_is_processed_once_per_address() {
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let mut second_option = watched_option_row();
+        second_option["invoice_uid"] = json!("inv_2");
+        second_option["address"] = json!(OTHER_ADDRESS);
+        second_option["outputs"] = json!([{ "address": OTHER_ADDRESS, "amount": 20_000 }]);
+        let router = mock_watched_options(vec![watched_option_row(), second_option], statuses.clone());
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let blockbook = client(&spawn_mock_supabase(router), ZeroConfPolicy::Accept)
+            .with_event_dispatcher(event_dispatcher.clone());
+        *blockbook.watched.write().unwrap() = HashSet::from([WATCHED_ADDRESS.to_string(), OTHER_ADDRESS.to_string()]);
+        let mut events = event_dispatcher.subscribe_events();
+
+        let tx: TransactionNotification = serde_json::from_value(json!({
+            "txid": "f".repeat(64),
+            "vin": [],
+            "vout": [
+                { "value": "20000", "n": 0, "addresses": [WATCHED_ADDRESS], "isAddress": true },
+                { "value": "20000", "n": 1, "addresses": [OTHER_ADDRESS], "isAddress": true }
+            ],
+            "blockHeight": -1,
+            "confirmations": 0,
+            "value": "40000",
+            "fees": "300"
+        })).unwrap();
+
+        // Blockbook sends the transaction once for each subscribed address it pays
+        for address in [WATCHED_ADDRESS, OTHER_ADDRESS] {
+            blockbook.process_address_transaction(address, &tx).await.unwrap();
+        }
+
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if event.topic != "payment.seen" {
+                continue;
+            }
+            seen.push((event.payload["invoice"]["uid"].clone(), event.payload["address"].clone()));
+        }
+        assert_eq!(seen, vec![(json!("inv_1"), json!(WATCHED_ADDRESS)), (json!("inv_2"), json!(OTHER_ADDRESS))]);
+        assert_eq!(*statuses.lock().unwrap(), vec![json!("paid"), json!("paid")]);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_policy_ignores_mempool() {
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let blockbook = client(&spawn_mock_supabase(mock_watched_invoice(statuses)), ZeroConfPolicy::Disabled);
+
+        let events = blockbook.process_transaction(WATCHED_ADDRESS, &mempool_transaction(20_000)).await.unwrap();
+
+        assert!(events.is_empty());
+    }
+}
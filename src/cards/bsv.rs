@@ -0,0 +1,248 @@
+use super::{Card, Language, parse_mnemonic, RECEIVE_BRANCH, CHANGE_BRANCH};
+use super::cache::BALANCE_CACHE;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use bitcoin::{
+    Network, Address, PublicKey, ScriptBuf, Amount, Transaction, TxOut,
+    consensus::encode::serialize,
+    hashes::{sha256d, Hash},
+    script::{Builder, PushBytes},
+    secp256k1::{Secp256k1, SecretKey, Message},
+    psbt::Psbt,
+};
+use bip32::DerivationPath;
+use std::str::FromStr;
+
+/// SIGHASH_ALL | SIGHASH_FORKID, the sighash type BSV requires on every signature
+pub const SIGHASH_ALL_FORKID: u32 = 0x41;
+
+/// The digest BSV signs for `input_index`: the BIP143 algorithm applied to a legacy transaction,
+/// committing to the value being spent, with SIGHASH_ALL_FORKID as the sighash type
+pub fn forkid_signature_hash(tx: &Transaction, input_index: usize, script_code: &ScriptBuf, value: Amount) -> Result<[u8; 32]> {
+    let input = tx.input.get(input_index)
+        .ok_or_else(|| anyhow!("Input {} not found in transaction", input_index))?;
+
+    let prevouts: Vec<u8> = tx.input.iter().flat_map(|txin| serialize(&txin.previous_output)).collect();
+    let sequences: Vec<u8> = tx.input.iter().flat_map(|txin| serialize(&txin.sequence)).collect();
+    let outputs: Vec<u8> = tx.output.iter().flat_map(serialize).collect();
+
+    let mut preimage = serialize(&tx.version);
+    preimage.extend(sha256d::Hash::hash(&prevouts).to_byte_array());
+    preimage.extend(sha256d::Hash::hash(&sequences).to_byte_array());
+    preimage.extend(serialize(&input.previous_output));
+    preimage.extend(serialize(script_code));
+    preimage.extend(value.to_sat().to_le_bytes());
+    preimage.extend(serialize(&input.sequence));
+    preimage.extend(sha256d::Hash::hash(&outputs).to_byte_array());
+    preimage.extend(serialize(&tx.lock_time));
+    preimage.extend(SIGHASH_ALL_FORKID.to_le_bytes());
+
+    Ok(sha256d::Hash::hash(&preimage).to_byte_array())
+}
+
+pub struct BitcoinSVCard {
+    network: Network,
+    account: u32,
+    /// Kept to derive change addresses
+    seed: [u8; 64],
+    address: String,
+    derivation_path: String,
+    private_key: SecretKey,
+    public_key: PublicKey,
+}
+
+impl BitcoinSVCard {
+    pub fn new(network: Network, account: u32, seed_phrase: &str, language: Option<Language>) -> Result<Self> {
+        let mnemonic = parse_mnemonic(seed_phrase, language)?;
+        Self::derive(network, account, mnemonic.to_seed(""), RECEIVE_BRANCH, 0)
+    }
+
+    /// Derive the card for address `index` on `branch` (receive or change) of `account`
+    fn derive(network: Network, account: u32, seed: [u8; 64], branch: u32, index: u32) -> Result<Self> {
+        let secp = Secp256k1::new();
+
+        // Derive BIP44 path: m/44'/236'/account'/branch/index for BSV
+        let path = format!("m/44'/236'/{}'/{}/{}", account, branch, index);
+        let derivation_path = DerivationPath::from_str(&path)
+            .map_err(|e| anyhow!("Invalid derivation path: {}", e))?;
+
+        let xpriv = bip32::XPrv::derive_from_path(seed, &derivation_path)
+            .map_err(|e| anyhow!("Failed to derive private key: {}", e))?;
+
+        let private_key = SecretKey::from_slice(&xpriv.private_key().to_bytes())
+            .map_err(|e| anyhow!("Failed to create secret key: {}", e))?;
+        let public_key = PublicKey::new(secp256k1::PublicKey::from_secret_key(&secp, &private_key));
+
+        // BSV never adopted segwit, so payments go to legacy P2PKH addresses
+        let address = Address::p2pkh(&public_key, network);
+
+        Ok(Self {
+            network,
+            account,
+            seed,
+            address: address.to_string(),
+            derivation_path: path,
+            private_key,
+            public_key,
+        })
+    }
+
+    /// The output script of this card's address, which inputs it can sign must spend
+    fn script_pubkey(&self) -> ScriptBuf {
+        ScriptBuf::new_p2pkh(&self.public_key.pubkey_hash())
+    }
+
+    /// Fetch the balance from upstream, bypassing the balance cache
+    async fn fetch_balance(&self) -> Result<u64> {
+        let api_key = std::env::var("ANYPAY_API_KEY")
+            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
+
+        let client = crate::client::AnypayClient::new(&api_key);
+        let utxos = client.get_utxos(&self.address).await?;
+
+        let total_sats: u64 = utxos.iter()
+            .map(|utxo| Amount::from_btc(utxo.amount).unwrap_or(Amount::ZERO))
+            .map(|amount| amount.to_sat())
+            .sum();
+
+        Ok(total_sats)
+    }
+}
+
+#[async_trait]
+impl Card for BitcoinSVCard {
+    fn chain(&self) -> &str {
+        "BSV"
+    }
+
+    fn currency(&self) -> &str {
+        "BSV"
+    }
+
+    fn network(&self) -> Network {
+        self.network
+    }
+
+    fn derivation_path(&self) -> &str {
+        &self.derivation_path
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn account(&self) -> u32 {
+        self.account
+    }
+
+    fn change_card(&self, index: u32) -> Result<Box<dyn Card>> {
+        Ok(Box::new(Self::derive(self.network, self.account, self.seed, CHANGE_BRANCH, index)?))
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        BALANCE_CACHE.get_or_fetch(self.chain(), self.network, &self.address, || self.fetch_balance()).await
+    }
+
+    fn decimals(&self) -> u32 {
+        8
+    }
+
+    async fn get_usd_price(&self) -> Result<f64> {
+        let api_key = std::env::var("ANYPAY_API_KEY")
+            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
+
+        let client = crate::client::AnypayClient::new(&api_key);
+        client.get_price("BSV").await
+    }
+
+    /// Sign and finalize every input spending this card's address. BSV signatures cover the
+    /// spent value, so each input needs its previous output as `witness_utxo` or `non_witness_utxo`.
+    fn sign_transaction(&self, psbt: &mut Psbt) -> Result<()> {
+        let secp = Secp256k1::new();
+        let script_pubkey = self.script_pubkey();
+
+        for i in 0..psbt.inputs.len() {
+            let input = &psbt.inputs[i];
+            let vout = psbt.unsigned_tx.input[i].previous_output.vout as usize;
+            let spent: Option<TxOut> = input.witness_utxo.clone()
+                .or_else(|| input.non_witness_utxo.as_ref().and_then(|tx| tx.output.get(vout).cloned()));
+
+            let Some(spent) = spent.filter(|spent| spent.script_pubkey == script_pubkey) else {
+                continue;
+            };
+
+            let sighash = forkid_signature_hash(&psbt.unsigned_tx, i, &script_pubkey, spent.value)?;
+            let msg = Message::from_digest_slice(&sighash)
+                .map_err(|e| anyhow!("Failed to create message: {}", e))?;
+            let mut sig_bytes = secp.sign_ecdsa(&msg, &self.private_key).serialize_der().to_vec();
+            sig_bytes.push(SIGHASH_ALL_FORKID as u8);
+
+            let sig: &PushBytes = sig_bytes.as_slice().try_into()
+                .map_err(|e| anyhow!("Failed to push signature: {}", e))?;
+            psbt.inputs[i].final_script_sig = Some(Builder::new()
+                .push_slice(sig)
+                .push_key(&self.public_key)
+                .into_script());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{OutPoint, Sequence, TxIn, Witness, absolute::LockTime, transaction::Version};
+    use bitcoin::secp256k1::ecdsa::Signature;
+    use bitcoin::script::Instruction;
+
+    const SEED_PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_derives_legacy_p2pkh_address() {
+        let card = BitcoinSVCard::new(Network::Bitcoin, 0, SEED_PHRASE, None).unwrap();
+        assert_eq!(card.derivation_path(), "m/44'/236'/0'/0/0");
+        assert_eq!(card.address(), "1K6LZdwpKT5XkEZo2T2kW197aMXYbYMc4f");
+    }
+
+    #[test]
+    fn test_signs_inputs_with_sighash_forkid() {
+        let card = BitcoinSVCard::new(Network::Bitcoin, 0, SEED_PHRASE, None).unwrap();
+        let spent = TxOut { value: Amount::from_sat(50_000), script_pubkey: card.script_pubkey() };
+        let tx = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut { value: Amount::from_sat(40_000), script_pubkey: card.script_pubkey() }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(spent.clone());
+
+        card.sign_transaction(&mut psbt).unwrap();
+
+        let script_sig = psbt.inputs[0].final_script_sig.clone().unwrap();
+        let pushes: Vec<_> = script_sig.instructions()
+            .map(|instruction| match instruction.unwrap() {
+                Instruction::PushBytes(bytes) => bytes.as_bytes().to_vec(),
+                Instruction::Op(op) => panic!("unexpected opcode {}", op),
+            })
+            .collect();
+        assert_eq!(pushes.len(), 2);
+        assert_eq!(pushes[1], card.public_key.to_bytes());
+
+        let (sighash_type, der) = pushes[0].split_last().unwrap();
+        assert_eq!(*sighash_type as u32, SIGHASH_ALL_FORKID);
+        let digest = forkid_signature_hash(&psbt.unsigned_tx, 0, &card.script_pubkey(), spent.value).unwrap();
+        let secp = Secp256k1::verification_only();
+        secp.verify_ecdsa(
+            &Message::from_digest_slice(&digest).unwrap(),
+            &Signature::from_der(der).unwrap(),
+            &card.public_key.inner,
+        ).unwrap();
+    }
+}
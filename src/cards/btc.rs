@@ -1,38 +1,42 @@
-use super::Card;
+use super::{Card, Language, parse_mnemonic, RECEIVE_BRANCH, CHANGE_BRANCH};
+use super::cache::BALANCE_CACHE;
 use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use bitcoin::{
-    Network, Address, PublicKey,
+    Network, Address, PublicKey, ScriptBuf,
     secp256k1::{Secp256k1, SecretKey},
     psbt::Psbt,
 };
 use bip32::{DerivationPath, XPrv};
 use std::str::FromStr;
-use bip39::Mnemonic;
 
 pub struct BitcoinCard {
     network: Network,
     account: u32,
+    /// Kept to derive change addresses
+    seed: [u8; 64],
     address: String,
     derivation_path: String,
     private_key: SecretKey,
 }
 
 impl BitcoinCard {
-    pub fn new(network: Network, account: u32, seed_phrase: &str) -> Result<Self> {
-        let mnemonic = Mnemonic::parse(seed_phrase)
-            .map_err(|e| anyhow!("Invalid seed phrase: {}", e))?;
-        
-        let seed = mnemonic.to_seed("");
+    pub fn new(network: Network, account: u32, seed_phrase: &str, language: Option<Language>) -> Result<Self> {
+        let mnemonic = parse_mnemonic(seed_phrase, language)?;
+        Self::derive(network, account, mnemonic.to_seed(""), RECEIVE_BRANCH, 0)
+    }
+
+    /// Derive the card for address `index` on `branch` (receive or change) of `account`
+    fn derive(network: Network, account: u32, seed: [u8; 64], branch: u32, index: u32) -> Result<Self> {
         let secp = Secp256k1::new();
 
-        // Derive BIP44 path: m/44'/0'/account'/0/0 for BTC
-        let path = format!("m/44'/0'/{}'/0/0", account);
+        // Derive BIP44 path: m/44'/0'/account'/branch/index for BTC
+        let path = format!("m/44'/0'/{}'/{}/{}", account, branch, index);
         let derivation_path = DerivationPath::from_str(&path)
             .map_err(|e| anyhow!("Invalid derivation path: {}", e))?;
 
         // Use the separate bip32 crate to derive keys
-        let xpriv = bip32::XPrv::derive_from_path(&seed, &derivation_path)
+        let xpriv = XPrv::derive_from_path(seed, &derivation_path)
             .map_err(|e| anyhow!("Failed to derive private key: {}", e))?;
         
         // Convert to bitcoin SecretKey
@@ -49,11 +53,28 @@ impl BitcoinCard {
         Ok(Self {
             network,
             account,
+            seed,
             address: address.to_string(),
             derivation_path: path,
             private_key,
         })
     }
+
+    /// Fetch the balance from upstream, bypassing the balance cache
+    async fn fetch_balance(&self) -> Result<u64> {
+        let api_key = std::env::var("ANYPAY_API_KEY")
+            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
+        
+        let client = crate::client::AnypayClient::new(&api_key);
+        let utxos = client.get_utxos(&self.address).await?;
+        
+        let total_sats: u64 = utxos.iter()
+            .map(|utxo| bitcoin::Amount::from_btc(utxo.amount).unwrap_or(bitcoin::Amount::ZERO))
+            .map(|amount| amount.to_sat())
+            .sum();
+
+        Ok(total_sats)
+    }
 }
 
 #[async_trait]
@@ -82,35 +103,24 @@ impl Card for BitcoinCard {
         self.account
     }
 
-    async fn get_balance(&self) -> Result<u64> {
-        let api_key = std::env::var("ANYPAY_API_KEY")
-            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-        
-        let client = crate::client::AnypayClient::new(&api_key);
-        let utxos = client.get_utxos(&self.address).await?;
-        
-        let total_sats: u64 = utxos.iter()
-            .map(|utxo| bitcoin::Amount::from_btc(utxo.amount).unwrap_or(bitcoin::Amount::ZERO))
-            .map(|amount| amount.to_sat())
-            .sum();
+    fn change_card(&self, index: u32) -> Result<Box<dyn Card>> {
+        Ok(Box::new(Self::derive(self.network, self.account, self.seed, CHANGE_BRANCH, index)?))
+    }
 
-        Ok(total_sats)
+    async fn get_balance(&self) -> Result<u64> {
+        BALANCE_CACHE.get_or_fetch(self.chain(), self.network, &self.address, || self.fetch_balance()).await
     }
 
-    async fn get_decimal_balance(&self) -> Result<f64> {
-        let sats = self.get_balance().await?;
-        Ok(sats as f64 / 100_000_000.0)
+    fn decimals(&self) -> u32 {
+        8
     }
 
-    async fn get_usd_balance(&self) -> Result<f64> {
-        let btc = self.get_decimal_balance().await?;
+    async fn get_usd_price(&self) -> Result<f64> {
         let api_key = std::env::var("ANYPAY_API_KEY")
             .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-        
+
         let client = crate::client::AnypayClient::new(&api_key);
-        let btc_price = client.get_btc_price().await?;
-        
-        Ok(btc * btc_price)
+        client.get_btc_price().await
     }
 
     fn sign_transaction(&self, psbt: &mut Psbt) -> Result<()> {
@@ -119,14 +129,16 @@ impl Card for BitcoinCard {
 
         let secp = Secp256k1::new();
         let mut sighash_cache = SighashCache::new(&psbt.unsigned_tx);
-        
-        // Sign each input
+
+        // Same pattern as in new() method
+        let secp256k1_pubkey = secp256k1::PublicKey::from_secret_key(&secp, &self.private_key);
+        let public_key = PublicKey::new(secp256k1_pubkey);
+        let script_pubkey = ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash()
+            .ok_or_else(|| anyhow!("Public key is not compressed"))?);
+
+        // Sign each input spending this card's address, leaving the rest to the cards that own them
         for (i, input) in psbt.inputs.iter_mut().enumerate() {
-            if let Some(witness_utxo) = &input.witness_utxo {
-                // Same pattern as in new() method
-                let secp256k1_pubkey = secp256k1::PublicKey::from_secret_key(&secp, &self.private_key);
-                let public_key = PublicKey::new(secp256k1_pubkey);
-                
+            if let Some(witness_utxo) = input.witness_utxo.as_ref().filter(|utxo| utxo.script_pubkey == script_pubkey) {
                 // Calculate sighash - use p2wpkh instead of segwit hash
                 let sighash = sighash_cache
                     .p2wpkh_signature_hash(i, &witness_utxo.script_pubkey, witness_utxo.value, EcdsaSighashType::All)
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use bitcoin::Network;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    /// Process-wide balance cache used by all cards, configured from ANYPAY_BALANCE_CACHE_TTL
+    pub static ref BALANCE_CACHE: BalanceCache = BalanceCache::from_env();
+}
+
+/// Short-lived cache of raw balances keyed by (chain, network, address), since one address
+/// can hold different balances on mainnet and testnet.
+/// Disabled (every lookup goes upstream) unless a TTL is configured.
+pub struct BalanceCache {
+    ttl: Option<Duration>,
+    entries: RwLock<HashMap<(String, Network, String), (Instant, u64)>>,
+}
+
+impl BalanceCache {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// TTL in seconds from ANYPAY_BALANCE_CACHE_TTL; unset or 0 disables caching
+    pub fn from_env() -> Self {
+        let ttl = std::env::var("ANYPAY_BALANCE_CACHE_TTL")
+            .ok()
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+
+        Self::new(ttl)
+    }
+
+    pub async fn get_or_fetch<F, Fut>(&self, chain: &str, network: Network, address: &str, fetch: F) -> Result<u64>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<u64>>,
+    {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return fetch().await,
+        };
+
+        let key = (chain.to_string(), network, address.to_string());
+        let cached = self.entries.read().unwrap().get(&key).copied();
+        if let Some((fetched_at, balance)) = cached {
+            if fetched_at.elapsed() < ttl {
+                return Ok(balance);
+            }
+        }
+
+        let balance = fetch().await?;
+        self.entries.write().unwrap().insert(key, (Instant::now(), balance));
+        Ok(balance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_rapid_calls_within_ttl_fetch_once() {
+        let cache = BalanceCache::new(Some(Duration::from_secs(5)));
+        let fetches = AtomicUsize::new(0);
+
+        for _ in 0..2 {
+            let balance = cache.get_or_fetch("BTC", Network::Bitcoin, "bc1qtest", || async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                Ok(42)
+            }).await.unwrap();
+            assert_eq!(balance, 42);
+        }
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_cache_always_fetches() {
+        let cache = BalanceCache::new(None);
+        let fetches = AtomicUsize::new(0);
+
+        for _ in 0..2 {
+            cache.get_or_fetch("BTC", Network::Bitcoin, "bc1qtest", || async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                Ok(42)
+            }).await.unwrap();
+        }
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_networks_are_cached_separately() {
+        let cache = BalanceCache::new(Some(Duration::from_secs(5)));
+
+        let mainnet = cache.get_or_fetch("BTC", Network::Bitcoin, "bc1qtest", || async { Ok(42) }).await.unwrap();
+        let testnet = cache.get_or_fetch("BTC", Network::Testnet, "bc1qtest", || async { Ok(7) }).await.unwrap();
+
+        assert_eq!((mainnet, testnet), (42, 7));
+    }
+}
@@ -1,4 +1,5 @@
-use super::Card;
+use super::{Card, Language, parse_mnemonic};
+use super::cache::BALANCE_CACHE;
 use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use bitcoin::Network;
@@ -6,7 +7,6 @@ use bitcoin::psbt::Psbt;
 use nintondo_dogecoin::{
     bip32::{DerivationPath, ExtendedPrivKey}, key::Secp256k1, Address, Network as DogeNetwork, PrivateKey, PublicKey
 };
-use bip39::Mnemonic;
 
 
 pub struct DogeCard {
@@ -19,9 +19,8 @@ pub struct DogeCard {
 }
 
 impl DogeCard {
-    pub fn new(network: Network, account: u32, seed_phrase: &str) -> Result<Self> {
-        let mnemonic = Mnemonic::parse(seed_phrase)
-            .map_err(|e| anyhow!("Invalid seed phrase: {}", e))?;
+    pub fn new(network: Network, account: u32, seed_phrase: &str, language: Option<Language>) -> Result<Self> {
+        let mnemonic = parse_mnemonic(seed_phrase, language)?;
         
         let seed = mnemonic.to_seed("");
         
@@ -62,6 +61,21 @@ impl DogeCard {
             public_key,
         })
     }
+
+    /// Fetch the balance from upstream, bypassing the balance cache
+    async fn fetch_balance(&self) -> Result<u64> {
+        let api_key = std::env::var("ANYPAY_API_KEY")
+            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
+            
+        let client = crate::client::AnypayClient::new(&api_key);
+        let utxos = client.get_utxos(&self.address).await?;
+        
+        let total_sats: u64 = utxos.iter()
+            .map(|utxo| utxo.amount as u64)
+            .sum();
+            
+        Ok(total_sats)
+    }
 }
 
 #[async_trait]
@@ -91,33 +105,20 @@ impl Card for DogeCard {
     }
 
     async fn get_balance(&self) -> Result<u64> {
-        let api_key = std::env::var("ANYPAY_API_KEY")
-            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-            
-        let client = crate::client::AnypayClient::new(&api_key);
-        let utxos = client.get_utxos(&self.address).await?;
-        
-        let total_sats: u64 = utxos.iter()
-            .map(|utxo| utxo.amount as u64)
-            .sum();
-            
-        Ok(total_sats)
+        BALANCE_CACHE.get_or_fetch(self.chain(), self.network, &self.address, || self.fetch_balance()).await
     }
 
-    async fn get_decimal_balance(&self) -> Result<f64> {
-        let sats = self.get_balance().await?;
-        Ok(sats as f64 / 100_000_000.0)  // Convert satoshis to DOGE
+    fn decimals(&self) -> u32 {
+        // 1 DOGE = 100,000,000 satoshis
+        8
     }
 
-    async fn get_usd_balance(&self) -> Result<f64> {
-        let doge = self.get_decimal_balance().await?;
+    async fn get_usd_price(&self) -> Result<f64> {
         let api_key = std::env::var("ANYPAY_API_KEY")
             .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-        
+
         let client = crate::client::AnypayClient::new(&api_key);
-        let doge_price = client.get_price("DOGE").await?;
-        
-        Ok(doge * doge_price)
+        client.get_price("DOGE").await
     }
 
     fn sign_transaction(&self, psbt: &mut Psbt) -> Result<()> {
@@ -1,12 +1,29 @@
-use super::Card;
+use super::{Card, Language, parse_mnemonic};
+use super::cache::BALANCE_CACHE;
 use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use bitcoin::Network;
 use bitcoin::psbt::Psbt;
+use std::str::FromStr;
 use ethers::{
-    core::k256::ecdsa::SigningKey, providers::{Http, Middleware, Provider}, signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer, Wallet}, types::H160
+    core::k256::ecdsa::SigningKey, providers::{Http, Middleware, Provider}, signers::{LocalWallet, Signer, Wallet}, types::{H160, U256, Bytes, BlockNumber, FeeHistory, Eip1559TransactionRequest, TransactionRequest, transaction::eip2718::TypedTransaction}
 };
 
+// Number of recent blocks and the reward percentile sampled when estimating EIP-1559 fees
+const FEE_HISTORY_BLOCKS: u64 = 10;
+const PRIORITY_FEE_PERCENTILE: f64 = 50.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvmFees {
+    Eip1559 {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+    Legacy {
+        gas_price: U256,
+    },
+}
+
 pub struct EthereumCard {
     network: Network,
     account: u32,
@@ -15,32 +32,40 @@ pub struct EthereumCard {
     wallet: Wallet<SigningKey>,
     chain: String,
     currency: String,
+    chain_id: u64,
 }
 
 impl EthereumCard {
-    pub fn new(network: Network, account: u32, seed_phrase: &str, chain: &str, currency: &str) -> Result<Self> {
+    pub fn new(network: Network, account: u32, seed_phrase: &str, chain: &str, currency: &str, language: Option<Language>) -> Result<Self> {
 
 
         // Derive BIP44 path
         // ETH: m/44'/60'/account'/0/0
         // MATIC: m/44'/966'/account'/0/0
+        // AVAX (C-Chain) and BNB (BSC) use the ETH coin type like other EVM wallets
         let coin_type = match chain {
-            "ETH" => 60,
+            "ETH" | "AVAX" | "BNB" => 60,
             "POLYGON" => 966,
             _ => return Err(anyhow!("Unsupported chain: {}", chain)),
         };
+        let chain_id = evm_chain_id(chain, network)?;
         
         let path = format!("m/44'/{}'/{:?}'/0/0", coin_type, account);
         
-        // Create wallet from mnemonic using MnemonicBuilder
-        let wallet = MnemonicBuilder::<English>::default()
-            .phrase(seed_phrase)
-            .derivation_path(&path)
-            .unwrap()
-            .build()
-            .map_err(|e| anyhow!("Failed to create wallet: {}", e))?;
+        // Derive the key with bip39/bip32 rather than MnemonicBuilder, which only accepts English phrases
+        let seed = parse_mnemonic(seed_phrase, language)?.to_seed("");
+        let derivation_path = bip32::DerivationPath::from_str(&path)
+            .map_err(|e| anyhow!("Invalid derivation path: {}", e))?;
+        let xpriv = bip32::XPrv::derive_from_path(seed, &derivation_path)
+            .map_err(|e| anyhow!("Failed to derive private key: {}", e))?;
+
+        // Bind the wallet to the chain id for EIP-155 replay protection
+        let wallet = LocalWallet::from_bytes(&xpriv.to_bytes())
+            .map_err(|e| anyhow!("Failed to create wallet: {}", e))?
+            .with_chain_id(chain_id);
         
-        let address = wallet.address().to_string();
+        // H160 Display abbreviates the address, so format the full EIP-55 checksummed form
+        let address = ethers::utils::to_checksum(&wallet.address(), None);
 
         Ok(Self {
             network,
@@ -50,18 +75,137 @@ impl EthereumCard {
             wallet,
             chain: chain.to_string(),
             currency: currency.to_string(),
+            chain_id,
         })
     }
     
+    /// EIP-155 chain id this card signs for
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    /// Sign an EVM transaction for this card's chain, returning the raw signed RLP bytes.
+    /// The chain id is always overwritten so a transaction can't be replayed on another chain.
+    pub async fn sign_evm_transaction(&self, tx: &mut TypedTransaction) -> Result<Bytes> {
+        tx.set_chain_id(self.chain_id());
+        tx.set_from(self.wallet.address());
+
+        let signature = self.wallet.sign_transaction(tx).await
+            .map_err(|e| anyhow!("Failed to sign {} transaction: {}", self.chain, e))?;
+
+        Ok(tx.rlp_signed(&signature))
+    }
+    
+    /// Build an unsigned payment transaction with estimated gas and fees from the chain's RPC
+    pub async fn prepare_payment(&self, to: &str, value: U256) -> Result<TypedTransaction> {
+        let provider = Provider::<Http>::try_from(self.get_rpc_url())
+            .map_err(|e| anyhow!("Failed to create provider: {}", e))?;
+        let to = to.parse::<H160>()
+            .map_err(|e| anyhow!("Invalid address: {}", e))?;
+        let from = self.wallet.address();
+
+        let nonce = provider.get_transaction_count(from, None).await
+            .map_err(|e| anyhow!("Failed to get nonce: {}", e))?;
+
+        let mut tx: TypedTransaction = match estimate_fees(&provider).await? {
+            EvmFees::Eip1559 { max_fee_per_gas, max_priority_fee_per_gas } => Eip1559TransactionRequest::new()
+                .from(from)
+                .to(to)
+                .value(value)
+                .nonce(nonce)
+                .chain_id(self.chain_id())
+                .max_fee_per_gas(max_fee_per_gas)
+                .max_priority_fee_per_gas(max_priority_fee_per_gas)
+                .into(),
+            EvmFees::Legacy { gas_price } => TransactionRequest::new()
+                .from(from)
+                .to(to)
+                .value(value)
+                .nonce(nonce)
+                .chain_id(self.chain_id())
+                .gas_price(gas_price)
+                .into(),
+        };
+
+        let gas = provider.estimate_gas(&tx, None).await
+            .map_err(|e| anyhow!("Failed to estimate gas: {}", e))?;
+        tx.set_gas(gas);
+
+        Ok(tx)
+    }
+
     fn get_rpc_url(&self) -> &str {
         match (self.chain.as_str(), self.network) {
             ("ETH", Network::Bitcoin) => "https://eth-mainnet.g.alchemy.com/v2/your-api-key",
             ("ETH", _) => "https://eth-sepolia.g.alchemy.com/v2/your-api-key",
             ("POLYGON", Network::Bitcoin) => "https://polygon-mainnet.g.alchemy.com/v2/your-api-key",
             ("POLYGON", _) => "https://polygon-mumbai.g.alchemy.com/v2/your-api-key",
+            ("AVAX", Network::Bitcoin) => "https://api.avax.network/ext/bc/C/rpc",
+            ("AVAX", _) => "https://api.avax-test.network/ext/bc/C/rpc",
+            ("BNB", Network::Bitcoin) => "https://bsc-dataseed.binance.org",
+            ("BNB", _) => "https://data-seed-prebsc-1-s1.binance.org:8545",
             _ => "https://eth-mainnet.g.alchemy.com/v2/your-api-key", // default to ETH mainnet
         }
     }
+
+    /// Fetch the balance from upstream, bypassing the balance cache
+    async fn fetch_balance(&self) -> Result<u64> {
+        let provider = Provider::<Http>::try_from(self.get_rpc_url())
+            .map_err(|e| anyhow!("Failed to create provider: {}", e))?;
+            
+        let address = self.address.parse::<H160>()
+            .map_err(|e| anyhow!("Invalid address: {}", e))?;
+            
+        // Use the Middleware trait for get_balance
+        let balance = provider.get_balance(address, None).await
+            .map_err(|e| anyhow!("Failed to get balance: {}", e))?;
+            
+        Ok(balance.low_u64())  // Convert U256 to u64
+    }
+}
+
+/// Estimate EIP-1559 fee caps from recent fee history, falling back to the legacy
+/// gas price on chains that don't support eth_feeHistory
+pub async fn estimate_fees<M: Middleware>(provider: &M) -> Result<EvmFees> {
+    match provider.fee_history(FEE_HISTORY_BLOCKS, BlockNumber::Latest, &[PRIORITY_FEE_PERCENTILE]).await {
+        Ok(history) if !history.base_fee_per_gas.is_empty() => Ok(fees_from_history(&history)),
+        _ => {
+            let gas_price = provider.get_gas_price().await
+                .map_err(|e| anyhow!("Failed to get gas price: {}", e))?;
+            Ok(EvmFees::Legacy { gas_price })
+        }
+    }
+}
+
+fn fees_from_history(history: &FeeHistory) -> EvmFees {
+    // The last base fee is the pending block's; doubling it keeps the tx valid through several full blocks
+    let base_fee = history.base_fee_per_gas.last().copied().unwrap_or_default();
+
+    let rewards: Vec<U256> = history.reward.iter()
+        .filter_map(|block| block.first().copied())
+        .collect();
+    let max_priority_fee_per_gas = if rewards.is_empty() {
+        U256::zero()
+    } else {
+        rewards.iter().fold(U256::zero(), |acc, r| acc + *r) / U256::from(rewards.len())
+    };
+
+    EvmFees::Eip1559 {
+        max_fee_per_gas: base_fee * U256::from(2) + max_priority_fee_per_gas,
+        max_priority_fee_per_gas,
+    }
+}
+
+/// EIP-155 chain id for an EVM chain on the given network
+pub fn evm_chain_id(chain: &str, network: Network) -> Result<u64> {
+    let mainnet = network == Network::Bitcoin;
+    match chain {
+        "ETH" => Ok(if mainnet { 1 } else { 11155111 }),        // Sepolia
+        "POLYGON" => Ok(if mainnet { 137 } else { 80001 }),     // Mumbai
+        "AVAX" => Ok(if mainnet { 43114 } else { 43113 }),      // Fuji
+        "BNB" => Ok(if mainnet { 56 } else { 97 }),             // BSC testnet
+        _ => Err(anyhow!("Unsupported chain: {}", chain)),
+    }
 }
 
 #[async_trait]
@@ -91,37 +235,100 @@ impl Card for EthereumCard {
     }
 
     async fn get_balance(&self) -> Result<u64> {
-        let provider = Provider::<Http>::try_from(self.get_rpc_url())
-            .map_err(|e| anyhow!("Failed to create provider: {}", e))?;
-            
-        let address = self.address.parse::<H160>()
-            .map_err(|e| anyhow!("Invalid address: {}", e))?;
-            
-        // Use the Middleware trait for get_balance
-        let balance = provider.get_balance(address, None).await
-            .map_err(|e| anyhow!("Failed to get balance: {}", e))?;
-            
-        Ok(balance.low_u64())  // Convert U256 to u64
+        BALANCE_CACHE.get_or_fetch(self.chain(), self.network, &self.address, || self.fetch_balance()).await
     }
 
-    async fn get_decimal_balance(&self) -> Result<f64> {
-        let wei = self.get_balance().await?;
-        Ok(wei as f64 / 1_000_000_000_000_000_000.0)  // Convert wei to ETH/MATIC (1 = 1e18 wei)
+    fn decimals(&self) -> u32 {
+        // 1 ETH/MATIC/AVAX/BNB = 1e18 wei
+        18
     }
 
-    async fn get_usd_balance(&self) -> Result<f64> {
-        let amount = self.get_decimal_balance().await?;
+    async fn get_usd_price(&self) -> Result<f64> {
         let api_key = std::env::var("ANYPAY_API_KEY")
             .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-        
+
         let client = crate::client::AnypayClient::new(&api_key);
-        let price = client.get_price(&self.currency).await?;
-        
-        Ok(amount * price)
+        client.get_price(&self.currency).await
     }
 
     fn sign_transaction(&self, _psbt: &mut Psbt) -> Result<()> {
-        // ETH/MATIC don't use PSBT format
+        // EVM chains don't use PSBT format
         Err(anyhow!("{} does not support PSBT transactions", self.chain))
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED_PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_avax_and_bnb_derive_eth_address() {
+        let eth = EthereumCard::new(Network::Bitcoin, 0, SEED_PHRASE, "ETH", "ETH", None).unwrap();
+        let avax = EthereumCard::new(Network::Bitcoin, 0, SEED_PHRASE, "AVAX", "AVAX", None).unwrap();
+        let bnb = EthereumCard::new(Network::Bitcoin, 0, SEED_PHRASE, "BNB", "BNB", None).unwrap();
+
+        assert_eq!(avax.address(), eth.address());
+        assert_eq!(bnb.address(), eth.address());
+        assert_eq!(avax.derivation_path(), "m/44'/60'/0'/0/0");
+    }
+
+    #[test]
+    fn test_signer_uses_chain_id() {
+        let avax = EthereumCard::new(Network::Bitcoin, 0, SEED_PHRASE, "AVAX", "AVAX", None).unwrap();
+        let bnb = EthereumCard::new(Network::Bitcoin, 0, SEED_PHRASE, "BNB", "BNB", None).unwrap();
+        let bnb_testnet = EthereumCard::new(Network::Testnet, 0, SEED_PHRASE, "BNB", "BNB", None).unwrap();
+
+        assert_eq!(avax.wallet.chain_id(), 43114);
+        assert_eq!(bnb.wallet.chain_id(), 56);
+        assert_eq!(bnb_testnet.wallet.chain_id(), 97);
+    }
+
+    async fn signed_chain_id(card: &EthereumCard) -> Option<u64> {
+        use ethers::types::TransactionRequest;
+        use ethers::utils::rlp::Rlp;
+
+        let mut tx: TypedTransaction = TransactionRequest::new()
+            .to("0x9858EfFD232B4033E47d90003D41EC34EcaEda94".parse::<H160>().unwrap())
+            .value(1_000u64)
+            .gas(21_000u64)
+            .gas_price(1_000_000_000u64)
+            .nonce(0u64)
+            .into();
+
+        let raw = card.sign_evm_transaction(&mut tx).await.unwrap();
+        let (decoded, _signature) = TypedTransaction::decode_signed(&Rlp::new(&raw)).unwrap();
+        decoded.chain_id().map(|id| id.as_u64())
+    }
+
+    #[tokio::test]
+    async fn test_signed_transactions_carry_chain_id() {
+        let eth = EthereumCard::new(Network::Bitcoin, 0, SEED_PHRASE, "ETH", "ETH", None).unwrap();
+        let polygon = EthereumCard::new(Network::Bitcoin, 0, SEED_PHRASE, "POLYGON", "MATIC", None).unwrap();
+
+        assert_eq!(eth.chain_id(), 1);
+        assert_eq!(polygon.chain_id(), 137);
+        assert_eq!(signed_chain_id(&eth).await, Some(1));
+        assert_eq!(signed_chain_id(&polygon).await, Some(137));
+    }
+
+    #[tokio::test]
+    async fn test_estimate_fees_from_fee_history() {
+        let (provider, mock) = Provider::mocked();
+        let history = FeeHistory {
+            base_fee_per_gas: vec![U256::from(90), U256::from(100)],
+            gas_used_ratio: vec![0.5],
+            oldest_block: U256::from(1),
+            reward: vec![vec![U256::from(2)], vec![U256::from(4)]],
+        };
+        mock.push::<FeeHistory, _>(history).unwrap();
+
+        let fees = estimate_fees(&provider).await.unwrap();
+
+        assert_eq!(fees, EvmFees::Eip1559 {
+            max_fee_per_gas: U256::from(203),
+            max_priority_fee_per_gas: U256::from(3),
+        });
+    }
+}
@@ -2,20 +2,39 @@ use bitcoin::Network;
 use anyhow::Result;
 use async_trait::async_trait;
 use bitcoin::psbt::Psbt;
+use bip39::Mnemonic;
+
+pub use bip39::Language;
 
 //pub mod btc;
+pub mod bsv;
+pub mod cache;
+#[cfg(feature = "btc")]
+pub mod watch;
+#[cfg(feature = "xrp")]
 pub mod xrp;
+#[cfg(feature = "sol")]
 pub mod sol;
+#[cfg(feature = "eth")]
 pub mod eth;
+#[cfg(feature = "doge")]
 pub mod doge;
+#[cfg(feature = "fb")]
 pub mod fb;
+#[cfg(feature = "btc")]
 pub mod btc;
 
 use std::fmt;
 
+/// BIP44 branch of external (receive) addresses
+pub const RECEIVE_BRANCH: u32 = 0;
+
+/// BIP44 branch of internal (change) addresses
+pub const CHANGE_BRANCH: u32 = 1;
+
 #[async_trait]
 pub trait Card: Send + Sync {
-    /// Get the chain identifier (e.g., "BTC", "XRPL")
+    /// Get the canonical chain identifier (e.g., "BTC", "XRP"), as `get_plugin` expects
     fn chain(&self) -> &str;
     
     /// Get the currency identifier (e.g., "BTC", "XRP")
@@ -32,15 +51,44 @@ pub trait Card: Send + Sync {
     
     /// Get the account index used to generate this card
     fn account(&self) -> u32;
+
+    /// Get the card for change address `index` on this account's internal chain (.../1/index),
+    /// for chains that derive one
+    fn change_card(&self, index: u32) -> Result<Box<dyn Card>> {
+        let _ = index;
+        Err(anyhow::anyhow!("{} cards have no change addresses", self.chain()))
+    }
+
+    /// Get change address `index` on this account's internal chain (.../1/index)
+    fn get_change_address(&self, index: u32) -> Result<String> {
+        Ok(self.change_card(index)?.address().to_string())
+    }
     
+    /// Get the number of smallest units in a standard unit, as a power of ten (8 for BTC, 6 for XRP)
+    fn decimals(&self) -> u32;
+
     /// Get the balance in the smallest unit (satoshis for BTC, drops for XRP)
     async fn get_balance(&self) -> Result<u64>;
-    
+
+    /// Get the USD price of one standard unit
+    async fn get_usd_price(&self) -> Result<f64>;
+
     /// Get the balance in the standard unit (BTC for Bitcoin, XRP for Ripple)
-    async fn get_decimal_balance(&self) -> Result<f64>;
-    
+    async fn get_decimal_balance(&self) -> Result<f64> {
+        Ok(to_decimal(self.get_balance().await?, self.decimals()))
+    }
+
     /// Get the balance in USD
-    async fn get_usd_balance(&self) -> Result<f64>;
+    async fn get_usd_balance(&self) -> Result<f64> {
+        Ok(self.balance().await?.usd)
+    }
+
+    /// Get the balance in every unit, from a single balance lookup and a single price lookup
+    async fn balance(&self) -> Result<Balance> {
+        let smallest_unit = self.get_balance().await?;
+        let usd_price = self.get_usd_price().await?;
+        Ok(Balance::new(smallest_unit, self.decimals(), usd_price))
+    }
     
     /// Sign a transaction (implementation depends on chain)
     fn sign_transaction(&self, tx: &mut Psbt) -> Result<()>;
@@ -70,13 +118,63 @@ impl fmt::Debug for Box<dyn Card> {
     }
 }
 
-#[derive(Debug)]
+/// Parse a network name, accepting common aliases case-insensitively
+pub fn parse_network(network: &str) -> Result<Network> {
+    match network.trim().to_lowercase().as_str() {
+        "mainnet" | "main" | "bitcoin" => Ok(Network::Bitcoin),
+        "testnet" | "test" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(anyhow::anyhow!("Invalid network: {} (expected mainnet, testnet, signet or regtest)", network))
+    }
+}
+
+/// Parse a language name for BIP39 wordlists, e.g. "english", "spanish", "japanese"
+pub fn parse_language(language: &str) -> Result<Language> {
+    match language.trim().to_lowercase().replace(['-', '_'], " ").as_str() {
+        "english" | "en" => Ok(Language::English),
+        "spanish" | "es" => Ok(Language::Spanish),
+        "french" | "fr" => Ok(Language::French),
+        "italian" | "it" => Ok(Language::Italian),
+        "portuguese" | "pt" => Ok(Language::Portuguese),
+        "czech" | "cs" => Ok(Language::Czech),
+        "japanese" | "ja" => Ok(Language::Japanese),
+        "korean" | "ko" => Ok(Language::Korean),
+        "chinese simplified" | "zh hans" => Ok(Language::SimplifiedChinese),
+        "chinese traditional" | "zh hant" => Ok(Language::TraditionalChinese),
+        _ => Err(anyhow::anyhow!("Unsupported mnemonic language: {}", language))
+    }
+}
+
+/// Parse a BIP39 seed phrase in the given language, or detect the wordlist when none is given
+pub fn parse_mnemonic(seed_phrase: &str, language: Option<Language>) -> Result<Mnemonic> {
+    match language {
+        Some(language) => Mnemonic::parse_in(language, seed_phrase),
+        None => Mnemonic::parse(seed_phrase),
+    }
+    .map_err(|e| anyhow::anyhow!("Invalid seed phrase: {}", e))
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Balance {
     pub smallest_unit: u64,  // satoshis, drops, etc.
     pub decimal: f64,        // BTC, XRP, etc.
     pub usd: f64,
 }
 
+/// `smallest_unit` in the standard unit, which has `decimals` decimal places
+fn to_decimal(smallest_unit: u64, decimals: u32) -> f64 {
+    smallest_unit as f64 / 10f64.powi(decimals as i32)
+}
+
+impl Balance {
+    /// A balance of `smallest_unit`, with `decimals` decimal places in the standard unit, valued at `usd_price` per standard unit
+    pub fn new(smallest_unit: u64, decimals: u32, usd_price: f64) -> Self {
+        let decimal = to_decimal(smallest_unit, decimals);
+        Balance { smallest_unit, decimal, usd: decimal * usd_price }
+    }
+}
+
 // Factory function to create the appropriate card type
 pub fn create_card(
     chain: &str,
@@ -84,17 +182,142 @@ pub fn create_card(
     network: Network,
     account: u32,
     seed_phrase: &str,
+    language: Option<Language>,
 ) -> Result<Box<dyn Card>> {
     println!("Creating card for chain: {}, currency: {}, network: {:?}, account: {}", chain, currency, network, account);
-    match (chain, currency) {
-        ("ETH", "ETH") => Ok(Box::new(eth::EthereumCard::new(network, account, seed_phrase, "ETH", "ETH")?)),
-        ("POLYGON", "MATIC") => Ok(Box::new(eth::EthereumCard::new(network, account, seed_phrase, "POLYGON", "MATIC")?)),
-        ("XRPL", "XRP") => Ok(Box::new(xrp::RippleCard::new(network, account, seed_phrase)?)),
-        ("SOL", "SOL") => Ok(Box::new(sol::SolanaCard::new(network, account, seed_phrase)?)),
-        ("DOGE", "DOGE") => Ok(Box::new(doge::DogeCard::new(network, account, seed_phrase)?)),
-        ("FB", "FB") => Ok(Box::new(fb::FractalBitcoinCard::new(network, account, seed_phrase)?)),
-        ("BTC", "BTC") => Ok(Box::new(btc::BitcoinCard::new(network, account, seed_phrase)?)),
-        //("BTC", "BTC") => Ok(Box::new(btc::BitcoinCard::new(network, account, seed_phrase)?)),
+    let (canonical_chain, canonical_currency) = crate::symbols::normalize(chain, currency);
+    match (canonical_chain.as_str(), canonical_currency.as_str()) {
+        #[cfg(feature = "eth")]
+        ("ETH", "ETH") => Ok(Box::new(eth::EthereumCard::new(network, account, seed_phrase, "ETH", "ETH", language)?)),
+        #[cfg(feature = "eth")]
+        ("POLYGON", "MATIC") => Ok(Box::new(eth::EthereumCard::new(network, account, seed_phrase, "POLYGON", "MATIC", language)?)),
+        #[cfg(feature = "eth")]
+        ("AVAX", "AVAX") => Ok(Box::new(eth::EthereumCard::new(network, account, seed_phrase, "AVAX", "AVAX", language)?)),
+        #[cfg(feature = "eth")]
+        ("BNB", "BNB") => Ok(Box::new(eth::EthereumCard::new(network, account, seed_phrase, "BNB", "BNB", language)?)),
+        #[cfg(feature = "xrp")]
+        ("XRP", "XRP") => Ok(Box::new(xrp::RippleCard::new(network, account, seed_phrase, language)?)),
+        #[cfg(feature = "sol")]
+        ("SOL", "SOL") => Ok(Box::new(sol::SolanaCard::new(network, account, seed_phrase, language)?)),
+        #[cfg(feature = "doge")]
+        ("DOGE", "DOGE") => Ok(Box::new(doge::DogeCard::new(network, account, seed_phrase, language)?)),
+        #[cfg(feature = "fb")]
+        ("FB", "FB") => Ok(Box::new(fb::FractalBitcoinCard::new(network, account, seed_phrase, language)?)),
+        #[cfg(feature = "btc")]
+        ("BTC", "BTC") => Ok(Box::new(btc::BitcoinCard::new(network, account, seed_phrase, language)?)),
+        ("BSV", "BSV") => Ok(Box::new(bsv::BitcoinSVCard::new(network, account, seed_phrase, language)?)),
+        //("BTC", "BTC") => Ok(Box::new(btc::BitcoinCard::new(network, account, seed_phrase, language)?)),
         _ => Err(anyhow::anyhow!("Unsupported chain/currency combination: {}/{}", chain, currency))
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_network_aliases() {
+        assert_eq!(parse_network("mainnet").unwrap(), Network::Bitcoin);
+        assert_eq!(parse_network("Main").unwrap(), Network::Bitcoin);
+        assert_eq!(parse_network("testnet").unwrap(), Network::Testnet);
+        assert_eq!(parse_network("TEST").unwrap(), Network::Testnet);
+        assert_eq!(parse_network("signet").unwrap(), Network::Signet);
+        assert_eq!(parse_network("regtest").unwrap(), Network::Regtest);
+    }
+
+    #[test]
+    fn test_parse_network_invalid() {
+        assert!(parse_network("moonnet").is_err());
+    }
+
+    /// A card whose balance and price lookups are counted rather than sent upstream
+    struct MockCard {
+        balance_lookups: std::sync::atomic::AtomicUsize,
+        price_lookups: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Card for MockCard {
+        fn chain(&self) -> &str { "XRP" }
+        fn currency(&self) -> &str { "XRP" }
+        fn network(&self) -> Network { Network::Bitcoin }
+        fn derivation_path(&self) -> &str { "m/44'/144'/0'/0/0" }
+        fn address(&self) -> &str { "rMock" }
+        fn account(&self) -> u32 { 0 }
+        fn decimals(&self) -> u32 { 6 }
+
+        async fn get_balance(&self) -> Result<u64> {
+            self.balance_lookups.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(2_500_000)
+        }
+
+        async fn get_usd_price(&self) -> Result<f64> {
+            self.price_lookups.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(0.5)
+        }
+
+        fn sign_transaction(&self, _psbt: &mut Psbt) -> Result<()> {
+            Err(anyhow!("MockCard cannot sign transactions"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_balance_fills_every_unit_from_one_lookup_each() {
+        let card = MockCard { balance_lookups: Default::default(), price_lookups: Default::default() };
+
+        let balance = card.balance().await.unwrap();
+
+        assert_eq!(balance, Balance { smallest_unit: 2_500_000, decimal: 2.5, usd: 1.25 });
+        assert_eq!(balance.decimal, balance.smallest_unit as f64 / 1_000_000.0);
+        assert_eq!(balance.usd, balance.decimal * 0.5);
+        assert_eq!(card.balance_lookups.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(card.price_lookups.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    const SPANISH_SEED_PHRASE: &str = "ábaco ábaco ábaco ábaco ábaco ábaco ábaco ábaco ábaco ábaco ábaco abierto";
+
+    #[test]
+    fn test_parse_mnemonic_language() {
+        assert_eq!(parse_mnemonic(SPANISH_SEED_PHRASE, None).unwrap().language(), Language::Spanish);
+        assert_eq!(parse_mnemonic(SPANISH_SEED_PHRASE, Some(Language::Spanish)).unwrap().language(), Language::Spanish);
+        assert!(parse_mnemonic(SPANISH_SEED_PHRASE, Some(Language::English)).is_err());
+        assert_eq!(parse_language("es").unwrap(), Language::Spanish);
+        assert_eq!(parse_language("Chinese-Simplified").unwrap(), Language::SimplifiedChinese);
+    }
+
+    // Run with `--no-default-features --features btc` to check a bitcoin-only build still resolves BTC cards
+    #[test]
+    #[cfg(feature = "btc")]
+    fn test_spanish_mnemonic_derives_expected_btc_address() {
+        let btc = create_card("BTC", "BTC", Network::Bitcoin, 0, SPANISH_SEED_PHRASE, Some(Language::Spanish)).unwrap();
+        assert_eq!((btc.chain(), btc.currency()), ("BTC", "BTC"));
+        assert_eq!(btc.address(), "bc1qh6nuxtv4pln3wmxy8aymvn0g6uwyyjma5s46yj");
+    }
+
+    #[test]
+    #[cfg(feature = "xrp")]
+    fn test_xrp_and_xrpl_create_the_same_card() {
+        for (chain, currency) in [("XRP", "XRP"), ("XRPL", "XRP"), ("xrpl", "xrp")] {
+            let card = create_card(chain, currency, Network::Bitcoin, 0, SPANISH_SEED_PHRASE, None).unwrap();
+            assert_eq!((card.chain(), card.currency()), ("XRP", "XRP"));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "xrp")]
+    fn test_one_pair_resolves_both_card_and_plugin() {
+        let card = create_card("XRP", "XRP", Network::Bitcoin, 0, SPANISH_SEED_PHRASE, None).unwrap();
+        let plugin = crate::plugin::get_plugin("XRP", "XRP").unwrap();
+        assert_eq!((plugin.chain(), plugin.currency()), (card.chain(), card.currency()));
+
+        // Wallets look plugins up by the card's own pair
+        assert!(crate::plugin::get_plugin(card.chain(), card.currency()).is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "eth")]
+    fn test_spanish_mnemonic_derives_expected_eth_address() {
+        let eth = eth::EthereumCard::new(Network::Bitcoin, 0, SPANISH_SEED_PHRASE, "ETH", "ETH", None).unwrap();
+        assert_eq!(eth.address(), "0x97Eb7E2D802949D2739E08f9935Abd03A1e046Cb");
+    }
+}
@@ -1,9 +1,9 @@
-use super::Card;
+use super::{Card, Language, parse_mnemonic};
+use super::cache::BALANCE_CACHE;
 use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use bitcoin::Network;
 use bitcoin::psbt::Psbt;
-use bip39::Mnemonic;
 use ed25519_dalek::{Keypair, SecretKey, PublicKey};
 use solana_sdk::{
     commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Keypair as SolanaKeypair, signer::Signer
@@ -17,12 +17,12 @@ pub struct SolanaCard {
     address: String,
     derivation_path: String,
     keypair: SolanaKeypair,
+    rpc_url: String,
 }
 
 impl SolanaCard {
-    pub fn new(network: Network, account: u32, seed_phrase: &str) -> Result<Self> {
-        let mnemonic = Mnemonic::parse(seed_phrase)
-            .map_err(|e| anyhow!("Invalid seed phrase: {}", e))?;
+    pub fn new(network: Network, account: u32, seed_phrase: &str, language: Option<Language>) -> Result<Self> {
+        let mnemonic = parse_mnemonic(seed_phrase, language)?;
         
         let seed = mnemonic.to_seed("");
         
@@ -59,17 +59,44 @@ impl SolanaCard {
             address,
             derivation_path: path,
             keypair,
+            rpc_url: resolve_rpc_url(network, std::env::var("SOLANA_RPC_URL").ok().as_deref()),
         })
     }
     
     fn get_rpc_url(&self) -> &str {
-        match self.network {
-            Network::Bitcoin => "https://api.mainnet-beta.solana.com",
-            _ => "https://api.testnet.solana.com",
-        }
+        &self.rpc_url
+    }
+
+    /// Fetch the balance from upstream, bypassing the balance cache
+    async fn fetch_balance(&self) -> Result<u64> {
+        let rpc_client = RpcClient::new(self.get_rpc_url());
+        let pubkey = Pubkey::from_str(&self.address)
+            .map_err(|e| anyhow!("Invalid Solana address: {}", e))?;
+            
+        let balance = rpc_client
+            .get_balance_with_commitment(&pubkey, CommitmentConfig::confirmed())
+            .map_err(|e| anyhow!("Failed to get balance: {}", e))?
+            .value;
+            
+        Ok(balance)
     }
 }
 
+/// Pick the Solana RPC endpoint, preferring an explicit override (SOLANA_RPC_URL)
+/// over the network-based default. Signet maps to devnet and regtest to a local validator.
+pub fn resolve_rpc_url(network: Network, override_url: Option<&str>) -> String {
+    if let Some(url) = override_url.map(str::trim).filter(|url| !url.is_empty()) {
+        return url.to_string();
+    }
+
+    match network {
+        Network::Bitcoin => "https://api.mainnet-beta.solana.com",
+        Network::Signet => "https://api.devnet.solana.com",
+        Network::Regtest => "http://127.0.0.1:8899",
+        _ => "https://api.testnet.solana.com",
+    }.to_string()
+}
+
 #[async_trait]
 impl Card for SolanaCard {
     fn chain(&self) -> &str {
@@ -97,36 +124,45 @@ impl Card for SolanaCard {
     }
 
     async fn get_balance(&self) -> Result<u64> {
-        let rpc_client = RpcClient::new(self.get_rpc_url());
-        let pubkey = Pubkey::from_str(&self.address)
-            .map_err(|e| anyhow!("Invalid Solana address: {}", e))?;
-            
-        let balance = rpc_client
-            .get_balance_with_commitment(&pubkey, CommitmentConfig::confirmed())
-            .map_err(|e| anyhow!("Failed to get balance: {}", e))?
-            .value;
-            
-        Ok(balance)
+        BALANCE_CACHE.get_or_fetch(self.chain(), self.network, &self.address, || self.fetch_balance()).await
     }
 
-    async fn get_decimal_balance(&self) -> Result<f64> {
-        let lamports = self.get_balance().await?;
-        Ok(lamports as f64 / 1_000_000_000.0)  // Convert lamports to SOL (1 SOL = 1e9 lamports)
+    fn decimals(&self) -> u32 {
+        // 1 SOL = 1e9 lamports
+        9
     }
 
-    async fn get_usd_balance(&self) -> Result<f64> {
-        let sol = self.get_decimal_balance().await?;
+    async fn get_usd_price(&self) -> Result<f64> {
         let api_key = std::env::var("ANYPAY_API_KEY")
             .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-        
+
         let client = crate::client::AnypayClient::new(&api_key);
-        let sol_price = client.get_price("SOL").await?;
-        
-        Ok(sol * sol_price)
+        client.get_price("SOL").await
     }
 
     fn sign_transaction(&self, _psbt: &mut Psbt) -> Result<()> {
         // Solana doesn't use PSBT format
         Err(anyhow!("Solana does not support PSBT transactions"))
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_url_override_takes_precedence() {
+        assert_eq!(
+            resolve_rpc_url(Network::Bitcoin, Some("https://rpc.example.com")),
+            "https://rpc.example.com"
+        );
+        assert_eq!(resolve_rpc_url(Network::Bitcoin, Some("  ")), "https://api.mainnet-beta.solana.com");
+    }
+
+    #[test]
+    fn test_rpc_url_network_defaults() {
+        assert_eq!(resolve_rpc_url(Network::Bitcoin, None), "https://api.mainnet-beta.solana.com");
+        assert_eq!(resolve_rpc_url(Network::Testnet, None), "https://api.testnet.solana.com");
+        assert_eq!(resolve_rpc_url(Network::Signet, None), "https://api.devnet.solana.com");
+    }
+}
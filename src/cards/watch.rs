@@ -0,0 +1,144 @@
+use super::Card;
+use super::cache::BALANCE_CACHE;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use bitcoin::{Network, Address, PublicKey, psbt::Psbt};
+use bip32::{ChildNumber, XPub};
+use std::str::FromStr;
+
+/// A BTC card derived from an account-level xpub (m/44'/0'/account').
+/// It can derive addresses and check balances but holds no private key, so it cannot sign.
+pub struct WatchOnlyCard {
+    network: Network,
+    account: u32,
+    address: String,
+    derivation_path: String,
+}
+
+impl WatchOnlyCard {
+    /// `xpub_account` is the account index `xpub` was exported at; it labels the card but
+    /// doesn't change the addresses, which come from the xpub alone
+    pub fn new(network: Network, xpub_account: u32, xpub: &str) -> Result<Self> {
+        let account_xpub = XPub::from_str(xpub)
+            .map_err(|e| anyhow!("Invalid xpub: {}", e))?;
+
+        // Receive chain /0, first address /0 - matching BitcoinCard's m/44'/0'/account'/0/0
+        let child = account_xpub
+            .derive_child(ChildNumber::new(0, false).map_err(|e| anyhow!("Invalid child number: {}", e))?)
+            .and_then(|receive| receive.derive_child(ChildNumber::new(0, false)?))
+            .map_err(|e| anyhow!("Failed to derive public key: {}", e))?;
+
+        let public_key = PublicKey::from_slice(&child.to_bytes())
+            .map_err(|e| anyhow!("Failed to create public key: {}", e))?;
+        let address = Address::p2wpkh(&public_key, network)
+            .map_err(|e| anyhow!("Failed to create address: {}", e))?;
+
+        Ok(Self {
+            network,
+            account: xpub_account,
+            address: address.to_string(),
+            derivation_path: format!("m/44'/0'/{}'/0/0", xpub_account),
+        })
+    }
+
+    /// Fetch the balance from upstream, bypassing the balance cache
+    async fn fetch_balance(&self) -> Result<u64> {
+        let api_key = std::env::var("ANYPAY_API_KEY")
+            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
+
+        let client = crate::client::AnypayClient::new(&api_key);
+        let utxos = client.get_utxos(&self.address).await?;
+
+        let total_sats: u64 = utxos.iter()
+            .map(|utxo| bitcoin::Amount::from_btc(utxo.amount).unwrap_or(bitcoin::Amount::ZERO))
+            .map(|amount| amount.to_sat())
+            .sum();
+
+        Ok(total_sats)
+    }
+}
+
+#[async_trait]
+impl Card for WatchOnlyCard {
+    fn chain(&self) -> &str {
+        "BTC"
+    }
+
+    fn currency(&self) -> &str {
+        "BTC"
+    }
+
+    fn network(&self) -> Network {
+        self.network
+    }
+
+    fn derivation_path(&self) -> &str {
+        &self.derivation_path
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn account(&self) -> u32 {
+        self.account
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        BALANCE_CACHE.get_or_fetch(self.chain(), self.network, &self.address, || self.fetch_balance()).await
+    }
+
+    fn decimals(&self) -> u32 {
+        8
+    }
+
+    async fn get_usd_price(&self) -> Result<f64> {
+        let api_key = std::env::var("ANYPAY_API_KEY")
+            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
+
+        let client = crate::client::AnypayClient::new(&api_key);
+        client.get_btc_price().await
+    }
+
+    fn sign_transaction(&self, _psbt: &mut Psbt) -> Result<()> {
+        Err(anyhow!("Watch-only card for {} cannot sign transactions", self.address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cards::btc::BitcoinCard;
+    use bip32::{DerivationPath, Prefix, XPrv};
+    use bitcoin::{Transaction, absolute::LockTime, transaction::Version};
+
+    const SEED_PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn account_xpub() -> String {
+        let seed = bip39::Mnemonic::parse(SEED_PHRASE).unwrap().to_seed("");
+        let path = DerivationPath::from_str("m/44'/0'/0'").unwrap();
+        XPrv::derive_from_path(seed, &path).unwrap().public_key().to_string(Prefix::XPUB)
+    }
+
+    #[test]
+    fn test_watch_only_card_matches_seed_card_address() {
+        let watch_only = WatchOnlyCard::new(Network::Bitcoin, 0, &account_xpub()).unwrap();
+        let seeded = BitcoinCard::new(Network::Bitcoin, 0, SEED_PHRASE, None).unwrap();
+
+        assert_eq!(watch_only.address(), seeded.address());
+    }
+
+    #[test]
+    fn test_watch_only_card_cannot_sign() {
+        let card = WatchOnlyCard::new(Network::Bitcoin, 0, &account_xpub()).unwrap();
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+
+        assert!(card.sign_transaction(&mut psbt).is_err());
+    }
+}
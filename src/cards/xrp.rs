@@ -1,10 +1,10 @@
-use super::Card;
+use super::{Card, Language, parse_mnemonic};
+use super::cache::BALANCE_CACHE;
 use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use bitcoin::Network;
 use bitcoin::psbt::Psbt;
 use xrpl::core::keypairs::derive_keypair;
-use bip39::Mnemonic;
 use zerocopy::AsBytes;
 use reqwest;
 use serde_json;
@@ -19,9 +19,8 @@ pub struct RippleCard {
 }
 
 impl RippleCard {
-    pub fn new(network: Network, account: u32, seed_phrase: &str) -> Result<Self> {
-        let mnemonic = Mnemonic::parse(seed_phrase)
-            .map_err(|e| anyhow!("Invalid seed phrase: {}", e))?;
+    pub fn new(network: Network, account: u32, seed_phrase: &str, language: Option<Language>) -> Result<Self> {
+        let mnemonic = parse_mnemonic(seed_phrase, language)?;
         
         let seed = mnemonic.to_seed("");
 
@@ -53,12 +52,40 @@ impl RippleCard {
             public_key: "".to_string(),
         })
     }
+
+    /// Fetch the balance from upstream, bypassing the balance cache
+    async fn fetch_balance(&self) -> Result<u64> {
+        let client = reqwest::Client::new();
+        let response = client
+            .post("https://s1.ripple.com:51234")
+            .json(&serde_json::json!({
+                "method": "account_info",
+                "params": [{
+                    "account": self.address,
+                    "strict": true,
+                    "ledger_index": "current",
+                    "queue": true
+                }]
+            }))
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+
+        let balance = response["result"]["account_data"]["Balance"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Balance not found"))?
+            .parse::<f64>()
+            .map_err(|e| anyhow!("Failed to parse balance: {}", e))?;
+            
+        Ok((balance * 1_000_000.0) as u64)
+    }
 }
 
 #[async_trait]
 impl Card for RippleCard {
     fn chain(&self) -> &str {
-        "XRPL"
+        "XRP"
     }
 
     fn currency(&self) -> &str {
@@ -82,46 +109,20 @@ impl Card for RippleCard {
     }
 
     async fn get_balance(&self) -> Result<u64> {
-        let client = reqwest::Client::new();
-        let response = client
-            .post("https://s1.ripple.com:51234")
-            .json(&serde_json::json!({
-                "method": "account_info",
-                "params": [{
-                    "account": self.address,
-                    "strict": true,
-                    "ledger_index": "current",
-                    "queue": true
-                }]
-            }))
-            .send()
-            .await?
-            .json::<serde_json::Value>()
-            .await?;
-
-        let balance = response["result"]["account_data"]["Balance"]
-            .as_str()
-            .ok_or_else(|| anyhow!("Balance not found"))?
-            .parse::<f64>()
-            .map_err(|e| anyhow!("Failed to parse balance: {}", e))?;
-            
-        Ok((balance * 1_000_000.0) as u64)
+        BALANCE_CACHE.get_or_fetch(self.chain(), self.network, &self.address, || self.fetch_balance()).await
     }
 
-    async fn get_decimal_balance(&self) -> Result<f64> {
-        let drops = self.get_balance().await?;
-        Ok(drops as f64 / 1_000_000.0)  // Convert drops to XRP
+    fn decimals(&self) -> u32 {
+        // 1 XRP = 1,000,000 drops
+        6
     }
 
-    async fn get_usd_balance(&self) -> Result<f64> {
-        let xrp = self.get_decimal_balance().await?;
+    async fn get_usd_price(&self) -> Result<f64> {
         let api_key = std::env::var("ANYPAY_API_KEY")
             .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-        
+
         let client = crate::client::AnypayClient::new(&api_key);
-        let xrp_price = client.get_price("XRP").await?;
-        
-        Ok(xrp * xrp_price)
+        client.get_price("XRP").await
     }
 
     fn sign_transaction(&self, _psbt: &mut Psbt) -> Result<()> {
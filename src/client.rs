@@ -2,15 +2,28 @@ use anyhow::{Result, anyhow};
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, ACCEPT};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use serde::{Deserialize, Serialize};
-use bitcoin::Transaction;
+use bitcoin::{Transaction, TxOut, Amount, ScriptBuf, consensus::deserialize};
+use std::future::Future;
+use std::time::Duration;
+
+pub use crate::types::InvoiceStatus;
 
 const DEFAULT_API_URL: &str = "https://api.anypayx.com";
 const MEMPOOL_API_URL: &str = "https://mempool.space/api";
+const FRACTAL_API_URL: &str = "https://mempool.fractalbitcoin.io/api/v1";
+
+/// How long the Fractal mempool API has to respond before the request fails
+pub const FRACTAL_API_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long a mempool.space-compatible API has to respond before the request fails
+pub const MEMPOOL_API_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Characters of an unexpected response body quoted in errors
+const MAX_ERROR_BODY_CHARS: usize = 200;
 
 #[derive(Debug, Deserialize)]
 pub struct Invoice {
     pub uid: String,
-    pub status: String,
+    pub status: InvoiceStatus,
     pub currency: String,
     pub amount: f64,
     pub uri: String,
@@ -49,12 +62,19 @@ pub struct PaymentInstruction {
     #[serde(rename = "requiredFeeRate")]
     pub required_fee_rate: u32,
     pub outputs: Vec<Output>,
+    /// Hex-encoded data the payment must carry in an OP_RETURN output
+    #[serde(rename = "opReturn", default)]
+    pub op_return: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Output {
+    #[serde(default)]
     pub address: String,
     pub amount: u64,
+    /// Hex-encoded output script, for outputs paid to a script rather than an address
+    #[serde(default)]
+    pub script: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -77,6 +97,64 @@ struct MempoolUtxo {
     status: MempoolUtxoStatus,
 }
 
+#[derive(Debug, Deserialize)]
+struct MempoolAddressStats {
+    tx_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MempoolAddress {
+    chain_stats: MempoolAddressStats,
+    mempool_stats: MempoolAddressStats,
+}
+
+#[derive(Debug, Deserialize)]
+struct MempoolTxStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MempoolPrevout {
+    scriptpubkey: String,
+    value: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MempoolVin {
+    prevout: Option<MempoolPrevout>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MempoolTx {
+    vin: Vec<MempoolVin>,
+    status: MempoolUtxoStatus,
+}
+
+/// A transaction fetched from a mempool API, with the outputs its inputs spend
+#[derive(Debug, Clone)]
+pub struct MempoolTransaction {
+    pub tx: Transaction,
+    pub prevouts: Vec<TxOut>,
+    pub confirmed: bool,
+}
+
+/// A UTXO as the Fractal Bitcoin mempool API reports it, with its value in satoshis
+#[derive(Debug, Deserialize, Clone)]
+pub struct FractalUtxo {
+    pub txid: String,
+    pub vout: u32,
+    pub value: u64,
+    pub status: FractalUtxoStatus,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FractalUtxoStatus {
+    pub confirmed: bool,
+    pub block_height: Option<u32>,
+    pub block_time: Option<u64>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Utxo {
     pub txid: String,
@@ -101,24 +179,10 @@ pub struct PriceList {
     pub prices: Vec<Price>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ConversionResponse {
-    conversion: Conversion,
-}
-
-#[derive(Debug, Deserialize)]
-struct Conversion {
-    output: ConversionOutput,
-}
-
-#[derive(Debug, Deserialize)]
-struct ConversionOutput {
-    value: f64,
-}
-
 pub struct AnypayClient {
     client: reqwest::Client,
     api_url: String,
+    mempool_api_url: String,
 }
 
 impl AnypayClient {
@@ -139,9 +203,27 @@ impl AnypayClient {
         Self {
             client,
             api_url: DEFAULT_API_URL.to_string(),
+            mempool_api_url: MEMPOOL_API_URL.to_string(),
         }
     }
 
+    /// Use the Anypay API at `api_url` instead of the default
+    pub fn with_api_url(mut self, api_url: &str) -> Self {
+        self.api_url = api_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Fetch BTC UTXOs and address history from the mempool API at `mempool_api_url` instead of mempool.space
+    pub fn with_mempool_api_url(mut self, mempool_api_url: &str) -> Self {
+        self.mempool_api_url = mempool_api_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// The mempool API this client uses for `chain`: the Fractal API for FB and its own otherwise
+    pub fn mempool_api_url(&self, chain: &str) -> &str {
+        if chain == "FB" { FRACTAL_API_URL } else { &self.mempool_api_url }
+    }
+
     pub async fn get_invoice(&self, uid: &str) -> Result<Invoice> {
         let response = self.client
             .get(&format!("{}/api/v1/invoices/{}", self.api_url, uid))
@@ -189,9 +271,15 @@ impl AnypayClient {
             .map_err(|e| anyhow!("Failed to parse invoice with payment options: {}", e))
     }
 
+    /// Fetch the payment option for a single chain/currency, erroring if the invoice doesn't offer it
+    pub async fn get_payment_options(&self, uid: &str, chain: &str, currency: &str) -> Result<PaymentOption> {
+        let invoice = self.get_payment_option(uid, chain, currency).await?;
+        select_payment_option(invoice, chain, currency)
+    }
+
     pub async fn get_utxos(&self, address: &str) -> Result<Vec<Utxo>> {
         let response = reqwest::Client::new()
-            .get(&format!("{}/address/{}/utxo", MEMPOOL_API_URL, address))
+            .get(&format!("{}/address/{}/utxo", self.mempool_api_url, address))
             .send()
             .await?;
 
@@ -204,7 +292,7 @@ impl AnypayClient {
         
         // Get the current block height for calculating confirmations
         let tip_response = reqwest::Client::new()
-            .get(&format!("{}/blocks/tip/height", MEMPOOL_API_URL))
+            .get(&format!("{}/blocks/tip/height", self.mempool_api_url))
             .send()
             .await?;
 
@@ -287,18 +375,412 @@ impl AnypayClient {
             .map_err(|e| anyhow!("Failed to parse BTC price: {}", e))
     }
 
+    /// The USD price of one `currency` from the client's API, cached briefly
     pub async fn get_price(&self, currency: &str) -> Result<f64> {
-        let response = self.client
-            .get(&format!("{}/convert/1-{}/to-USD", self.api_url, currency))
+        crate::prices::fetch_convert_to_usd_from(&self.api_url, currency).await
+    }
+
+    /// Poll an invoice until it is paid or confirmed, giving up once the timeout elapses
+    pub async fn wait_for_payment(&self, uid: &str, timeout: Duration, poll_interval: Duration) -> Result<InvoiceStatus> {
+        poll_invoice_status(
+            move || async move { Ok(self.get_invoice(uid).await?.status) },
+            timeout,
+            poll_interval,
+        )
+        .await
+        .map_err(|e| anyhow!("Invoice {}: {}", uid, e))
+    }
+
+    /// Confirmations of transaction `txid` on `chain`, zero while it is unconfirmed
+    pub async fn get_confirmations(&self, chain: &str, txid: &str) -> Result<u32> {
+        let api_url = self.mempool_api_url(chain);
+        let response = reqwest::Client::new()
+            .get(&format!("{}/tx/{}/status", api_url, txid))
+            .timeout(MEMPOOL_API_TIMEOUT)
             .send()
             .await?;
-
         if !response.status().is_success() {
             let error = response.text().await?;
-            return Err(anyhow!("Failed to fetch price: {}", error));
+            return Err(anyhow!("Failed to fetch status of transaction {}: {}", txid, error));
+        }
+        let status = response.json::<MempoolTxStatus>().await?;
+
+        let Some(block_height) = status.block_height.filter(|_| status.confirmed) else {
+            return Ok(0);
+        };
+        let tip_height = reqwest::Client::new()
+            .get(&format!("{}/blocks/tip/height", api_url))
+            .timeout(MEMPOOL_API_TIMEOUT)
+            .send()
+            .await?
+            .text()
+            .await?
+            .trim()
+            .parse::<u32>()
+            .map_err(|e| anyhow!("Failed to parse block height: {}", e))?;
+
+        Ok(tip_height.saturating_sub(block_height) + 1)
+    }
+
+    /// Poll transaction `txid` on `chain` until it has `required` confirmations, giving up once the timeout elapses
+    pub async fn wait_for_confirmations(&self, chain: &str, txid: &str, required: u32, timeout: Duration, poll_interval: Duration) -> Result<u32> {
+        poll_confirmations(
+            move || async move { self.get_confirmations(chain, txid).await },
+            required,
+            timeout,
+            poll_interval,
+        )
+        .await
+        .map_err(|e| anyhow!("Transaction {}: {}", txid, e))
+    }
+}
+
+fn select_payment_option(invoice: Invoice, chain: &str, currency: &str) -> Result<PaymentOption> {
+    invoice.payment_options.into_iter()
+        .find(|option| option.chain.eq_ignore_ascii_case(chain) && option.currency.eq_ignore_ascii_case(currency))
+        .ok_or_else(|| anyhow!("Invoice {} does not support {}/{}", invoice.uid, chain, currency))
+}
+
+/// The start of `body`, for quoting an unexpected response in an error
+fn excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(MAX_ERROR_BODY_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+/// Fetch the UTXOs of a Fractal Bitcoin address from the Fractal mempool API
+pub async fn get_fractal_utxos(address: &str) -> Result<Vec<FractalUtxo>> {
+    fetch_fractal_utxos(FRACTAL_API_URL, address).await
+}
+
+/// Fetch UTXOs from the Fractal mempool API at `api_url`. Error pages, which its proxy serves
+/// as HTML, are reported with their status and body rather than as a JSON parse error.
+async fn fetch_fractal_utxos(api_url: &str, address: &str) -> Result<Vec<FractalUtxo>> {
+    let url = format!("{}/address/{}/utxo", api_url, address);
+    let response = reqwest::Client::new()
+        .get(&url)
+        .timeout(FRACTAL_API_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| if e.is_timeout() {
+            anyhow!("Fractal API did not respond within {}s for {}", FRACTAL_API_TIMEOUT.as_secs(), url)
+        } else {
+            anyhow!("Failed to connect to Fractal API: {}", e)
+        })?;
+
+    let status = response.status();
+    let content_type = response.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("no content type")
+        .to_string();
+    let body = response.text().await
+        .map_err(|e| anyhow!("Failed to read Fractal API response: {}", e))?;
+
+    if !status.is_success() {
+        return Err(anyhow!("Fractal API returned {} ({}) for {}: {}", status, content_type, url, excerpt(&body)));
+    }
+    if !content_type.starts_with("application/json") {
+        return Err(anyhow!("Fractal API returned {} instead of JSON for {}: {}", content_type, url, excerpt(&body)));
+    }
+
+    serde_json::from_str(&body)
+        .map_err(|e| anyhow!("Failed to parse UTXOs from Fractal API: {}: {}", e, excerpt(&body)))
+}
+
+/// The mempool API serving `chain` by default: the Fractal API for FB and mempool.space otherwise
+pub fn default_mempool_api_url(chain: &str) -> &'static str {
+    if chain == "FB" { FRACTAL_API_URL } else { MEMPOOL_API_URL }
+}
+
+/// Fetch a transaction and the outputs its inputs spend from the mempool API serving `chain`
+pub async fn get_transaction(chain: &str, txid: &str) -> Result<MempoolTransaction> {
+    fetch_transaction(default_mempool_api_url(chain), txid).await
+}
+
+async fn fetch_transaction(api_url: &str, txid: &str) -> Result<MempoolTransaction> {
+    let client = reqwest::Client::new();
+
+    let url = format!("{}/tx/{}/hex", api_url, txid);
+    let response = client.get(&url).timeout(MEMPOOL_API_TIMEOUT).send().await
+        .map_err(|e| anyhow!("Failed to fetch transaction {}: {}", txid, e))?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(anyhow!("Failed to fetch transaction from {}: {} {}", url, status, excerpt(&body)));
+    }
+    let tx: Transaction = deserialize(&hex::decode(body.trim())?)
+        .map_err(|e| anyhow!("Failed to decode transaction {}: {}", txid, e))?;
+
+    let url = format!("{}/tx/{}", api_url, txid);
+    let response = client.get(&url).timeout(MEMPOOL_API_TIMEOUT).send().await
+        .map_err(|e| anyhow!("Failed to fetch transaction {}: {}", txid, e))?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(anyhow!("Failed to fetch transaction from {}: {} {}", url, status, excerpt(&body)));
+    }
+    let details: MempoolTx = serde_json::from_str(&body)
+        .map_err(|e| anyhow!("Failed to parse transaction {}: {}: {}", txid, e, excerpt(&body)))?;
+
+    let prevouts = details.vin.into_iter()
+        .map(|vin| {
+            let prevout = vin.prevout.ok_or_else(|| anyhow!("Transaction {} has an input without a previous output", txid))?;
+            Ok(TxOut {
+                value: Amount::from_sat(prevout.value),
+                script_pubkey: ScriptBuf::from_hex(&prevout.scriptpubkey)
+                    .map_err(|e| anyhow!("Invalid previous output script {}: {}", prevout.scriptpubkey, e))?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(MempoolTransaction { tx, prevouts, confirmed: details.status.confirmed })
+}
+
+/// Broadcast a raw transaction through the mempool API serving `chain`, returning its txid
+pub async fn broadcast_transaction(chain: &str, tx_hex: &str) -> Result<String> {
+    let url = format!("{}/tx", default_mempool_api_url(chain));
+    let response = reqwest::Client::new()
+        .post(&url)
+        .timeout(MEMPOOL_API_TIMEOUT)
+        .body(tx_hex.to_string())
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to broadcast transaction: {}", e))?;
+
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(anyhow!("Transaction was rejected: {} {}", status, excerpt(&body)));
+    }
+    Ok(body.trim().to_string())
+}
+
+/// Whether any transaction, confirmed or not, has paid to or spent from `address`,
+/// according to the mempool API at `api_url`
+pub async fn address_has_history(api_url: &str, address: &str) -> Result<bool> {
+    let url = format!("{}/address/{}", api_url, address);
+    let response = reqwest::Client::new()
+        .get(&url)
+        .timeout(MEMPOOL_API_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to fetch address history for {}: {}", address, e))?;
+
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(anyhow!("Failed to fetch address history from {}: {} {}", url, status, excerpt(&body)));
+    }
+
+    let stats: MempoolAddress = serde_json::from_str(&body)
+        .map_err(|e| anyhow!("Failed to parse address history for {}: {}: {}", address, e, excerpt(&body)))?;
+    Ok(stats.chain_stats.tx_count + stats.mempool_stats.tx_count > 0)
+}
+
+async fn poll_invoice_status<F, Fut>(mut fetch_status: F, timeout: Duration, poll_interval: Duration) -> Result<InvoiceStatus>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<InvoiceStatus>>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let status = fetch_status().await?;
+        if status.is_paid() {
+            return Ok(status);
+        }
+        if status == InvoiceStatus::Expired {
+            return Err(anyhow!("invoice expired before payment was received"));
+        }
+        if tokio::time::Instant::now() + poll_interval > deadline {
+            return Err(anyhow!("timed out waiting for payment (last status: {:?})", status));
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+async fn poll_confirmations<F, Fut>(mut fetch_confirmations: F, required: u32, timeout: Duration, poll_interval: Duration) -> Result<u32>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u32>>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        // A just-broadcast transaction may not be indexed yet and APIs have hiccups, so failed
+        // lookups are retried like unconfirmed ones until the deadline
+        let last_seen = match fetch_confirmations().await {
+            Ok(confirmations) if confirmations >= required => return Ok(confirmations),
+            Ok(confirmations) => confirmations.to_string(),
+            Err(e) => format!("error: {}", e),
+        };
+        if tokio::time::Instant::now() + poll_interval > deadline {
+            return Err(anyhow!("timed out waiting for {} confirmations (last seen: {})", required, last_seen));
         }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::supabase::tests::spawn_mock_supabase;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_poll_until_paid() {
+        let counter = AtomicUsize::new(0);
+        let polls = &counter;
+        let status = poll_invoice_status(
+            move || async move {
+                // Flip to paid on the third poll
+                let n = polls.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(if n >= 3 { InvoiceStatus::Paid } else { InvoiceStatus::Unpaid })
+            },
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+        ).await.unwrap();
+
+        assert_eq!(status, InvoiceStatus::Paid);
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_select_btc_payment_option() {
+        let option = |chain: &str, address: &str, amount: u64, fee_rate: u32| serde_json::json!({
+            "time": "2024-01-01T00:00:00Z",
+            "expires": "2024-01-01T00:15:00Z",
+            "memo": "",
+            "paymentUrl": "https://api.anypayx.com/r/inv_123",
+            "paymentId": "inv_123",
+            "chain": chain,
+            "currency": chain,
+            "network": "main",
+            "instructions": [{
+                "type": "transaction",
+                "requiredFeeRate": fee_rate,
+                "outputs": [{ "address": address, "amount": amount }]
+            }]
+        });
+
+        let invoice: Invoice = serde_json::from_value(serde_json::json!({
+            "uid": "inv_123",
+            "status": "unpaid",
+            "currency": "USD",
+            "amount": 10.0,
+            "uri": "pay:?r=https://api.anypayx.com/r/inv_123",
+            "createdAt": "2024-01-01T00:00:00Z",
+            "expiresAt": null,
+            "payment_options": [
+                option("BSV", "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", 500_000, 1),
+                option("BTC", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", 25_000, 12),
+            ],
+            "notes": []
+        })).unwrap();
+
+        let btc = select_payment_option(invoice, "BTC", "BTC").unwrap();
+        let instruction = &btc.instructions[0];
+
+        assert_eq!(instruction.outputs[0].address, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+        assert_eq!(instruction.outputs[0].amount, 25_000);
+        assert_eq!(instruction.required_fee_rate, 12);
+    }
+
+    #[tokio::test]
+    async fn test_fractal_error_page_is_reported_with_status_and_body() {
+        use axum::{http::{header, StatusCode}, routing::get, Router};
+
+        let router = Router::new().route("/address/:address/utxo", get(|| async {
+            (
+                StatusCode::BAD_GATEWAY,
+                [(header::CONTENT_TYPE, "text/html")],
+                "<html><body><h1>502 Bad Gateway</h1></body></html>",
+            )
+        }));
+        let url = spawn_mock_supabase(router);
+
+        let err = fetch_fractal_utxos(&url, "bc1qtest").await.unwrap_err().to_string();
 
-        let conversion = response.json::<ConversionResponse>().await?;
-        Ok(conversion.conversion.output.value)
+        assert!(err.contains("502 Bad Gateway (text/html)"), "unexpected error: {}", err);
+        assert!(err.contains("<h1>502 Bad Gateway</h1>"), "unexpected error: {}", err);
+        assert!(!err.contains("parse"), "unexpected error: {}", err);
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_waits_for_required_confirmations() {
+        use axum::{routing::get, Json, Router};
+        use std::sync::Arc;
+
+        // Not yet indexed on the first poll, unconfirmed on the second, then mined two blocks below the tip
+        let polls = Arc::new(AtomicUsize::new(0));
+        let router = Router::new()
+            .route("/tx/:txid/status", get({
+                let polls = polls.clone();
+                move || async move {
+                    let n = polls.fetch_add(1, Ordering::SeqCst) + 1;
+                    match n {
+                        1 => Err(axum::http::StatusCode::NOT_FOUND),
+                        2 => Ok(Json(serde_json::json!({ "confirmed": false }))),
+                        _ => Ok(Json(serde_json::json!({ "confirmed": true, "block_height": 98 }))),
+                    }
+                }
+            }))
+            .route("/blocks/tip/height", get(|| async { "100" }));
+        let url = spawn_mock_supabase(router);
+
+        let client = AnypayClient::new("test").with_mempool_api_url(&url);
+        let confirmations = client.wait_for_confirmations("BTC", "abc", 3, Duration::from_secs(5), Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        assert_eq!(confirmations, 3);
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+
+        let timed_out = client.wait_for_confirmations("BTC", "abc", 6, Duration::from_millis(50), Duration::from_millis(10)).await;
+        assert!(timed_out.unwrap_err().to_string().contains("6 confirmations"));
+    }
+
+    #[tokio::test]
+    async fn test_price_comes_from_the_configured_api() {
+        use axum::{routing::get, Json, Router};
+
+        let router = Router::new().route("/convert/1-DOGE/to-USD", get(|| async {
+            Json(serde_json::json!({ "conversion": { "output": { "currency": "USD", "value": 0.12 } } }))
+        }));
+        let url = spawn_mock_supabase(router);
+
+        let client = AnypayClient::new("test").with_api_url(&url);
+
+        assert_eq!(client.get_price("DOGE").await.unwrap(), 0.12);
+    }
+
+    #[tokio::test]
+    async fn test_poll_times_out() {
+        let result = poll_invoice_status(
+            || async { Ok(InvoiceStatus::Unpaid) },
+            Duration::from_millis(50),
+            Duration::from_millis(10),
+        ).await;
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_select_unsupported_payment_option() {
+        let invoice: Invoice = serde_json::from_value(serde_json::json!({
+            "uid": "inv_123",
+            "status": "unpaid",
+            "currency": "USD",
+            "amount": 10.0,
+            "uri": "pay:?r=https://api.anypayx.com/r/inv_123",
+            "createdAt": "2024-01-01T00:00:00Z",
+            "payment_options": [],
+            "notes": []
+        })).unwrap();
+
+        assert!(select_payment_option(invoice, "DOGE", "DOGE").is_err());
+    }
+}
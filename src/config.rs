@@ -1,44 +1,336 @@
 use serde::Deserialize;
 use anyhow::{Result, anyhow};
+use crate::session::{BackpressurePolicy, DEFAULT_MAX_SUBSCRIPTIONS, DEFAULT_SEND_BUFFER};
+use crate::http::{parse_payment_network, DEFAULT_CORS_ALLOWED_ORIGINS, DEFAULT_MAX_BODY_BYTES, DEFAULT_PAYMENT_NETWORK};
+use crate::confirmations::ZeroConfPolicy;
+use crate::rate_limit::{RateLimit, DEFAULT_RATE_LIMIT_BURST};
+use crate::prices::{PriceSources, DEFAULT_PRICE_MAX_AGE_SECONDS};
+use crate::amqp::{AmqpTopology, DEFAULT_BINDING_KEY, DEFAULT_EXCHANGE};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub supabase_url: String,
     pub supabase_anon_key: String,
     pub supabase_service_role_key: String,
     pub amqp_url: Option<String>,
+    /// Exchange, and optional bound queue, declared on the AMQP broker
+    pub amqp_topology: AmqpTopology,
     pub xrpl_wss_url: Option<String>,
+    pub eth_wss_url: Option<String>,
+    pub polygon_wss_url: Option<String>,
+    pub avax_wss_url: Option<String>,
+    pub bnb_wss_url: Option<String>,
     pub websocket_host: String,
     pub websocket_port: u16,
     pub http_host: String,
     pub http_port: u16,
+    pub blockbook_url: Option<String>,
+    pub blockbook_api_key: Option<String>,
+    pub websocket_send_buffer: usize,
+    pub websocket_backpressure: BackpressurePolicy,
+    pub websocket_max_subscriptions: usize,
+    pub cors_allowed_origins: Vec<String>,
+    pub http_max_body_bytes: usize,
+    /// Payment protocol network, "main" or "test", wallets are told invoices are paid on
+    pub payment_network: String,
+    pub zero_conf_policy: ZeroConfPolicy,
+    /// Requests each HTTP client, and messages each websocket session, may make; unlimited when unset
+    pub rate_limit: Option<RateLimit>,
+    /// Price sources in order of preference, for pairs quoted by more than one
+    pub price_sources: Vec<String>,
+    /// Seconds before a price is stale and the next source's is used instead
+    pub price_max_age_secs: i64,
+    /// Bearer token for the admin endpoints, which are disabled when unset
+    pub admin_api_key: Option<String>,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
         dotenv::dotenv().ok();
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
 
+    /// Build the configuration from the variables `var` returns, named as in the environment
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
         Ok(Config {
-            supabase_url: std::env::var("SUPABASE_URL")
-                .map_err(|_| anyhow!("SUPABASE_URL not set"))?,
-            supabase_anon_key: std::env::var("SUPABASE_ANON_KEY")
-                .map_err(|_| anyhow!("SUPABASE_ANON_KEY not set"))?,
-            supabase_service_role_key: std::env::var("SUPABASE_SERVICE_ROLE_KEY")
-                .map_err(|_| anyhow!("SUPABASE_SERVICE_ROLE_KEY not set"))?,
-            amqp_url: std::env::var("AMQP_URL").ok(),
-            xrpl_wss_url: std::env::var("XRPL_WSS_URL").ok(),
-            websocket_host: std::env::var("WEBSOCKET_HOST")
-                .unwrap_or_else(|_| "127.0.0.1".to_string()),
-            websocket_port: std::env::var("WEBSOCKET_PORT")
-                .unwrap_or_else(|_| "8080".to_string())
+            supabase_url: var("SUPABASE_URL")
+                .ok_or_else(|| anyhow!("SUPABASE_URL not set"))?,
+            supabase_anon_key: var("SUPABASE_ANON_KEY")
+                .ok_or_else(|| anyhow!("SUPABASE_ANON_KEY not set"))?,
+            supabase_service_role_key: var("SUPABASE_SERVICE_ROLE_KEY")
+                .ok_or_else(|| anyhow!("SUPABASE_SERVICE_ROLE_KEY not set"))?,
+            amqp_url: var("AMQP_URL"),
+            amqp_topology: AmqpTopology {
+                exchange: var("AMQP_EXCHANGE")
+                    .unwrap_or_else(|| DEFAULT_EXCHANGE.to_string()),
+                exchange_type: match var("AMQP_EXCHANGE_TYPE") {
+                    Some(exchange_type) => exchange_type.parse()?,
+                    None => AmqpTopology::default().exchange_type,
+                },
+                queue: var("AMQP_QUEUE").filter(|queue| !queue.is_empty()),
+                binding_key: var("AMQP_BINDING_KEY")
+                    .unwrap_or_else(|| DEFAULT_BINDING_KEY.to_string()),
+            },
+            xrpl_wss_url: var("XRPL_WSS_URL"),
+            eth_wss_url: var("ETH_WSS_URL"),
+            polygon_wss_url: var("POLYGON_WSS_URL"),
+            avax_wss_url: var("AVAX_WSS_URL"),
+            bnb_wss_url: var("BNB_WSS_URL"),
+            websocket_host: var("WEBSOCKET_HOST")
+                .unwrap_or_else(|| "127.0.0.1".to_string()),
+            websocket_port: var("WEBSOCKET_PORT")
+                .unwrap_or_else(|| "8080".to_string())
                 .parse()
                 .map_err(|e| anyhow!("Invalid WEBSOCKET_PORT: {}", e))?,
-            http_host: std::env::var("HTTP_HOST")
-                .unwrap_or_else(|_| "127.0.0.1".to_string()),
-            http_port: std::env::var("HTTP_PORT")
-                .unwrap_or_else(|_| "3000".to_string())
+            http_host: var("HTTP_HOST")
+                .unwrap_or_else(|| "127.0.0.1".to_string()),
+            http_port: var("HTTP_PORT")
+                .unwrap_or_else(|| "3000".to_string())
                 .parse()
                 .map_err(|e| anyhow!("Invalid HTTP_PORT: {}", e))?,
+            blockbook_url: var("BLOCKBOOK_WS_URL"),
+            blockbook_api_key: var("BLOCKBOOK_API_KEY"),
+            websocket_send_buffer: match var("WEBSOCKET_SEND_BUFFER") {
+                Some(size) => size.parse()
+                    .map_err(|e| anyhow!("Invalid WEBSOCKET_SEND_BUFFER: {}", e))?,
+                None => DEFAULT_SEND_BUFFER,
+            },
+            websocket_backpressure: match var("WEBSOCKET_BACKPRESSURE") {
+                Some(policy) => policy.parse()?,
+                None => BackpressurePolicy::Disconnect,
+            },
+            websocket_max_subscriptions: match var("WEBSOCKET_MAX_SUBSCRIPTIONS") {
+                Some(max) => max.parse()
+                    .map_err(|e| anyhow!("Invalid WEBSOCKET_MAX_SUBSCRIPTIONS: {}", e))?,
+                None => DEFAULT_MAX_SUBSCRIPTIONS,
+            },
+            // Comma-separated, e.g. "https://anypayx.com,https://shop.example.com"
+            cors_allowed_origins: match var("CORS_ALLOWED_ORIGINS") {
+                Some(origins) => origins.split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect(),
+                None => DEFAULT_CORS_ALLOWED_ORIGINS.iter().map(|origin| origin.to_string()).collect(),
+            },
+            http_max_body_bytes: match var("HTTP_MAX_BODY_BYTES") {
+                Some(size) => size.parse()
+                    .map_err(|e| anyhow!("Invalid HTTP_MAX_BODY_BYTES: {}", e))?,
+                None => DEFAULT_MAX_BODY_BYTES,
+            },
+            payment_network: match var("PAYMENT_NETWORK") {
+                Some(network) => parse_payment_network(&network)?,
+                None => DEFAULT_PAYMENT_NETWORK.to_string(),
+            },
+            zero_conf_policy: match var("ZERO_CONF_POLICY") {
+                Some(policy) => policy.parse()?,
+                None => ZeroConfPolicy::Notify,
+            },
+            // RATE_LIMIT_PER_SECOND enables rate limiting, with bursts of RATE_LIMIT_BURST
+            rate_limit: match var("RATE_LIMIT_PER_SECOND") {
+                Some(per_second) => Some(RateLimit {
+                    per_second: per_second.parse()
+                        .map_err(|e| anyhow!("Invalid RATE_LIMIT_PER_SECOND: {}", e))?,
+                    burst: match var("RATE_LIMIT_BURST") {
+                        Some(burst) => burst.parse()
+                            .map_err(|e| anyhow!("Invalid RATE_LIMIT_BURST: {}", e))?,
+                        None => DEFAULT_RATE_LIMIT_BURST,
+                    },
+                }),
+                None => None,
+            },
+            // Comma-separated, most preferred first, e.g. "coinbase,kraken"
+            price_sources: var("PRICE_SOURCES")
+                .map(|sources| sources.split(',')
+                    .map(|source| source.trim().to_string())
+                    .filter(|source| !source.is_empty())
+                    .collect())
+                .unwrap_or_default(),
+            price_max_age_secs: match var("PRICE_MAX_AGE_SECONDS") {
+                Some(secs) => secs.parse()
+                    .map_err(|e| anyhow!("Invalid PRICE_MAX_AGE_SECONDS: {}", e))?,
+                None => DEFAULT_PRICE_MAX_AGE_SECONDS,
+            },
+            admin_api_key: var("ADMIN_API_KEY").filter(|key| !key.is_empty()),
         })
     }
-} 
\ No newline at end of file
+
+    /// The price source preference configured by PRICE_SOURCES and PRICE_MAX_AGE_SECONDS
+    pub fn price_sources(&self) -> PriceSources {
+        PriceSources {
+            priority: self.price_sources.clone(),
+            max_age: chrono::Duration::seconds(self.price_max_age_secs),
+        }
+    }
+
+    /// Check cross-field constraints that can't be expressed by parsing alone
+    pub fn validate(&self) -> Result<()> {
+        if self.websocket_port == self.http_port {
+            return Err(anyhow!(
+                "WEBSOCKET_PORT and HTTP_PORT must differ (both set to {})",
+                self.websocket_port
+            ));
+        }
+
+        url::Url::parse(&self.supabase_url)
+            .map_err(|e| anyhow!("Invalid SUPABASE_URL {}: {}", self.supabase_url, e))?;
+
+        if let Some(amqp_url) = &self.amqp_url {
+            url::Url::parse(amqp_url)
+                .map_err(|e| anyhow!("Invalid AMQP_URL {}: {}", amqp_url, e))?;
+        }
+
+        // The unnamed default exchange is predeclared and can't be declared again
+        if self.amqp_topology.exchange.trim().is_empty() {
+            return Err(anyhow!("AMQP_EXCHANGE must not be empty"));
+        }
+
+        if let Some(xrpl_wss_url) = &self.xrpl_wss_url {
+            url::Url::parse(xrpl_wss_url)
+                .map_err(|e| anyhow!("Invalid XRPL_WSS_URL {}: {}", xrpl_wss_url, e))?;
+        }
+
+        for (name, value) in [
+            ("ETH_WSS_URL", &self.eth_wss_url),
+            ("POLYGON_WSS_URL", &self.polygon_wss_url),
+            ("AVAX_WSS_URL", &self.avax_wss_url),
+            ("BNB_WSS_URL", &self.bnb_wss_url),
+        ] {
+            if let Some(url) = value {
+                url::Url::parse(url)
+                    .map_err(|e| anyhow!("Invalid {} {}: {}", name, url, e))?;
+            }
+        }
+
+        if self.blockbook_url.is_some() && self.blockbook_api_key.is_none() {
+            return Err(anyhow!("BLOCKBOOK_API_KEY is required when BLOCKBOOK_WS_URL is set"));
+        }
+
+        for origin in &self.cors_allowed_origins {
+            url::Url::parse(origin)
+                .map_err(|e| anyhow!("Invalid origin in CORS_ALLOWED_ORIGINS {}: {}", origin, e))?;
+        }
+
+        if self.websocket_send_buffer == 0 {
+            return Err(anyhow!("WEBSOCKET_SEND_BUFFER must be greater than zero"));
+        }
+
+        if self.websocket_max_subscriptions == 0 {
+            return Err(anyhow!("WEBSOCKET_MAX_SUBSCRIPTIONS must be greater than zero"));
+        }
+
+        if self.price_max_age_secs <= 0 {
+            return Err(anyhow!("PRICE_MAX_AGE_SECONDS must be greater than zero"));
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            if !(rate_limit.per_second.is_finite() && rate_limit.per_second > 0.0) {
+                return Err(anyhow!("RATE_LIMIT_PER_SECOND must be greater than zero"));
+            }
+            if rate_limit.burst == 0 {
+                return Err(anyhow!("RATE_LIMIT_BURST must be greater than zero"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// A valid configuration with no chains, AMQP or Blockbook, for tests to adjust
+    pub(crate) fn for_tests() -> Self {
+        Config {
+            supabase_url: "https://example.supabase.co".to_string(),
+            supabase_anon_key: "anon".to_string(),
+            supabase_service_role_key: "service".to_string(),
+            amqp_url: None,
+            amqp_topology: AmqpTopology::default(),
+            xrpl_wss_url: None,
+            eth_wss_url: None,
+            polygon_wss_url: None,
+            avax_wss_url: None,
+            bnb_wss_url: None,
+            websocket_host: "127.0.0.1".to_string(),
+            websocket_port: 8080,
+            http_host: "127.0.0.1".to_string(),
+            http_port: 3000,
+            blockbook_url: None,
+            blockbook_api_key: None,
+            websocket_send_buffer: DEFAULT_SEND_BUFFER,
+            websocket_backpressure: BackpressurePolicy::Disconnect,
+            websocket_max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            cors_allowed_origins: vec!["https://anypayx.com".to_string()],
+            http_max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            payment_network: DEFAULT_PAYMENT_NETWORK.to_string(),
+            zero_conf_policy: ZeroConfPolicy::Notify,
+            rate_limit: None,
+            price_sources: vec![],
+            price_max_age_secs: DEFAULT_PRICE_MAX_AGE_SECONDS,
+            admin_api_key: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::ExchangeType;
+
+    #[test]
+    fn test_validate_accepts_valid_config() {
+        assert!(Config::for_tests().validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_colliding_ports() {
+        let config = Config { http_port: 8080, ..Config::for_tests() };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_blockbook_without_api_key() {
+        let config = Config {
+            blockbook_url: Some("btc.example.com".to_string()),
+            ..Config::for_tests()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_empty_amqp_exchange() {
+        let config = Config {
+            amqp_topology: AmqpTopology { exchange: String::new(), ..AmqpTopology::default() },
+            ..Config::for_tests()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_from_vars_populates_chain_and_blockbook_fields() {
+        let vars = std::collections::HashMap::from([
+            ("SUPABASE_URL", "https://example.supabase.co"),
+            ("SUPABASE_ANON_KEY", "anon"),
+            ("SUPABASE_SERVICE_ROLE_KEY", "service"),
+            ("ETH_WSS_URL", "wss://eth.example.com"),
+            ("POLYGON_WSS_URL", "wss://polygon.example.com"),
+            ("AVAX_WSS_URL", "wss://avax.example.com"),
+            ("BNB_WSS_URL", "wss://bnb.example.com"),
+            ("BLOCKBOOK_WS_URL", "btc.example.com"),
+            ("BLOCKBOOK_API_KEY", "blockbook-key"),
+            ("AMQP_EXCHANGE", "events"),
+            ("AMQP_EXCHANGE_TYPE", "fanout"),
+        ]);
+
+        let config = Config::from_vars(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+
+        assert_eq!(config.eth_wss_url.as_deref(), Some("wss://eth.example.com"));
+        assert_eq!(config.polygon_wss_url.as_deref(), Some("wss://polygon.example.com"));
+        assert_eq!(config.avax_wss_url.as_deref(), Some("wss://avax.example.com"));
+        assert_eq!(config.bnb_wss_url.as_deref(), Some("wss://bnb.example.com"));
+        assert_eq!(config.blockbook_url.as_deref(), Some("btc.example.com"));
+        assert_eq!(config.blockbook_api_key.as_deref(), Some("blockbook-key"));
+        assert_eq!(config.amqp_topology.exchange, "events");
+        assert_eq!(config.amqp_topology.exchange_type, ExchangeType::Fanout);
+        assert_eq!(config.amqp_topology.binding_key, DEFAULT_BINDING_KEY);
+    }
+}
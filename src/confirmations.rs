@@ -1,9 +1,13 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast;
 use tracing::{info, error, debug};
 use crate::supabase::SupabaseClient;
+use crate::types::{InvoiceStatus, PaymentStatus};
+use crate::webhooks::send_webhook;
 use anyhow::anyhow;
 // Core types
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,8 +24,10 @@ pub struct Payment {
     pub txid: String,
     pub chain: String,
     pub currency: String,
-    pub status: String,
+    pub status: PaymentStatus,
     pub invoice_uid: String,
+    #[serde(default)]
+    pub amount: Option<i64>,
     pub confirmation_hash: Option<String>,
     pub confirmation_height: Option<i32>,
     pub confirmation_date: Option<DateTime<Utc>>,
@@ -31,7 +37,7 @@ pub struct Payment {
 pub struct Invoice {
     pub id: i32,
     pub uid: String,
-    pub status: String,
+    pub status: InvoiceStatus,
     pub account_id: Option<String>,
     pub app_id: Option<String>,
     pub hash: Option<String>,
@@ -57,13 +63,13 @@ pub struct PaymentInfo {
     pub chain: String,
     pub currency: String,
     pub txid: String,
-    pub status: String,
+    pub status: PaymentStatus,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct InvoiceInfo {
     pub uid: String,
-    pub status: String,
+    pub status: InvoiceStatus,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -72,7 +78,71 @@ pub struct ConfirmationInfo {
     pub height: i32,
 }
 
-#[derive(Debug, Clone)]
+/// How payments seen in the mempool, before their first confirmation, are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZeroConfPolicy {
+    /// Ignore unconfirmed transactions and wait for the block
+    Disabled,
+    /// Emit payment.seen, but leave the invoice unpaid until the payment confirms
+    Notify,
+    /// Emit payment.seen, record the payment and mark the invoice paid straight away
+    Accept,
+}
+
+impl std::str::FromStr for ZeroConfPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy.trim().to_lowercase().as_str() {
+            "disabled" => Ok(ZeroConfPolicy::Disabled),
+            "notify" => Ok(ZeroConfPolicy::Notify),
+            "accept" => Ok(ZeroConfPolicy::Accept),
+            _ => Err(anyhow!("Invalid zero-conf policy: {} (expected disabled, notify or accept)", policy)),
+        }
+    }
+}
+
+/// Published when an unconfirmed transaction pays a watched address
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentSeenEvent {
+    pub topic: String,
+    pub payload: PaymentSeenPayload,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentSeenPayload {
+    pub account_id: Option<String>,
+    pub payment: PaymentInfo,
+    pub invoice: InvoiceInfo,
+    pub address: String,
+    /// Base units paid to the address by the transaction
+    pub amount: i64,
+    /// Whether the zero-conf policy accepted the payment as paying the invoice
+    pub accepted: bool,
+}
+
+/// Published as each block adds a confirmation to a payment, up to the required count
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentConfirmationEvent {
+    pub topic: String,
+    pub payload: PaymentConfirmationPayload,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentConfirmationPayload {
+    pub payment: PaymentInfo,
+    pub invoice: InvoiceInfo,
+    /// The block the payment was mined in
+    pub confirmation: ConfirmationInfo,
+    pub confirmations: u32,
+    pub required_confirmations: u32,
+}
+
+/// payment.confirmation events buffered for slow subscribers before the oldest are dropped
+const CONFIRMATION_EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Default)]
 pub struct BlockNotification {
     pub hash: String,
     pub height: u32,
@@ -80,14 +150,103 @@ pub struct BlockNotification {
     pub txids: Vec<String>,
 }
 
+/// Most blocks a single replay may scan
+pub const MAX_REPLAY_BLOCKS: u32 = 1000;
+
+/// Check that a replay range is ordered and no longer than MAX_REPLAY_BLOCKS
+pub fn validate_replay_range(from_height: u32, to_height: u32) -> Result<()> {
+    if from_height > to_height {
+        return Err(anyhow!("from_height {} is above to_height {}", from_height, to_height));
+    }
+    if to_height - from_height >= MAX_REPLAY_BLOCKS {
+        return Err(anyhow!("Cannot replay more than {} blocks at once", MAX_REPLAY_BLOCKS));
+    }
+    Ok(())
+}
+
+/// Outcome of re-scanning a block range
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ReplaySummary {
+    pub from_height: u32,
+    pub to_height: u32,
+    pub blocks_scanned: u32,
+    pub payments_confirmed: usize,
+}
+
 pub struct ConfirmationService {
     supabase: SupabaseClient,
     block_tx: broadcast::Sender<BlockNotification>,
+    required_confirmations: u32,
+    events: broadcast::Sender<PaymentConfirmationEvent>,
+    /// Mined payments still short of the required confirmations, by txid
+    confirming: Mutex<HashMap<String, PaymentConfirmationPayload>>,
 }
 
 impl ConfirmationService {
     pub fn new(supabase: SupabaseClient, block_tx: broadcast::Sender<BlockNotification>) -> Self {
-        Self { supabase, block_tx }
+        let (events, _) = broadcast::channel(CONFIRMATION_EVENT_BUFFER);
+        Self {
+            supabase,
+            block_tx,
+            required_confirmations: 1,
+            events,
+            confirming: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep publishing payment.confirmation for each new block until a payment has `required` confirmations
+    pub fn with_required_confirmations(mut self, required: u32) -> Self {
+        self.required_confirmations = required.max(1);
+        self
+    }
+
+    /// Receive a payment.confirmation event each time a block adds a confirmation to a payment
+    pub fn subscribe_events(&self) -> broadcast::Receiver<PaymentConfirmationEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish_confirmation(&self, payload: PaymentConfirmationPayload) {
+        debug!(
+            "Payment {} has {}/{} confirmations",
+            payload.payment.txid, payload.confirmations, payload.required_confirmations
+        );
+        if let Some(event_dispatcher) = self.supabase.event_dispatcher() {
+            event_dispatcher.publish_event("payment.confirmation", &payload);
+        }
+        // Nobody listening is fine; the event only drives progress displays
+        let _ = self.events.send(PaymentConfirmationEvent {
+            topic: "payment.confirmation".to_string(),
+            payload,
+        });
+    }
+
+    /// Count `block` towards the payments mined in earlier blocks, publishing their new confirmation
+    /// counts and forgetting those that reach the required count
+    fn advance_confirmations(&self, block: &BlockNotification) {
+        let mut confirming = self.confirming.lock().unwrap();
+        let mut reached = Vec::new();
+
+        for (txid, payload) in confirming.iter_mut() {
+            let mined_at = payload.confirmation.height as u32;
+            if block.height <= mined_at {
+                continue;
+            }
+            let confirmations = (block.height - mined_at + 1).min(self.required_confirmations);
+            // The same height again, after a reorg or a replay, adds nothing
+            if confirmations <= payload.confirmations {
+                continue;
+            }
+
+            payload.confirmations = confirmations;
+            self.publish_confirmation(payload.clone());
+            if confirmations >= self.required_confirmations {
+                reached.push(txid.clone());
+            }
+        }
+
+        for txid in reached {
+            confirming.remove(&txid);
+        }
     }
 
     pub async fn confirm_payment(&self, payment: Payment, confirmation: Confirmation) -> Result<Payment> {
@@ -111,8 +270,10 @@ impl ConfirmationService {
         let (invoice, _) = self.supabase.get_invoice(&payment.invoice_uid, true).await?.ok_or_else(|| anyhow!("Invoice not found"))?;
         
         debug!("Found associated invoice {}", invoice.id);
+        let webhook_url = invoice.webhook_url.clone();
+        let account_id = invoice.account_id;
         // Update invoice status
-        self.supabase.update_invoice_status(&invoice.uid, "paid").await?;
+        self.supabase.update_invoice_status(&invoice.uid, InvoiceStatus::Paid).await?;
 
         // Publish confirmation event
         let event = PaymentConfirmedEvent {
@@ -124,11 +285,11 @@ impl ConfirmationService {
                     chain: payment.chain,
                     currency: payment.currency,
                     txid: payment.txid,
-                    status: updated_payment.status.clone(),
+                    status: updated_payment.status,
                 },
                 invoice: InvoiceInfo {
                     uid: invoice.uid,
-                    status: "paid".to_string(),
+                    status: InvoiceStatus::Paid,
                 },
                 confirmation: ConfirmationInfo {
                     hash: confirmation.confirmation_hash,
@@ -137,8 +298,24 @@ impl ConfirmationService {
             },
         };
 
-        // TODO: Implement webhook sending
-        // await create_and_send_webhook("payment.confirmed", event);
+        // Deliver in the background, so a slow receiver doesn't hold up the rest of the block
+        if let Some(webhook_url) = webhook_url {
+            let supabase = self.supabase.clone();
+            tokio::spawn(async move {
+                let secret = match supabase.get_webhook_secret(account_id).await {
+                    Ok(secret) => secret,
+                    Err(e) => {
+                        error!("Not sending payment.confirmed for invoice {}: failed to load webhook secret: {}", event.payload.invoice.uid, e);
+                        return;
+                    }
+                };
+                match send_webhook(&webhook_url, secret.as_deref(), &event).await {
+                    Ok(status) if status.is_success() => info!("Sent payment.confirmed for invoice {} to {}", event.payload.invoice.uid, webhook_url),
+                    Ok(status) => error!("Webhook {} answered payment.confirmed for invoice {} with {}", webhook_url, event.payload.invoice.uid, status),
+                    Err(e) => error!("Failed to send payment.confirmed for invoice {} to {}: {}", event.payload.invoice.uid, webhook_url, e),
+                }
+            });
+        }
 
         Ok(updated_payment)
     }
@@ -167,43 +344,299 @@ impl ConfirmationService {
         self.supabase.get_unconfirmed_payments(chain, currency).await
     }
 
-    pub async fn start_confirmation_monitoring(&self) {
+    /// Run `process_block` on every block sent to the service's block channel, until the channel closes
+    pub fn start_confirmation_monitoring(self: &Arc<Self>) {
         info!("Starting confirmation monitoring process");
 
         let mut block_rx = self.block_tx.subscribe();
+        let service = self.clone();
 
         tokio::spawn(async move {
-            while let Ok(block) = block_rx.recv().await {
-                debug!("Processing new block: {}", block.hash);
-                
-                // TODO: 
-                // 1. Fetch full block details including txids
-                // 2. Check for matching unconfirmed payments
-                // 3. Confirm any found payments
+            loop {
+                match block_rx.recv().await {
+                    Ok(block) => {
+                        debug!("Processing new block: {}", block.hash);
+                        if let Err(e) = service.process_block(block).await {
+                            error!("Failed to process block: {}", e);
+                        }
+                    }
+                    // Payments in skipped blocks are still confirmed by their address notifications
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        error!("Confirmation monitoring skipped {} blocks", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
             }
         });
     }
 
-    pub async fn process_block(&self, block: BlockNotification) -> Result<()> {
+    /// Confirm the unconfirmed payments in a block, returning how many were confirmed
+    pub async fn process_block(&self, block: BlockNotification) -> Result<usize> {
         debug!("Processing block {} at height {}", block.hash, block.height);
         
-        // Check each transaction in block against unconfirmed payments
-        for txid in &block.txids {
-            if let Some(payment) = self.supabase.get_unconfirmed_payment_by_txid(txid).await? {
-                let confirmation = Confirmation {
-                    confirmation_hash: block.hash.clone(),
-                    confirmation_height: block.height as i32,
-                    confirmation_date: DateTime::from_timestamp(block.timestamp, 0)
-                        .unwrap_or_else(|| Utc::now()),
-                    confirmations: Some(1),
-                };
+        // Look up every transaction in the block against unconfirmed payments at once
+        let payments = self.supabase.get_unconfirmed_payments_by_txids(&block.txids).await?;
+        debug!("Found {} unconfirmed payments in block {}", payments.len(), block.hash);
+
+        self.advance_confirmations(&block);
+
+        let mut confirmed = 0;
+        for payment in payments {
+            let txid = payment.txid.clone();
+            let confirmation = Confirmation {
+                confirmation_hash: block.hash.clone(),
+                confirmation_height: block.height as i32,
+                confirmation_date: DateTime::from_timestamp(block.timestamp, 0)
+                    .unwrap_or_else(|| Utc::now()),
+                confirmations: Some(1),
+            };
 
-                match self.confirm_payment(payment, confirmation).await {
-                    Ok(_) => info!("Confirmed payment for txid {}", txid),
-                    Err(e) => error!("Failed to confirm payment for txid {}: {}", txid, e),
+            match self.confirm_payment(payment, confirmation).await {
+                Ok(payment) => {
+                    info!("Confirmed payment for txid {}", txid);
+                    confirmed += 1;
+
+                    let progress = PaymentConfirmationPayload {
+                        payment: PaymentInfo {
+                            chain: payment.chain,
+                            currency: payment.currency,
+                            txid: txid.clone(),
+                            status: payment.status,
+                        },
+                        invoice: InvoiceInfo {
+                            uid: payment.invoice_uid,
+                            status: InvoiceStatus::Paid,
+                        },
+                        confirmation: ConfirmationInfo {
+                            hash: block.hash.clone(),
+                            height: block.height as i32,
+                        },
+                        confirmations: 1,
+                        required_confirmations: self.required_confirmations,
+                    };
+                    self.publish_confirmation(progress.clone());
+                    if self.required_confirmations > 1 {
+                        self.confirming.lock().unwrap().insert(txid, progress);
+                    }
                 }
+                Err(e) => error!("Failed to confirm payment for txid {}: {}", txid, e),
+            }
+        }
+        Ok(confirmed)
+    }
+
+    /// Run `process_block` over the blocks from `from_height` to `to_height` inclusive, to pick up
+    /// payments in blocks that were missed while the server was down
+    pub async fn replay_blocks<F, Fut>(&self, from_height: u32, to_height: u32, fetch_block: F) -> Result<ReplaySummary>
+    where
+        F: Fn(u32) -> Fut,
+        Fut: std::future::Future<Output = Result<BlockNotification>>,
+    {
+        validate_replay_range(from_height, to_height)?;
+
+        let mut summary = ReplaySummary { from_height, to_height, ..Default::default() };
+        for height in from_height..=to_height {
+            let block = fetch_block(height).await
+                .map_err(|e| anyhow!("Failed to fetch block {}: {}", height, e))?;
+            summary.payments_confirmed += self.process_block(block).await?;
+            summary.blocks_scanned += 1;
+        }
+
+        info!(
+            "Replayed blocks {} to {}: confirmed {} payments",
+            from_height, to_height, summary.payments_confirmed
+        );
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::Uri, Json, Router};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use crate::supabase::tests::{invoice_transition_rows, spawn_mock_supabase};
+
+    #[tokio::test]
+    async fn test_block_txids_are_looked_up_in_chunks() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let url = spawn_mock_supabase(Router::new().fallback({
+            let requests = requests.clone();
+            move |uri: Uri| async move {
+                requests.lock().unwrap().push(uri.to_string());
+                Json(json!([]))
             }
+        }));
+        let (block_tx, _) = broadcast::channel(1);
+        let service = ConfirmationService::new(SupabaseClient::new(&url, "anon", "service"), block_tx);
+        let txids = (0..1000).map(|i| format!("{:064x}", i)).collect::<Vec<_>>();
+
+        service.process_block(BlockNotification {
+            hash: "00000000000000000001d8f3c4a9b1e2f5a6c7d8e9f0a1b2c3d4e5f6a7b8c9d0".to_string(),
+            height: 840_000,
+            timestamp: 1_700_000_000,
+            txids: txids.clone(),
+        }).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 10);
+        assert!(requests.iter().all(|request| request.starts_with("/rest/v1/payments?txid=in.(")));
+        assert!(txids.iter().all(|txid| requests.iter().filter(|request| request.contains(txid.as_str())).count() == 1));
+    }
+
+    fn block(height: u32, txids: &[&str]) -> BlockNotification {
+        BlockNotification {
+            hash: format!("{:064x}", height),
+            height,
+            timestamp: 1_700_000_000,
+            txids: txids.iter().map(|txid| txid.to_string()).collect(),
         }
-        Ok(())
     }
-} 
\ No newline at end of file
+
+    /// An unpaid invoice inv_1 with a pending payment for `txid`, recording payment patches and invoice status updates
+    fn mock_pending_payment(txid: &'static str, patches: Arc<Mutex<Vec<(String, serde_json::Value)>>>) -> Router {
+        use axum::routing::{get, post};
+
+        let payment = json!({
+            "id": 1,
+            "txid": txid,
+            "chain": "BTC",
+            "currency": "BTC",
+            "status": "pending",
+            "invoice_uid": "inv_1",
+            "amount": 20_000,
+            "confirmation_hash": null,
+            "confirmation_height": null,
+            "confirmation_date": null
+        });
+
+        Router::new()
+            .route("/rest/v1/payments", get({
+                let payment = payment.clone();
+                // Only the lookup for a block containing the txid finds the payment
+                move |uri: Uri| async move {
+                    let query = uri.query().unwrap_or_default().to_string();
+                    if query.contains("txid=in.") && !query.contains(txid) {
+                        Json(json!([]))
+                    } else {
+                        Json(json!([payment]))
+                    }
+                }
+            }).patch({
+                let patches = patches.clone();
+                move |Json(body): Json<serde_json::Value>| async move {
+                    let mut confirmed = payment.clone();
+                    for (key, value) in body.as_object().unwrap() {
+                        confirmed[key] = value.clone();
+                    }
+                    patches.lock().unwrap().push(("payments".to_string(), body));
+                    Json(confirmed)
+                }
+            }))
+            .route("/rest/v1/invoices", get(|| async {
+                Json(json!([{
+                    "id": 1,
+                    "uid": "inv_1",
+                    "amount": 1000,
+                    "currency": "USD",
+                    "status": "unpaid",
+                    "account_id": 1,
+                    "complete": false,
+                    "webhook_url": null,
+                    "redirect_url": null,
+                    "memo": null,
+                    "uri": "anypay:btc_inv_1",
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": "2024-01-01T00:00:00Z"
+                }]))
+            }))
+            .route("/rest/v1/rpc/update_invoice_status", post(move |Json(params): Json<serde_json::Value>| async move {
+                patches.lock().unwrap().push(("update_invoice_status".to_string(), params.clone()));
+                Json(invoice_transition_rows(&params))
+            }))
+            .route("/rest/v1/payment_options", get(|| async { Json(json!([])) }))
+            .route("/rest/v1/accounts", get(|| async { Json(json!([{ "id": 1, "denomination": "USD" }])) }))
+    }
+
+    #[tokio::test]
+    async fn test_replay_confirms_payment_in_skipped_block() {
+        const TXID: &str = "aa11aa11aa11aa11aa11aa11aa11aa11aa11aa11aa11aa11aa11aa11aa11aa11";
+        let patches = Arc::new(Mutex::new(Vec::new()));
+        let url = spawn_mock_supabase(mock_pending_payment(TXID, patches.clone()));
+        let (block_tx, _) = broadcast::channel(1);
+        let service = ConfirmationService::new(SupabaseClient::new(&url, "anon", "service"), block_tx);
+
+        // The server was down for blocks 100 to 102; the payment was mined in 101
+        let summary = service.replay_blocks(100, 102, |height| async move {
+            Ok(match height {
+                101 => block(101, &["bb22", TXID]),
+                _ => block(height, &["cc33"]),
+            })
+        }).await.unwrap();
+
+        assert_eq!(summary, ReplaySummary { from_height: 100, to_height: 102, blocks_scanned: 3, payments_confirmed: 1 });
+        let patches = patches.lock().unwrap();
+        let (table, payment) = &patches[0];
+        assert_eq!(table, "payments");
+        assert_eq!(payment["confirmation_hash"], format!("{:064x}", 101));
+        assert_eq!(payment["confirmation_height"], 101);
+        let (function, params) = &patches[1];
+        assert_eq!(function, "update_invoice_status");
+        assert_eq!(params["p_status"], "paid");
+    }
+
+    #[tokio::test]
+    async fn test_each_block_publishes_the_confirmation_count() {
+        const TXID: &str = "dd44dd44dd44dd44dd44dd44dd44dd44dd44dd44dd44dd44dd44dd44dd44dd44";
+        let url = spawn_mock_supabase(mock_pending_payment(TXID, Arc::new(Mutex::new(Vec::new()))));
+        let (block_tx, _) = broadcast::channel(1);
+        let service = ConfirmationService::new(SupabaseClient::new(&url, "anon", "service"), block_tx)
+            .with_required_confirmations(3);
+        let mut events = service.subscribe_events();
+
+        // Mined in 200, then two more blocks on top, then a fourth past the threshold
+        for next in [block(200, &[TXID]), block(201, &["ee55"]), block(202, &["ee55"]), block(203, &["ee55"])] {
+            service.process_block(next).await.unwrap();
+        }
+
+        let mut counts = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.topic, "payment.confirmation");
+            assert_eq!(event.payload.payment.txid, TXID);
+            assert_eq!(event.payload.invoice.uid, "inv_1");
+            assert_eq!(event.payload.confirmation.height, 200);
+            assert_eq!(event.payload.required_confirmations, 3);
+            counts.push(event.payload.confirmations);
+        }
+        assert_eq!(counts, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_monitoring_processes_each_block_sent() {
+        const TXID: &str = "ff66ff66ff66ff66ff66ff66ff66ff66ff66ff66ff66ff66ff66ff66ff66ff66";
+        let patches = Arc::new(Mutex::new(Vec::new()));
+        let url = spawn_mock_supabase(mock_pending_payment(TXID, patches.clone()));
+        let (block_tx, _) = broadcast::channel(4);
+        let service = Arc::new(ConfirmationService::new(SupabaseClient::new(&url, "anon", "service"), block_tx.clone()));
+        let mut events = service.subscribe_events();
+
+        service.start_confirmation_monitoring();
+        block_tx.send(block(300, &[TXID])).unwrap();
+
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event.payload.payment.txid, TXID);
+        assert_eq!(event.payload.confirmation.height, 300);
+        assert_eq!(patches.lock().unwrap()[0].0, "payments");
+    }
+
+    #[tokio::test]
+    async fn test_replay_rejects_invalid_ranges() {
+        let (block_tx, _) = broadcast::channel(1);
+        let service = ConfirmationService::new(SupabaseClient::new("http://127.0.0.1:1", "anon", "service"), block_tx);
+        let fetch = |height| async move { Ok(block(height, &[])) };
+
+        assert!(service.replay_blocks(10, 9, fetch).await.is_err());
+        assert!(service.replay_blocks(0, MAX_REPLAY_BLOCKS, fetch).await.is_err());
+    }
+}
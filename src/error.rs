@@ -0,0 +1,73 @@
+/// Errors returned by the library's public API, so callers can match on what went wrong
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("No price for {from} to {to}")]
+    PriceNotFound { from: String, to: String },
+
+    #[error("{currency} on {chain} is not supported")]
+    CoinNotFound { chain: String, currency: String },
+
+    #[error("Invoice {0} not found")]
+    InvoiceNotFound(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    /// A chain rejected or couldn't process a transaction or address
+    #[error("{chain}: {message}")]
+    Chain { chain: String, message: String },
+
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// Reading from or writing to Supabase failed
+    #[error("Database error: {0}")]
+    Db(#[source] anyhow::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    pub fn chain(chain: &str, message: impl std::fmt::Display) -> Self {
+        Error::Chain { chain: chain.to_string(), message: message.to_string() }
+    }
+
+    pub fn coin_not_found(chain: &str, currency: &str) -> Self {
+        Error::CoinNotFound { chain: chain.to_string(), currency: currency.to_string() }
+    }
+
+    /// Recover the typed error behind an internal anyhow error, such as a missing price or coin
+    /// hit while building payment options, so callers can still match on it. Errors without
+    /// one are treated as database failures.
+    pub fn from_anyhow(error: anyhow::Error) -> Self {
+        let typed = error.chain().find_map(|cause| cause.downcast_ref::<Error>());
+        match typed {
+            Some(Error::PriceNotFound { from, to }) => Error::PriceNotFound { from: from.clone(), to: to.clone() },
+            Some(Error::CoinNotFound { chain, currency }) => Error::coin_not_found(chain, currency),
+            Some(Error::InvoiceNotFound(uid)) => Error::InvoiceNotFound(uid.clone()),
+            Some(Error::Unauthorized(message)) => Error::Unauthorized(message.clone()),
+            Some(Error::Chain { chain, message }) => Error::chain(chain, message),
+            _ => Error::Db(error),
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_from_anyhow_keeps_the_typed_cause() {
+        let wrapped = Err::<(), _>(Error::coin_not_found("ETH", "USDC"))
+            .context("Failed to create payment options")
+            .unwrap_err();
+        assert!(matches!(Error::from_anyhow(wrapped), Error::CoinNotFound { chain, currency } if chain == "ETH" && currency == "USDC"));
+
+        let untyped = anyhow::anyhow!("Supabase returned 500");
+        assert!(matches!(Error::from_anyhow(untyped), Error::Db(_)));
+    }
+}
@@ -3,6 +3,7 @@ use alloy::pubsub::PubSubFrontend;
 use futures_util::StreamExt;
 use std::sync::Arc;
 use anyhow::Result;
+use crate::status::chain_status;
 
 pub struct EthereumClient {
     provider: Arc<dyn Provider<PubSubFrontend>>,
@@ -22,14 +23,17 @@ impl EthereumClient {
 
     pub async fn subscribe_blocks(&self) -> Result<()> {
         let sub = self.provider.subscribe_blocks().await?;
-        let mut stream = sub.into_stream();
+        let stream = sub.into_stream();
         let chain = self.chain.clone();
+        chain_status().mark_connected(&chain);
 
         let handle = tokio::spawn(async move {
             println!("Awaiting block headers...");
-            while let Some(block) = stream.next().await {
+            let heights = stream.map(|block| {
                 tracing::debug!("Latest {} block number: {}", chain, block.header.number);
-            }
+                block.header.number
+            });
+            chain_status().track_blocks(&chain, heights).await;
         });
 
         // Keep the subscription alive
@@ -1,20 +1,99 @@
 use std::collections::{HashMap, HashSet};
-use tokio::sync::RwLock;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use futures::Stream;
+use tokio::sync::{broadcast, RwLock};
+use serde::Serialize;
 use uuid::Uuid;
-use crate::types::Subscription;
+use crate::types::{InvoiceEvent, PublishedEvent, Subscription};
 use crate::session::Session;
 
+/// How long a resumable session's subscriptions are kept after it disconnects
+pub const RESUME_TTL: Duration = Duration::from_secs(120);
+
+/// Events buffered per invoice before a stream that isn't polled starts missing them
+pub const INVOICE_EVENT_BUFFER: usize = 16;
+
+/// Events buffered for each `subscribe_events` receiver before a slow one starts missing them
+pub const PUBLISHED_EVENT_BUFFER: usize = 256;
+
+/// Subscriptions of a disconnected resumable session, waiting for the client to reconnect
+struct ParkedSubscriptions {
+    subscriptions: HashSet<Subscription>,
+    expires_at: Instant,
+}
+
 pub struct EventDispatcher {
     subscriptions: RwLock<HashMap<Subscription, HashSet<Uuid>>>,
+    /// Resume tokens of the connected sessions that opted in
+    resume_tokens: RwLock<HashMap<Uuid, String>>,
+    parked: RwLock<HashMap<String, ParkedSubscriptions>>,
+    resume_ttl: Duration,
+    /// A channel per invoice with open streams, for embedders that don't go through the websocket server
+    invoice_events: Mutex<HashMap<String, broadcast::Sender<InvoiceEvent>>>,
+    /// Every event, for publishers outside the process such as AMQP
+    events: broadcast::Sender<PublishedEvent>,
 }
 
 impl EventDispatcher {
     pub fn new() -> Self {
         EventDispatcher {
             subscriptions: RwLock::new(HashMap::new()),
+            resume_tokens: RwLock::new(HashMap::new()),
+            parked: RwLock::new(HashMap::new()),
+            resume_ttl: RESUME_TTL,
+            invoice_events: Mutex::new(HashMap::new()),
+            events: broadcast::channel(PUBLISHED_EVENT_BUFFER).0,
         }
     }
 
+    /// Keep a disconnected session's subscriptions for `ttl` instead of RESUME_TTL
+    pub fn with_resume_ttl(mut self, ttl: Duration) -> Self {
+        self.resume_ttl = ttl;
+        self
+    }
+
+    /// Make `session` resumable, restoring the subscriptions parked under `token` if it names a
+    /// disconnected session that hasn't expired. Subscriptions past the session's limit are not
+    /// restored. Returns the token to reconnect with and the restored subscriptions.
+    pub async fn resume(&self, session: &mut Session, token: Option<&str>) -> (String, Vec<Subscription>) {
+        let parked = match token {
+            Some(token) => self.parked.write().await.remove(token)
+                .filter(|parked| parked.expires_at > Instant::now()),
+            None => None,
+        };
+
+        // An unknown or expired token starts afresh rather than reusing a guessable value
+        let token = match (&parked, token) {
+            (Some(_), Some(token)) => token.to_string(),
+            _ => Uuid::new_v4().to_string(),
+        };
+        self.resume_tokens.write().await.insert(session.id, token.clone());
+
+        let mut restored = Vec::new();
+        for subscription in parked.map(|parked| parked.subscriptions).unwrap_or_default() {
+            if session.exceeds_subscription_limit(&subscription) {
+                tracing::debug!("Not restoring {} {} for session {}: subscription limit reached", subscription.sub_type, subscription.id, session.id);
+                continue;
+            }
+            self.subscribe(session.clone(), &subscription.sub_type, &subscription.id).await;
+            session.add_subscription(subscription.clone());
+            restored.push(subscription);
+        }
+        (token, restored)
+    }
+
+    /// The subscriptions `session_id` holds
+    pub async fn subscriptions_for(&self, session_id: Uuid) -> HashSet<Subscription> {
+        self.subscriptions
+            .read()
+            .await
+            .iter()
+            .filter(|(_, sessions)| sessions.contains(&session_id))
+            .map(|(subscription, _)| subscription.clone())
+            .collect()
+    }
+
     pub async fn subscribe(&self, session: Session, sub_type: &str, id: &str) {
         let subscription = Subscription {
             sub_type: sub_type.to_string(),
@@ -50,4 +129,215 @@ impl EventDispatcher {
             .cloned()
             .unwrap_or_default()
     }
-} 
\ No newline at end of file
+
+    /// Drop a session from every subscription, removing subscriptions left with no sessions. A resumable
+    /// session's subscriptions are parked under its token first, so a reconnect can restore them.
+    pub async fn remove_session(&self, session_id: Uuid) {
+        let token = self.resume_tokens.write().await.remove(&session_id);
+        if let Some(token) = token {
+            self.park(session_id, token).await;
+        }
+
+        self.unsubscribe_all(session_id).await;
+    }
+
+    /// Keep the subscriptions `session_id` holds under `token` until the resume TTL passes
+    async fn park(&self, session_id: Uuid, token: String) {
+        let subscriptions = self.subscriptions_for(session_id).await;
+        let now = Instant::now();
+        let mut parked = self.parked.write().await;
+        parked.retain(|_, parked| parked.expires_at > now);
+        parked.insert(token, ParkedSubscriptions { subscriptions, expires_at: now + self.resume_ttl });
+    }
+
+    /// Drop a session from every subscription without parking them for a resume, returning how many it held
+    pub async fn unsubscribe_all(&self, session_id: Uuid) -> usize {
+        let mut removed = 0;
+        let mut subs = self.subscriptions.write().await;
+        subs.retain(|_, sessions| {
+            if sessions.remove(&session_id) {
+                removed += 1;
+            }
+            !sessions.is_empty()
+        });
+        removed
+    }
+
+    /// Stream the events published for invoice `uid` from now on
+    pub fn subscribe_invoice(&self, uid: &str) -> impl Stream<Item = InvoiceEvent> + Send + 'static {
+        let receiver = self.invoice_events.lock().unwrap()
+            .entry(uid.to_string())
+            .or_insert_with(|| broadcast::channel(INVOICE_EVENT_BUFFER).0)
+            .subscribe();
+
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    // A stream that fell behind carries on from the oldest event still buffered
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Invoice event stream skipped {} events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Receive every event published from now on, invoice events under `invoice.<status>`
+    pub fn subscribe_events(&self) -> broadcast::Receiver<PublishedEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish `payload` under `topic` to the `subscribe_events` receivers, returning how many there were
+    pub fn publish_event<T: Serialize>(&self, topic: &str, payload: &T) -> usize {
+        let payload = match serde_json::to_value(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!("Failed to serialize {} event: {}", topic, e);
+                return 0;
+            }
+        };
+        // Nobody listening is fine; publishers are optional
+        self.events.send(PublishedEvent { topic: topic.to_string(), payload }).unwrap_or(0)
+    }
+
+    /// Deliver `event` to the open streams of its invoice, returning how many there were, and
+    /// publish it as `invoice.<status>`
+    pub fn publish_invoice_event(&self, event: InvoiceEvent) -> usize {
+        self.publish_event(&format!("invoice.{}", event.status), &event);
+
+        let mut channels = self.invoice_events.lock().unwrap();
+        let Some(sender) = channels.get(&event.uid) else {
+            return 0;
+        };
+
+        match sender.send(event.clone()) {
+            Ok(receivers) => receivers,
+            // Every stream has been dropped
+            Err(_) => {
+                channels.remove(&event.uid);
+                0
+            }
+        }
+    }
+
+    /// Drop every session not in `live` from the subscriptions, returning how many subscribers were removed.
+    /// Resumable sessions among them are parked as on a disconnect, and their tokens released.
+    pub async fn retain_sessions(&self, live: &HashSet<Uuid>) -> usize {
+        let reaped_tokens = {
+            let mut tokens = self.resume_tokens.write().await;
+            let reaped = tokens.keys()
+                .filter(|session_id| !live.contains(session_id))
+                .copied()
+                .collect::<Vec<_>>();
+            reaped.into_iter()
+                .filter_map(|session_id| tokens.remove(&session_id).map(|token| (session_id, token)))
+                .collect::<Vec<_>>()
+        };
+        for (session_id, token) in reaped_tokens {
+            self.park(session_id, token).await;
+        }
+
+        let mut removed = 0;
+        let mut subs = self.subscriptions.write().await;
+        subs.retain(|_, sessions| {
+            let before = sessions.len();
+            sessions.retain(|session_id| live.contains(session_id));
+            removed += before - sessions.len();
+            !sessions.is_empty()
+        });
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        let (sender, _receiver) = futures::channel::mpsc::channel(1);
+        Session::new(Uuid::new_v4(), sender, crate::session::BackpressurePolicy::Disconnect)
+    }
+
+    #[tokio::test]
+    async fn test_remove_session_purges_subscriptions() {
+        let dispatcher = EventDispatcher::new();
+        let leaving = session();
+        let staying = session();
+
+        dispatcher.subscribe(leaving.clone(), "invoice", "inv_1").await;
+        dispatcher.subscribe(leaving.clone(), "invoice", "inv_2").await;
+        dispatcher.subscribe(staying.clone(), "invoice", "inv_2").await;
+
+        dispatcher.remove_session(leaving.id).await;
+
+        let subs = dispatcher.subscriptions.read().await;
+        assert!(subs.values().all(|sessions| !sessions.contains(&leaving.id)));
+        assert_eq!(subs.len(), 1);
+
+        let inv_2 = Subscription { sub_type: "invoice".to_string(), id: "inv_2".to_string() };
+        assert_eq!(subs.get(&inv_2).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retain_sessions_releases_resume_tokens_of_reaped_sessions() {
+        let dispatcher = EventDispatcher::new();
+        let mut reaped = session();
+        let mut live = session();
+
+        let (token, _) = dispatcher.resume(&mut reaped, None).await;
+        dispatcher.resume(&mut live, None).await;
+        dispatcher.subscribe(reaped.clone(), "invoice", "inv_1").await;
+
+        assert_eq!(dispatcher.retain_sessions(&HashSet::from([live.id])).await, 1);
+
+        let tokens = dispatcher.resume_tokens.read().await;
+        assert!(!tokens.contains_key(&reaped.id));
+        assert!(tokens.contains_key(&live.id));
+        drop(tokens);
+
+        // The reaped session's subscriptions can still be resumed
+        let (_, restored) = dispatcher.resume(&mut session(), Some(&token)).await;
+        assert_eq!(restored, vec![Subscription { sub_type: "invoice".to_string(), id: "inv_1".to_string() }]);
+    }
+
+    #[tokio::test]
+    async fn test_resume_restores_up_to_the_subscription_limit() {
+        let dispatcher = EventDispatcher::new();
+        let mut first = session();
+        let (token, _) = dispatcher.resume(&mut first, None).await;
+        for id in ["inv_1", "inv_2", "inv_3"] {
+            dispatcher.subscribe(first.clone(), "invoice", id).await;
+        }
+        dispatcher.remove_session(first.id).await;
+
+        let mut second = session().with_max_subscriptions(2);
+        let (_, restored) = dispatcher.resume(&mut second, Some(&token)).await;
+
+        assert_eq!(restored.len(), 2);
+        assert_eq!(second.subscription_count(), 2);
+        assert_eq!(dispatcher.subscriptions_for(second.id).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_stream_receives_only_its_invoice() {
+        use futures::StreamExt;
+        use crate::types::InvoiceStatus;
+
+        let dispatcher = EventDispatcher::new();
+        assert_eq!(dispatcher.publish_invoice_event(InvoiceEvent { uid: "inv_1".to_string(), status: InvoiceStatus::Paid }), 0);
+
+        let mut events = Box::pin(dispatcher.subscribe_invoice("inv_1"));
+        dispatcher.publish_invoice_event(InvoiceEvent { uid: "inv_2".to_string(), status: InvoiceStatus::Paid });
+        assert_eq!(dispatcher.publish_invoice_event(InvoiceEvent { uid: "inv_1".to_string(), status: InvoiceStatus::Expired }), 1);
+
+        let event = events.next().await.unwrap();
+        assert_eq!((event.uid.as_str(), event.status), ("inv_1", InvoiceStatus::Expired));
+
+        // A channel is dropped once its last stream is
+        drop(events);
+        dispatcher.publish_invoice_event(InvoiceEvent { uid: "inv_1".to_string(), status: InvoiceStatus::Paid });
+        assert!(dispatcher.invoice_events.lock().unwrap().is_empty());
+    }
+}
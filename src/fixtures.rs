@@ -0,0 +1,33 @@
+//! Coin and price fixtures shared by the unit tests and the integration tests,
+//! whose tests/common includes this file by path
+
+use serde_json::{json, Value};
+
+/// Price of one US dollar in `base_currency`
+pub fn usd_price(base_currency: &str) -> Option<f64> {
+    match base_currency {
+        "BTC" => Some(0.00002),
+        "BSV" => Some(0.02),
+        "ETH" => Some(0.0004),
+        "XRP" => Some(2.0),
+        _ => None,
+    }
+}
+
+/// A `coins` row for the native coin of `chain`, refusing payments below `min_amount` base units
+pub fn coin_row(id: i64, chain: &str, min_amount: Option<i64>) -> Value {
+    json!({
+        "id": id,
+        "currency": chain,
+        "chain": chain,
+        "precision": 8,
+        "unavailable": false,
+        "uri_template": null,
+        "createdAt": "2024-01-01T00:00:00Z",
+        "updatedAt": "2024-01-01T00:00:00Z",
+        "supported": true,
+        "required_fee_rate": null,
+        "color": (chain == "BTC").then_some("#f7931a"),
+        "min_amount": min_amount
+    })
+}
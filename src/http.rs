@@ -1,23 +1,42 @@
 use axum::{
     routing::{get, post, delete},
     Router,
-    extract::{Path, Json},
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{Path, Json, Query, Extension, ConnectInfo, DefaultBodyLimit, FromRequest, rejection::JsonRejection},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
 };
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use uuid::Uuid;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::{supabase::SupabaseClient, types::PaymentOption};
-use crate::types::{Invoice, Price, PaymentRequest};
+use crate::{error::Error, supabase::SupabaseClient, types::PaymentOption};
+use crate::prices::{convert, ConversionRequest, MAX_PRECISION};
+use crate::payment::{decode_op_return, submit_payment, PaymentSubmission};
+use crate::uri::{get_base_url, sanitize_memo};
+use crate::confirmations::{validate_replay_range, ReplaySummary};
+use crate::status::{chain_status, to_prometheus, ChainStatus};
+use crate::auth::{require_account, require_admin, AuthenticatedAccount};
+use crate::blockbook::BlockbookClient;
+use crate::webhooks::{sample_payment_confirmed, WebhookTarget};
+use crate::rate_limit::{retry_after_ms, retry_after_secs, RateLimit, RateLimiter};
+use crate::types::{Coin, Invoice, Price, PaymentRequest};
 
 // Request/Response types matching swagger spec
 #[derive(Deserialize)]
 pub struct CreateInvoiceRequest {
-    amount: i64,
+    /// Null for a donation invoice, where the payer chooses the amount
+    amount: Option<i64>,
     currency: String,
-    account_id: i64,
+    /// Ignored: invoices are created for the account that owns the API token
+    #[serde(default)]
+    account_id: Option<i64>,
     redirect_url: Option<String>,
     webhook_url: Option<String>,
     wordpress_site_url: Option<String>,
@@ -27,7 +46,17 @@ pub struct CreateInvoiceRequest {
     business_id: Option<String>,
     location_id: Option<String>,
     register_id: Option<String>,
+    /// Minimum fee rate for wallets paying this invoice, overriding the coin's
     required_fee_rate: Option<String>,
+    /// Restrict the invoice's payment options to these currencies
+    #[serde(default)]
+    accepted_currencies: Option<Vec<String>>,
+    /// Charge `amount` base units of `currency`, a crypto, instead of converting from fiat
+    #[serde(default)]
+    crypto_denominated: bool,
+    /// Hex-encoded data payments on bitcoin-family chains must carry in an OP_RETURN output
+    #[serde(default)]
+    op_return: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -41,19 +70,568 @@ pub struct PricesResponse {
     prices: Vec<Price>,
 }
 
+#[derive(Serialize)]
+pub struct StatusResponse {
+    chains: Vec<ChainStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookTestRequest {
+    url: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReplayRequest {
+    from_height: u32,
+    to_height: u32,
+}
+
+/// Progress of a replay started through the admin API
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum ReplayJob {
+    Running { from_height: u32, to_height: u32 },
+    Completed(ReplaySummary),
+    Failed { from_height: u32, to_height: u32, error: String },
+}
+
+#[derive(Serialize)]
+struct ReplayJobResponse {
+    id: Uuid,
+    #[serde(flatten)]
+    job: ReplayJob,
+}
+
+#[derive(Deserialize)]
+pub struct StatusQuery {
+    /// "prometheus" for the text exposition format, JSON otherwise
+    format: Option<String>,
+}
+
+// All optional so missing parameters get a descriptive error rather than axum's default rejection
+#[derive(Deserialize)]
+pub struct ConvertQuery {
+    quote_currency: Option<String>,
+    base_currency: Option<String>,
+    quote_value: Option<String>,
+    precision: Option<String>,
+}
+
+impl ConvertQuery {
+    fn into_request(self) -> Result<ConversionRequest, String> {
+        let quote_currency = self.quote_currency
+            .filter(|c| !c.is_empty())
+            .ok_or("Missing required parameter: quote_currency")?;
+        let base_currency = self.base_currency
+            .filter(|c| !c.is_empty())
+            .ok_or("Missing required parameter: base_currency")?;
+        let quote_value = self.quote_value
+            .ok_or("Missing required parameter: quote_value")?
+            .parse::<f64>()
+            .map_err(|_| "Invalid quote_value: expected a number".to_string())?;
+        let precision = self.precision
+            .map(|precision| precision.parse::<i32>()
+                .ok()
+                .filter(|precision| (0..=MAX_PRECISION).contains(precision))
+                .ok_or(format!("Invalid precision: expected 0 to {}", MAX_PRECISION)))
+            .transpose()?;
+
+        Ok(ConversionRequest {
+            quote_currency: quote_currency.to_uppercase(),
+            base_currency: base_currency.to_uppercase(),
+            quote_value,
+            precision,
+        })
+    }
+}
+
+/// Accept header wallets send to request the payment-options document instead of the invoice
+const PAYMENT_OPTIONS_MEDIA_TYPE: &str = "application/payment-options";
+
+/// Base URL wallets reach the invoice's account on: its own domain when configured, otherwise
+/// the default. Falls back to the default when the account can't be loaded.
+async fn account_base_url(supabase: &SupabaseClient, account_id: i64) -> String {
+    match supabase.get_account(account_id).await {
+        Ok(account) => get_base_url(account.base_url.as_deref()),
+        Err(e) => {
+            tracing::warn!("Failed to load account {} for its base URL: {}", account_id, e);
+            get_base_url(None)
+        }
+    }
+}
+
+/// JSON payment protocol listing of the currencies an invoice can be paid in
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentOptionsDocument {
+    time: String,
+    expires: String,
+    memo: String,
+    payment_url: String,
+    payment_id: String,
+    payment_options: Vec<PaymentOptionEntry>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentOptionEntry {
+    chain: String,
+    currency: String,
+    network: String,
+    estimated_amount: i64,
+    miner_fee: i64,
+    payment_url: String,
+    expires: String,
+    selected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+}
+
+impl PaymentOptionsDocument {
+    fn new(invoice: &Invoice, options: &[PaymentOption], base_url: &str, network: &str) -> Self {
+        let payment_url = format!("{}/i/{}", base_url, invoice.uid);
+
+        Self {
+            time: invoice.createdAt.clone(),
+            // RFC 3339 timestamps in the same zone sort lexically
+            expires: options.iter().map(|option| option.expires.clone()).max().unwrap_or_default(),
+            memo: invoice.memo.as_deref().and_then(sanitize_memo).unwrap_or_default(),
+            payment_url: payment_url.clone(),
+            payment_id: invoice.uid.clone(),
+            payment_options: options.iter()
+                .map(|option| PaymentOptionEntry {
+                    chain: option.chain.clone(),
+                    currency: option.currency.clone(),
+                    network: network.to_string(),
+                    estimated_amount: option.amount,
+                    miner_fee: option.fee,
+                    payment_url: payment_url.clone(),
+                    expires: option.expires.clone(),
+                    selected: false,
+                    color: option.color.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Fee rate wallets are asked to pay when neither the invoice nor the coin sets one
+const DEFAULT_REQUIRED_FEE_RATE: i64 = 1;
+
+/// Fee rate for paying `invoice` in `coin`: the invoice's override, then the coin's, then the default
+fn required_fee_rate(invoice: &Invoice, coin: Option<&Coin>) -> i64 {
+    invoice.required_fee_rate
+        .or_else(|| coin.and_then(|coin| coin.required_fee_rate))
+        .unwrap_or(DEFAULT_REQUIRED_FEE_RATE)
+}
+
+/// Body of an application/payment-request POST; the x-chain/x-currency headers fill in missing fields
+#[derive(Default, Deserialize)]
+pub struct PaymentRequestSelection {
+    chain: Option<String>,
+    currency: Option<String>,
+}
+
+impl PaymentRequestSelection {
+    fn resolve(self, headers: &HeaderMap) -> Result<(String, String), String> {
+        let header = |name: &str| headers.get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let chain = self.chain.or_else(|| header("x-chain"))
+            .filter(|chain| !chain.is_empty())
+            .ok_or("Missing chain: set it in the body or the x-chain header")?;
+        let currency = self.currency.or_else(|| header("x-currency"))
+            .filter(|currency| !currency.is_empty())
+            .ok_or("Missing currency: set it in the body or the x-currency header")?;
+
+        Ok((chain.to_uppercase(), currency.to_uppercase()))
+    }
+}
+
+/// Invoice with the payment instructions for the selected payment option, in the shape
+/// `AnypayClient::get_payment_option` expects
+fn payment_request_response(invoice: &Invoice, option: &PaymentOption, required_fee_rate: i64, base_url: &str, network: &str) -> serde_json::Value {
+    let outputs = if option.outputs.is_empty() {
+        vec![json!({ "address": option.address, "amount": option.amount })]
+    } else {
+        option.outputs.iter()
+            .map(|output| json!(output))
+            .collect()
+    };
+
+    json!({
+        "invoice": {
+            "uid": invoice.uid,
+            "status": invoice.status,
+            "currency": invoice.currency,
+            "amount": invoice.amount,
+            "uri": invoice.uri,
+            "createdAt": invoice.createdAt,
+            "expiresAt": option.expires,
+            "payment_options": [{
+                "time": invoice.createdAt,
+                "expires": option.expires,
+                "memo": invoice.memo.as_deref().and_then(sanitize_memo).unwrap_or_default(),
+                "paymentUrl": format!("{}/r/{}", base_url, invoice.uid),
+                "paymentId": invoice.uid,
+                "chain": option.chain,
+                "currency": option.currency,
+                "network": network,
+                "instructions": [{
+                    "type": "transaction",
+                    "requiredFeeRate": required_fee_rate,
+                    "outputs": outputs,
+                    "opReturn": option.op_return
+                }]
+            }],
+            "notes": []
+        }
+    })
+}
+
+fn wants_payment_options(headers: &HeaderMap) -> bool {
+    headers.get_all(header::ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split(',').any(|media| media.trim().starts_with(PAYMENT_OPTIONS_MEDIA_TYPE)))
+}
+
+fn error_response(status: StatusCode, message: impl ToString) -> (StatusCode, Json<serde_json::Value>) {
+    (status, Json(json!({
+        "status": "error",
+        "message": message.to_string()
+    })))
+}
+
+/// Status for a failed conversion: 400 for what the caller asked for, such as a currency without
+/// a price, and 502 or 503 when the price source or database couldn't answer
+fn conversion_error_status(error: &Error) -> StatusCode {
+    match error {
+        Error::Db(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::Http(_) | Error::Chain { .. } => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Largest request body accepted when HTTP_MAX_BODY_BYTES is not set
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// A `Json` extractor whose rejections are returned as structured JSON errors
+/// rather than axum's plain-text responses
+pub struct ApiJson<T>(pub T);
+
+#[axum::async_trait]
+impl<S, B, T> FromRequest<S, B> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, B, Rejection = JsonRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = (StatusCode, Json<serde_json::Value>);
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(ApiJson(value)),
+            Err(rejection) => {
+                let error = match &rejection {
+                    JsonRejection::JsonDataError(_) => "invalid_body",
+                    JsonRejection::JsonSyntaxError(_) => "malformed_json",
+                    JsonRejection::MissingJsonContentType(_) => "unsupported_media_type",
+                    JsonRejection::BytesRejection(_) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => "body_too_large",
+                    _ => "invalid_request",
+                };
+
+                Err((rejection.status(), Json(json!({
+                    "status": "error",
+                    "error": error,
+                    "message": rejection.body_text()
+                }))))
+            }
+        }
+    }
+}
+
+/// Middleware answering 429 Too Many Requests to clients that have spent their tokens, with a
+/// Retry-After header and `retry_after_ms` saying when the next request will be allowed
+async fn throttle(limiter: Arc<RateLimiter>, req: Request<Body>, next: Next<Body>) -> Response {
+    // Clients are told apart by IP when served with connect info, and otherwise share a bucket
+    let client = req.extensions().get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_default();
+
+    match limiter.check(&client) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            let retry_after_ms = retry_after_ms(retry_after);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after_secs(retry_after).to_string())],
+                Json(json!({
+                    "status": "error",
+                    "error": "rate_limited",
+                    "message": format!("Rate limit exceeded, retry in {} ms", retry_after_ms),
+                    "retry_after_ms": retry_after_ms
+                })),
+            ).into_response()
+        }
+    }
+}
+
+/// Payment protocol network invoices are paid on when PAYMENT_NETWORK is not set
+pub const DEFAULT_PAYMENT_NETWORK: &str = "main";
+
+/// Parse a payment protocol network, "main" or "test", accepting the mainnet and testnet aliases
+pub fn parse_payment_network(network: &str) -> anyhow::Result<String> {
+    match network.trim().to_lowercase().as_str() {
+        "main" | "mainnet" => Ok("main".to_string()),
+        "test" | "testnet" => Ok("test".to_string()),
+        _ => Err(anyhow::anyhow!("Invalid payment network: {} (expected main or test)", network)),
+    }
+}
+
+/// Origins allowed to call the API from a browser when CORS_ALLOWED_ORIGINS is not set
+pub const DEFAULT_CORS_ALLOWED_ORIGINS: &[&str] = &["https://anypayx.com", "https://app.anypayx.com"];
+
 pub struct HttpServer {
     supabase: Arc<SupabaseClient>,
+    cors_allowed_origins: Vec<String>,
+    max_body_bytes: usize,
+    admin_api_key: Option<Arc<String>>,
+    blockbook: Option<BlockbookClient>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    network: Arc<String>,
 }
 
 impl HttpServer {
     pub fn new(supabase: Arc<SupabaseClient>) -> Self {
-        Self { supabase }
+        Self {
+            supabase,
+            cors_allowed_origins: DEFAULT_CORS_ALLOWED_ORIGINS.iter().map(|origin| origin.to_string()).collect(),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            admin_api_key: None,
+            blockbook: None,
+            rate_limiter: None,
+            network: Arc::new(DEFAULT_PAYMENT_NETWORK.to_string()),
+        }
+    }
+
+    /// Tell wallets invoices are paid on `network`, "main" or "test", instead of DEFAULT_PAYMENT_NETWORK
+    pub fn with_network(mut self, network: String) -> Self {
+        self.network = Arc::new(network);
+        self
+    }
+
+    /// Enable the admin routes, authenticated by this bearer token
+    pub fn with_admin_api_key(mut self, admin_api_key: Option<String>) -> Self {
+        self.admin_api_key = admin_api_key.map(Arc::new);
+        self
+    }
+
+    /// Blockbook client used by the admin replay endpoint to fetch blocks
+    pub fn with_blockbook(mut self, blockbook: BlockbookClient) -> Self {
+        self.blockbook = Some(blockbook);
+        self
+    }
+
+    /// Set the largest request body the routes will accept
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+
+    /// Throttle each client to `rate_limit`, or not at all when None
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limiter = rate_limit.map(|rate_limit| Arc::new(RateLimiter::new(rate_limit)));
+        self
+    }
+
+    /// Replace the default list of origins allowed to make cross-origin requests
+    pub fn with_cors_allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.cors_allowed_origins = origins;
+        self
+    }
+
+    fn cors_layer(&self) -> CorsLayer {
+        let origins = self.cors_allowed_origins.iter()
+            .filter_map(|origin| match HeaderValue::from_str(origin) {
+                Ok(origin) => Some(origin),
+                Err(_) => {
+                    tracing::warn!("Ignoring invalid CORS origin: {}", origin);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::ACCEPT,
+                HeaderName::from_static("x-currency"),
+                HeaderName::from_static("x-chain"),
+            ])
+            // Lets browser clients read when to retry a throttled request
+            .expose_headers([header::RETRY_AFTER])
+            .max_age(Duration::from_secs(3600))
+    }
+
+    /// Operator routes, only mounted when an admin API key is configured
+    fn admin_router(&self, admin_key: Arc<String>) -> Router {
+        let blockbook = self.blockbook.clone();
+        let jobs: Arc<std::sync::Mutex<HashMap<Uuid, ReplayJob>>> = Arc::default();
+
+        let start_replay = {
+            let jobs = jobs.clone();
+            move |ApiJson(payload): ApiJson<ReplayRequest>| async move {
+                let Some(blockbook) = blockbook else {
+                    return Err(error_response(StatusCode::SERVICE_UNAVAILABLE, "Blockbook is not configured"));
+                };
+                validate_replay_range(payload.from_height, payload.to_height)
+                    .map_err(|e| error_response(StatusCode::BAD_REQUEST, e))?;
+
+                // Fetching up to MAX_REPLAY_BLOCKS blocks takes longer than a request should stay open
+                let id = Uuid::new_v4();
+                let (from_height, to_height) = (payload.from_height, payload.to_height);
+                let job = ReplayJob::Running { from_height, to_height };
+                jobs.lock().unwrap().insert(id, job.clone());
+
+                let jobs = jobs.clone();
+                tokio::spawn(async move {
+                    let finished = match blockbook.replay_blocks(from_height, to_height).await {
+                        Ok(summary) => ReplayJob::Completed(summary),
+                        Err(e) => {
+                            tracing::error!("Error replaying blocks {} to {}: {}", from_height, to_height, e);
+                            ReplayJob::Failed { from_height, to_height, error: e.to_string() }
+                        }
+                    };
+                    jobs.lock().unwrap().insert(id, finished);
+                });
+
+                Ok((StatusCode::ACCEPTED, Json(ReplayJobResponse { id, job })))
+            }
+        };
+
+        let get_replay = move |Path(id): Path<Uuid>| async move {
+            match jobs.lock().unwrap().get(&id) {
+                Some(job) => Ok(Json(ReplayJobResponse { id, job: job.clone() })),
+                None => Err(error_response(StatusCode::NOT_FOUND, "Replay not found")),
+            }
+        };
+
+        Router::new()
+            // Re-scan blocks missed while the Blockbook subscription was down
+            .route("/api/v1/admin/replay", post(start_replay))
+            .route("/api/v1/admin/replay/:id", get(get_replay))
+            .route_layer(middleware::from_fn(move |req: Request<Body>, next: Next<Body>| {
+                require_admin(admin_key.clone(), req, next)
+            }))
     }
 
     pub fn router(&self) -> Router {
         let supabase = self.supabase.clone();
 
-        Router::new()
+        let get_invoice = {
+            let supabase = supabase.clone();
+            let network = self.network.clone();
+            move |Path(invoice_id): Path<String>, headers: HeaderMap| async move {
+                tracing::info!("Fetching invoice with id: {}", invoice_id);
+                match supabase.get_invoice(&invoice_id, true).await {
+                    Ok(Some((invoice, payment_options))) if wants_payment_options(&headers) => {
+                        let base_url = account_base_url(&supabase, invoice.account_id).await;
+                        Ok(Json(PaymentOptionsDocument::new(&invoice, &payment_options, &base_url, &network)).into_response())
+                    }
+                    Ok(Some(result)) => {
+                        tracing::info!("Invoice fetched successfully: {:?}", result);
+                        Ok(Json(InvoiceResponse { invoice: result.0, payment_options: result.1 }).into_response())
+                    }
+                    Ok(None) => Err(StatusCode::NOT_FOUND),
+                    Err(e) => {
+                        tracing::error!("Error fetching invoice: {}", e);
+                        Err(StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                }
+            }
+        };
+
+        // Routes that act on behalf of an account require a valid API token
+        let protected = Router::new()
+            .route("/api/v1/invoices", post({
+                let supabase = supabase.clone();
+                move |Extension(AuthenticatedAccount(account_id)): Extension<AuthenticatedAccount>,
+                      ApiJson(payload): ApiJson<CreateInvoiceRequest>| async move {
+                    let required_fee_rate = match payload.required_fee_rate.as_deref().map(str::parse::<i64>) {
+                        Some(Ok(rate)) if rate >= 0 => Some(rate),
+                        Some(_) => return Err(StatusCode::BAD_REQUEST),
+                        None => None,
+                    };
+                    if payload.op_return.as_deref().map_or(false, |data| decode_op_return(data).is_err()) {
+                        return Err(StatusCode::BAD_REQUEST);
+                    }
+
+                    match supabase.create_invoice(
+                        payload.amount, 
+                        &payload.currency, 
+                        account_id as i64,
+                        payload.webhook_url,
+                        payload.redirect_url,
+                        payload.memo,
+                        payload.accepted_currencies,
+                        required_fee_rate,
+                        payload.crypto_denominated,
+                        payload.op_return
+                    ).await {
+                        Ok(response) => {
+                            let data = response.as_object().unwrap();
+                            Ok(Json(InvoiceResponse { 
+                                invoice: serde_json::from_value(data["invoice"].clone()).unwrap(),
+                                payment_options: serde_json::from_value(data["payment_options"].clone()).unwrap(),
+                            }))
+                        },
+                        Err(e) => {
+                            tracing::error!("Error creating invoice: {}", e);
+                            Err(StatusCode::INTERNAL_SERVER_ERROR)
+                        }
+                    }
+                }
+            }))
+            // Send a signed sample payment.confirmed so merchants can check their receiver before going live
+            .route("/api/v1/webhooks/test", post({
+                let supabase = supabase.clone();
+                move |Extension(AuthenticatedAccount(account_id)): Extension<AuthenticatedAccount>,
+                      ApiJson(payload): ApiJson<WebhookTestRequest>| async move {
+                    // Refuse hosts inside our network, so the route can't be used to probe it
+                    let target = WebhookTarget::resolve(&payload.url).await
+                        .map_err(|e| error_response(StatusCode::BAD_REQUEST, e))?;
+
+                    let secret = supabase.get_webhook_secret(account_id as i64).await.map_err(|e| {
+                        tracing::error!("Error fetching webhook secret for account {}: {}", account_id, e);
+                        error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to load webhook secret")
+                    })?;
+
+                    let event = sample_payment_confirmed(account_id);
+                    match target.send(secret.as_deref(), &event).await {
+                        Ok(status) => Ok(Json(json!({
+                            "url": payload.url,
+                            "topic": event.topic,
+                            "signed": secret.is_some(),
+                            "status": status.as_u16()
+                        }))),
+                        Err(e) => Err(error_response(StatusCode::BAD_GATEWAY, e)),
+                    }
+                }
+            }))
+            .route("/invoices/:uid", delete(move |Path(uid): Path<String>| async move {
+                // TODO: Implement invoice cancellation
+                StatusCode::NOT_IMPLEMENTED
+            }))
+            .route_layer(middleware::from_fn({
+                let supabase = supabase.clone();
+                move |req: Request<Body>, next: Next<Body>| require_account(supabase.clone(), req, next)
+            }));
+
+        let router = Router::new()
             // Prices endpoint
             .route("/api/v1/prices", get({
                 let supabase = supabase.clone();
@@ -68,53 +646,85 @@ impl HttpServer {
                 }
             }))
 
-            // Invoice endpoints
-            .route("/api/v1/invoices/:invoice_id", get({
+            // Chain connectivity, for operators and monitoring
+            .route("/api/v1/status", get(|Query(query): Query<StatusQuery>| async move {
+                let chains = chain_status().snapshot();
+                match query.format.as_deref() {
+                    Some("prometheus") => (
+                        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                        to_prometheus(&chains),
+                    ).into_response(),
+                    _ => Json(StatusResponse { chains }).into_response(),
+                }
+            }))
+
+            // Conversion endpoint, mirroring the websocket convert_price action
+            .route("/api/v1/convert", get({
                 let supabase = supabase.clone();
-                move |Path(invoice_id): Path<String>| async move {
-                    tracing::info!("Fetching invoice with id: {}", invoice_id);
-                    match supabase.get_invoice(&invoice_id, true).await {
-                        Ok(Some(result)) => {
-                            tracing::info!("Invoice fetched successfully: {:?}", result);
-                            Ok(Json(InvoiceResponse { invoice: result.0, payment_options: result.1 }))
-                        }
-                        Ok(None) => Err(StatusCode::NOT_FOUND),
+                move |Query(query): Query<ConvertQuery>| async move {
+                    let req = query.into_request()
+                        .map_err(|e| error_response(StatusCode::BAD_REQUEST, e))?;
+
+                    match convert(req, &supabase).await {
+                        Ok(result) => Ok(Json(result)),
                         Err(e) => {
-                            tracing::error!("Error fetching invoice: {}", e);
-                            Err(StatusCode::INTERNAL_SERVER_ERROR)
+                            tracing::error!("Error converting price: {}", e);
+                            Err(error_response(conversion_error_status(&e), format!("Conversion failed: {}", e)))
                         }
                     }
                 }
             }))
-            .route("/api/v1/invoices", post(move |Json(payload): Json<CreateInvoiceRequest>| async move {
-                match supabase.create_invoice(
-                    payload.amount, 
-                    &payload.currency, 
-                    payload.account_id,  // TODO: Get real account_id
-                    payload.webhook_url,
-                    payload.redirect_url,
-                    payload.memo
-                ).await {
-                    Ok(response) => {
-                        let data = response.as_object().unwrap();
-                        Ok(Json(InvoiceResponse { 
-                            invoice: serde_json::from_value(data["invoice"].clone()).unwrap(),
-                            payment_options: serde_json::from_value(data["payment_options"].clone()).unwrap(),
-                        }))
-                    },
-                    Err(e) => {
-                        tracing::error!("Error creating invoice: {}", e);
-                        Err(StatusCode::INTERNAL_SERVER_ERROR)
-                    }
+
+            // Invoice endpoints, also served at /i/:uid for payment protocol wallets
+            .route("/api/v1/invoices/:invoice_id", get(get_invoice.clone()))
+            .route("/i/:invoice_id", get(get_invoice).post({
+                let supabase = supabase.clone();
+                let network = self.network.clone();
+                // Wallets send Content-Type: application/payment-request, so the body is parsed by hand
+                move |Path(invoice_id): Path<String>, headers: HeaderMap, body: Bytes| async move {
+                    let selection = if body.is_empty() {
+                        PaymentRequestSelection::default()
+                    } else {
+                        serde_json::from_slice::<PaymentRequestSelection>(&body)
+                            .map_err(|e| error_response(StatusCode::BAD_REQUEST, format!("Invalid payment request: {}", e)))?
+                    };
+                    let (chain, currency) = selection.resolve(&headers)
+                        .map_err(|e| error_response(StatusCode::BAD_REQUEST, e))?;
+
+                    let (invoice, payment_options) = match supabase.get_invoice(&invoice_id, true).await {
+                        Ok(Some(result)) => result,
+                        Ok(None) => return Err(error_response(StatusCode::NOT_FOUND, format!("Invoice {} not found", invoice_id))),
+                        Err(e) => {
+                            tracing::error!("Error fetching invoice: {}", e);
+                            return Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch invoice"));
+                        }
+                    };
+
+                    let option = payment_options.iter()
+                        .find(|option| option.chain == chain && option.currency == currency)
+                        .ok_or_else(|| error_response(
+                            StatusCode::BAD_REQUEST,
+                            format!("Invoice {} does not accept {} on {}", invoice_id, currency, chain),
+                        ))?;
+
+                    let coin = supabase.get_coin(&currency, &chain).await
+                        .unwrap_or_else(|e| {
+                            tracing::warn!("Failed to load coin {} on {}: {}", currency, chain, e);
+                            None
+                        });
+
+                    let base_url = account_base_url(&supabase, invoice.account_id).await;
+                    Ok(Json(payment_request_response(&invoice, option, required_fee_rate(&invoice, coin.as_ref()), &base_url, &network)))
                 }
             }))
-            .route("/invoices/:uid", delete(move |Path(uid): Path<String>| async move {
-                // TODO: Implement invoice cancellation
-                StatusCode::NOT_IMPLEMENTED
-            }))
+            .merge(protected)
+            .merge(match &self.admin_api_key {
+                Some(admin_key) => self.admin_router(admin_key.clone()),
+                None => Router::new(),
+            })
 
             // Payment platform routes
-            .route("/r", post(move |Json(payload): Json<PaymentRequest>| async move {
+            .route("/r", post(move |ApiJson(payload): ApiJson<PaymentRequest>| async move {
                 // TODO: Implement payment request creation
                 tracing::info!("Creating payment request: {:?}", payload);
                 Ok::<Json<serde_json::Value>, StatusCode>(Json(json!({
@@ -123,14 +733,27 @@ impl HttpServer {
                 })))
             }))
             .route("/r/:uid", 
-                post(move |
-                    Path(uid): Path<String>,
-                | async move {
-                    // TODO: Process payment for existing request
-                    tracing::info!("Processing payment for {}", uid);
-                    Ok::<Json<serde_json::Value>, StatusCode>(Json(json!({
-                        "status": "processing"
-                    })))
+                post({
+                    let supabase = supabase.clone();
+                    // Wallets send Content-Type: application/payment, so the body is parsed by hand
+                    move |Path(uid): Path<String>, body: Bytes| async move {
+                        let submission: PaymentSubmission = serde_json::from_slice(&body)
+                            .map_err(|e| error_response(StatusCode::BAD_REQUEST, format!("Invalid payment: {}", e)))?;
+
+                        tracing::info!("Processing payment for {}", uid);
+                        match submit_payment(&supabase, &uid, &submission).await {
+                            // Payment ACK in the JSON payment protocol's shape, plus the recorded payments
+                            Ok(payments) => Ok(Json(json!({
+                                "payment": submission,
+                                "memo": "Transaction received. The invoice will be marked as paid once it confirms.",
+                                "payments": payments
+                            }))),
+                            Err(e) => {
+                                tracing::error!("Error submitting payment for {}: {}", uid, e);
+                                Err(error_response(StatusCode::BAD_REQUEST, format!("Payment failed: {}", e)))
+                            }
+                        }
+                    }
                 })
                 .delete(move |Path(uid): Path<String>| async move {
                     // TODO: Cancel payment request
@@ -138,6 +761,712 @@ impl HttpServer {
                     StatusCode::OK
                 })
             )
+            .layer(DefaultBodyLimit::max(self.max_body_bytes));
+
+        // Inside the CORS layer, so throttled responses still carry CORS headers
+        let router = match &self.rate_limiter {
+            Some(limiter) => {
+                let limiter = limiter.clone();
+                router.layer(middleware::from_fn(move |req: Request<Body>, next: Next<Body>| throttle(limiter.clone(), req, next)))
+            }
+            None => router,
+        };
+
+        router.layer(self.cors_layer())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+    use crate::supabase::tests::{btc_option_row, invoice_row, mock_chain_apis, mock_invoice, spawn_mock_supabase};
+    use crate::uri::DEFAULT_BASE_URL;
+
+    fn mock_prices() -> Router {
+        Router::new().route("/rest/v1/prices", get(|Query(params): Query<HashMap<String, String>>| async move {
+            let is_btc_usd = params.get("base_currency").map(String::as_str) == Some("eq.BTC")
+                && params.get("currency").map(String::as_str) == Some("eq.USD");
+
+            if is_btc_usd {
+                Json(json!([{
+                    "id": 1,
+                    "currency": "USD",
+                    "value": 0.00002,
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": "2024-01-01T00:00:00Z"
+                }]))
+            } else {
+                Json(json!([]))
+            }
+        }))
+    }
+
+    async fn get_json(router: Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = router
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn router(supabase_url: &str) -> Router {
+        HttpServer::new(Arc::new(SupabaseClient::new(supabase_url, "anon", "service"))).router()
+    }
+
+    #[tokio::test]
+    async fn test_convert_route() {
+        let router = router(&spawn_mock_supabase(mock_prices()));
+
+        let (status, body) = get_json(router, "/api/v1/convert?quote_currency=USD&base_currency=BTC&quote_value=100").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["base_value"].as_f64(), Some(0.002));
+        assert_eq!(body["quote_currency"], "USD");
+        assert_eq!(body["base_currency"], "BTC");
+    }
+
+    #[tokio::test]
+    async fn test_status_route_reports_chain_connectivity() {
+        // BNB is reported by nothing else under test
+        chain_status().record_block("BNB", 42);
+        let router = router(&spawn_mock_supabase(Router::new()));
+
+        let (status, body) = get_json(router.clone(), "/api/v1/status").await;
+
+        assert_eq!(status, StatusCode::OK);
+        let chains = body["chains"].as_array().unwrap();
+        assert_eq!(chains.len(), crate::status::TRACKED_CHAINS.len());
+        let bnb = chains.iter().find(|chain| chain["chain"] == "BNB").unwrap();
+        assert_eq!(bnb["connected"], true);
+        assert_eq!(bnb["last_block_height"], 42);
+        let polygon = chains.iter().find(|chain| chain["chain"] == "POLYGON").unwrap();
+        assert_eq!(polygon["connected"], false);
+        assert!(polygon["last_block_height"].is_null());
+
+        let response = router.oneshot(
+            Request::builder().uri("/api/v1/status?format=prometheus").body(Body::empty()).unwrap()
+        ).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("anypay_chain_last_block_height{chain=\"BNB\"} 42"));
+    }
+
+    fn replay_request(token: Option<&str>, body: serde_json::Value) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/v1/admin/replay")
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_replay_requires_admin_key() {
+        let supabase = Arc::new(SupabaseClient::new("http://127.0.0.1:1", "anon", "service"));
+        let range = json!({ "from_height": 100, "to_height": 102 });
+
+        // Without an admin key configured the route doesn't exist
+        let router = HttpServer::new(supabase.clone()).router();
+        let response = router.oneshot(replay_request(Some("s3cret"), range.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let router = HttpServer::new(supabase).with_admin_api_key(Some("s3cret".to_string())).router();
+        for token in [None, Some("wrong")] {
+            let response = router.clone().oneshot(replay_request(token, range.clone())).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // Authorized, but there is no Blockbook client to fetch blocks with
+        let response = router.clone().oneshot(replay_request(Some("s3cret"), range)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_admin_replay_rejects_invalid_range() {
+        let supabase = SupabaseClient::new("http://127.0.0.1:1", "anon", "service");
+        let router = HttpServer::new(Arc::new(supabase.clone()))
+            .with_admin_api_key(Some("s3cret".to_string()))
+            .with_blockbook(BlockbookClient::new("127.0.0.1:1".to_string(), "key".to_string(), supabase))
+            .router();
+
+        let response = router.oneshot(replay_request(Some("s3cret"), json!({ "from_height": 102, "to_height": 100 }))).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_admin_replay_runs_in_the_background() {
+        let supabase = SupabaseClient::new("http://127.0.0.1:1", "anon", "service");
+        let router = HttpServer::new(Arc::new(supabase.clone()))
+            .with_admin_api_key(Some("s3cret".to_string()))
+            .with_blockbook(BlockbookClient::new("127.0.0.1:1".to_string(), "key".to_string(), supabase))
+            .router();
+
+        let response = router.clone().oneshot(replay_request(Some("s3cret"), json!({ "from_height": 100, "to_height": 102 }))).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(job["status"], "running");
+        assert_eq!(job["from_height"], 100);
+
+        // Blockbook is unreachable, so the job fails once it tries to fetch the first block
+        let status_request = || Request::builder()
+            .uri(format!("/api/v1/admin/replay/{}", job["id"].as_str().unwrap()))
+            .header(header::AUTHORIZATION, "Bearer s3cret")
+            .body(Body::empty())
+            .unwrap();
+        let finished = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let response = router.clone().oneshot(status_request()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                if job["status"] != "running" {
+                    return job;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(finished["status"], "failed");
+        assert!(finished["error"].as_str().unwrap().contains("Failed to fetch block 100"));
+    }
+
+    #[tokio::test]
+    async fn test_convert_route_missing_param() {
+        let router = router(&spawn_mock_supabase(mock_prices()));
+
+        let (status, body) = get_json(router, "/api/v1/convert?quote_currency=USD&base_currency=BTC").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "Missing required parameter: quote_value");
+    }
+
+    #[tokio::test]
+    async fn test_convert_route_unknown_currency() {
+        let router = router(&spawn_mock_supabase(mock_prices()));
+
+        let (status, body) = get_json(router, "/api/v1/convert?quote_currency=USD&base_currency=NOPE&quote_value=1").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("No price for USD to NOPE"));
+    }
+
+    #[tokio::test]
+    async fn test_convert_route_unavailable_prices() {
+        // Every table is missing, so the price lookup itself fails
+        let router = router(&spawn_mock_supabase(Router::new()));
+
+        let (status, body) = get_json(router, "/api/v1/convert?quote_currency=USD&base_currency=BTC&quote_value=100").await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["message"].as_str().unwrap().starts_with("Conversion failed"));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_request_carries_retry_hint() {
+        let router = HttpServer::new(Arc::new(SupabaseClient::new("http://127.0.0.1:1", "anon", "service")))
+            .with_rate_limit(Some(RateLimit { burst: 1, per_second: 0.5 }))
+            .router();
+        let request = || Request::builder().uri("/api/v1/convert").body(Body::empty()).unwrap();
+
+        let response = router.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = router.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        let body: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["error"], "rate_limited");
+        // The bucket refills a token every two seconds, less the moment since the first request
+        let retry_after_ms = body["retry_after_ms"].as_u64().unwrap();
+        assert!((1_900..=2_000).contains(&retry_after_ms), "unexpected retry_after_ms: {}", retry_after_ms);
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        let preflight = |origin: &str| Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api/v1/invoices")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization,content-type")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = router("http://127.0.0.1:1").oneshot(preflight("https://app.anypayx.com")).await.unwrap();
+        let headers = response.headers();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.anypayx.com");
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap().contains("POST"));
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().contains("authorization"));
+
+        let response = router("http://127.0.0.1:1").oneshot(preflight("https://evil.example.com")).await.unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    fn mock_access_tokens() -> Router {
+        Router::new().route("/rest/v1/access_tokens", get(|Query(params): Query<HashMap<String, String>>| async move {
+            if params.get("uid").map(String::as_str) == Some("eq.valid-token") {
+                (StatusCode::OK, Json(json!({ "account_id": 42 })))
+            } else {
+                // PostgREST's response when .single() matches no rows
+                (StatusCode::NOT_ACCEPTABLE, Json(json!({ "code": "PGRST116", "message": "no rows" })))
+            }
+        }))
+    }
+
+    fn whoami_router(supabase_url: &str) -> Router {
+        let supabase = Arc::new(SupabaseClient::new(supabase_url, "anon", "service"));
+        Router::new()
+            .route("/whoami", get(|Extension(AuthenticatedAccount(account_id)): Extension<AuthenticatedAccount>| async move {
+                Json(json!({ "account_id": account_id }))
+            }))
+            .route_layer(middleware::from_fn(move |req: Request<Body>, next: Next<Body>| {
+                require_account(supabase.clone(), req, next)
+            }))
+    }
+
+    async fn whoami(router: Router, authorization: Option<&str>) -> (StatusCode, serde_json::Value) {
+        let mut request = Request::builder().uri("/whoami");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let response = router.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_request_resolves_account() {
+        let url = spawn_mock_supabase(mock_access_tokens());
+
+        let (status, body) = whoami(whoami_router(&url), Some("Bearer valid-token")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["account_id"], 42);
+
+        // base64("valid-token:"), as sent by AnypayClient
+        let (status, _) = whoami(whoami_router(&url), Some("Basic dmFsaWQtdG9rZW46")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_requests_rejected() {
+        let url = spawn_mock_supabase(mock_access_tokens());
+
+        let (status, body) = whoami(whoami_router(&url), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["message"], "Missing API token");
+
+        let (status, body) = whoami(whoami_router(&url), Some("Bearer wrong-token")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["message"], "Invalid API token");
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_requires_token() {
+        let router = router(&spawn_mock_supabase(mock_access_tokens()));
+
+        let response = router.oneshot(Request::builder()
+            .method(Method::POST)
+            .uri("/api/v1/invoices")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"amount": 100, "currency": "USD", "account_id": 1}"#))
+            .unwrap()
+        ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_test_refuses_internal_urls() {
+        let called = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let receiver = Router::new().route("/hooks", post({
+            let called = called.clone();
+            move || async move {
+                called.store(true, std::sync::atomic::Ordering::SeqCst);
+                StatusCode::ACCEPTED
+            }
+        }));
+        let receiver_url = format!("{}/hooks", spawn_mock_supabase(receiver));
+
+        let supabase = mock_access_tokens().route("/rest/v1/accounts", get(|| async {
+            Json(json!([{ "webhook_secret": "whsec_test" }]))
+        }));
+        let router = router(&spawn_mock_supabase(supabase));
+        for url in [receiver_url.as_str(), "http://169.254.169.254/latest/meta-data"] {
+            let response = router.clone().oneshot(Request::builder()
+                .method(Method::POST)
+                .uri("/api/v1/webhooks/test")
+                .header(header::AUTHORIZATION, "Bearer valid-token")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "url": url }).to_string()))
+                .unwrap()
+            ).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(!called.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    async fn post_payment_request(router: Router, body: Body) -> (StatusCode, serde_json::Value) {
+        let response = router.oneshot(Request::builder()
+            .method(Method::POST)
+            .uri("/r")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap()
+        ).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected() {
+        let router = HttpServer::new(Arc::new(SupabaseClient::new("http://127.0.0.1:1", "anon", "service")))
+            .with_max_body_bytes(1024)
+            .router();
+        let body = format!(r#"{{"template": [], "padding": "{}"}}"#, "x".repeat(2048));
+
+        let (status, body) = post_payment_request(router, Body::from(body)).await;
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "body_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_json_rejected() {
+        let (status, body) = post_payment_request(router("http://127.0.0.1:1"), Body::from(r#"{"template": ["#)).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["error"], "malformed_json");
+        assert!(body["message"].as_str().unwrap().contains("Failed to parse the request body as JSON"));
+    }
+
+    // Version 1 transaction with one input and a single 1000 sat output to BTC_ADDRESS
+    const BTC_TX_HEX: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff00ffffffff01e803000000000000160014000000000000000000000000000000000000000000000000";
+    const BTC_TXID: &str = "7251f2a9275823e4a8586b24ccab7bd8093d1c2a58ab29c82f6005c23b010798";
+
+    async fn get_invoice_with_accept(router: Router, path: &str, accept: &str) -> (StatusCode, serde_json::Value) {
+        let response = router.oneshot(
+            Request::builder()
+                .uri(path)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        ).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_invoice_content_negotiation() {
+        let mut invoice = invoice_row();
+        invoice["memo"] = json!("Coffee");
+        let url = spawn_mock_supabase(mock_invoice(invoice));
+
+        let (status, invoice) = get_invoice_with_accept(router(&url), "/i/inv_1", "application/json").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(invoice["invoice"]["uid"], "inv_1");
+        assert_eq!(invoice["payment_options"][0]["chain"], "BTC");
+        assert!(invoice.get("paymentOptions").is_none());
+
+        let (status, document) = get_invoice_with_accept(router(&url), "/i/inv_1", "application/payment-options").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(document.get("invoice").is_none());
+        assert_eq!(document["paymentId"], "inv_1");
+        assert_eq!(document["memo"], "Coffee");
+        assert!(document["paymentUrl"].as_str().unwrap().ends_with("/i/inv_1"));
+        assert_eq!(document["paymentOptions"][0]["currency"], "BTC");
+        assert_eq!(document["paymentOptions"][0]["estimatedAmount"], 1000);
+        assert_eq!(document["paymentOptions"][0]["expires"], "2099-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn test_payment_request_carries_sanitized_memo() {
+        let mut row = invoice_row();
+        row["memo"] = json!("Order #42\n  Coffee & cake");
+        row["uri"] = json!("anypay:btc_inv_1?message=Order%20%2342%20Coffee%20%26%20cake");
+        let invoice: Invoice = serde_json::from_value(row).unwrap();
+        let option: PaymentOption = serde_json::from_value(btc_option_row()).unwrap();
+
+        let response = payment_request_response(&invoice, &option, DEFAULT_REQUIRED_FEE_RATE, DEFAULT_BASE_URL, DEFAULT_PAYMENT_NETWORK);
+
+        assert_eq!(response["invoice"]["payment_options"][0]["memo"], "Order #42 Coffee & cake");
+    }
+
+    #[tokio::test]
+    async fn test_payment_url_is_on_the_account_domain() {
+        let url = spawn_mock_supabase(Router::new().route("/rest/v1/accounts", get(|| async {
+            Json(json!([{ "id": 7, "denomination": "USD", "base_url": "https://pay.shop.example/" }]))
+        })));
+        let supabase = SupabaseClient::new(&url, "anon", "service");
+        let mut row = invoice_row();
+        row["account_id"] = json!(7);
+        row["uri"] = json!("pay:?r=https://pay.shop.example/r/inv_1");
+        let invoice: Invoice = serde_json::from_value(row).unwrap();
+        let option: PaymentOption = serde_json::from_value(btc_option_row()).unwrap();
+
+        let base_url = account_base_url(&supabase, invoice.account_id).await;
+        assert_eq!(base_url, "https://pay.shop.example");
+
+        let response = payment_request_response(&invoice, &option, DEFAULT_REQUIRED_FEE_RATE, &base_url, DEFAULT_PAYMENT_NETWORK);
+        assert_eq!(response["invoice"]["payment_options"][0]["paymentUrl"], "https://pay.shop.example/r/inv_1");
+        let document = PaymentOptionsDocument::new(&invoice, &[option], &base_url, DEFAULT_PAYMENT_NETWORK);
+        assert_eq!(document.payment_url, "https://pay.shop.example/i/inv_1");
+    }
+
+    #[test]
+    fn test_payment_request_carries_op_return() {
+        let invoice: Invoice = serde_json::from_value(invoice_row()).unwrap();
+        let mut option: PaymentOption = serde_json::from_value(btc_option_row()).unwrap();
+
+        let response = payment_request_response(&invoice, &option, DEFAULT_REQUIRED_FEE_RATE, DEFAULT_BASE_URL, DEFAULT_PAYMENT_NETWORK);
+        assert!(response["invoice"]["payment_options"][0]["instructions"][0]["opReturn"].is_null());
+
+        option.op_return = Some("616e79706179".to_string());
+        let response = payment_request_response(&invoice, &option, DEFAULT_REQUIRED_FEE_RATE, DEFAULT_BASE_URL, DEFAULT_PAYMENT_NETWORK);
+        assert_eq!(response["invoice"]["payment_options"][0]["instructions"][0]["opReturn"], "616e79706179");
+    }
+
+    #[test]
+    fn test_payment_documents_use_the_configured_network() {
+        let invoice: Invoice = serde_json::from_value(invoice_row()).unwrap();
+        let option: PaymentOption = serde_json::from_value(btc_option_row()).unwrap();
+        let network = parse_payment_network("testnet").unwrap();
+
+        let response = payment_request_response(&invoice, &option, DEFAULT_REQUIRED_FEE_RATE, DEFAULT_BASE_URL, &network);
+        assert_eq!(response["invoice"]["payment_options"][0]["network"], "test");
+        let document = PaymentOptionsDocument::new(&invoice, &[option], DEFAULT_BASE_URL, &network);
+        assert_eq!(document.payment_options[0].network, "test");
+        assert!(parse_payment_network("regtest").is_err());
+    }
+
+    #[test]
+    fn test_payment_request_carries_required_fee_rate() {
+        let mut invoice: Invoice = serde_json::from_value(invoice_row()).unwrap();
+        let coin: Coin = serde_json::from_value(json!({
+            "id": 1,
+            "currency": "BTC",
+            "chain": "BTC",
+            "precision": 8,
+            "uri_template": null,
+            "createdAt": "2024-01-01T00:00:00Z",
+            "updatedAt": "2024-01-01T00:00:00Z",
+            "required_fee_rate": 5,
+            "color": null
+        })).unwrap();
+        let option: PaymentOption = serde_json::from_value(btc_option_row()).unwrap();
+
+        let fee_rate = required_fee_rate(&invoice, Some(&coin));
+        let response = payment_request_response(&invoice, &option, fee_rate, DEFAULT_BASE_URL, DEFAULT_PAYMENT_NETWORK);
+        assert_eq!(response["invoice"]["payment_options"][0]["instructions"][0]["requiredFeeRate"], 5);
+
+        invoice.required_fee_rate = Some(12);
+        assert_eq!(required_fee_rate(&invoice, Some(&coin)), 12);
+        assert_eq!(required_fee_rate(&invoice, None), 12);
+
+        invoice.required_fee_rate = None;
+        assert_eq!(required_fee_rate(&invoice, None), DEFAULT_REQUIRED_FEE_RATE);
+    }
+
+    async fn post_payment_request_for(router: Router, uid: &str, chain: &str, currency: &str) -> (StatusCode, serde_json::Value) {
+        let response = router.oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/i/{}", uid))
+                .header(header::CONTENT_TYPE, "application/payment-request")
+                .header("x-chain", chain)
+                .header("x-currency", currency)
+                .body(Body::from(json!({ "chain": chain, "currency": currency }).to_string()))
+                .unwrap()
+        ).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_payment_request_returns_selected_instruction() {
+        let url = spawn_mock_supabase(mock_invoice(invoice_row()));
+
+        let (status, body) = post_payment_request_for(router(&url), "inv_1", "BTC", "BTC").await;
+
+        assert_eq!(status, StatusCode::OK);
+        let option = &body["invoice"]["payment_options"][0];
+        assert_eq!(option["chain"], "BTC");
+        assert_eq!(option["paymentId"], "inv_1");
+        let instruction = &option["instructions"][0];
+        assert_eq!(instruction["type"], "transaction");
+        assert_eq!(instruction["requiredFeeRate"], DEFAULT_REQUIRED_FEE_RATE);
+        assert_eq!(instruction["outputs"], btc_option_row()["outputs"]);
+
+        let (status, body) = post_payment_request_for(router(&url), "inv_1", "ETH", "ETH").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("does not accept ETH"));
+    }
+
+    /// Mock payment_options and payments tables, recording every inserted payment row
+    fn mock_payments(inserted: Arc<std::sync::Mutex<Vec<serde_json::Value>>>) -> Router {
+        mock_payments_expecting(inserted, 1000)
+    }
+
+    /// Like `mock_payments`, with the BTC payment option asking for `amount` sats
+    fn mock_payments_expecting(inserted: Arc<std::sync::Mutex<Vec<serde_json::Value>>>, amount: i64) -> Router {
+        Router::new()
+            .route("/rest/v1/payment_options", get(move |Query(params): Query<HashMap<String, String>>| async move {
+                if params.get("chain").map(String::as_str) != Some("eq.BTC") {
+                    return Json(json!([]));
+                }
+                let mut option = btc_option_row();
+                option["amount"] = json!(amount);
+                option["outputs"][0]["amount"] = json!(amount);
+                Json(json!([option]))
+            }))
+            .route("/rest/v1/payments", get({
+                let inserted = inserted.clone();
+                move |Query(params): Query<HashMap<String, String>>| async move {
+                    let matches = |row: &serde_json::Value, column: &str| match params.get(column) {
+                        Some(filter) => *filter == format!("eq.{}", row[column].as_str().unwrap_or_default()),
+                        None => true,
+                    };
+                    let rows = inserted.lock().unwrap().iter()
+                        .filter(|row| matches(row, "invoice_uid") && matches(row, "txid"))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    Json(rows)
+                }
+            }).post(move |Json(rows): Json<Vec<serde_json::Value>>| async move {
+                let mut inserted = inserted.lock().unwrap();
+                let rows = rows.into_iter()
+                    .map(|mut row| {
+                        row["id"] = json!(inserted.len() + 1);
+                        inserted.push(row.clone());
+                        row
+                    })
+                    .collect::<Vec<_>>();
+                (StatusCode::CREATED, Json(rows))
+            }))
+    }
+
+    async fn post_payment(router: Router, uid: &str, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        let response = router.oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/r/{}", uid))
+                .header(header::CONTENT_TYPE, "application/payment")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        ).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_submitted_payment_creates_pending_row() {
+        mock_chain_apis();
+        let inserted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let router = router(&spawn_mock_supabase(mock_payments(inserted.clone())));
+
+        let (status, body) = post_payment(router, "inv_1", json!({
+            "chain": "BTC",
+            "currency": "BTC",
+            "transactions": [{ "tx": BTC_TX_HEX }]
+        })).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payment"]["transactions"][0]["tx"], BTC_TX_HEX);
+        assert!(body["memo"].is_string());
+        assert_eq!(body["payments"][0]["txid"], BTC_TXID);
+        assert_eq!(body["payments"][0]["status"], "pending");
+
+        let inserted = inserted.lock().unwrap();
+        assert_eq!(inserted.len(), 1);
+        assert_eq!(inserted[0]["invoice_uid"], "inv_1");
+        assert_eq!(inserted[0]["txid"], BTC_TXID);
+        assert_eq!(inserted[0]["amount"], 1000);
+        assert_eq!(inserted[0]["status"], "pending");
+    }
+
+    #[tokio::test]
+    async fn test_resubmitted_payment_is_idempotent() {
+        mock_chain_apis();
+        let inserted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let url = spawn_mock_supabase(mock_payments(inserted.clone()));
+        let submission = json!({
+            "chain": "BTC",
+            "currency": "BTC",
+            "transactions": [{ "tx": BTC_TX_HEX }]
+        });
+
+        let (_, first) = post_payment(router(&url), "inv_1", submission.clone()).await;
+        let (status, second) = post_payment(router(&url), "inv_1", submission).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(second["payments"][0]["id"], first["payments"][0]["id"]);
+        assert_eq!(second["payments"][0]["txid"], BTC_TXID);
+        assert_eq!(inserted.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_overpayment_records_the_amount_paid() {
+        mock_chain_apis();
+        let inserted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        // The transaction pays 1000 sats against an option asking for 600
+        let router = router(&spawn_mock_supabase(mock_payments_expecting(inserted.clone(), 600)));
+
+        let (status, _) = post_payment(router, "inv_1", json!({
+            "chain": "BTC",
+            "currency": "BTC",
+            "transactions": [{ "tx": BTC_TX_HEX }]
+        })).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(inserted.lock().unwrap()[0]["amount"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_underpaying_transaction_rejected() {
+        let inserted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        // The transaction only pays 1000 sats
+        let router = router(&spawn_mock_supabase(mock_payments_expecting(inserted.clone(), 5000)));
+
+        let (status, body) = post_payment(router, "inv_1", json!({
+            "chain": "BTC",
+            "currency": "BTC",
+            "transactions": [{ "tx": BTC_TX_HEX }]
+        })).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("does not pay 5000 BTC"));
+        assert!(inserted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_payment_for_unoffered_chain_rejected() {
+        let inserted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let router = router(&spawn_mock_supabase(mock_payments(inserted.clone())));
+
+        let (status, body) = post_payment(router, "inv_1", json!({
+            "chain": "ETH",
+            "currency": "ETH",
+            "transactions": [{ "tx": "0xdeadbeef", "txid": "0xabc" }]
+        })).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("does not accept ETH"));
+        assert!(inserted.lock().unwrap().is_empty());
+    }
+}
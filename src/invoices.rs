@@ -1,17 +1,31 @@
 use crate::supabase::SupabaseClient;
-use crate::types::{Invoice, PaymentOption};
+use crate::confirmations::Payment;
+use crate::types::{Invoice, InvoiceStatus, InvoiceSummary, PaymentOption, PaymentStatus};
 use serde_json::json;
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
+use std::future::Future;
 use crate::payment::generate_uid;
 
+/// Actor recorded for status changes the service makes on its own, such as marking a payment received
+pub const SYSTEM_ACTOR: &str = "system";
+/// Actor recorded for invoices expired by the sweeper
+pub const EXPIRY_ACTOR: &str = "system:expiry";
+
+/// Actor recorded for status changes requested by an account, such as a cancellation
+pub fn account_actor(account_id: i64) -> String {
+    format!("account:{}", account_id)
+}
+
 pub async fn create_invoice(
     supabase: &SupabaseClient,
-    amount: i64,
+    amount: Option<i64>,
     currency: &str,
     account_id: i32,
     webhook_url: Option<String>,
     redirect_url: Option<String>,
     memo: Option<String>,
+    accepted_currencies: Option<Vec<String>>,
+    crypto_denominated: bool,
 ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
     let now = Utc::now().to_rfc3339();
     let invoice_uid = format!("inv_{}", generate_uid());
@@ -21,7 +35,7 @@ pub async fn create_invoice(
         "amount": amount,
         "currency": currency,
         "account_id": account_id as i64,
-        "status": "unpaid",
+        "status": InvoiceStatus::Unpaid,
         "createdAt": now,
         "updatedAt": now,
         "payment_options": []
@@ -45,8 +59,209 @@ pub async fn create_invoice(
         account_id as i64,
         webhook_url,
         redirect_url,
-        memo
+        memo,
+        accepted_currencies,
+        None,
+        crypto_denominated,
+        None
     ).await?;
 
     Ok(response)
-} 
\ No newline at end of file
+} 
+/// How long an unpaid invoice stays open after its last payment option expires
+pub const EXPIRY_GRACE_PERIOD_MINUTES: i64 = 15;
+
+/// Payment options live for 15 minutes, so an invoice without any is judged from its creation time
+const PAYMENT_OPTION_LIFETIME_MINUTES: i64 = 15;
+
+/// An invoice is expired once it is unpaid and every payment option expired more than `grace` ago
+pub fn is_invoice_expired(
+    invoice: &Invoice,
+    payment_options: &[PaymentOption],
+    now: DateTime<Utc>,
+    grace: Duration,
+) -> bool {
+    if invoice.status != InvoiceStatus::Unpaid {
+        return false;
+    }
+
+    let parse = |timestamp: &str| DateTime::parse_from_rfc3339(timestamp)
+        .map(|t| t.with_timezone(&Utc))
+        .ok();
+
+    let last_expiry = if payment_options.is_empty() {
+        parse(&invoice.createdAt).map(|created| created + Duration::minutes(PAYMENT_OPTION_LIFETIME_MINUTES))
+    } else {
+        // Unparseable expiries count as already expired, matching is_payment_option_expired
+        Some(payment_options.iter()
+            .map(|option| parse(&option.expires).unwrap_or(DateTime::<Utc>::MIN_UTC))
+            .max()
+            .unwrap_or(DateTime::<Utc>::MIN_UTC))
+    };
+
+    match last_expiry {
+        Some(expiry) => expiry + grace < now,
+        None => false,
+    }
+}
+
+/// Mark each expired invoice via `mark_expired`, returning the uids that were expired
+pub async fn sweep_expired_invoices<F, Fut>(
+    invoices: Vec<(Invoice, Vec<PaymentOption>)>,
+    now: DateTime<Utc>,
+    grace: Duration,
+    mut mark_expired: F,
+) -> Vec<String>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut expired = Vec::new();
+
+    for (invoice, payment_options) in invoices {
+        if !is_invoice_expired(&invoice, &payment_options, now, grace) {
+            continue;
+        }
+
+        match mark_expired(invoice.uid.clone()).await {
+            Ok(()) => {
+                tracing::info!(event = "invoice.expired", uid = %invoice.uid, account_id = invoice.account_id, "Invoice expired");
+                expired.push(invoice.uid);
+            }
+            Err(e) => tracing::error!("Failed to expire invoice {}: {}", invoice.uid, e),
+        }
+    }
+
+    expired
+}
+
+/// Sum the confirmed payments on an invoice. Each payment is valued in the invoice currency
+/// at the rate locked into the payment option it paid, so partial payments in different
+/// coins add up. Donation invoices have no amount, and their options no rate, so aren't summarized.
+pub fn summarize_payments(invoice: &Invoice, payment_options: &[PaymentOption], payments: &[Payment]) -> Option<InvoiceSummary> {
+    let invoice_amount = invoice.amount?;
+    let total_received = payments.iter()
+        .filter(|payment| payment.status == PaymentStatus::Confirmed)
+        .filter_map(|payment| {
+            let option = payment_options.iter()
+                .find(|option| option.chain == payment.chain && option.currency == payment.currency && option.amount > 0)?;
+            let paid = payment.amount.unwrap_or(option.amount);
+            Some((invoice_amount as i128 * paid as i128 / option.amount as i128) as i64)
+        })
+        .sum::<i64>();
+
+    Some(InvoiceSummary {
+        total_expected: invoice_amount,
+        total_received,
+        outstanding: (invoice_amount - total_received).max(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn invoice(uid: &str, status: InvoiceStatus, created_at: DateTime<Utc>) -> Invoice {
+        Invoice {
+            id: 1,
+            uid: uid.to_string(),
+            amount: Some(1000),
+            currency: "USD".to_string(),
+            status,
+            account_id: 1,
+            complete: None,
+            webhook_url: None,
+            redirect_url: None,
+            memo: None,
+            uri: format!("pay:?r=https://api.anypayx.com/r/{}", uid),
+            createdAt: created_at.to_rfc3339(),
+            updatedAt: created_at.to_rfc3339(),
+            accepted_currencies: None,
+            required_fee_rate: None,
+            crypto_denominated: false,
+            op_return: None,
+            summary: None,
+        }
+    }
+
+    fn option(uid: &str, expires: DateTime<Utc>) -> PaymentOption {
+        PaymentOption {
+            invoice_uid: uid.to_string(),
+            currency: "BTC".to_string(),
+            chain: "BTC".to_string(),
+            amount: 1000,
+            address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+            outputs: vec![],
+            uri: String::new(),
+            fee: 0,
+            created_at: expires.to_rfc3339(),
+            updated_at: expires.to_rfc3339(),
+            expires: expires.to_rfc3339(),
+            derivation_index: None,
+            color: None,
+            op_return: None,
+        }
+    }
+
+    fn payment(id: i32, status: PaymentStatus, amount: i64) -> Payment {
+        Payment {
+            id,
+            txid: format!("tx{}", id),
+            chain: "BTC".to_string(),
+            currency: "BTC".to_string(),
+            status,
+            invoice_uid: "inv_partial".to_string(),
+            amount: Some(amount),
+            confirmation_hash: None,
+            confirmation_height: None,
+            confirmation_date: None,
+        }
+    }
+
+    #[test]
+    fn test_summary_with_two_partial_payments() {
+        let now = Utc::now();
+        let invoice = invoice("inv_partial", InvoiceStatus::Unpaid, now);
+        // The BTC option asks 1000 sats for the full 1000 cent invoice
+        let options = vec![option("inv_partial", now + Duration::minutes(15))];
+        let payments = vec![
+            payment(1, PaymentStatus::Confirmed, 250),
+            payment(2, PaymentStatus::Confirmed, 350),
+            // Unconfirmed payments don't count yet
+            payment(3, PaymentStatus::Pending, 400),
+        ];
+
+        let summary = summarize_payments(&invoice, &options, &payments);
+
+        assert_eq!(summary, Some(InvoiceSummary {
+            total_expected: 1000,
+            total_received: 600,
+            outstanding: 400,
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_expires_old_unpaid_invoice() {
+        let now = Utc::now();
+        let grace = Duration::minutes(EXPIRY_GRACE_PERIOD_MINUTES);
+        let old = now - Duration::hours(2);
+
+        let invoices = vec![
+            (invoice("inv_old", InvoiceStatus::Unpaid, old), vec![option("inv_old", old + Duration::minutes(15))]),
+            (invoice("inv_fresh", InvoiceStatus::Unpaid, now), vec![option("inv_fresh", now + Duration::minutes(15))]),
+            (invoice("inv_paid", InvoiceStatus::Paid, old), vec![option("inv_paid", old + Duration::minutes(15))]),
+            // Options expired, but still within the grace period
+            (invoice("inv_grace", InvoiceStatus::Unpaid, now - Duration::minutes(20)), vec![option("inv_grace", now - Duration::minutes(5))]),
+        ];
+
+        let marked = Mutex::new(Vec::new());
+        let expired = sweep_expired_invoices(invoices, now, grace, |uid| {
+            marked.lock().unwrap().push(uid);
+            async { Ok(()) }
+        }).await;
+
+        assert_eq!(expired, vec!["inv_old".to_string()]);
+        assert_eq!(*marked.lock().unwrap(), vec!["inv_old".to_string()]);
+    }
+}
@@ -1,5 +1,6 @@
 pub mod server;
 pub mod http;
+pub mod auth;
 pub mod session;
 pub mod event_dispatcher;
 pub mod supabase;
@@ -8,14 +9,163 @@ pub mod payment;
 pub mod payment_options;
 pub mod prices;
 pub mod invoices;
+#[cfg(all(feature = "eth", feature = "xrp"))]
 pub mod anypay_server;
 pub mod amqp;
+#[cfg(feature = "eth")]
 pub mod ethereum;
+#[cfg(feature = "xrp")]
 pub mod xrpl;
 pub mod uri;
 pub mod plugin;
+pub mod symbols;
 pub mod wallet;
 pub mod client;
 pub mod cards;
 pub mod blockbook;
-pub mod confirmations;
\ No newline at end of file
+pub mod confirmations;
+pub mod config;
+pub mod status;
+pub mod webhooks;
+pub mod rate_limit;
+pub mod error;
+#[cfg(test)]
+pub(crate) mod fixtures;
+
+use std::sync::Arc;
+use anyhow::anyhow;
+use event_dispatcher::EventDispatcher;
+use futures::Stream;
+use plugin::Plugin;
+use prices::{ConversionRequest, ConversionResult};
+use supabase::SupabaseClient;
+use types::{Invoice, InvoiceEvent, PaymentOption};
+
+pub use error::{Error, Result};
+
+/// Optional settings for an invoice created through [`Anypay::create_invoice`]
+#[derive(Debug, Clone, Default)]
+pub struct InvoiceOptions {
+    pub webhook_url: Option<String>,
+    pub redirect_url: Option<String>,
+    pub memo: Option<String>,
+    /// Restrict the invoice's payment options to these currencies
+    pub accepted_currencies: Option<Vec<String>>,
+    /// Minimum fee rate wallets must pay, overriding the coin's
+    pub required_fee_rate: Option<i64>,
+    /// Charge `amount` base units of `currency`, a crypto, instead of converting from fiat
+    pub crypto_denominated: bool,
+    /// Hex-encoded data payments on bitcoin-family chains must carry in an OP_RETURN output
+    pub op_return: Option<String>,
+}
+
+/// Entry point for using anypay as a library: creates invoices and converts prices
+/// against Supabase directly, without running the websocket or HTTP servers
+#[derive(Clone)]
+pub struct Anypay {
+    supabase: Arc<SupabaseClient>,
+    event_dispatcher: Arc<EventDispatcher>,
+}
+
+impl Anypay {
+    pub fn new(supabase_url: &str, anon_key: &str, service_role_key: &str) -> Self {
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let supabase = SupabaseClient::new(supabase_url, anon_key, service_role_key)
+            .with_event_dispatcher(event_dispatcher.clone());
+        Self { supabase: Arc::new(supabase), event_dispatcher }
+    }
+
+    /// Share a client with the rest of an application, e.g. one also passed to the servers.
+    /// Invoice events are only streamed when the client was built `with_event_dispatcher`.
+    pub fn with_supabase(supabase: Arc<SupabaseClient>) -> Self {
+        let event_dispatcher = supabase.event_dispatcher()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(EventDispatcher::new()));
+        Self { supabase, event_dispatcher }
+    }
+
+    pub fn supabase(&self) -> &SupabaseClient {
+        &self.supabase
+    }
+
+    /// Create an invoice for `amount` of `currency` along with its payment options
+    pub async fn create_invoice(
+        &self,
+        account_id: i64,
+        amount: i64,
+        currency: &str,
+        options: InvoiceOptions,
+    ) -> Result<(Invoice, Vec<PaymentOption>)> {
+        self.insert_invoice(account_id, Some(amount), currency, options).await
+    }
+
+    /// Create a "pay what you want" invoice in `currency`. Its payment options carry only an
+    /// address, and any positive amount paid to one is accepted.
+    pub async fn create_donation_invoice(
+        &self,
+        account_id: i64,
+        currency: &str,
+        options: InvoiceOptions,
+    ) -> Result<(Invoice, Vec<PaymentOption>)> {
+        self.insert_invoice(account_id, None, currency, options).await
+    }
+
+    async fn insert_invoice(
+        &self,
+        account_id: i64,
+        amount: Option<i64>,
+        currency: &str,
+        options: InvoiceOptions,
+    ) -> Result<(Invoice, Vec<PaymentOption>)> {
+        let created = self.supabase.create_invoice(
+            amount,
+            currency,
+            account_id,
+            options.webhook_url,
+            options.redirect_url,
+            options.memo,
+            options.accepted_currencies,
+            options.required_fee_rate,
+            options.crypto_denominated,
+            options.op_return,
+        ).await.map_err(Error::from_anyhow)?;
+
+        let invoice = serde_json::from_value(created["invoice"].clone())
+            .map_err(|e| anyhow!("Failed to parse created invoice: {}", e))?;
+        let payment_options = serde_json::from_value(created["payment_options"].clone())
+            .map_err(|e| anyhow!("Failed to parse payment options: {}", e))?;
+        Ok((invoice, payment_options))
+    }
+
+    /// Fetch an invoice and its payment options, refreshing any that have expired
+    pub async fn get_invoice(&self, uid: &str) -> Result<(Invoice, Vec<PaymentOption>)> {
+        self.supabase.get_invoice(uid, true).await
+            .map_err(Error::Db)?
+            .ok_or_else(|| Error::InvoiceNotFound(uid.to_string()))
+    }
+
+    /// Cancel an unpaid invoice, which must belong to `account_id`
+    pub async fn cancel_invoice(&self, uid: &str, account_id: i32) -> Result<()> {
+        self.supabase.cancel_invoice(uid, account_id).await
+    }
+
+    /// Convert `quote_value` of `quote_currency` into `base_currency`, at the base coin's precision
+    pub async fn convert(&self, quote_value: f64, quote_currency: &str, base_currency: &str) -> Result<ConversionResult> {
+        prices::convert(ConversionRequest {
+            quote_currency: quote_currency.to_string(),
+            base_currency: base_currency.to_string(),
+            quote_value,
+            precision: None,
+        }, &self.supabase).await
+    }
+
+    /// Stream the status changes of invoice `uid` made from now on
+    pub fn subscribe_invoice(&self, uid: &str) -> impl Stream<Item = InvoiceEvent> + Send + 'static {
+        self.event_dispatcher.subscribe_invoice(uid)
+    }
+
+    /// The plugin for a chain and currency, or None when it isn't supported by this build
+    pub fn plugin(&self, chain: &str, currency: &str) -> Option<Box<dyn Plugin>> {
+        plugin::get_plugin(chain, currency)
+    }
+}
\ No newline at end of file
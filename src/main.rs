@@ -4,6 +4,7 @@ mod types;
 mod server;
 mod supabase;
 mod http;
+mod auth;
 mod xrpl;
 mod amqp;
 mod payment_options;
@@ -15,17 +16,27 @@ mod ethereum;
 mod uri;
 mod blockbook;
 mod confirmations;
+mod plugin;
+mod symbols;
+mod status;
+mod webhooks;
+mod rate_limit;
+mod error;
+#[cfg(test)]
+mod fixtures;
 use std::sync::Arc;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 
 use dotenv::dotenv;
 use server::AnypayEventsServer;
 use axum::Server;
 use supabase::SupabaseClient;
 use amqp::AmqpClient;
+use event_dispatcher::EventDispatcher;
 use xrpl::XRPLClient;
 use config::Config;
 use ethereum::EthereumClient;
+use blockbook::BlockbookClient;
 use anyhow::Result;
 
 #[tokio::main]
@@ -37,26 +48,40 @@ async fn main() -> Result<()> {
 
     // Load configuration
     let config = Config::from_env()?;
+    config.validate()?;
+
+    // One dispatcher carries every event, from the HTTP and WebSocket servers alike
+    let event_dispatcher = Arc::new(EventDispatcher::new());
 
     // Initialize services
     let supabase = Arc::new(SupabaseClient::new(
         &config.supabase_url,
         &config.supabase_anon_key,
         &config.supabase_service_role_key
-    ));
-
-    // Initialize AMQP if configured
-    if let Some(amqp_url) = &config.amqp_url {
-        tracing::info!("Connecting to AMQP...");
-        let _amqp = AmqpClient::new(amqp_url).await?;
-        tracing::info!("✅ AMQP Connected");
-    }
+    )
+    .with_price_sources(config.price_sources())
+    .with_event_dispatcher(event_dispatcher.clone()));
+
+    // Initialize AMQP if configured; an unreachable broker is retried by the health check.
+    // The client lives until main returns, publishing the dispatcher's events.
+    let _amqp = match &config.amqp_url {
+        Some(amqp_url) => {
+            tracing::info!("Connecting to AMQP...");
+            let amqp = AmqpClient::connect(amqp_url, config.amqp_topology.clone()).await;
+            amqp.forward_events(event_dispatcher.subscribe_events());
+            tracing::info!("Publishing events to AMQP exchange {}", amqp.exchange());
+            Some(amqp)
+        }
+        None => None,
+    };
 
     // Initial price load
     supabase.refresh_prices().await.unwrap();
     
     // Start price updater
     SupabaseClient::start_price_updater(supabase.clone());
+    SupabaseClient::start_invoice_expiry_sweeper(supabase.clone());
+    status::start_block_watchdog(std::time::Duration::from_secs(60));
 
     // Initialize servers
     let ws_addr = format!("{}:{}", config.websocket_host, config.websocket_port);
@@ -65,14 +90,31 @@ async fn main() -> Result<()> {
         &config.supabase_url,
         &config.supabase_anon_key,
         &config.supabase_service_role_key,
-    );
+    )
+    .with_event_dispatcher(event_dispatcher.clone())
+    .with_backpressure(config.websocket_send_buffer, config.websocket_backpressure)
+    .with_max_subscriptions(config.websocket_max_subscriptions)
+    .with_rate_limit(config.rate_limit)
+    .with_price_sources(config.price_sources());
     
-    let http_server = http::HttpServer::new(supabase);
+    let mut http_server = http::HttpServer::new(supabase.clone())
+        .with_cors_allowed_origins(config.cors_allowed_origins.clone())
+        .with_max_body_bytes(config.http_max_body_bytes)
+        .with_network(config.payment_network.clone())
+        .with_admin_api_key(config.admin_api_key.clone())
+        .with_rate_limit(config.rate_limit);
+    if let (Some(blockbook_url), Some(api_key)) = (&config.blockbook_url, &config.blockbook_api_key) {
+        http_server = http_server.with_blockbook(
+            BlockbookClient::new(blockbook_url.clone(), api_key.clone(), supabase.as_ref().clone())
+        );
+    }
     let http_app = http_server.router();
-    let http_addr = SocketAddr::from(([127, 0, 0, 1], config.http_port));
+    let http_addr = (config.http_host.as_str(), config.http_port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("HTTP_HOST {} has no addresses", config.http_host))?;
 
     tracing::info!("Starting WebSocket server on ws://{}", ws_addr);
-    tracing::info!("Starting HTTP server on http://127.0.0.1:{}", config.http_port);
+    tracing::info!("Starting HTTP server on http://{}", http_addr);
 
 
     let eth_client = if let Ok(ws_url) = std::env::var("ETH_WSS_URL") {
@@ -80,8 +122,13 @@ async fn main() -> Result<()> {
         match EthereumClient::new("ETH", &ws_url).await {
             Ok(client) => {
                 tracing::info!("Connected to Ethereum node");
-                client.subscribe_blocks().await?;
-                Some(client)
+                match client.subscribe_blocks().await {
+                    Ok(()) => Some(client),
+                    Err(e) => {
+                        tracing::error!("Failed to subscribe to blocks: {}", e);
+                        None
+                    }
+                }
             }
             Err(e) => {
                 tracing::error!("Failed to connect to Ethereum node: {}", e);
@@ -97,8 +144,13 @@ async fn main() -> Result<()> {
         match EthereumClient::new("POLYGON", &ws_url).await {
             Ok(client) => {
                 tracing::info!("Connected to Polygon node");
-                client.subscribe_blocks().await?;
-                Some(client)
+                match client.subscribe_blocks().await {
+                    Ok(()) => Some(client),
+                    Err(e) => {
+                        tracing::error!("Failed to subscribe to blocks: {}", e);
+                        None
+                    }
+                }
             }
             Err(e) => {
                 tracing::error!("Failed to connect to Polygon node: {}", e);
@@ -114,8 +166,13 @@ async fn main() -> Result<()> {
         match EthereumClient::new("AVAX", &ws_url).await {
             Ok(client) => {
                 tracing::info!("Connected to Avalanche node");
-                client.subscribe_blocks().await?;
-                Some(client)
+                match client.subscribe_blocks().await {
+                    Ok(()) => Some(client),
+                    Err(e) => {
+                        tracing::error!("Failed to subscribe to blocks: {}", e);
+                        None
+                    }
+                }
             }
             Err(e) => {
                 tracing::error!("Failed to connect to Avalanche node: {}", e);
@@ -132,8 +189,13 @@ async fn main() -> Result<()> {
         match EthereumClient::new("BNB", &ws_url).await {
             Ok(client) => {
                 tracing::info!("Connected to Binance Smart Chain node");
-                client.subscribe_blocks().await?;
-                Some(client)
+                match client.subscribe_blocks().await {
+                    Ok(()) => Some(client),
+                    Err(e) => {
+                        tracing::error!("Failed to subscribe to blocks: {}", e);
+                        None
+                    }
+                }
             }
             Err(e) => {
                 tracing::error!("Failed to connect to Binance Smart Chain node: {}", e);
@@ -150,14 +212,18 @@ async fn main() -> Result<()> {
             let mut xrpl = XRPLClient::new();
             tokio::join!(
                 ws_server.run(),
-                Server::bind(&http_addr).serve(http_app.into_make_service()),
-                async move { xrpl.run_with_url(xrpl_url).await }
+                Server::bind(&http_addr).serve(http_app.into_make_service_with_connect_info::<SocketAddr>()),
+                async move {
+                    if let Err(e) = xrpl.run_with_url(xrpl_url).await {
+                        tracing::error!("XRPL client stopped: {}", e);
+                    }
+                }
             );
         }
         None => {
             tokio::join!(
                 ws_server.run(),
-                Server::bind(&http_addr).serve(http_app.into_make_service())
+                Server::bind(&http_addr).serve(http_app.into_make_service_with_connect_info::<SocketAddr>())
             );
         }
     }
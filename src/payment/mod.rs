@@ -1,8 +1,10 @@
 use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
-use shortid::next_short_64;
+use crate::confirmations::Payment;
+use crate::error::Error;
+use crate::plugin::{self, get_plugin, Transaction};
 use crate::supabase::SupabaseClient;
-use crate::types::{Account, Address};
+use crate::types::{Account, Address, AddressIndex};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coin {
@@ -45,6 +47,179 @@ pub struct Fee {
     pub address: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmittedTransaction {
+    pub tx: String,
+    /// Required for chains whose transaction id can't be derived from the raw hex
+    #[serde(default)]
+    pub txid: Option<String>,
+    /// Transaction key proving what an XMR transaction paid
+    #[serde(default)]
+    pub txkey: Option<String>,
+}
+
+/// Body of a payment submitted to /r/:uid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentSubmission {
+    pub chain: String,
+    pub currency: String,
+    pub transactions: Vec<SubmittedTransaction>,
+}
+
+/// Chains whose raw transactions use the bitcoin serialization
+pub const BITCOIN_FAMILY_CHAINS: &[&str] = &["BTC", "BCH", "BSV", "LTC", "DOGE", "DASH", "FB"];
+
+/// Use the submitted txid, or derive it from the raw hex for bitcoin-family chains
+pub fn transaction_id(chain: &str, transaction: &SubmittedTransaction) -> Result<String> {
+    if let Some(txid) = &transaction.txid {
+        return Ok(txid.clone());
+    }
+
+    if !BITCOIN_FAMILY_CHAINS.contains(&chain) {
+        return Err(anyhow!("A txid is required for {} transactions", chain));
+    }
+
+    let bytes = hex::decode(&transaction.tx)
+        .map_err(|e| anyhow!("Invalid transaction hex: {}", e))?;
+    let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&bytes)
+        .map_err(|e| anyhow!("Invalid {} transaction: {}", chain, e))?;
+
+    Ok(tx.txid().to_string())
+}
+
+/// Most data relayed in an OP_RETURN output under default policy
+pub const MAX_OP_RETURN_BYTES: usize = 80;
+
+/// Decode hex OP_RETURN data for an invoice, refusing more than is relayed
+pub fn decode_op_return(data: &str) -> Result<Vec<u8>> {
+    let bytes = hex::decode(data)
+        .map_err(|e| anyhow!("Invalid OP_RETURN data: {}", e))?;
+    if bytes.len() > MAX_OP_RETURN_BYTES {
+        return Err(anyhow!("OP_RETURN data is {} bytes; at most {} are relayed", bytes.len(), MAX_OP_RETURN_BYTES));
+    }
+    Ok(bytes)
+}
+
+/// Whether a bitcoin-family transaction has an output carrying `data` (hex) after OP_RETURN
+pub fn carries_op_return(txhex: &str, data: &str) -> Result<bool> {
+    let bytes = hex::decode(txhex)
+        .map_err(|e| anyhow!("Invalid transaction hex: {}", e))?;
+    let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&bytes)
+        .map_err(|e| anyhow!("Invalid transaction: {}", e))?;
+
+    let data = decode_op_return(data)?;
+    let push: &bitcoin::script::PushBytes = data.as_slice().try_into()
+        .map_err(|e| anyhow!("Failed to push OP_RETURN data: {}", e))?;
+    let expected = bitcoin::script::Builder::new()
+        .push_opcode(bitcoin::opcodes::all::OP_RETURN)
+        .push_slice(push)
+        .into_script();
+
+    Ok(tx.output.iter().any(|output| output.script_pubkey == expected))
+}
+
+/// Verify each submitted transaction against the invoice's payment option, broadcast it and
+/// record it as a pending payment. Resubmitting a transaction already recorded for the invoice
+/// returns the existing payment without broadcasting it again.
+pub async fn submit_payment(
+    supabase: &SupabaseClient,
+    invoice_uid: &str,
+    submission: &PaymentSubmission,
+) -> crate::error::Result<Vec<Payment>> {
+    if submission.transactions.is_empty() {
+        return Err(anyhow!("No transactions submitted").into());
+    }
+
+    let option = supabase.get_payment_option(invoice_uid, &submission.chain, &submission.currency).await
+        .map_err(Error::Db)?
+        .ok_or_else(|| anyhow!("Invoice {} does not accept {} on {}", invoice_uid, submission.currency, submission.chain))?;
+
+    let plugin = get_plugin(&submission.chain, &submission.currency)
+        .ok_or_else(|| Error::coin_not_found(&submission.chain, &submission.currency))?;
+    // Each output (the merchant's and any platform fee) must be paid in full
+    let expected_outputs = if option.outputs.is_empty() {
+        vec![(option.address.clone(), option.amount, None)]
+    } else {
+        option.outputs.iter().map(|output| (output.address.clone(), output.amount, output.script.clone())).collect()
+    };
+    let expected = expected_outputs.into_iter()
+        .map(|(address, amount, script)| plugin::PaymentOption {
+            chain: option.chain.clone(),
+            currency: option.currency.clone(),
+            address,
+            amount,
+            uri: Some(option.uri.clone()),
+            script,
+        })
+        .collect::<Vec<_>>();
+
+    // Verify every transaction before broadcasting any, so a bad one doesn't leave a partial submission
+    let mut payments: Vec<Payment> = Vec::new();
+    let mut unrecorded: Vec<(String, &SubmittedTransaction, i64)> = Vec::new();
+    for submitted in &submission.transactions {
+        let txid = transaction_id(&submission.chain, submitted)
+            .map_err(|e| Error::chain(&submission.chain, e))?;
+        if payments.iter().any(|payment| payment.txid == txid) || unrecorded.iter().any(|(pending, _, _)| *pending == txid) {
+            continue;
+        }
+
+        if let Some(existing) = supabase.get_invoice_payment(invoice_uid, &txid).await.map_err(Error::Db)? {
+            tracing::info!("Payment {} already recorded for invoice {}", txid, invoice_uid);
+            payments.push(existing);
+            continue;
+        }
+
+        let transaction = Transaction {
+            txhex: submitted.tx.clone(),
+            txid: Some(txid.clone()),
+            txkey: submitted.txkey.clone(),
+        };
+        for output in &expected {
+            let verified = plugin.verify_payment(output, &transaction).await
+                .map_err(|e| Error::chain(&submission.chain, e))?;
+            if !verified {
+                return Err(Error::chain(&submission.chain, format!("Transaction {} does not pay {} {} to {}", txid, output.amount, output.currency, output.address)));
+            }
+        }
+        if let Some(data) = &option.op_return {
+            let carried = carries_op_return(&submitted.tx, data)
+                .map_err(|e| Error::chain(&submission.chain, e))?;
+            if !carried {
+                return Err(Error::chain(&submission.chain, format!("Transaction {} does not carry the invoice's OP_RETURN data", txid)));
+            }
+        }
+
+        // Record what the transaction actually paid to the option's outputs, which may be more
+        // than asked for, and is all there is to go on for donations
+        let amount = plugin.parse_payments(&transaction).await
+            .map_err(|e| Error::chain(&submission.chain, e))?
+            .iter()
+            .filter(|payment| expected.iter().any(|output| output.address == payment.address))
+            .map(|payment| payment.amount)
+            .sum();
+        unrecorded.push((txid, submitted, amount));
+    }
+
+    for (txid, submitted, amount) in unrecorded {
+        let broadcast = plugin.broadcast_tx(&submitted.tx, Some(&txid), submitted.txkey.as_deref()).await
+            .map_err(|e| Error::chain(&submission.chain, format!("Failed to broadcast transaction {}: {}", txid, e)))?;
+        let txid = broadcast.txid.unwrap_or(txid);
+
+        let payment = supabase.create_payment(invoice_uid, &submission.chain, &submission.currency, &txid, amount).await
+            .map_err(Error::Db)?;
+        tracing::info!("Recorded pending payment {} for invoice {}", payment.txid, invoice_uid);
+        payments.push(payment);
+    }
+
+    if let Some(index) = option.derivation_index {
+        if let Err(e) = supabase.mark_address_index_used(invoice_uid, &submission.chain, index).await {
+            tracing::warn!("Failed to mark address index {} used for invoice {}: {}", index, invoice_uid, e);
+        }
+    }
+
+    Ok(payments)
+}
+
 pub async fn convert(from: ConversionRequest, to_currency: &str, precision: Option<i32>) -> Result<f64> {
     // TODO: Implement price conversion using an external service
     // For now, using more realistic mock rates
@@ -56,49 +231,142 @@ pub async fn convert(from: ConversionRequest, to_currency: &str, precision: Opti
         ("USD", "USDC") => 1.0,      // 1:1 for stablecoins
         _ => return Err(anyhow!("Unsupported currency pair: {} to {}", from.currency, to_currency))
     };
-    
-    Ok(from.value * rate)
+
+    let converted = from.value * rate;
+    match precision {
+        Some(precision) => crate::prices::round_value(converted, precision),
+        None => Ok(converted),
+    }
 }
 
-pub async fn get_new_address(req: GetAddressRequest) -> Result<String> {
-    // For now, returning a mock address with a 64-bit shortid
-    let id_bytes = next_short_64(0)?;
-    let id = id_bytes.iter()
-        .map(|val| format!("{:0>2x}", val))
-        .collect::<String>();
-    
-    Ok(req.address.value)
+/// Addresses a wallet scans past the last used one before it stops looking (BIP44)
+pub const ADDRESS_GAP_LIMIT: i64 = 20;
+
+const EXTENDED_PUBLIC_KEY_PREFIXES: &[&str] = &["xpub", "ypub", "zpub", "tpub", "upub", "vpub"];
+
+/// A receiving address for a payment option
+#[derive(Debug, Clone)]
+pub struct NewAddress {
+    pub address: String,
+    /// Set when the address was derived from the account's extended public key
+    pub derivation_index: Option<i64>,
 }
 
-pub async fn to_satoshis(req: ToSatoshisRequest, supabase: &SupabaseClient) -> Result<i64> {
-    let coin = supabase.get_coin(&req.currency, &req.chain).await
-        .map_err(|e| anyhow!("Failed to get coin: {}", e))?
-        .ok_or_else(|| anyhow!("Coin not found"))?;
-
-    // Get precision, defaulting to 8 for BTC/BSV, 18 for ETH, and 6 for stablecoins
-    let precision = match req.chain.as_str() {
-        "BTC" | "BSV" => 8,
-        "ETH" => 18,
-        _ => 6  // Default for stablecoins
+pub fn is_extended_public_key(value: &str) -> bool {
+    EXTENDED_PUBLIC_KEY_PREFIXES.iter().any(|prefix| value.starts_with(prefix))
+}
+
+/// Pick the index to derive next. Indexes only ever increase, so no two invoices share an
+/// address even when many go unpaid.
+pub fn next_derivation_index(state: &AddressIndex) -> i64 {
+    state.next_index
+}
+
+/// Whether `index` lies past `ADDRESS_GAP_LIMIT` unpaid addresses after the last paid one,
+/// where a wallet restored from the xpub with the default gap limit stops looking
+pub fn exceeds_gap_limit(state: &AddressIndex, index: i64) -> bool {
+    let first_unused = state.last_used_index.map_or(0, |used| used + 1);
+    index >= first_unused + ADDRESS_GAP_LIMIT
+}
+
+/// Return the configured address, or derive a fresh one when the account has an extended public
+/// key for the chain, either stored per account or configured as the address itself.
+/// Each derivation claims the next index for the account and chain.
+pub async fn get_new_address(req: GetAddressRequest, supabase: &SupabaseClient) -> crate::error::Result<NewAddress> {
+    let xpub = match supabase.get_account_xpub(req.account.id, &req.chain).await.map_err(Error::Db)? {
+        Some(xpub) => xpub,
+        None if is_extended_public_key(&req.address.value) => req.address.value.clone(),
+        None => {
+            return Ok(NewAddress {
+                address: req.address.value,
+                derivation_index: None,
+            });
+        }
     };
 
-    let satoshis = (req.decimal * 10f64.powi(precision)) as i64;
+    // Tokens are paid to the same addresses as their chain's native coin
+    let plugin = get_plugin(&req.chain, &req.chain)
+        .ok_or_else(|| Error::coin_not_found(&req.chain, &req.chain))?;
+
+    let state = supabase.claim_address_index(req.account.id, &req.chain).await.map_err(Error::Db)?;
+    let index = next_derivation_index(&state);
+    if exceeds_gap_limit(&state, index) {
+        tracing::warn!(
+            "Deriving {} address at index {} for account {}, beyond the gap limit of {} unpaid addresses; \
+             the account's wallet needs a larger gap limit to see payments to it",
+            req.chain, index, req.account.id, ADDRESS_GAP_LIMIT
+        );
+    }
+    let child = u32::try_from(index)
+        .map_err(|_| Error::chain(&req.chain, format!("Derivation index {} is out of range", index)))?;
+    let address = plugin.derive_address(&xpub, child)
+        .map_err(|e| Error::chain(&req.chain, e))?;
+    tracing::info!("Derived {} address {} at index {} for account {}", req.chain, address, index, req.account.id);
+
+    Ok(NewAddress {
+        address,
+        derivation_index: Some(index),
+    })
+}
+
+pub async fn to_satoshis(req: ToSatoshisRequest, supabase: &SupabaseClient) -> crate::error::Result<i64> {
+    let coin = supabase.get_coin(&req.currency, &req.chain).await
+        .map_err(Error::Db)?
+        .ok_or_else(|| Error::coin_not_found(&req.chain, &req.currency))?;
+
+    let satoshis = (req.decimal * 10f64.powi(base_unit_decimals(&coin)?)) as i64;
     Ok(satoshis)
 }
 
-pub async fn get_fee(currency: &str, amount: i64) -> Result<Fee> {
+/// Decimal places of a coin's base unit. Tokens use their configured precision, since it
+/// differs per contract (6 for USDC, 18 for most others); native coins use their chain's.
+pub fn base_unit_decimals(coin: &crate::types::Coin) -> crate::error::Result<i32> {
+    if coin.contract_address.is_some() {
+        return coin.precision
+            .ok_or_else(|| Error::chain(&coin.chain, format!("Token {} has no precision configured", coin.currency)));
+    }
+
+    match coin.chain.as_str() {
+        "BTC" | "BSV" | "BCH" | "LTC" | "DOGE" | "DASH" | "ZEC" | "FB" => Ok(8),
+        "ETH" | "AVAX" | "BNB" | "MATIC" => Ok(18),
+        "SOL" => Ok(9),
+        "XRP" => Ok(6),
+        "XMR" => Ok(12),
+        chain => Err(Error::chain(chain, format!("Unknown base unit for {}", coin.currency))),
+    }
+}
+
+/// Decimal amount of a coin for `amount` base units, the inverse of `to_satoshis`
+pub fn from_satoshis(amount: i64, coin: &crate::types::Coin) -> crate::error::Result<f64> {
+    Ok(amount as f64 / 10f64.powi(base_unit_decimals(coin)?))
+}
+
+/// Address platform fees on `chain` are paid to, set with FEE_ADDRESS_<CHAIN> (e.g. FEE_ADDRESS_BTC)
+pub fn fee_address(chain: &str) -> Option<String> {
+    std::env::var(format!("FEE_ADDRESS_{}", chain.to_uppercase()))
+        .ok()
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+}
+
+/// Platform fee on a payment of `amount` base units, paid to `address`
+pub fn compute_fee(currency: &str, amount: i64, address: String) -> Fee {
     // Calculate fee based on currency
     let fee_rate = match currency {
         "BTC" | "BSV" => 0.0001,  // 0.01%
         "ETH" | "MATIC" => 0.001, // 0.1%
         _ => 0.001                // Default 0.1%
     };
-    
-    let fee_amount = (amount as f64 * fee_rate) as i64;
-    Ok(Fee {
-        amount: fee_amount,
-        address: "fee_address_mock".to_string(),
-    })
+
+    Fee {
+        amount: (amount as f64 * fee_rate) as i64,
+        address,
+    }
+}
+
+/// Platform fee on a payment, or None when no fee address is configured for the chain
+pub async fn get_fee(chain: &str, currency: &str, amount: i64) -> Result<Option<Fee>> {
+    Ok(fee_address(chain).map(|address| compute_fee(currency, amount, address)))
 }
 
 pub fn compute_invoice_uri(req: ComputeUriRequest) -> String {
@@ -108,4 +376,157 @@ pub fn compute_invoice_uri(req: ComputeUriRequest) -> String {
 // Helper function to generate IDs
 pub fn generate_uid() -> String {
     nanoid::nanoid!(12)  // 21 chars like in the JS version
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Json, Router};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use crate::supabase::tests::{mock_claim_address_index, spawn_mock_supabase};
+
+    /// BIP32 test vector 1 master public key
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    /// An account with no stored xpubs, claiming indexes from a counter like the database function
+    fn mock_address_indexes(next_index: Arc<Mutex<i64>>) -> Router {
+        mock_claim_address_index(next_index)
+            .route("/rest/v1/account_xpubs", get(|| async { Json(json!([])) }))
+    }
+
+    fn address_request(value: &str) -> GetAddressRequest {
+        GetAddressRequest {
+            account: Account { id: 7, denomination: None, base_url: None },
+            address: Address {
+                chain: "BTC".to_string(),
+                currency: "BTC".to_string(),
+                value: value.to_string(),
+            },
+            currency: "BTC".to_string(),
+            chain: "BTC".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_new_address_derives_a_fresh_address_per_call() {
+        let next_index = Arc::new(Mutex::new(0));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_address_indexes(next_index.clone())), "anon", "service");
+
+        let first = get_new_address(address_request(XPUB), &supabase).await.unwrap();
+        let second = get_new_address(address_request(XPUB), &supabase).await.unwrap();
+
+        assert_eq!(first.address, "bc1qp5wfcq48h6d63wyy9qz0awtpfqwwv4sma86mhz");
+        assert_eq!(first.derivation_index, Some(0));
+        assert_eq!(second.address, "bc1qrfxr69jqnhwufxgkqgcdep9prq4j4vuw2wyg0v");
+        assert_eq!(second.derivation_index, Some(1));
+        assert_eq!(*next_index.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_new_address_returns_a_static_address_unchanged() {
+        let next_index = Arc::new(Mutex::new(0));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_address_indexes(next_index.clone())), "anon", "service");
+
+        let address = get_new_address(address_request("bc1qp5wfcq48h6d63wyy9qz0awtpfqwwv4sma86mhz"), &supabase).await.unwrap();
+
+        assert_eq!(address.address, "bc1qp5wfcq48h6d63wyy9qz0awtpfqwwv4sma86mhz");
+        assert_eq!(address.derivation_index, None);
+        assert_eq!(*next_index.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_new_address_without_plugin_is_coin_not_found() {
+        let next_index = Arc::new(Mutex::new(0));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_address_indexes(next_index.clone())), "anon", "service");
+        let mut req = address_request(XPUB);
+        req.chain = "LTC".to_string();
+        req.currency = "LTC".to_string();
+
+        match get_new_address(req, &supabase).await {
+            Err(Error::CoinNotFound { chain, currency }) => assert_eq!((chain.as_str(), currency.as_str()), ("LTC", "LTC")),
+            other => panic!("expected CoinNotFound, got {:?}", other.map(|address| address.address)),
+        }
+        assert_eq!(*next_index.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_next_derivation_index_keeps_increasing() {
+        let state = |next_index, last_used_index| AddressIndex {
+            account_id: 7,
+            chain: "BTC".to_string(),
+            next_index,
+            last_used_index,
+        };
+
+        assert_eq!(next_derivation_index(&state(0, None)), 0);
+        assert_eq!(next_derivation_index(&state(19, None)), 19);
+        // Unpaid addresses are never handed out again
+        assert_eq!(next_derivation_index(&state(20, None)), 20);
+        assert_eq!(next_derivation_index(&state(25, Some(4))), 25);
+
+        assert!(!exceeds_gap_limit(&state(19, None), 19));
+        assert!(exceeds_gap_limit(&state(20, None), 20));
+        // A payment moves the window forward
+        assert!(!exceeds_gap_limit(&state(21, Some(4)), 21));
+        assert!(exceeds_gap_limit(&state(25, Some(4)), 25));
+    }
+
+    #[test]
+    fn test_carries_op_return() {
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version(2),
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![bitcoin::TxOut {
+                value: bitcoin::Amount::ZERO,
+                script_pubkey: bitcoin::script::Builder::new()
+                    .push_opcode(bitcoin::opcodes::all::OP_RETURN)
+                    .push_slice(b"anypay")
+                    .into_script(),
+            }],
+        };
+        let txhex = bitcoin::consensus::encode::serialize_hex(&tx);
+
+        assert!(carries_op_return(&txhex, &hex::encode(b"anypay")).unwrap());
+        assert!(!carries_op_return(&txhex, &hex::encode(b"other")).unwrap());
+        assert!(decode_op_return(&"00".repeat(MAX_OP_RETURN_BYTES + 1)).is_err());
+        assert!(decode_op_return("not hex").is_err());
+    }
+
+    #[test]
+    fn test_base_unit_decimals() {
+        let coin = |currency: &str, chain: &str, precision: i32, contract_address: Option<&str>| -> crate::types::Coin {
+            serde_json::from_value(json!({
+                "id": 1,
+                "currency": currency,
+                "chain": chain,
+                "precision": precision,
+                "uri_template": null,
+                "createdAt": "2024-01-01T00:00:00Z",
+                "updatedAt": "2024-01-01T00:00:00Z",
+                "required_fee_rate": null,
+                "color": null,
+                "contract_address": contract_address
+            })).unwrap()
+        };
+
+        for (chain, decimals) in [("BTC", 8), ("LTC", 8), ("DOGE", 8), ("BCH", 8), ("DASH", 8), ("ETH", 18), ("SOL", 9)] {
+            assert_eq!(base_unit_decimals(&coin(chain, chain, 2, None)).unwrap(), decimals, "{}", chain);
+        }
+        let usdc = coin("USDC", "ETH", 6, Some("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48"));
+        assert_eq!(base_unit_decimals(&usdc).unwrap(), 6);
+        assert_eq!(from_satoshis(1_500_000, &usdc).unwrap(), 1.5);
+
+        assert!(matches!(base_unit_decimals(&coin("ABC", "ABC", 8, None)), Err(Error::Chain { .. })));
+    }
+
+    #[test]
+    #[cfg(feature = "eth")]
+    fn test_derive_evm_address() {
+        let plugin = get_plugin("ETH", "ETH").unwrap();
+
+        assert_eq!(plugin.derive_address(XPUB, 0).unwrap(), "0x4B7115aD9623A528f1845eaf85D166dE1E869BFB");
+        assert_eq!(plugin.derive_address(XPUB, 1).unwrap(), "0xEb5A8aE75e395Ef05c96839a3FB088B2f65E7662");
+    }
+}
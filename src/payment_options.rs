@@ -1,10 +1,10 @@
 use serde::{Deserialize, Serialize};
 use anyhow::{Result, anyhow};
-use crate::types::{Invoice, PaymentOption, Output, Account, Address};
+use crate::types::{Invoice, PaymentOption, Output, Account, Address, Coin};
 use crate::payment::{
-    convert, get_fee, get_new_address, to_satoshis, ConversionRequest, GetAddressRequest, ToSatoshisRequest
+    self, convert, from_satoshis, get_fee, get_new_address, to_satoshis, ConversionRequest, GetAddressRequest, ToSatoshisRequest
 };
-use crate::uri::{compute_invoice_uri, InvoiceUriParams};
+use crate::uri::{compute_payment_uri, get_base_url, InvoiceUriParams};
 use crate::supabase::SupabaseClient;
 use futures::future::join_all;
 use chrono::{Duration, Utc};
@@ -15,6 +15,76 @@ pub struct Fee {
     pub address: String,
 }
 
+/// Getting the address for an option failed, e.g. looking up the account's xpub or claiming an
+/// index. Unlike an unpriced currency this fails the invoice, which would otherwise be created
+/// without options the account configured.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to get a {chain} address: {source}")]
+struct AddressError {
+    chain: String,
+    source: crate::error::Error,
+}
+
+/// Smallest amount in base units a network will relay to an address, used when a coin has
+/// no min_amount configured
+pub fn default_minimum_amount(chain: &str) -> i64 {
+    match chain {
+        "BTC" | "BCH" | "FB" => 546,
+        "LTC" | "DASH" => 5_460,
+        "DOGE" => 1_000_000,
+        _ => 1,
+    }
+}
+
+/// Smallest amount a payment option for `coin` may ask for
+pub fn minimum_amount(coin: &Coin) -> i64 {
+    coin.min_amount.unwrap_or_else(|| default_minimum_amount(&coin.chain))
+}
+
+/// Split a payment of `amount` into outputs: the platform fee gets its own output and the
+/// merchant receives the rest. Fees below `minimum` are waived since the network wouldn't relay
+/// them. Returns the outputs and the fee actually charged.
+pub fn build_outputs(address: &str, amount: i64, fee: Option<&payment::Fee>, minimum: i64) -> (Vec<Output>, i64) {
+    match fee {
+        Some(fee) if fee.amount >= minimum && fee.amount > 0 && amount - fee.amount >= minimum => (
+            vec![
+                Output { address: address.to_string(), amount: amount - fee.amount, script: None },
+                Output { address: fee.address.clone(), amount: fee.amount, script: None },
+            ],
+            fee.amount,
+        ),
+        _ => (vec![Output { address: address.to_string(), amount, script: None }], 0),
+    }
+}
+
+/// Currencies listed first in responses, in this order; the rest follow alphabetically
+pub const CURRENCY_PRIORITY: &[&str] = &["BTC", "BCH", "ETH", "BSV", "LTC", "DOGE", "DASH", "XRP", "SOL", "USDC", "USDT"];
+
+/// Order options by currency priority, then currency and chain, so responses are stable
+pub fn sort_payment_options(options: &mut [PaymentOption]) {
+    options.sort_by(|a, b| {
+        let priority = |option: &PaymentOption| CURRENCY_PRIORITY.iter()
+            .position(|currency| *currency == option.currency)
+            .unwrap_or(CURRENCY_PRIORITY.len());
+        priority(a).cmp(&priority(b))
+            .then_with(|| a.currency.cmp(&b.currency))
+            .then_with(|| a.chain.cmp(&b.chain))
+    });
+}
+
+/// Whether an invoice can be paid in `currency`: only its own currency when it is fixed in a crypto,
+/// otherwise per its optional allow-list
+pub fn accepts_currency(invoice: &Invoice, currency: &str) -> bool {
+    if invoice.crypto_denominated {
+        return invoice.currency.eq_ignore_ascii_case(currency);
+    }
+
+    match &invoice.accepted_currencies {
+        Some(accepted) => accepted.iter().any(|accepted| accepted.eq_ignore_ascii_case(currency)),
+        None => true,
+    }
+}
+
 pub async fn create_payment_options(
     account: &Account,
     invoice: &Invoice,
@@ -24,6 +94,9 @@ pub async fn create_payment_options(
 
     let addresses = supabase.list_available_addresses(account).await.map_err(|e| anyhow!("Failed to list addresses: {}", e))?;
     tracing::info!("Listed available addresses: {:?}", addresses);
+    let addresses = addresses.into_iter()
+        .filter(|address| accepts_currency(invoice, &address.currency))
+        .collect::<Vec<_>>();
 
     let mut payment_options = Vec::new();
 
@@ -44,8 +117,13 @@ pub async fn create_payment_options(
                 &currency,
                 &supabase,
             ).await {
-                Ok(Some(option)) => Some(option),
-                _ => None
+                Ok(option) => Ok(option),
+                Err(e) if e.is::<AddressError>() => Err(e),
+                // A currency that can't be priced is left out rather than failing the invoice
+                Err(e) => {
+                    tracing::warn!("Skipping {} on {} for invoice {}: {}", currency, chain, invoice.uid, e);
+                    Ok(None)
+                }
             }
         }
     });
@@ -55,106 +133,163 @@ pub async fn create_payment_options(
     
     // Filter out None values and collect into payment_options
     for result in results {
-        if let Some(option) = result {
+        if let Some(option) = result? {
             payment_options.push(option);
         }
     }
 
     // Create all payment options in the database
     if !payment_options.is_empty() {
-        let inserted_options = supabase.create_payment_options(&payment_options).await.map_err(|e| anyhow!("Failed to create payment options: {}", e))?;
+        let mut inserted_options = supabase.create_payment_options(&payment_options).await.map_err(|e| anyhow!("Failed to create payment options: {}", e))?;
+        sort_payment_options(&mut inserted_options);
         return Ok(inserted_options);
     }
 
     Ok(Vec::new())
 }
 
-async fn build_payment_option(
-    account: &Account,
-    invoice: &Invoice,
-    address_record: &Address,
-    chain: &str,
-    currency: &str,
-    supabase: &SupabaseClient,
-) -> Result<Option<PaymentOption>> {
-    // Get coin info for precision
-    let coin = supabase.get_coin(currency, chain).await.map_err(|e| anyhow!("Failed to get coin: {}", e))?.ok_or_else(|| anyhow!("Coin not found"))?;
+/// Scannable URI paying `amount` whole coins to `address`, preferring the coin's own template.
+/// Without an amount the URI carries only the address, for the payer to choose how much to send.
+fn payment_uri(coin: &Coin, invoice: &Invoice, account: &Account, address: &str, amount: Option<f64>, outputs: &[Output]) -> String {
+    compute_payment_uri(
+        coin.uri_template.as_deref(),
+        &InvoiceUriParams {
+            currency: coin.currency.clone(),
+            uid: invoice.uid.clone(),
+            memo: invoice.memo.clone(),
+            base_url: get_base_url(account.base_url.as_deref()),
+        },
+        address,
+        amount,
+        outputs.len(),
+    )
+}
+
+/// Amount an option in `coin` asks for, as a decimal and in base units. Crypto-denominated
+/// invoices are charged exactly their amount; the rest are converted from the account's denomination.
+/// Donation invoices have no amount, so their options don't ask for one.
+async fn option_amount(invoice: &Invoice, account: &Account, coin: &Coin, supabase: &SupabaseClient) -> Result<Option<(f64, i64)>> {
+    let Some(invoice_amount) = invoice.amount else {
+        return Ok(None);
+    };
+
+    if invoice.crypto_denominated {
+        return Ok(Some((from_satoshis(invoice_amount, coin)?, invoice_amount)));
+    }
 
-    println!("coin: {:?}", coin);
     // Convert invoice amount to payment currency
     let account_denomination = account.denomination.as_deref().unwrap_or("USD");
-    println!("account_denomination: {:?}", account_denomination);
 
     let conversion_request = crate::prices::ConversionRequest {
         quote_currency: account_denomination.to_string(),
-        base_currency: currency.to_string(),
-        quote_value: invoice.amount as f64,
+        base_currency: coin.currency.to_string(),
+        quote_value: invoice_amount as f64,
+        precision: coin.precision,
     };
 
-    println!("conversion_request: {:?}", conversion_request);
-
     let conversion = crate::prices::convert(
         conversion_request,
         supabase,
     ).await?;
 
     let amount = conversion.base_value;
-    println!("amount: {:?}", amount);
 
     tracing::info!(
-        "Converting {} {} to {} {}: {}",
-        invoice.amount,
+        "Converting {} {} to {} {}",
+        invoice_amount,
         account_denomination,
         amount,
-        currency,
-        amount
+        coin.currency
     );
 
-    // Get payment address
-    let mut address = get_new_address(GetAddressRequest {
-        account: account.clone(),
-        address: address_record.clone(),
-        currency: currency.to_string(),
-        chain: chain.to_string(),
-    }).await?;
-
-    // Clean up address if needed
-    if address.contains(':') {
-        address = address.split(':').nth(1).unwrap_or(&address).to_string();
-    }
-
     // Convert to smallest unit (satoshis/wei/etc)
     let payment_amount = to_satoshis(ToSatoshisRequest {
         decimal: amount,
-        currency: currency.to_string(),
-        chain: chain.to_string(),
+        currency: coin.currency.to_string(),
+        chain: coin.chain.to_string(),
     }, supabase).await?;
 
     tracing::info!(
         "Converted {} {} to {} satoshis",
         amount,
-        currency,
+        coin.currency,
         payment_amount
     );
 
-    // Calculate fee and outputs
-    let fee = get_fee(currency, payment_amount).await?;
-    let mut outputs = Vec::new();
+    Ok(Some((amount, payment_amount)))
+}
 
-    // Single output for all chains
-    outputs.push(Output {
-        address: address.clone(),
-        amount: payment_amount,
-    });
+async fn build_payment_option(
+    account: &Account,
+    invoice: &Invoice,
+    address_record: &Address,
+    chain: &str,
+    currency: &str,
+    supabase: &SupabaseClient,
+) -> Result<Option<PaymentOption>> {
+    // Get coin info for precision
+    let coin = supabase.get_coin(currency, chain).await.map_err(|e| anyhow!("Failed to get coin: {}", e))?.ok_or_else(|| anyhow!("Coin not found"))?;
 
-    // Compute payment URI
-    let uri = compute_invoice_uri(&InvoiceUriParams {
+    let amounts = option_amount(invoice, account, &coin, supabase).await?;
+
+    // A zero price converts to nothing, and an option asking for nothing would be paid by any transaction
+    if let Some((_, payment_amount)) = amounts.filter(|(_, payment_amount)| *payment_amount <= 0) {
+        tracing::warn!(
+            "Skipping {} on {} for invoice {}: {} {} converted to {} base units, check the {} price",
+            currency,
+            chain,
+            invoice.uid,
+            invoice.amount.unwrap_or_default(),
+            account.denomination.as_deref().unwrap_or("USD"),
+            payment_amount,
+            currency
+        );
+        return Ok(None);
+    }
+
+    // Get payment address
+    let new_address = get_new_address(GetAddressRequest {
+        account: account.clone(),
+        address: address_record.clone(),
         currency: currency.to_string(),
-        uid: invoice.uid.clone(),
-    });
+        chain: chain.to_string(),
+    }, supabase).await.map_err(|source| AddressError { chain: chain.to_string(), source })?;
+    let mut address = new_address.address;
+
+    // Clean up address if needed
+    if address.contains(':') {
+        address = address.split(':').nth(1).unwrap_or(&address).to_string();
+    }
+
+    // Donations ask for no amount, so there is no minimum to check or fee to split off,
+    // and the single output accepts whatever the payer sends
+    let (outputs, fee_amount, total_amount) = match amounts {
+        Some((_, payment_amount)) => {
+            // Skip currencies where the invoice is too small to be paid on-chain
+            let minimum = minimum_amount(&coin);
+            if payment_amount < minimum {
+                tracing::info!(
+                    "Skipping {} on {} for invoice {}: {} is below the minimum of {}",
+                    currency,
+                    chain,
+                    invoice.uid,
+                    payment_amount,
+                    minimum
+                );
+                return Ok(None);
+            }
+
+            // Calculate fee and outputs
+            let fee = get_fee(chain, currency, payment_amount).await?;
+            let (outputs, fee_amount) = build_outputs(&address, payment_amount, fee.as_ref(), minimum);
+
+            // The customer pays the invoice value; the fee comes out of the merchant's share
+            (outputs, fee_amount, payment_amount)
+        }
+        None => (vec![Output { address: address.clone(), amount: 0, script: None }], 0, 0),
+    };
 
-    // Total amount is just the payment amount
-    let total_amount = payment_amount;
+    let uri = payment_uri(&coin, invoice, account, &address, amounts.map(|(amount, _)| amount), &outputs);
 
     // Create payment option
     let now = Utc::now();
@@ -167,10 +302,13 @@ async fn build_payment_option(
         address,
         outputs,
         uri,
-        fee: fee.amount,
+        fee: fee_amount,
         created_at: now.to_rfc3339(),
         updated_at: now.to_rfc3339(),
         expires: expires_at.to_rfc3339(),
+        derivation_index: new_address.derivation_index,
+        color: coin.color.clone(),
+        op_return: invoice.op_return.clone().filter(|_| payment::BITCOIN_FAMILY_CHAINS.contains(&chain)),
     };
 
     Ok(Some(payment_option))
@@ -187,37 +325,26 @@ pub async fn refresh_payment_option(
         .await.map_err(|e| anyhow!("Failed to get coin: {}", e))?
         .ok_or_else(|| anyhow!("Coin not found"))?;
 
-    // Convert invoice amount to payment currency
-    let account_denomination = account.denomination.as_deref().unwrap_or("USD");
-
-    let conversion_request = crate::prices::ConversionRequest {
-        quote_currency: account_denomination.to_string(),
-        base_currency: payment_option.currency.to_string(),
-        quote_value: invoice.amount as f64,
+    let amounts = option_amount(invoice, account, &coin, supabase).await?;
+    let (outputs, fee_amount, payment_amount) = match amounts {
+        Some((_, payment_amount)) if payment_amount <= 0 => {
+            return Err(anyhow!(
+                "Refreshing {} on {} for invoice {} converted to {} base units",
+                payment_option.currency, payment_option.chain, invoice.uid, payment_amount
+            ));
+        }
+        Some((_, payment_amount)) => {
+            // Calculate fee and outputs
+            let fee = get_fee(&payment_option.chain, &payment_option.currency, payment_amount).await?;
+            let (outputs, fee_amount) = build_outputs(&payment_option.address, payment_amount, fee.as_ref(), minimum_amount(&coin));
+            (outputs, fee_amount, payment_amount)
+        }
+        // Donation options only need a new expiry
+        None => (payment_option.outputs.clone(), 0, 0),
     };
 
-    let conversion = crate::prices::convert(
-        conversion_request,
-        supabase,
-    ).await?;
-
-    let amount = conversion.base_value;
-
-    // Convert to smallest unit (satoshis/wei/etc)
-    let payment_amount = to_satoshis(ToSatoshisRequest {
-        decimal: amount,
-        currency: payment_option.currency.to_string(),
-        chain: payment_option.chain.to_string(),
-    }, supabase).await?;
-
-    // Calculate fee
-    let fee = get_fee(&payment_option.currency, payment_amount).await?;
-
-    // Create single output with new amount
-    let outputs = vec![Output {
-        address: payment_option.address.clone(),
-        amount: payment_amount,
-    }];
+    // The amount is part of BIP21 URIs, so they change with the rate
+    let uri = payment_uri(&coin, invoice, account, &payment_option.address, amounts.map(|(amount, _)| amount), &outputs);
 
     // Create updated payment option
     let now = Utc::now();
@@ -229,11 +356,14 @@ pub async fn refresh_payment_option(
         amount: payment_amount,
         address: payment_option.address.clone(),
         outputs,
-        uri: payment_option.uri.clone(),
-        fee: fee.amount,
+        uri,
+        fee: fee_amount,
         created_at: payment_option.created_at.clone(),
         updated_at: now.to_rfc3339(),
         expires: expires_at.to_rfc3339(),
+        derivation_index: payment_option.derivation_index,
+        color: coin.color.clone(),
+        op_return: payment_option.op_return.clone(),
     };
 
     Ok(updated)
@@ -276,5 +406,404 @@ pub async fn update_expired_payment_options(
             .await.map_err(|e| anyhow!("Failed to update payment options: {}", e))?;
     }
 
+    sort_payment_options(&mut updated_options);
     Ok(updated_options)
 } 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::InvoiceStatus;
+    use axum::{extract::Query, http::StatusCode, routing::{get, post}, Json, Router};
+    use serde_json::json;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use crate::fixtures::{coin_row, usd_price};
+    use crate::supabase::tests::{mock_claim_address_index, spawn_mock_supabase};
+
+    /// Circle's USDC token contract on Ethereum mainnet
+    const USDC_CONTRACT: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+
+    fn usdc_row() -> serde_json::Value {
+        let mut row = coin_row(5, "ETH", None);
+        row["currency"] = json!("USDC");
+        row["precision"] = json!(6);
+        row["contract_address"] = json!(USDC_CONTRACT);
+        row
+    }
+
+    fn address_row(currency: &str, value: &str) -> serde_json::Value {
+        json!({ "chain": currency, "currency": currency, "value": value })
+    }
+
+    /// An account accepting BTC (with a configured 10,000 sat minimum) and BSV, at $50,000/BTC and $50/BSV
+    fn mock_supabase() -> Router {
+        mock_supabase_with_xpubs(json!([]), Arc::new(Mutex::new(0)))
+    }
+
+    /// Like `mock_supabase`, with stored xpubs and address indexes claimed from `next_index`
+    fn mock_supabase_with_xpubs(xpubs: serde_json::Value, next_index: Arc<Mutex<i64>>) -> Router {
+        let addresses = json!([
+            address_row("BTC", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            address_row("BSV", "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")
+        ]);
+        mock_supabase_for(addresses, xpubs, next_index)
+    }
+
+    /// An account with the given addresses and xpubs. Coins and prices cover BTC, BSV, ETH and XRP
+    /// (plus USDC on ETH, without a price), the same in every mock since the coin cache is shared
+    /// between tests.
+    fn mock_supabase_for(addresses: serde_json::Value, xpubs: serde_json::Value, next_index: Arc<Mutex<i64>>) -> Router {
+        mock_supabase_priced(addresses, xpubs, next_index, usd_price)
+    }
+
+    /// Like `mock_supabase_for`, with prices from `price` instead of `usd_price`
+    fn mock_supabase_priced(
+        addresses: serde_json::Value,
+        xpubs: serde_json::Value,
+        next_index: Arc<Mutex<i64>>,
+        price: fn(&str) -> Option<f64>,
+    ) -> Router {
+        Router::new()
+            .route("/rest/v1/account_xpubs", get(move |Query(params): Query<HashMap<String, String>>| async move {
+                let rows = xpubs.as_array().unwrap().iter()
+                    .filter(|row| params.get("chain") == Some(&format!("eq.{}", row["chain"].as_str().unwrap())))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                Json(rows)
+            }))
+            .merge(mock_claim_address_index(next_index))
+            .route("/rest/v1/addresses", get(move || async move { Json(addresses) }))
+            .route("/rest/v1/coins", get(|| async {
+                Json(json!([coin_row(1, "BTC", Some(10_000)), coin_row(2, "BSV", None), coin_row(3, "ETH", None), coin_row(4, "XRP", None), usdc_row()]))
+            }))
+            .route("/rest/v1/prices", get(move |Query(params): Query<HashMap<String, String>>| async move {
+                let base_currency = params.get("base_currency").and_then(|base| base.strip_prefix("eq."));
+                let Some(value) = base_currency.and_then(price) else {
+                    return Json(json!([]));
+                };
+                Json(json!([{
+                    "id": 1,
+                    "currency": "USD",
+                    "value": value,
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": "2024-01-01T00:00:00Z"
+                }]))
+            }))
+            .route("/rest/v1/payment_options", get(|| async { Json(json!([])) })
+                .post(|Json(rows): Json<serde_json::Value>| async move { (StatusCode::CREATED, Json(rows)) }))
+    }
+
+    fn invoice(amount: i64) -> Invoice {
+        Invoice {
+            id: 1,
+            uid: "inv_tiny".to_string(),
+            amount: Some(amount),
+            currency: "USD".to_string(),
+            status: InvoiceStatus::Unpaid,
+            account_id: 1,
+            complete: None,
+            webhook_url: None,
+            redirect_url: None,
+            memo: None,
+            uri: "pay:?r=https://api.anypayx.com/r/inv_tiny".to_string(),
+            createdAt: Utc::now().to_rfc3339(),
+            updatedAt: Utc::now().to_rfc3339(),
+            accepted_currencies: None,
+            required_fee_rate: None,
+            crypto_denominated: false,
+            op_return: None,
+            summary: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tiny_invoice_skips_options_below_minimum() {
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_supabase()), "anon", "service");
+        let account = Account { id: 1, denomination: Some("USD".to_string()), base_url: None };
+
+        // $1 is 2,000 sats, under the configured BTC minimum, but plenty of BSV
+        let options = create_payment_options(&account, &invoice(1), &supabase).await.unwrap();
+
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0].currency, "BSV");
+        assert_eq!(options[0].amount, 2_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_zero_price_produces_no_option() {
+        let addresses = json!([
+            address_row("BTC", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            address_row("ETH", "0x4B7115aD9623A528f1845eaf85D166dE1E869BFB")
+        ]);
+        let zero_eth = |base_currency: &str| if base_currency == "ETH" { Some(0.0) } else { usd_price(base_currency) };
+        let router = mock_supabase_priced(addresses, json!([]), Arc::new(Mutex::new(0)), zero_eth);
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+        let account = Account { id: 1, denomination: Some("USD".to_string()), base_url: None };
+
+        let options = create_payment_options(&account, &invoice(1_000), &supabase).await.unwrap();
+
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0].currency, "BTC");
+        assert!(options.iter().all(|option| option.amount > 0));
+    }
+
+    #[tokio::test]
+    async fn test_crypto_denominated_invoice_is_not_converted() {
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_supabase()), "anon", "service");
+        let account = Account { id: 1, denomination: Some("USD".to_string()), base_url: None };
+        // Exactly 0.001 BTC, which as dollars would convert to 2 BTC
+        let invoice = Invoice { currency: "BTC".to_string(), crypto_denominated: true, ..invoice(100_000) };
+
+        let options = create_payment_options(&account, &invoice, &supabase).await.unwrap();
+
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0].currency, "BTC");
+        assert_eq!(options[0].amount, 100_000);
+        assert!(options[0].uri.contains("amount=0.001"));
+    }
+
+    #[tokio::test]
+    async fn test_get_coin_by_contract() {
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_supabase()), "anon", "service");
+
+        // Transfer logs carry lowercase addresses, while coins are configured checksummed
+        let coin = supabase.get_coin_by_contract("ETH", &USDC_CONTRACT.to_lowercase()).await.unwrap().unwrap();
+        assert_eq!((coin.currency.as_str(), coin.chain.as_str()), ("USDC", "ETH"));
+        assert_eq!(coin.precision, Some(6));
+
+        assert!(supabase.get_coin_by_contract("POLYGON", USDC_CONTRACT).await.unwrap().is_none());
+        assert!(supabase.get_coin_by_contract("ETH", "0x0000000000000000000000000000000000000000").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_payment_options_carry_coin_color() {
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_supabase()), "anon", "service");
+        let account = Account { id: 1, denomination: Some("USD".to_string()), base_url: None };
+
+        let options = create_payment_options(&account, &invoice(10), &supabase).await.unwrap();
+
+        let serialized = options.iter()
+            .map(|option| (option.currency.clone(), serde_json::to_value(option).unwrap()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(serialized["BTC"]["color"], "#f7931a");
+        assert!(serialized["BSV"].get("color").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_option_creation_claims_distinct_indexes() {
+        // BIP32 test vector 1 master public key
+        let xpubs = json!([{ "account_id": 1, "chain": "BTC", "xpub": "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8" }]);
+        let next_index = Arc::new(Mutex::new(0));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_supabase_with_xpubs(xpubs, next_index.clone())), "anon", "service");
+        let account = Account { id: 1, denomination: Some("USD".to_string()), base_url: None };
+        let invoices = (0..8).map(|_| invoice(10)).collect::<Vec<_>>();
+
+        let results = join_all(invoices.iter().map(|invoice| create_payment_options(&account, invoice, &supabase))).await;
+
+        let btc_options = results.into_iter()
+            .flat_map(|options| options.unwrap())
+            .filter(|option| option.chain == "BTC")
+            .collect::<Vec<_>>();
+        assert_eq!(btc_options.len(), 8);
+        let indexes = btc_options.iter().filter_map(|option| option.derivation_index).collect::<HashSet<_>>();
+        assert_eq!(indexes, (0..8).collect::<HashSet<_>>());
+        let addresses = btc_options.iter().map(|option| option.address.as_str()).collect::<HashSet<_>>();
+        assert_eq!(addresses.len(), 8);
+        // BSV has no xpub, so it keeps the configured address without claiming an index
+        assert_eq!(*next_index.lock().unwrap(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_xpub_lookup_failure_fails_option_creation() {
+        // A row that doesn't parse makes the xpub lookup fail
+        let xpubs = json!([{ "account_id": 1, "chain": "BTC", "xpub": 5 }]);
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(mock_supabase_with_xpubs(xpubs, Arc::new(Mutex::new(0)))), "anon", "service");
+        let account = Account { id: 1, denomination: Some("USD".to_string()), base_url: None };
+
+        let result = create_payment_options(&account, &invoice(10), &supabase).await;
+
+        let error = result.expect_err("the invoice should not be created without its BTC option");
+        assert!(error.is::<AddressError>());
+    }
+
+    #[tokio::test]
+    async fn test_invoice_accepting_only_btc_gets_only_btc_options() {
+        let addresses = json!([
+            address_row("BTC", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            address_row("ETH", "0x4B7115aD9623A528f1845eaf85D166dE1E869BFB"),
+            address_row("XRP", "rHb9CJAWyB4rj91VRWn96DkukG4bwdtyTh")
+        ]);
+        let router = mock_supabase_for(addresses, json!([]), Arc::new(Mutex::new(0)))
+            .route("/rest/v1/invoices", post(|Json(mut rows): Json<serde_json::Value>| async move {
+                for row in rows.as_array_mut().unwrap() {
+                    row["id"] = json!(1);
+                }
+                (StatusCode::CREATED, Json(rows))
+            }))
+            .route("/rest/v1/accounts", get(|| async { Json(json!([{ "id": 1, "denomination": "USD" }])) }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let created = supabase.create_invoice(Some(10), "USD", 1, None, None, None, Some(vec!["BTC".to_string()]), None, false, None).await.unwrap();
+        let options = created["payment_options"].as_array().unwrap();
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0]["currency"], "BTC");
+        assert_eq!(created["invoice"]["accepted_currencies"], json!(["BTC"]));
+
+        // Without an allow-list every address produces an option
+        let created = supabase.create_invoice(Some(10), "USD", 1, None, None, None, None, None, false, None).await.unwrap();
+        let mut currencies = created["payment_options"].as_array().unwrap().iter()
+            .map(|option| option["currency"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        currencies.sort();
+        assert_eq!(currencies, vec!["BTC", "ETH", "XRP"]);
+    }
+
+    #[tokio::test]
+    async fn test_created_options_have_scannable_uris() {
+        let addresses = json!([
+            address_row("BTC", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            address_row("ETH", "0x4B7115aD9623A528f1845eaf85D166dE1E869BFB"),
+            address_row("XRP", "rHb9CJAWyB4rj91VRWn96DkukG4bwdtyTh")
+        ]);
+        let router = mock_supabase_for(addresses, json!([]), Arc::new(Mutex::new(0)))
+            .route("/rest/v1/invoices", post(|Json(mut rows): Json<serde_json::Value>| async move {
+                for row in rows.as_array_mut().unwrap() {
+                    row["id"] = json!(1);
+                }
+                (StatusCode::CREATED, Json(rows))
+            }))
+            .route("/rest/v1/accounts", get(|| async {
+                Json(json!([{ "id": 1, "denomination": "USD", "base_url": "https://pay.shop-a.com" }]))
+            }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let created = supabase.create_invoice(Some(10), "USD", 1, None, None, None, None, None, false, None).await.unwrap();
+        let uid = created["invoice"]["uid"].as_str().unwrap();
+        let options = serde_json::from_value::<Vec<PaymentOption>>(created["payment_options"].clone()).unwrap();
+        assert_eq!(options.len(), 3);
+
+        for option in &options {
+            assert!(!option.uri.is_empty(), "{} option has no URI", option.currency);
+            let uri = url::Url::parse(&option.uri).unwrap();
+            let query = uri.query_pairs().into_owned().collect::<HashMap<_, _>>();
+
+            match option.currency.as_str() {
+                "BTC" => {
+                    assert_eq!(uri.scheme(), "bitcoin");
+                    assert_eq!(uri.path(), option.address);
+                    let amount = query["amount"].parse::<f64>().unwrap();
+                    assert_eq!((amount * 100_000_000.0).round() as i64, option.amount);
+                }
+                _ => {
+                    assert_eq!(uri.scheme(), "pay");
+                    assert_eq!(query["r"], format!("https://pay.shop-a.com/r/{}", uid));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoice_uris_use_each_accounts_domain() {
+        let router = mock_supabase_for(json!([]), json!([]), Arc::new(Mutex::new(0)))
+            .route("/rest/v1/invoices", post(|Json(mut rows): Json<serde_json::Value>| async move {
+                for row in rows.as_array_mut().unwrap() {
+                    row["id"] = json!(1);
+                }
+                (StatusCode::CREATED, Json(rows))
+            }))
+            .route("/rest/v1/accounts", get(|Query(params): Query<HashMap<String, String>>| async move {
+                let base_url = match params["id"].as_str() {
+                    "eq.1" => "https://pay.shop-a.com",
+                    _ => "https://checkout.shop-b.io/",
+                };
+                Json(json!([{ "id": 1, "denomination": "USD", "base_url": base_url }]))
+            }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let shop_a = supabase.create_invoice(Some(10), "USD", 1, None, None, None, None, None, false, None).await.unwrap();
+        let shop_b = supabase.create_invoice(Some(10), "USD", 2, None, None, None, None, None, false, None).await.unwrap();
+
+        let uri = |created: &serde_json::Value| created["invoice"]["uri"].as_str().unwrap().to_string();
+        let uid = |created: &serde_json::Value| created["invoice"]["uid"].as_str().unwrap().to_string();
+        assert_eq!(uri(&shop_a), format!("pay:?r=https://pay.shop-a.com/r/{}", uid(&shop_a)));
+        assert_eq!(uri(&shop_b), format!("pay:?r=https://checkout.shop-b.io/r/{}", uid(&shop_b)));
+    }
+
+    #[test]
+    fn test_payment_options_sorted_in_a_stable_order() {
+        let option = |currency: &str, chain: &str| PaymentOption {
+            invoice_uid: "inv_1".to_string(),
+            currency: currency.to_string(),
+            chain: chain.to_string(),
+            amount: 1000,
+            address: String::new(),
+            outputs: vec![],
+            uri: String::new(),
+            fee: 0,
+            created_at: String::new(),
+            updated_at: String::new(),
+            expires: String::new(),
+            derivation_index: None,
+            color: None,
+            op_return: None,
+        };
+        let options = vec![
+            option("XMR", "XMR"),
+            option("USDC", "SOL"),
+            option("BSV", "BSV"),
+            option("BTC", "BTC"),
+            option("USDC", "ETH"),
+            option("ETH", "ETH"),
+            option("AVAX", "AVAX"),
+        ];
+
+        // Every rotation of the input sorts to the same order
+        for rotation in 0..options.len() {
+            let mut rotated = options.clone();
+            rotated.rotate_left(rotation);
+            sort_payment_options(&mut rotated);
+
+            let order = rotated.iter()
+                .map(|option| format!("{}:{}", option.currency, option.chain))
+                .collect::<Vec<_>>();
+            assert_eq!(order, vec!["BTC:BTC", "ETH:ETH", "BSV:BSV", "USDC:ETH", "USDC:SOL", "AVAX:AVAX", "XMR:XMR"]);
+        }
+    }
+
+    #[test]
+    fn test_fee_routed_to_separate_output() {
+        let fee = payment::compute_fee("BTC", 2_000_000, "bc1qfeeaddress".to_string());
+        assert_eq!(fee.amount, 200);
+
+        let (outputs, charged) = build_outputs("bc1qmerchant", 2_000_000, Some(&fee), 1);
+
+        assert_eq!(charged, 200);
+        assert_eq!(outputs.len(), 2);
+        assert_eq!((outputs[0].address.as_str(), outputs[0].amount), ("bc1qmerchant", 1_999_800));
+        assert_eq!((outputs[1].address.as_str(), outputs[1].amount), ("bc1qfeeaddress", 200));
+        assert_eq!(outputs.iter().map(|output| output.amount).sum::<i64>(), 2_000_000);
+    }
+
+    #[test]
+    fn test_fee_below_dust_is_waived() {
+        let fee = payment::compute_fee("BTC", 2_000_000, "bc1qfeeaddress".to_string());
+
+        let (outputs, charged) = build_outputs("bc1qmerchant", 2_000_000, Some(&fee), 546);
+        assert_eq!(charged, 0);
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].amount, 2_000_000);
+
+        let (outputs, _) = build_outputs("bc1qmerchant", 2_000_000, None, 546);
+        assert_eq!(outputs.len(), 1);
+    }
+
+    #[test]
+    fn test_minimum_defaults_to_dust_limit() {
+        let coin: Coin = serde_json::from_value(coin_row(1, "BTC", None)).unwrap();
+        assert_eq!(minimum_amount(&coin), 546);
+
+        let coin: Coin = serde_json::from_value(coin_row(1, "BTC", Some(10_000))).unwrap();
+        assert_eq!(minimum_amount(&coin), 10_000);
+    }
+}
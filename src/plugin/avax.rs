@@ -0,0 +1,68 @@
+use super::{Plugin, Account, Address, PaymentOption, Transaction, Payment, Confirmation, Price};
+use anyhow::{Result, anyhow};
+
+pub struct AvalanchePlugin;
+
+#[async_trait::async_trait]
+impl Plugin for AvalanchePlugin {
+    fn currency(&self) -> &str { "AVAX" }
+    fn chain(&self) -> &str { "AVAX" }
+    fn decimals(&self) -> u8 { 18 }
+
+    async fn build_signed_payment(&self, _payment_option: &PaymentOption, _mnemonic: &str) -> Result<Transaction> {
+        // TODO: Implement Avalanche transaction signing via EthereumCard (chain id set per network)
+        Err(anyhow!("Signing {} transactions is not supported", self.chain()))
+    }
+
+    async fn verify_payment(&self, _payment_option: &PaymentOption, _transaction: &Transaction) -> Result<bool> {
+        // Reporting an unchecked transaction as paid would settle invoices that weren't
+        Err(anyhow!("Verifying {} payments is not supported", self.chain()))
+    }
+
+    async fn validate_address(&self, address: &str) -> Result<bool> {
+        // TODO: Implement Avalanche address validation
+        Ok(address.starts_with("0x") && address.len() == 42)
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<Transaction> {
+        // TODO: Implement Avalanche transaction fetching
+        Err(anyhow!("Fetching {} transaction {} is not supported", self.chain(), txid))
+    }
+
+    async fn broadcast_tx(&self, _txhex: &str, _txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
+        Err(anyhow!("Broadcasting {} transactions is not supported", self.chain()))
+    }
+
+    async fn get_new_address(&self, _account: &Account, address: &Address) -> Result<String> {
+        // TODO: Implement Avalanche address generation
+        Ok(address.value.clone())
+    }
+
+    fn derive_address(&self, xpub: &str, index: u32) -> Result<String> {
+        super::derive_evm_address(xpub, index)
+    }
+
+    async fn transform_address(&self, address: &str) -> Result<String> {
+        Ok(address.split(':').last().unwrap_or(address).to_string())
+    }
+
+    async fn get_confirmation(&self, txid: &str) -> Result<Option<Confirmation>> {
+        // TODO: Implement Avalanche confirmation checking
+        Err(anyhow!("Checking {} confirmations for {} is not supported", self.chain(), txid))
+    }
+
+    async fn get_payments(&self, txid: &str) -> Result<Vec<Payment>> {
+        // TODO: Implement Avalanche payment parsing
+        Err(anyhow!("Fetching {} payments for {} is not supported", self.chain(), txid))
+    }
+
+    async fn parse_payments(&self, _transaction: &Transaction) -> Result<Vec<Payment>> {
+        // TODO: Implement Avalanche transaction parsing
+        Ok(vec![])
+    }
+
+    async fn get_price(&self) -> Result<Price> {
+        // TODO: Implement price fetching from exchange
+        Err(anyhow!("Fetching the {} price is not supported", self.currency()))
+    }
+}
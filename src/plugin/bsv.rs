@@ -1,7 +1,27 @@
 use super::{Plugin, Account, Address, PaymentOption, Transaction, Payment, Confirmation, Price};
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use bigdecimal::BigDecimal;
 use std::str::FromStr;
+use bitcoin::{Transaction as BsvTransaction, consensus::deserialize, Address as BsvAddress, AddressType, Network};
+
+/// Decode a raw BSV transaction. BSV kept the pre-segwit serialization, which the bitcoin crate reads as-is.
+fn decode_transaction(txhex: &str) -> Result<BsvTransaction> {
+    let tx_bytes = hex::decode(txhex)?;
+    Ok(deserialize(&tx_bytes)?)
+}
+
+/// Parse a mainnet BSV address. BSV shares Bitcoin's base58 version bytes but has no segwit addresses.
+fn parse_address(address: &str) -> Result<BsvAddress> {
+    let address = BsvAddress::from_str(address)
+        .map_err(|e| anyhow!("Invalid BSV address: {}", e))?
+        .require_network(Network::Bitcoin)
+        .map_err(|e| anyhow!("Invalid BSV address: {}", e))?;
+
+    match address.address_type() {
+        Some(AddressType::P2pkh) | Some(AddressType::P2sh) => Ok(address),
+        _ => Err(anyhow!("Invalid BSV address: {} is not a legacy address", address)),
+    }
+}
 
 pub struct BitcoinSVPlugin;
 
@@ -21,13 +41,24 @@ impl Plugin for BitcoinSVPlugin {
     }
 
     async fn verify_payment(&self, payment_option: &PaymentOption, transaction: &Transaction) -> Result<bool> {
-        // TODO: Implement BSV transaction verification
-        Ok(true)
+        let bsv_tx = decode_transaction(&transaction.txhex)?;
+        let payment_script = match &payment_option.script {
+            Some(script) => bitcoin::ScriptBuf::from_hex(script)
+                .map_err(|e| anyhow!("Invalid output script {}: {}", script, e))?,
+            None => parse_address(&payment_option.address)?.script_pubkey(),
+        };
+
+        // Only outputs paying the payment address count towards the expected amount
+        let paid_to_address: u64 = bsv_tx.output.iter()
+            .filter(|output| output.script_pubkey == payment_script)
+            .map(|output| output.value.to_sat())
+            .sum();
+
+        Ok(paid_to_address > 0 && paid_to_address >= payment_option.amount as u64)
     }
 
     async fn validate_address(&self, address: &str) -> Result<bool> {
-        // TODO: Implement BSV address validation
-        Ok(address.starts_with("1") || address.starts_with("3") || address.starts_with("q"))
+        Ok(parse_address(address).is_ok())
     }
 
     async fn get_transaction(&self, txid: &str) -> Result<Transaction> {
@@ -39,11 +70,25 @@ impl Plugin for BitcoinSVPlugin {
         })
     }
 
-    async fn broadcast_tx(&self, txhex: &str, txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
-        // TODO: Implement BSV transaction broadcasting
+    async fn broadcast_tx(&self, txhex: &str, _txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
+        let api_url = std::env::var("WHATSONCHAIN_API_URL").unwrap_or_else(|_| super::WHATSONCHAIN_API_URL.to_string());
+        let response = reqwest::Client::new()
+            .post(format!("{}/tx/raw", api_url.trim_end_matches('/')))
+            .json(&serde_json::json!({ "txhex": txhex }))
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to broadcast BSV transaction: {}", e))?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("BSV broadcast rejected: {}", response.text().await?));
+        }
+
+        // WhatsOnChain answers with the txid as a JSON string
+        let txid = response.json::<String>().await
+            .map_err(|e| anyhow!("Failed to parse BSV broadcast response: {}", e))?;
         Ok(Transaction {
             txhex: txhex.to_string(),
-            txid: txid.map(String::from),
+            txid: Some(txid),
             txkey: None,
         })
     }
@@ -53,6 +98,10 @@ impl Plugin for BitcoinSVPlugin {
         Ok(address.value.clone())
     }
 
+    fn derive_address(&self, xpub: &str, index: u32) -> Result<String> {
+        super::derive_p2pkh_address(xpub, index, Network::Bitcoin)
+    }
+
     async fn transform_address(&self, address: &str) -> Result<String> {
         Ok(address.split(':').last().unwrap_or(address).to_string())
     }
@@ -77,8 +126,22 @@ impl Plugin for BitcoinSVPlugin {
     }
 
     async fn parse_payments(&self, transaction: &Transaction) -> Result<Vec<Payment>> {
-        // TODO: Implement BSV transaction parsing
-        Ok(vec![])
+        let bsv_tx = decode_transaction(&transaction.txhex)?;
+        let txid = bsv_tx.txid().to_string();
+
+        // Outputs without an address, such as OP_RETURN data, aren't payments
+        Ok(bsv_tx.output.iter()
+            .filter_map(|output| {
+                let address = BsvAddress::from_script(&output.script_pubkey, Network::Bitcoin).ok()?;
+                Some(Payment {
+                    chain: self.chain().to_string(),
+                    currency: self.currency().to_string(),
+                    address: address.to_string(),
+                    amount: output.value.to_sat() as i64,
+                    txid: txid.clone(),
+                })
+            })
+            .collect())
     }
 
     async fn get_price(&self) -> Result<Price> {
@@ -89,4 +152,59 @@ impl Plugin for BitcoinSVPlugin {
             timestamp: chrono::Utc::now().timestamp(),
         })
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, TxIn, TxOut, Witness, absolute::LockTime, transaction::Version};
+    use bitcoin::consensus::encode::serialize_hex;
+
+    // The BitcoinSVCard address for the "abandon ... about" test mnemonic, m/44'/236'/0'/0/0
+    const CARD_ADDRESS: &str = "1K6LZdwpKT5XkEZo2T2kW197aMXYbYMc4f";
+
+    #[tokio::test]
+    async fn test_verifies_outputs_paying_card_address() {
+        let payee = parse_address(CARD_ADDRESS).unwrap();
+        let change = parse_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2").unwrap();
+
+        let tx = BsvTransaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut { value: Amount::from_sat(25_000), script_pubkey: payee.script_pubkey() },
+                TxOut { value: Amount::from_sat(9_000), script_pubkey: change.script_pubkey() },
+                TxOut { value: Amount::ZERO, script_pubkey: ScriptBuf::new_op_return(b"anypay") },
+            ],
+        };
+        let transaction = Transaction { txhex: serialize_hex(&tx), txid: None, txkey: None };
+
+        let option = |amount| PaymentOption {
+            chain: "BSV".to_string(),
+            currency: "BSV".to_string(),
+            address: CARD_ADDRESS.to_string(),
+            amount,
+            uri: None,
+            script: None,
+        };
+        assert!(BitcoinSVPlugin.verify_payment(&option(25_000), &transaction).await.unwrap());
+        assert!(!BitcoinSVPlugin.verify_payment(&option(25_001), &transaction).await.unwrap());
+
+        let payments = BitcoinSVPlugin.parse_payments(&transaction).await.unwrap();
+        let outputs: Vec<_> = payments.iter().map(|p| (p.address.as_str(), p.amount)).collect();
+        assert_eq!(outputs, vec![(CARD_ADDRESS, 25_000), ("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", 9_000)]);
+        assert!(payments.iter().all(|p| p.txid == tx.txid().to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_segwit_addresses() {
+        assert!(BitcoinSVPlugin.validate_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2").await.unwrap());
+        assert!(!BitcoinSVPlugin.validate_address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").await.unwrap());
+    }
+}
@@ -2,10 +2,15 @@ use super::{Plugin, Account, Address, PaymentOption, Transaction, Payment, Confi
 use anyhow::{Result, anyhow};
 use bigdecimal::BigDecimal;
 use std::str::FromStr;
-use bitcoin::{Transaction as BtcTransaction, consensus::deserialize, Address as BtcAddress};
+use bitcoin::{Transaction as BtcTransaction, consensus::deserialize, Address as BtcAddress, ScriptBuf};
 
 pub struct BitcoinPlugin;
 
+/// The mempool.space-compatible API fees are estimated and transactions broadcast through
+fn mempool_api_url() -> String {
+    std::env::var("MEMPOOL_API_URL").unwrap_or_else(|_| super::MEMPOOL_API_URL.to_string())
+}
+
 #[async_trait::async_trait]
 impl Plugin for BitcoinPlugin {
     fn currency(&self) -> &str { "BTC" }
@@ -26,34 +31,24 @@ impl Plugin for BitcoinPlugin {
         let tx_bytes = hex::decode(&transaction.txhex)?;
         let btc_tx: BtcTransaction = deserialize(&tx_bytes)?;
 
-        // Calculate total output amount in satoshis
-        let total_output_amount: u64 = btc_tx.output.iter()
+        // Pay the option's raw script when it has one, otherwise its address
+        let payment_script = match &payment_option.script {
+            Some(script) => ScriptBuf::from_hex(script)
+                .map_err(|e| anyhow!("Invalid output script {}: {}", script, e))?,
+            None => BtcAddress::from_str(&payment_option.address)
+                .map_err(|e| anyhow!("Invalid Bitcoin address: {}", e))?
+                .require_network(bitcoin::Network::Bitcoin)
+                .map_err(|e| anyhow!("Invalid Bitcoin address: {}", e))?
+                .script_pubkey(),
+        };
+
+        // Only outputs paying the payment address count towards the expected amount
+        let paid_to_address: u64 = btc_tx.output.iter()
+            .filter(|output| output.script_pubkey == payment_script)
             .map(|output| output.value.to_sat())
             .sum();
 
-        // Convert payment option amount to satoshis for comparison
-        let expected_amount = payment_option.amount as u64;
-
-        // Verify that total outputs meet or exceed expected amount
-        if total_output_amount < expected_amount {
-            return Ok(false);
-        }
-
-        // Parse the payment address
-        let payment_address = BtcAddress::from_str(&payment_option.address)
-            .map_err(|e| anyhow!("Invalid Bitcoin address: {}", e))?;
-
-        // Verify that at least one output matches the payment address
-        let has_matching_output = btc_tx.output.iter().any(|output| {
-            // Try to parse the output script to an address
-            if let Ok(script_addr) = BtcAddress::from_script(&output.script_pubkey, bitcoin::Network::Bitcoin) {
-                script_addr == payment_address
-            } else {
-                false
-            }
-        });
-
-        Ok(has_matching_output)
+        Ok(paid_to_address > 0 && paid_to_address >= payment_option.amount as u64)
     }
 
     async fn validate_address(&self, address: &str) -> Result<bool> {
@@ -70,11 +65,11 @@ impl Plugin for BitcoinPlugin {
         })
     }
 
-    async fn broadcast_tx(&self, txhex: &str, txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
-        // TODO: Implement BTC transaction broadcasting
+    async fn broadcast_tx(&self, txhex: &str, _txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
+        let txid = super::broadcast_mempool_tx(&mempool_api_url(), txhex).await?;
         Ok(Transaction {
             txhex: txhex.to_string(),
-            txid: txid.map(String::from),
+            txid: Some(txid),
             txkey: None,
         })
     }
@@ -84,6 +79,10 @@ impl Plugin for BitcoinPlugin {
         Ok(address.value.clone())
     }
 
+    fn derive_address(&self, xpub: &str, index: u32) -> Result<String> {
+        super::derive_p2wpkh_address(xpub, index, bitcoin::Network::Bitcoin)
+    }
+
     async fn transform_address(&self, address: &str) -> Result<String> {
         Ok(address.split(':').last().unwrap_or(address).to_string())
     }
@@ -112,6 +111,14 @@ impl Plugin for BitcoinPlugin {
         Ok(vec![])
     }
 
+    async fn estimate_fee(&self, _payment_option: &PaymentOption) -> Result<i64> {
+        super::estimate_mempool_fee(&mempool_api_url()).await
+    }
+
+    async fn estimate_fee_rate(&self) -> Result<i64> {
+        super::mempool_fee_rate(&mempool_api_url()).await
+    }
+
     async fn get_price(&self) -> Result<Price> {
         // TODO: Implement price fetching from exchange
         Ok(Price {
@@ -120,4 +127,30 @@ impl Plugin for BitcoinPlugin {
             timestamp: chrono::Utc::now().timestamp(),
         })
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::supabase::tests::mock_chain_apis;
+
+    #[tokio::test]
+    async fn test_estimate_fee_from_mempool_rates() {
+        // The mock reports a half-hour rate of 12 sat/vbyte
+        mock_chain_apis();
+
+        let option = PaymentOption {
+            chain: "BTC".to_string(),
+            currency: "BTC".to_string(),
+            address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+            amount: 25_000,
+            uri: None,
+            script: None,
+        };
+        let fee = BitcoinPlugin.estimate_fee(&option).await.unwrap();
+
+        // 12 sat/vbyte for a 141 vbyte payment
+        assert_eq!(fee, 12 * 141);
+        assert_eq!(BitcoinPlugin.estimate_fee_rate().await.unwrap(), 12);
+    }
+}
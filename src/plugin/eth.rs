@@ -1,5 +1,5 @@
 use super::{Plugin, Account, Address, PaymentOption, Transaction, Payment, Confirmation, Price};
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use bigdecimal::BigDecimal;
 use std::str::FromStr;
 
@@ -20,9 +20,9 @@ impl Plugin for EthereumPlugin {
         })
     }
 
-    async fn verify_payment(&self, payment_option: &PaymentOption, transaction: &Transaction) -> Result<bool> {
-        // TODO: Implement Ethereum transaction verification
-        Ok(true)
+    async fn verify_payment(&self, _payment_option: &PaymentOption, _transaction: &Transaction) -> Result<bool> {
+        // Reporting an unchecked transaction as paid would settle invoices that weren't
+        Err(anyhow!("Verifying {} payments is not supported", self.chain()))
     }
 
     async fn validate_address(&self, address: &str) -> Result<bool> {
@@ -39,13 +39,8 @@ impl Plugin for EthereumPlugin {
         })
     }
 
-    async fn broadcast_tx(&self, txhex: &str, txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
-        // TODO: Implement Ethereum transaction broadcasting
-        Ok(Transaction {
-            txhex: txhex.to_string(),
-            txid: txid.map(String::from),
-            txkey: None,
-        })
+    async fn broadcast_tx(&self, _txhex: &str, _txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
+        Err(anyhow!("Broadcasting {} transactions is not supported", self.chain()))
     }
 
     async fn get_new_address(&self, _account: &Account, address: &Address) -> Result<String> {
@@ -53,6 +48,10 @@ impl Plugin for EthereumPlugin {
         Ok(address.value.clone())
     }
 
+    fn derive_address(&self, xpub: &str, index: u32) -> Result<String> {
+        super::derive_evm_address(xpub, index)
+    }
+
     async fn transform_address(&self, address: &str) -> Result<String> {
         Ok(address.split(':').last().unwrap_or(address).to_string())
     }
@@ -81,6 +80,12 @@ impl Plugin for EthereumPlugin {
         Ok(vec![])
     }
 
+    async fn estimate_fee(&self, _payment_option: &PaymentOption) -> Result<i64> {
+        // A plain ETH transfer uses at most 21_000 gas
+        let rpc_url = std::env::var("ETH_RPC_URL").unwrap_or_else(|_| super::ETH_RPC_URL.to_string());
+        super::estimate_evm_fee(&rpc_url, 21_000).await
+    }
+
     async fn get_price(&self) -> Result<Price> {
         // TODO: Implement price fetching from exchange
         Ok(Price {
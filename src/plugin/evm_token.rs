@@ -0,0 +1,328 @@
+use super::{evm_network, evm_rpc, EvmNetwork, Plugin, Account, Address, PaymentOption, Transaction, Payment, Confirmation, Price};
+use anyhow::{Result, anyhow};
+use ethers::types::{transaction::eip2718::TypedTransaction, Address as EvmAddress, NameOrAddress, U256};
+use ethers::utils::rlp::Rlp;
+use serde_json::{json, Value};
+
+/// keccak256("Transfer(address,address,uint256)"), the first topic of ERC-20 transfer logs
+const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+/// Selector of transfer(address,uint256)
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+
+/// An ERC-20 style token on an EVM chain, configured from its coin row rather than in code
+pub struct EvmTokenPlugin {
+    chain: String,
+    currency: String,
+    contract_address: String,
+    decimals: u8,
+    rpc_url: Option<String>,
+}
+
+impl EvmTokenPlugin {
+    pub fn new(chain: &str, currency: &str, contract_address: &str, decimals: u8) -> Self {
+        Self {
+            chain: chain.to_string(),
+            currency: currency.to_string(),
+            contract_address: contract_address.to_string(),
+            decimals,
+            rpc_url: None,
+        }
+    }
+
+    /// Query `rpc_url` instead of the network's configured provider
+    pub fn with_rpc_url(mut self, rpc_url: &str) -> Self {
+        self.rpc_url = Some(rpc_url.to_string());
+        self
+    }
+
+    pub fn contract_address(&self) -> &str {
+        &self.contract_address
+    }
+
+    fn network(&self) -> Result<&'static EvmNetwork> {
+        evm_network(&self.chain).ok_or_else(|| anyhow!("{} is not a supported EVM network", self.chain))
+    }
+
+    fn rpc_url(&self) -> Result<String> {
+        match &self.rpc_url {
+            Some(rpc_url) => Ok(rpc_url.clone()),
+            None => Ok(self.network()?.rpc_url()),
+        }
+    }
+
+    fn parse_address(&self, address: &str) -> Result<EvmAddress> {
+        address.parse().map_err(|e| anyhow!("Invalid {} address {}: {}", self.chain, address, e))
+    }
+
+    /// Tokens a mined transaction's Transfer logs from this contract pay to `to`; nothing if it reverted
+    fn transferred_in_receipt(&self, receipt: &Value, to: &str) -> Result<U256> {
+        if receipt["status"].as_str() == Some("0x0") {
+            return Ok(U256::zero());
+        }
+
+        let recipient_topic = format!("0x{:0>64}", to.trim_start_matches("0x").to_lowercase());
+        let mut paid = U256::zero();
+        for log in receipt["logs"].as_array().into_iter().flatten() {
+            let topics = log["topics"].as_array().map(Vec::as_slice).unwrap_or_default();
+            let is_transfer_to_recipient = log["address"].as_str().is_some_and(|address| address.eq_ignore_ascii_case(&self.contract_address))
+                && topics.first().and_then(Value::as_str) == Some(TRANSFER_TOPIC)
+                && topics.get(2).and_then(Value::as_str).is_some_and(|topic| topic.eq_ignore_ascii_case(&recipient_topic));
+
+            if is_transfer_to_recipient {
+                let data = log["data"].as_str().unwrap_or("0x0").trim_start_matches("0x");
+                let value = U256::from_str_radix(data, 16)
+                    .map_err(|e| anyhow!("Invalid transfer amount {}: {}", data, e))?;
+                paid = paid.saturating_add(value);
+            }
+        }
+
+        Ok(paid)
+    }
+
+    /// Tokens a signed, not yet mined transfer(address,uint256) call pays to `to`
+    fn transferred_in_signed_transaction(&self, txhex: &str, chain_id: u64, to: &str) -> Result<U256> {
+        let bytes = hex::decode(txhex.trim_start_matches("0x"))
+            .map_err(|e| anyhow!("Invalid transaction hex: {}", e))?;
+        let (tx, _) = TypedTransaction::decode_signed(&Rlp::new(&bytes))
+            .map_err(|e| anyhow!("Invalid {} transaction: {}", self.chain, e))?;
+
+        // A transaction signed for another network can't be included on this one
+        let tx_chain_id = tx.chain_id().map(|id| id.as_u64());
+        if tx_chain_id != Some(chain_id) {
+            return Err(anyhow!("Transaction is for chain {:?}, not {} ({})", tx_chain_id, self.chain, chain_id));
+        }
+
+        let contract = self.parse_address(&self.contract_address)?;
+        if tx.to() != Some(&NameOrAddress::Address(contract)) {
+            return Ok(U256::zero());
+        }
+
+        let data: &[u8] = tx.data().map(|data| data.as_ref()).unwrap_or_default();
+        if data.len() != 68 || data[..4] != TRANSFER_SELECTOR || EvmAddress::from_slice(&data[16..36]) != self.parse_address(to)? {
+            return Ok(U256::zero());
+        }
+
+        Ok(U256::from_big_endian(&data[36..68]))
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for EvmTokenPlugin {
+    fn currency(&self) -> &str { &self.currency }
+    fn chain(&self) -> &str { &self.chain }
+    fn decimals(&self) -> u8 { self.decimals }
+
+    async fn build_signed_payment(&self, _payment_option: &PaymentOption, _mnemonic: &str) -> Result<Transaction> {
+        Err(anyhow!("Signing {} transfers is not supported", self.currency))
+    }
+
+    async fn verify_payment(&self, payment_option: &PaymentOption, transaction: &Transaction) -> Result<bool> {
+        let network = self.network()?;
+        let rpc_url = self.rpc_url()?;
+
+        // A provider for another network would report that network's logs for the same hash
+        let chain_id = evm_rpc(&rpc_url, "eth_chainId", json!([])).await?;
+        let chain_id = chain_id.as_str()
+            .and_then(|id| u64::from_str_radix(id.trim_start_matches("0x"), 16).ok())
+            .ok_or_else(|| anyhow!("Invalid eth_chainId response: {}", chain_id))?;
+        if chain_id != network.chain_id {
+            return Err(anyhow!("{} provider is on chain {}, expected {}", self.chain, chain_id, network.chain_id));
+        }
+
+        // Check the logs once the transaction is mined, otherwise the signed transfer itself
+        let receipt = match &transaction.txid {
+            Some(txid) => evm_rpc(&rpc_url, "eth_getTransactionReceipt", json!([txid])).await?,
+            None => Value::Null,
+        };
+        let paid = if receipt.is_null() {
+            self.transferred_in_signed_transaction(&transaction.txhex, network.chain_id, &payment_option.address)?
+        } else {
+            self.transferred_in_receipt(&receipt, &payment_option.address)?
+        };
+
+        Ok(!paid.is_zero() && paid >= U256::from(payment_option.amount.max(0) as u64))
+    }
+
+    async fn validate_address(&self, address: &str) -> Result<bool> {
+        Ok(address.starts_with("0x") && address.len() == 42)
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<Transaction> {
+        Err(anyhow!("Fetching {} transaction {} is not supported", self.currency, txid))
+    }
+
+    async fn broadcast_tx(&self, txhex: &str, txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
+        let rpc_url = self.rpc_url()?;
+
+        // A transfer the payer already sent only needs to be known to the node
+        if let Some(txid) = txid {
+            if !evm_rpc(&rpc_url, "eth_getTransactionByHash", json!([txid])).await?.is_null() {
+                return Ok(Transaction { txhex: txhex.to_string(), txid: Some(txid.to_string()), txkey: None });
+            }
+        }
+        if txhex.is_empty() {
+            return Err(anyhow!("{} transaction {:?} is unknown and no signed transaction was given", self.chain, txid));
+        }
+
+        let hash = evm_rpc(&rpc_url, "eth_sendRawTransaction", json!([txhex])).await?;
+        let hash = hash.as_str()
+            .ok_or_else(|| anyhow!("Invalid eth_sendRawTransaction response: {}", hash))?;
+        Ok(Transaction {
+            txhex: txhex.to_string(),
+            txid: Some(hash.to_string()),
+            txkey: None,
+        })
+    }
+
+    async fn get_new_address(&self, _account: &Account, address: &Address) -> Result<String> {
+        Ok(address.value.clone())
+    }
+
+    fn derive_address(&self, xpub: &str, index: u32) -> Result<String> {
+        super::derive_evm_address(xpub, index)
+    }
+
+    async fn transform_address(&self, address: &str) -> Result<String> {
+        Ok(address.split(':').last().unwrap_or(address).to_string())
+    }
+
+    async fn get_confirmation(&self, _txid: &str) -> Result<Option<Confirmation>> {
+        Ok(None)
+    }
+
+    async fn get_payments(&self, _txid: &str) -> Result<Vec<Payment>> {
+        Ok(vec![])
+    }
+
+    async fn parse_payments(&self, _transaction: &Transaction) -> Result<Vec<Payment>> {
+        Ok(vec![])
+    }
+
+    async fn estimate_fee(&self, _payment_option: &PaymentOption) -> Result<i64> {
+        // A token transfer uses at most 65_000 gas
+        super::estimate_evm_fee(&self.rpc_url()?, 65_000).await
+    }
+
+    async fn get_price(&self) -> Result<Price> {
+        Err(anyhow!("No price source for {}", self.currency))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::{Bytes, Eip1559TransactionRequest};
+    use crate::supabase::tests::spawn_mock_supabase;
+
+    /// Circle's USDC contract on Polygon PoS
+    const POLYGON_USDC: &str = "0x3c499c542cEF5E3811e1192ce70d8cC03d5c3359";
+    const MERCHANT: &str = "0x4B7115aD9623A528f1845eaf85D166dE1E869BFB";
+    const TXID: &str = "0x7d1fb1a0e4ac3a2bbb5ea4c8cf3c43d0bb22fa09bd1e1d8b0a1b44f6e0f3f0aa";
+
+    fn address_topic(address: &str) -> String {
+        format!("0x{:0>64}", address.trim_start_matches("0x").to_lowercase())
+    }
+
+    /// A Polygon node that mined TXID, a 25 USDC transfer to MERCHANT
+    fn mock_polygon_provider(chain_id: &'static str) -> Router {
+        Router::new().route("/", post(move |Json(request): Json<Value>| async move {
+            let result = match request["method"].as_str() {
+                Some("eth_chainId") => json!(chain_id),
+                Some("eth_getTransactionReceipt") if request["params"][0] == TXID => json!({
+                    "transactionHash": TXID,
+                    "status": "0x1",
+                    "logs": [{
+                        "address": POLYGON_USDC.to_lowercase(),
+                        "topics": [
+                            TRANSFER_TOPIC,
+                            address_topic("0x0000000000000000000000000000000000000001"),
+                            address_topic(MERCHANT)
+                        ],
+                        "data": format!("0x{:064x}", 25_000_000)
+                    }]
+                }),
+                _ => Value::Null,
+            };
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }))
+    }
+
+    fn spawn_mock_polygon(chain_id: &'static str) -> String {
+        spawn_mock_supabase(mock_polygon_provider(chain_id))
+    }
+
+    fn option(amount: i64) -> PaymentOption {
+        PaymentOption {
+            chain: "POLYGON".to_string(),
+            currency: "USDC".to_string(),
+            address: MERCHANT.to_string(),
+            amount,
+            uri: None,
+            script: None,
+        }
+    }
+
+    fn mined(txid: &str) -> Transaction {
+        Transaction { txhex: String::new(), txid: Some(txid.to_string()), txkey: None }
+    }
+
+    #[tokio::test]
+    async fn test_verifies_usdc_transfer_on_polygon() {
+        let plugin = EvmTokenPlugin::new("POLYGON", "USDC", POLYGON_USDC, 6)
+            .with_rpc_url(&spawn_mock_polygon("0x89"));
+
+        assert!(plugin.verify_payment(&option(25_000_000), &mined(TXID)).await.unwrap());
+        assert!(!plugin.verify_payment(&option(30_000_000), &mined(TXID)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_provider_for_another_network() {
+        // An AVAX token pointed at the Polygon node must not trust its logs
+        let plugin = EvmTokenPlugin::new("AVAX", "USDC", POLYGON_USDC, 6)
+            .with_rpc_url(&spawn_mock_polygon("0x89"));
+
+        let error = plugin.verify_payment(&option(25_000_000), &mined(TXID)).await.unwrap_err();
+        assert!(error.to_string().contains("expected 43114"));
+    }
+
+    /// A signed, unbroadcast transfer of `amount` USDC base units to MERCHANT for `chain_id`
+    fn signed_transfer(chain_id: u64, amount: u64) -> String {
+        let mut data = TRANSFER_SELECTOR.to_vec();
+        data.extend_from_slice(&[0u8; 12]);
+        data.extend_from_slice(MERCHANT.parse::<EvmAddress>().unwrap().as_bytes());
+        let mut value = [0u8; 32];
+        U256::from(amount).to_big_endian(&mut value);
+        data.extend_from_slice(&value);
+
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(POLYGON_USDC.parse::<EvmAddress>().unwrap())
+            .data(Bytes::from(data))
+            .nonce(0)
+            .gas(65_000)
+            .max_fee_per_gas(50_000_000_000u64)
+            .max_priority_fee_per_gas(30_000_000_000u64)
+            .chain_id(chain_id)
+            .into();
+        let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse::<LocalWallet>()
+            .unwrap()
+            .with_chain_id(chain_id);
+        let signature = wallet.sign_transaction_sync(&tx).unwrap();
+
+        hex::encode(tx.rlp_signed(&signature))
+    }
+
+    #[tokio::test]
+    async fn test_verifies_unmined_transfer_against_chain_id() {
+        let plugin = EvmTokenPlugin::new("POLYGON", "USDC", POLYGON_USDC, 6)
+            .with_rpc_url(&spawn_mock_polygon("0x89"));
+        let unmined = |txhex: String| Transaction { txhex, txid: Some("0xabc".to_string()), txkey: None };
+
+        assert!(plugin.verify_payment(&option(25_000_000), &unmined(signed_transfer(137, 25_000_000))).await.unwrap());
+        // Signed for Ethereum mainnet, so it can't pay on Polygon
+        assert!(plugin.verify_payment(&option(25_000_000), &unmined(signed_transfer(1, 25_000_000))).await.is_err());
+    }
+}
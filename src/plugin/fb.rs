@@ -41,6 +41,17 @@ impl Plugin for FractalBitcoinPlugin {
             return Ok(false);
         }
 
+        // A raw script option is paid by outputs carrying exactly that script
+        if let Some(script) = &payment_option.script {
+            let payment_script = bitcoin::ScriptBuf::from_hex(script)
+                .map_err(|e| anyhow!("Invalid output script {}: {}", script, e))?;
+            let paid_to_script: u64 = btc_tx.output.iter()
+                .filter(|output| output.script_pubkey == payment_script)
+                .map(|output| output.value.to_sat())
+                .sum();
+            return Ok(paid_to_script > 0 && paid_to_script >= expected_amount);
+        }
+
         // Parse the payment address
         let payment_address = BtcAddress::from_str(&payment_option.address)
             .map_err(|e| anyhow!("Invalid Fractal Bitcoin address: {}", e))?;
@@ -97,11 +108,23 @@ impl Plugin for FractalBitcoinPlugin {
     }
 
 
+    async fn estimate_fee(&self, _payment_option: &PaymentOption) -> Result<i64> {
+        super::estimate_mempool_fee("https://mempool.fractalbitcoin.io/api").await
+    }
+
+    async fn estimate_fee_rate(&self) -> Result<i64> {
+        super::mempool_fee_rate("https://mempool.fractalbitcoin.io/api").await
+    }
+
     async fn get_new_address(&self, _account: &Account, address: &Address) -> Result<String> {
         // TODO: Implement FB address generation
         Ok(address.value.clone())
     }
 
+    fn derive_address(&self, xpub: &str, index: u32) -> Result<String> {
+        super::derive_p2wpkh_address(xpub, index, bitcoin::Network::Bitcoin)
+    }
+
     async fn transform_address(&self, address: &str) -> Result<String> {
         Ok(address.split(':').last().unwrap_or(address).to_string())
     }
@@ -131,10 +154,11 @@ impl Plugin for FractalBitcoinPlugin {
     }
 
     async fn get_price(&self) -> Result<Price> {
-        // TODO: Implement price fetching from exchange
+        // FB isn't in the prices table, so it's quoted by the conversion API
+        let price = crate::prices::fetch_convert_to_usd(self.currency()).await?;
         Ok(Price {
             currency: self.currency().to_string(),
-            price: BigDecimal::from_str("15000.00")?,
+            price: BigDecimal::from_str(&price.to_string())?,
             timestamp: chrono::Utc::now().timestamp(),
         })
     }
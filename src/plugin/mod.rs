@@ -1,24 +1,58 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use bigdecimal::BigDecimal;
+use bip32::{ChildNumber, XPub};
 use chrono::Utc;
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use lazy_static::lazy_static;
+#[cfg(feature = "eth")]
+use crate::types::Coin;
+use crate::symbols::normalize;
 
+#[cfg(feature = "btc")]
 mod btc;
 mod bsv;
+#[cfg(feature = "eth")]
 mod eth;
+#[cfg(feature = "xrp")]
 mod xrp;
+#[cfg(feature = "sol")]
 mod sol;
+#[cfg(feature = "eth")]
 mod rlusd_eth;
+#[cfg(feature = "fb")]
 mod fb;
+mod xmr;
+#[cfg(feature = "eth")]
+mod avax;
+#[cfg(feature = "eth")]
+mod bnb;
+#[cfg(feature = "eth")]
+mod evm_token;
 
+#[cfg(feature = "btc")]
 pub use btc::BitcoinPlugin;
 pub use bsv::BitcoinSVPlugin;
+#[cfg(feature = "eth")]
 pub use eth::EthereumPlugin;
+#[cfg(feature = "xrp")]
 pub use xrp::RipplePlugin;
+#[cfg(feature = "sol")]
 pub use sol::SolanaPlugin;
+#[cfg(feature = "eth")]
 pub use rlusd_eth::RLUSDEthereumPlugin;
+#[cfg(feature = "fb")]
 pub use fb::FractalBitcoinPlugin;
+pub use xmr::{MoneroPlugin, MoneroAddressType};
+#[cfg(feature = "eth")]
+pub use avax::AvalanchePlugin;
+#[cfg(feature = "eth")]
+pub use bnb::BscPlugin;
+#[cfg(feature = "eth")]
+pub use evm_token::EvmTokenPlugin;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
@@ -41,6 +75,9 @@ pub struct PaymentOption {
     pub address: String,
     pub amount: i64,
     pub uri: Option<String>,
+    /// Hex-encoded output script paid instead of the address's, where the chain supports it
+    #[serde(default)]
+    pub script: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -72,6 +109,167 @@ pub struct Price {
     pub timestamp: i64,
 }
 
+/// mempool.space-compatible API used for bitcoin fee estimates, overridable with MEMPOOL_API_URL
+pub const MEMPOOL_API_URL: &str = "https://mempool.space/api";
+
+/// WhatsOnChain API BSV transactions are broadcast through, overridable with WHATSONCHAIN_API_URL
+pub const WHATSONCHAIN_API_URL: &str = "https://api.whatsonchain.com/v1/bsv/main";
+
+/// Ethereum JSON-RPC endpoint used for gas prices, overridable with ETH_RPC_URL
+pub const ETH_RPC_URL: &str = "https://cloudflare-eth.com";
+
+/// An EVM network tokens can be paid on, with its default JSON-RPC provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvmNetwork {
+    pub chain: &'static str,
+    pub chain_id: u64,
+    pub default_rpc_url: &'static str,
+}
+
+impl EvmNetwork {
+    /// The network's JSON-RPC endpoint, overridable with <CHAIN>_RPC_URL (e.g. POLYGON_RPC_URL)
+    pub fn rpc_url(&self) -> String {
+        std::env::var(format!("{}_RPC_URL", self.chain)).unwrap_or_else(|_| self.default_rpc_url.to_string())
+    }
+}
+
+/// Networks whose tokens are served by `EvmTokenPlugin`
+pub const EVM_NETWORKS: &[EvmNetwork] = &[
+    EvmNetwork { chain: "ETH", chain_id: 1, default_rpc_url: ETH_RPC_URL },
+    EvmNetwork { chain: "POLYGON", chain_id: 137, default_rpc_url: "https://polygon-rpc.com" },
+    EvmNetwork { chain: "AVAX", chain_id: 43114, default_rpc_url: "https://api.avax.network/ext/bc/C/rpc" },
+    EvmNetwork { chain: "BNB", chain_id: 56, default_rpc_url: "https://bsc-dataseed.binance.org" },
+];
+
+pub fn evm_network(chain: &str) -> Option<&'static EvmNetwork> {
+    EVM_NETWORKS.iter().find(|network| network.chain == chain)
+}
+
+/// Call a JSON-RPC method on an EVM node and return its result, which is null for unknown objects
+pub async fn evm_rpc(rpc_url: &str, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+    let response = reqwest::Client::new()
+        .post(rpc_url)
+        .json(&serde_json::json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }))
+        .send()
+        .await
+        .map_err(|e| anyhow!("{} request failed: {}", method, e))?
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| anyhow!("Failed to parse {} response: {}", method, e))?;
+
+    if let Some(error) = response.get("error") {
+        return Err(anyhow!("{} failed: {}", method, error));
+    }
+    Ok(response["result"].clone())
+}
+
+/// Virtual size of a typical one-input P2WPKH payment with a change output
+const TYPICAL_PAYMENT_VSIZE: i64 = 141;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecommendedFees {
+    half_hour_fee: i64,
+}
+
+/// Estimate the fee in sats for a typical payment at the half-hour rate reported by a
+/// mempool.space-compatible API rooted at `api_url` (e.g. https://mempool.space/api)
+pub async fn estimate_mempool_fee(api_url: &str) -> Result<i64> {
+    Ok(mempool_fee_rate(api_url).await? * TYPICAL_PAYMENT_VSIZE)
+}
+
+/// The half-hour fee rate in sat/vbyte reported by a mempool.space-compatible API rooted at `api_url`
+pub async fn mempool_fee_rate(api_url: &str) -> Result<i64> {
+    let response = reqwest::get(format!("{}/v1/fees/recommended", api_url.trim_end_matches('/'))).await
+        .map_err(|e| anyhow!("Failed to fetch fee rates: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to fetch fee rates: {}", response.text().await?));
+    }
+
+    let fees = response.json::<RecommendedFees>().await
+        .map_err(|e| anyhow!("Failed to parse fee rates: {}", e))?;
+    Ok(fees.half_hour_fee)
+}
+
+/// Broadcast a raw transaction through a mempool.space-compatible API rooted at `api_url`,
+/// returning the txid it reports
+pub async fn broadcast_mempool_tx(api_url: &str, txhex: &str) -> Result<String> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/tx", api_url.trim_end_matches('/')))
+        .header("Content-Type", "text/plain")
+        .body(txhex.to_string())
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to broadcast transaction: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Broadcast rejected: {}", response.text().await?));
+    }
+
+    Ok(response.text().await?.trim().to_string())
+}
+
+/// Estimate the fee in wei for a transaction using `gas_limit` gas at the current gas price
+pub async fn estimate_evm_fee(rpc_url: &str, gas_limit: i64) -> Result<i64> {
+    let response = reqwest::Client::new()
+        .post(rpc_url)
+        .json(&serde_json::json!({ "jsonrpc": "2.0", "method": "eth_gasPrice", "params": [], "id": 1 }))
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to fetch gas price: {}", e))?
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| anyhow!("Failed to parse gas price: {}", e))?;
+
+    let gas_price = response["result"].as_str()
+        .and_then(|price| i64::from_str_radix(price.trim_start_matches("0x"), 16).ok())
+        .ok_or_else(|| anyhow!("Invalid eth_gasPrice response: {}", response))?;
+
+    gas_price.checked_mul(gas_limit)
+        .ok_or_else(|| anyhow!("Fee estimate overflowed: {} gas at {} wei", gas_limit, gas_price))
+}
+
+/// Derive the public key at `index` on the receive chain (/0) of an account-level extended public key
+pub fn derive_receive_key(xpub: &str, index: u32) -> Result<XPub> {
+    let account_xpub = XPub::from_str(xpub)
+        .map_err(|e| anyhow!("Invalid extended public key: {}", e))?;
+
+    account_xpub
+        .derive_child(ChildNumber::new(0, false).map_err(|e| anyhow!("Invalid child number: {}", e))?)
+        .and_then(|receive| receive.derive_child(ChildNumber::new(index, false)?))
+        .map_err(|e| anyhow!("Failed to derive public key {}: {}", index, e))
+}
+
+/// Derive the P2WPKH address at `index` from an account-level xpub/zpub
+pub fn derive_p2wpkh_address(xpub: &str, index: u32, network: bitcoin::Network) -> Result<String> {
+    let child = derive_receive_key(xpub, index)?;
+    let public_key = bitcoin::PublicKey::from_slice(&child.to_bytes())
+        .map_err(|e| anyhow!("Failed to create public key: {}", e))?;
+    let address = bitcoin::Address::p2wpkh(&public_key, network)
+        .map_err(|e| anyhow!("Failed to create address: {}", e))?;
+
+    Ok(address.to_string())
+}
+
+/// Derive the legacy P2PKH address at `index` from an account-level xpub
+pub fn derive_p2pkh_address(xpub: &str, index: u32, network: bitcoin::Network) -> Result<String> {
+    let child = derive_receive_key(xpub, index)?;
+    let public_key = bitcoin::PublicKey::from_slice(&child.to_bytes())
+        .map_err(|e| anyhow!("Failed to create public key: {}", e))?;
+
+    Ok(bitcoin::Address::p2pkh(&public_key, network).to_string())
+}
+
+/// Derive the EIP-55 checksummed EVM address at `index` from an account-level xpub
+#[cfg(feature = "eth")]
+pub fn derive_evm_address(xpub: &str, index: u32) -> Result<String> {
+    let child = derive_receive_key(xpub, index)?;
+    let address = ethers::utils::public_key_to_address(child.public_key());
+
+    Ok(ethers::utils::to_checksum(&address, None))
+}
+
 #[async_trait]
 pub trait Plugin: Send + Sync {
     fn currency(&self) -> &str;
@@ -83,12 +281,27 @@ pub trait Plugin: Send + Sync {
     async fn get_transaction(&self, txid: &str) -> Result<Transaction>;
     async fn broadcast_tx(&self, txhex: &str, txid: Option<&str>, txkey: Option<&str>) -> Result<Transaction>;
     async fn get_new_address(&self, account: &Account, address: &Address) -> Result<String>;
+
+    /// Derive the receiving address at `index` from an account-level extended public key
+    fn derive_address(&self, _xpub: &str, _index: u32) -> Result<String> {
+        Err(anyhow!("Address derivation is not supported for {}", self.chain()))
+    }
     async fn transform_address(&self, address: &str) -> Result<String>;
     async fn get_confirmation(&self, txid: &str) -> Result<Option<Confirmation>>;
     async fn get_payments(&self, txid: &str) -> Result<Vec<Payment>>;
     async fn parse_payments(&self, transaction: &Transaction) -> Result<Vec<Payment>>;
     async fn get_price(&self) -> Result<Price>;
 
+    /// Estimate the network fee, in base units, a wallet should pay to settle `payment_option`
+    async fn estimate_fee(&self, _payment_option: &PaymentOption) -> Result<i64> {
+        Err(anyhow!("Fee estimation is not supported for {}", self.chain()))
+    }
+
+    /// Estimate the fee rate, in base units per virtual byte, for chains that price by size
+    async fn estimate_fee_rate(&self) -> Result<i64> {
+        Err(anyhow!("Fee rate estimation is not supported for {}", self.chain()))
+    }
+
     fn satoshis_to_decimal(&self, satoshis: i64) -> BigDecimal {
         let decimals = self.decimals() as u32;
         let divisor = BigDecimal::from(10i64.pow(decimals));
@@ -102,15 +315,168 @@ pub trait Plugin: Send + Sync {
     }
 }
 
+/// Builds a plugin for a (chain, currency) pair
+pub type PluginConstructor = Arc<dyn Fn() -> Box<dyn Plugin> + Send + Sync>;
+
+lazy_static! {
+    static ref PLUGIN_REGISTRY: RwLock<HashMap<(String, String), PluginConstructor>> = RwLock::new(default_plugins());
+}
+
+fn default_plugins() -> HashMap<(String, String), PluginConstructor> {
+    #[allow(unused_mut)]
+    let mut defaults: Vec<(&str, &str, PluginConstructor)> = vec![
+        ("BSV", "BSV", Arc::new(|| Box::new(BitcoinSVPlugin) as Box<dyn Plugin>)),
+        ("XMR", "XMR", Arc::new(|| Box::new(MoneroPlugin) as Box<dyn Plugin>)),
+    ];
+
+    // Chains left out of the build have no plugin, so get_plugin reports them as unsupported
+    #[cfg(feature = "btc")]
+    defaults.push(("BTC", "BTC", Arc::new(|| Box::new(BitcoinPlugin) as Box<dyn Plugin>)));
+    #[cfg(feature = "eth")]
+    defaults.extend([
+        ("ETH", "ETH", Arc::new(|| Box::new(EthereumPlugin) as Box<dyn Plugin>) as PluginConstructor),
+        ("ETH", "RLUSD", Arc::new(|| Box::new(RLUSDEthereumPlugin) as Box<dyn Plugin>)),
+        ("AVAX", "AVAX", Arc::new(|| Box::new(AvalanchePlugin) as Box<dyn Plugin>)),
+        ("BNB", "BNB", Arc::new(|| Box::new(BscPlugin) as Box<dyn Plugin>)),
+    ]);
+    #[cfg(feature = "xrp")]
+    defaults.push(("XRP", "XRP", Arc::new(|| Box::new(RipplePlugin) as Box<dyn Plugin>)));
+    #[cfg(feature = "sol")]
+    defaults.push(("SOL", "SOL", Arc::new(|| Box::new(SolanaPlugin) as Box<dyn Plugin>)));
+    #[cfg(feature = "fb")]
+    defaults.push(("FB", "FB", Arc::new(|| Box::new(FractalBitcoinPlugin) as Box<dyn Plugin>)));
+
+    defaults.into_iter()
+        .map(|(chain, currency, constructor)| (normalize(chain, currency), constructor))
+        .collect()
+}
+
+/// Register (or replace) the plugin used for a chain and currency
+pub fn register_plugin<F>(chain: &str, currency: &str, constructor: F)
+where
+    F: Fn() -> Box<dyn Plugin> + Send + Sync + 'static,
+{
+    PLUGIN_REGISTRY.write().unwrap()
+        .insert(normalize(chain, currency), Arc::new(constructor));
+}
+
+/// Register an `EvmTokenPlugin` for each token coin with a contract address that has no plugin
+/// yet, so tokens added to the coins table are supported without a code change.
+/// Returns how many plugins were registered.
+#[cfg(feature = "eth")]
+pub fn register_coin_plugins<'a>(coins: impl IntoIterator<Item = &'a Coin>) -> usize {
+    let mut registry = PLUGIN_REGISTRY.write().unwrap();
+    let mut registered = 0;
+
+    for coin in coins {
+        let key = normalize(&coin.chain, &coin.currency);
+        if registry.contains_key(&key) || evm_network(&coin.chain).is_none() {
+            continue;
+        }
+        let Some(contract_address) = coin.contract_address.clone() else {
+            continue;
+        };
+
+        let (chain, currency) = key.clone();
+        let decimals = coin.precision.unwrap_or(18) as u8;
+        registry.insert(key, Arc::new(move || Box::new(EvmTokenPlugin::new(&chain, &currency, &contract_address, decimals)) as Box<dyn Plugin>));
+        tracing::info!("Registered token plugin for {} on {}", coin.currency, coin.chain);
+        registered += 1;
+    }
+
+    registered
+}
+
+/// The plugin for a chain and currency, in any of the spellings `symbols::normalize` accepts
 pub fn get_plugin(chain: &str, currency: &str) -> Option<Box<dyn Plugin>> {
-    match (chain, currency) {
-        ("BTC", "BTC") => Some(Box::new(BitcoinPlugin)),
-        ("BSV", "BSV") => Some(Box::new(BitcoinSVPlugin)),
-        ("ETH", "ETH") => Some(Box::new(EthereumPlugin)),
-        ("ETH", "RLUSD") => Some(Box::new(RLUSDEthereumPlugin)),
-        ("XRP", "XRP") => Some(Box::new(RipplePlugin)),
-        ("SOL", "SOL") => Some(Box::new(SolanaPlugin)),
-        ("FB", "FB") => Some(Box::new(FractalBitcoinPlugin)),
-        _ => None,
-    }
-} 
\ No newline at end of file
+    let constructor = PLUGIN_REGISTRY.read().unwrap()
+        .get(&normalize(chain, currency))
+        .cloned()?;
+    Some(constructor())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "eth")]
+    fn token_coin(chain: &str, currency: &str, contract_address: Option<&str>) -> Coin {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "currency": currency,
+            "chain": chain,
+            "precision": 6,
+            "unavailable": false,
+            "uri_template": null,
+            "createdAt": "2024-01-01T00:00:00Z",
+            "updatedAt": "2024-01-01T00:00:00Z",
+            "supported": true,
+            "required_fee_rate": null,
+            "color": null,
+            "contract_address": contract_address
+        })).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "btc")]
+    fn test_btc_plugin_is_registered() {
+        let plugin = get_plugin("BTC", "BTC").unwrap();
+        assert_eq!((plugin.chain(), plugin.currency()), ("BTC", "BTC"));
+    }
+
+    #[test]
+    #[cfg(feature = "xrp")]
+    fn test_xrp_and_xrpl_resolve_the_ripple_plugin() {
+        for (chain, currency) in [("XRP", "XRP"), ("XRPL", "XRP"), ("xrpl", "xrp")] {
+            let plugin = get_plugin(chain, currency).unwrap();
+            assert_eq!(plugin.currency(), "XRP");
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "eth")]
+    async fn test_unverifiable_chains_refuse_payments() {
+        let option = PaymentOption {
+            chain: "ETH".to_string(),
+            currency: "ETH".to_string(),
+            address: "0x4B7115aD9623A528f1845eaf85D166dE1E869BFB".to_string(),
+            amount: 1_000,
+            uri: None,
+            script: None,
+        };
+        let transaction = Transaction { txhex: "0xdeadbeef".to_string(), txid: Some("0xabc".to_string()), txkey: None };
+
+        let plugin = get_plugin("ETH", "ETH").unwrap();
+        let error = plugin.verify_payment(&option, &transaction).await.unwrap_err();
+        assert!(error.to_string().contains("not supported"), "{}", error);
+        assert!(plugin.broadcast_tx(&transaction.txhex, transaction.txid.as_deref(), None).await.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "eth")]
+    fn test_defaults_are_registered() {
+        let plugin = get_plugin("ETH", "RLUSD").unwrap();
+        assert_eq!((plugin.chain(), plugin.currency()), ("ETH", "RLUSD"));
+        assert!(get_plugin("ETH", "DOESNOTEXIST").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "eth")]
+    fn test_register_token_coin_resolves_plugin() {
+        assert!(get_plugin("ETH", "PYUSD").is_none());
+
+        let coins = vec![
+            token_coin("ETH", "PYUSD", Some("0x6c3ea9036406852006290770BEdFcAbA0e23A0e8")),
+            // Without a contract there is nothing to build a plugin from
+            token_coin("ETH", "NOCONTRACT", None),
+            // Already registered, so the built-in plugin is kept
+            token_coin("ETH", "RLUSD", Some("0x8292Bb45bf1Ee4d140127049757C2E0fF06317eD")),
+        ];
+        assert_eq!(register_coin_plugins(&coins), 1);
+
+        let plugin = get_plugin("ETH", "PYUSD").unwrap();
+        assert_eq!((plugin.chain(), plugin.currency(), plugin.decimals()), ("ETH", "PYUSD", 6));
+        assert!(get_plugin("ETH", "NOCONTRACT").is_none());
+        assert_eq!(get_plugin("ETH", "RLUSD").unwrap().decimals(), 18);
+    }
+}
@@ -1,5 +1,5 @@
 use super::{Plugin, Account, Address, PaymentOption, Transaction, Payment, Confirmation, Price};
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use bigdecimal::BigDecimal;
 use std::str::FromStr;
 
@@ -20,9 +20,9 @@ impl Plugin for SolanaPlugin {
         })
     }
 
-    async fn verify_payment(&self, payment_option: &PaymentOption, transaction: &Transaction) -> Result<bool> {
-        // TODO: Implement Solana transaction verification
-        Ok(true)
+    async fn verify_payment(&self, _payment_option: &PaymentOption, _transaction: &Transaction) -> Result<bool> {
+        // Reporting an unchecked transaction as paid would settle invoices that weren't
+        Err(anyhow!("Verifying {} payments is not supported", self.chain()))
     }
 
     async fn validate_address(&self, address: &str) -> Result<bool> {
@@ -39,13 +39,8 @@ impl Plugin for SolanaPlugin {
         })
     }
 
-    async fn broadcast_tx(&self, txhex: &str, txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
-        // TODO: Implement Solana transaction broadcasting
-        Ok(Transaction {
-            txhex: txhex.to_string(),
-            txid: txid.map(String::from),
-            txkey: None,
-        })
+    async fn broadcast_tx(&self, _txhex: &str, _txid: Option<&str>, _txkey: Option<&str>) -> Result<Transaction> {
+        Err(anyhow!("Broadcasting {} transactions is not supported", self.chain()))
     }
 
     async fn get_new_address(&self, _account: &Account, address: &Address) -> Result<String> {
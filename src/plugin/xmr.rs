@@ -0,0 +1,174 @@
+use super::{Plugin, Account, Address, PaymentOption, Transaction, Payment, Confirmation, Price};
+use anyhow::{Result, anyhow};
+
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoneroAddressType {
+    Standard,
+    Integrated,
+    Subaddress,
+}
+
+/// Classify a Monero address by its length and network prefix character.
+/// Returns None if the address is not a well-formed mainnet, testnet or stagenet address.
+pub fn classify_address(address: &str) -> Option<MoneroAddressType> {
+    if !address.chars().all(|c| BASE58_ALPHABET.contains(c)) {
+        return None;
+    }
+
+    let prefix = address.chars().next()?;
+    match (address.len(), prefix) {
+        (95, '4') | (95, '9') | (95, 'A') | (95, '5') => Some(MoneroAddressType::Standard),
+        (95, '8') | (95, 'B') | (95, '7') => Some(MoneroAddressType::Subaddress),
+        (106, '4') | (106, 'A') | (106, '5') => Some(MoneroAddressType::Integrated),
+        _ => None,
+    }
+}
+
+pub struct MoneroPlugin;
+
+impl MoneroPlugin {
+    /// Build a monero: URI with the amount in XMR (tx_amount), per the Monero URI scheme
+    pub fn payment_uri(&self, address: &str, amount: i64) -> String {
+        format!("monero:{}?tx_amount={}", address, self.satoshis_to_decimal(amount).normalized())
+    }
+
+    fn wallet_rpc_url(&self) -> Result<String> {
+        std::env::var("MONERO_WALLET_RPC_URL")
+            .map_err(|_| anyhow!("MONERO_WALLET_RPC_URL environment variable not set"))
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for MoneroPlugin {
+    fn currency(&self) -> &str { "XMR" }
+    fn chain(&self) -> &str { "XMR" }
+    fn decimals(&self) -> u8 { 12 }
+
+    async fn build_signed_payment(&self, _payment_option: &PaymentOption, _mnemonic: &str) -> Result<Transaction> {
+        // TODO: Implement XMR transaction signing via wallet RPC
+        Err(anyhow!("XMR transaction signing is not implemented"))
+    }
+
+    async fn verify_payment(&self, payment_option: &PaymentOption, transaction: &Transaction) -> Result<bool> {
+        let txid = transaction.txid.as_deref()
+            .ok_or_else(|| anyhow!("XMR payment verification requires a txid"))?;
+        let txkey = transaction.txkey.as_deref()
+            .ok_or_else(|| anyhow!("XMR payment verification requires a txkey"))?;
+
+        // Ask the configured wallet RPC how much the tx key proves was sent to the address
+        let response = reqwest::Client::new()
+            .post(format!("{}/json_rpc", self.wallet_rpc_url()?.trim_end_matches('/')))
+            .json(&serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": "0",
+                "method": "check_tx_key",
+                "params": {
+                    "txid": txid,
+                    "tx_key": txkey,
+                    "address": payment_option.address
+                }
+            }))
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+
+        if let Some(error) = response.get("error") {
+            return Err(anyhow!("Monero wallet RPC error: {}", error));
+        }
+
+        let received = response["result"]["received"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("Missing received amount in check_tx_key response"))?;
+
+        Ok(received >= payment_option.amount as u64)
+    }
+
+    async fn validate_address(&self, address: &str) -> Result<bool> {
+        Ok(classify_address(address).is_some())
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<Transaction> {
+        // TODO: Implement XMR transaction fetching
+        Err(anyhow!("XMR transaction lookup is not implemented (txid {})", txid))
+    }
+
+    async fn broadcast_tx(&self, txhex: &str, txid: Option<&str>, txkey: Option<&str>) -> Result<Transaction> {
+        // Wallets usually relay their own transactions, which check_tx_key has then already found
+        if !txhex.is_empty() {
+            let daemon_url = std::env::var("MONERO_DAEMON_URL")
+                .map_err(|_| anyhow!("MONERO_DAEMON_URL environment variable not set"))?;
+            let response = reqwest::Client::new()
+                .post(format!("{}/send_raw_transaction", daemon_url.trim_end_matches('/')))
+                .json(&serde_json::json!({ "tx_as_hex": txhex }))
+                .send()
+                .await?
+                .json::<serde_json::Value>()
+                .await?;
+            if response["status"].as_str() != Some("OK") {
+                return Err(anyhow!("Monero daemon rejected the transaction: {}", response));
+            }
+        }
+
+        Ok(Transaction {
+            txhex: txhex.to_string(),
+            txid: txid.map(String::from),
+            txkey: txkey.map(String::from),
+        })
+    }
+
+    async fn get_new_address(&self, _account: &Account, address: &Address) -> Result<String> {
+        // TODO: Implement XMR subaddress generation
+        Ok(address.value.clone())
+    }
+
+    async fn transform_address(&self, address: &str) -> Result<String> {
+        Ok(address.split(':').last().unwrap_or(address).to_string())
+    }
+
+    async fn get_confirmation(&self, txid: &str) -> Result<Option<Confirmation>> {
+        // TODO: Implement XMR confirmation checking
+        Err(anyhow!("XMR confirmation checking is not implemented (txid {})", txid))
+    }
+
+    async fn get_payments(&self, txid: &str) -> Result<Vec<Payment>> {
+        // TODO: Implement XMR payment parsing
+        Err(anyhow!("XMR payment lookup is not implemented (txid {})", txid))
+    }
+
+    async fn parse_payments(&self, _transaction: &Transaction) -> Result<Vec<Payment>> {
+        // TODO: Implement XMR transaction parsing
+        Ok(vec![])
+    }
+
+    async fn get_price(&self) -> Result<Price> {
+        // TODO: Implement price fetching from exchange
+        Err(anyhow!("XMR price fetching is not implemented"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STANDARD: &str = "44AFFq5kSiGBoZ4NMDwYtN18obc8AemS33DBLWs3H7otXft3XjrpDtQGv7SqSsaBYBb98uNbr2VBBEt7f2wfn3RVGQBEP3A";
+    const INTEGRATED: &str = "4LL9oSLmtpccfufTMvppY6JwXNouMBzSkbLYfpAV5Usx3skxNgYeYTRj5UzqtReoS44qo9mtmXCqY45DJ852K5Jv2bYXZKKQePHES9khPK";
+    const SUBADDRESS: &str = "888tNkZrPN6JsEgekjMnABU4TBzc2Dt29EPAvkRxbANsAnjyPbb3iQ1YBRk1UXcdRsiKc9dhwMVgN5S9cQUiyoogDavup3H";
+
+    #[test]
+    fn test_classify_address() {
+        assert_eq!(classify_address(STANDARD), Some(MoneroAddressType::Standard));
+        assert_eq!(classify_address(INTEGRATED), Some(MoneroAddressType::Integrated));
+        assert_eq!(classify_address(SUBADDRESS), Some(MoneroAddressType::Subaddress));
+        assert_eq!(classify_address("1BoatSLRHtKNngkdXEeobR76b53LETtpyT"), None);
+        assert_eq!(classify_address(&STANDARD.replace('A', "0")), None);
+    }
+
+    #[test]
+    fn test_payment_uri() {
+        let uri = MoneroPlugin.payment_uri(STANDARD, 1_500_000_000_000);
+        assert_eq!(uri, format!("monero:{}?tx_amount=1.5", STANDARD));
+    }
+}
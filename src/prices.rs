@@ -1,14 +1,81 @@
+use std::collections::HashMap;
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use anyhow::Result;
+use crate::error::Error;
 use crate::supabase::SupabaseClient;
-use bigdecimal::BigDecimal;
+use bigdecimal::{BigDecimal, RoundingMode};
 use std::str::FromStr;
 use std::ops::{Mul, Div};
+use chrono::{DateTime, Utc};
 
 const MAX_DECIMALS: i32 = 8;
 
+/// Anypay's public conversion API, which also quotes coins missing from the prices table, such as FB
+pub const CONVERT_API_URL: &str = "https://api.anypayx.com";
+
+/// How long a USD price from the conversion API is reused before it's fetched again
+pub const CONVERT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// USD prices from the conversion API, keyed by API URL and currency, with when they were fetched
+    static ref CONVERT_CACHE: RwLock<HashMap<(String, String), (Instant, f64)>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Deserialize)]
+struct ConvertResponse {
+    conversion: ConvertConversion,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConvertConversion {
+    output: ConvertOutput,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConvertOutput {
+    value: f64,
+}
+
+/// The USD price of one `currency` from the conversion API, reused for CONVERT_CACHE_TTL
+pub async fn fetch_convert_to_usd(currency: &str) -> Result<f64> {
+    fetch_convert_to_usd_from(CONVERT_API_URL, currency).await
+}
+
+/// Like `fetch_convert_to_usd`, against the conversion API at `api_url`
+pub async fn fetch_convert_to_usd_from(api_url: &str, currency: &str) -> Result<f64> {
+    let key = (api_url.to_string(), currency.to_uppercase());
+    let cached = CONVERT_CACHE.read().unwrap().get(&key).copied();
+    if let Some((fetched_at, price)) = cached {
+        if fetched_at.elapsed() < CONVERT_CACHE_TTL {
+            return Ok(price);
+        }
+    }
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/convert/1-{}/to-USD", api_url, key.1))
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to fetch {} price: {}", key.1, e))?;
+
+    if !response.status().is_success() {
+        let error = response.text().await?;
+        anyhow::bail!("Failed to fetch {} price: {}", key.1, error);
+    }
+
+    let price = response.json::<ConvertResponse>().await
+        .map_err(|e| anyhow::anyhow!("Failed to parse {} price: {}", key.1, e))?
+        .conversion.output.value;
+
+    CONVERT_CACHE.write().unwrap().insert(key, (Instant::now(), price));
+    Ok(price)
+}
+
+/// Most decimals a conversion may be rounded to (wei)
+pub const MAX_PRECISION: i32 = 18;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Amount {
     pub currency: String,
@@ -20,6 +87,9 @@ pub struct ConversionRequest {
     pub quote_currency: String,
     pub base_currency: String,
     pub quote_value: f64,
+    /// Decimals to round the converted value to; the base coin's precision when unset
+    #[serde(default)]
+    pub precision: Option<i32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,6 +99,11 @@ pub struct ConversionResult {
     pub quote_value: f64,
     pub base_value: f64,
     pub timestamp: String,
+    /// When the price the conversion used was last updated
+    pub price_updated_at: String,
+    /// Seconds between that update and the conversion, so clients can judge whether to trust it;
+    /// None when the update time couldn't be parsed
+    pub price_age_seconds: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,69 +124,137 @@ pub struct Conversion {
     pub source: String,
 }
 
+/// How old a price may be before a lower priority source is preferred, when PRICE_MAX_AGE_SECONDS is not set
+pub const DEFAULT_PRICE_MAX_AGE_SECONDS: i64 = 600;
+
+/// The order price sources are preferred in when a pair is quoted by more than one, and how long
+/// a price stays fresh. A stale or missing price from one source falls back to the next.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceSources {
+    /// Sources in order of preference; unlisted sources rank after every listed one
+    pub priority: Vec<String>,
+    pub max_age: chrono::Duration,
+}
+
+impl Default for PriceSources {
+    fn default() -> Self {
+        PriceSources {
+            priority: Vec::new(),
+            max_age: chrono::Duration::seconds(DEFAULT_PRICE_MAX_AGE_SECONDS),
+        }
+    }
+}
+
+impl PriceSources {
+    fn rank(&self, price: &crate::types::Price) -> usize {
+        price.source.as_ref()
+            .and_then(|source| self.priority.iter().position(|preferred| preferred.eq_ignore_ascii_case(source)))
+            .unwrap_or(self.priority.len())
+    }
+
+    fn is_fresh(&self, price: &crate::types::Price, now: DateTime<Utc>) -> bool {
+        updated_at(price).is_some_and(|updated_at| now.signed_duration_since(updated_at) <= self.max_age)
+    }
+
+    /// The fresh price from the most preferred source, else the most recently updated one
+    pub fn select(&self, mut prices: Vec<crate::types::Price>, now: DateTime<Utc>) -> Option<crate::types::Price> {
+        prices.sort_by(|a, b| self.rank(a).cmp(&self.rank(b)).then_with(|| updated_at(b).cmp(&updated_at(a))));
+
+        if let Some(index) = prices.iter().position(|price| self.is_fresh(price, now)) {
+            return Some(prices.swap_remove(index));
+        }
+
+        let latest = prices.into_iter().max_by_key(updated_at)?;
+        tracing::warn!("Every {} price is stale, using the latest from {}", latest.currency, latest.source.as_deref().unwrap_or("unknown source"));
+        Some(latest)
+    }
+}
+
+fn updated_at(price: &crate::types::Price) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&price.updated_at).ok().map(|updated_at| updated_at.with_timezone(&Utc))
+}
+
+/// Round to `precision` decimals, with ties rounded away from zero
+pub fn round_half_up(value: &BigDecimal, precision: i32) -> BigDecimal {
+    value.with_scale_round(precision.into(), RoundingMode::HalfUp)
+}
+
+/// Round an f64 amount to `precision` decimals, with ties rounded away from zero
+pub fn round_value(value: f64, precision: i32) -> Result<f64> {
+    Ok(round_half_up(&BigDecimal::from_str(&value.to_string())?, precision).to_string().parse::<f64>()?)
+}
+
+/// The requested precision, else the base coin's, else MAX_DECIMALS
+async fn conversion_precision(req: &ConversionRequest, supabase: &SupabaseClient) -> Result<i32> {
+    let precision = match req.precision {
+        Some(precision) => precision,
+        None => match supabase.get_coin(&req.base_currency, &req.base_currency).await {
+            Ok(Some(coin)) => coin.precision.unwrap_or(MAX_DECIMALS),
+            _ => MAX_DECIMALS,
+        },
+    };
+
+    if !(0..=MAX_PRECISION).contains(&precision) {
+        anyhow::bail!("Invalid precision {}: expected 0 to {}", precision, MAX_PRECISION);
+    }
+    Ok(precision)
+}
+
+fn decimal(value: f64) -> Result<BigDecimal> {
+    Ok(BigDecimal::from_str(&value.to_string())?)
+}
+
+/// `quote_value` times `rate`, rounded half up to `precision` decimals
+fn apply_rate(quote_value: f64, rate: &BigDecimal, precision: i32) -> Result<f64> {
+    Ok(round_half_up(&rate.mul(decimal(quote_value)?), precision)
+        .to_string()
+        .parse::<f64>()?)
+}
+
 pub async fn convert(
     req: ConversionRequest,
     supabase: &SupabaseClient,
-) -> Result<ConversionResult> {
-
-    // Try to find direct price
-    let price = supabase.find_price(
-        &req.base_currency,
-        &req.quote_currency
-    ).await.unwrap();
-
-    if let Some(price) = price {
-        let base_value = BigDecimal::from_str(&req.quote_value.to_string())?
-            .mul(BigDecimal::from_str(&price.value.to_string())?)
-            .with_scale(MAX_DECIMALS.into())
-            .to_string()
-            .parse::<f64>()?;
-
-        return Ok(ConversionResult {
-            quote_currency: req.quote_currency,
-            base_currency: req.base_currency,
-            quote_value: req.quote_value,
-            base_value,
-            timestamp: chrono::Utc::now().to_rfc3339(),
-        });
-    }
+) -> crate::error::Result<ConversionResult> {
+    let precision = conversion_precision(&req, supabase).await?;
 
-    // Try inverse price
-    let inverse = supabase.find_price(
-        &req.quote_currency,
-        &req.base_currency
-    ).await.unwrap();
-
-    if let Some(inverse) = inverse {
-        let price = BigDecimal::from_str("1")?
-            .div(BigDecimal::from_str(&inverse.value.to_string())?);
-            
-        let base_value = price
-            .mul(BigDecimal::from_str(&req.quote_value.to_string())?)
-            .with_scale(MAX_DECIMALS.into())
-            .to_string()
-            .parse::<f64>()?;
-
-        return Ok(ConversionResult {
-            quote_currency: req.quote_currency,
-            base_currency: req.base_currency,
-            quote_value: req.quote_value,
-            base_value,
-            timestamp: chrono::Utc::now().to_rfc3339(),
-        });
-    }
+    // Use the direct price, else the inverse of the opposite one
+    let direct = supabase.find_price(&req.base_currency, &req.quote_currency).await
+        .map_err(Error::Db)?;
+    let (rate, price) = match direct {
+        Some(price) => (decimal(price.value)?, price),
+        None => {
+            let inverse = supabase.find_price(&req.quote_currency, &req.base_currency).await
+                .map_err(Error::Db)?
+                .ok_or_else(|| Error::PriceNotFound {
+                    from: req.quote_currency.clone(),
+                    to: req.base_currency.clone(),
+                })?;
+            if inverse.value == 0.0 {
+                return Err(anyhow::anyhow!("Price of {} in {} is zero", req.base_currency, req.quote_currency).into());
+            }
+            (BigDecimal::from(1).div(decimal(inverse.value)?), inverse)
+        }
+    };
 
-    anyhow::bail!(
-        "No price for {} to {}", 
-        req.quote_currency, 
-        req.base_currency
-    )
+    let base_value = apply_rate(req.quote_value, &rate, precision)?;
+    let now = Utc::now();
+    let price_age_seconds = updated_at(&price)
+        .map(|updated_at| now.signed_duration_since(updated_at).num_seconds().max(0));
+    Ok(ConversionResult {
+        quote_currency: req.quote_currency,
+        base_currency: req.base_currency,
+        quote_value: req.quote_value,
+        base_value,
+        timestamp: now.to_rfc3339(),
+        price_updated_at: price.updated_at,
+        price_age_seconds,
+    })
 }
 
 pub async fn create_conversion(
     req: ConversionRequest,
     supabase: &SupabaseClient,
-) -> Result<Conversion> {
+) -> crate::error::Result<Conversion> {
     let result = convert(req, supabase).await?;
     
     Ok(Conversion {
@@ -122,4 +265,141 @@ pub async fn create_conversion(
         timestamp: result.timestamp,
         source: "anypay".to_string(), // Or get this from the price record
     })
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Json, Router};
+    use serde_json::json;
+    use crate::supabase::tests::spawn_mock_supabase;
+
+    fn request(precision: Option<i32>) -> ConversionRequest {
+        ConversionRequest {
+            quote_currency: "USD".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_value: 1.0,
+            precision,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_convert_rounds_to_requested_precision() {
+        let router = Router::new().route("/rest/v1/prices", get(|| async {
+            Json(json!([{
+                "id": 1,
+                "currency": "USD",
+                "value": 0.123456785,
+                "createdAt": "2024-01-01T00:00:00Z",
+                "updatedAt": "2024-01-01T00:00:00Z"
+            }]))
+        }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let cents = convert(request(Some(2)), &supabase).await.unwrap();
+        let satoshis = convert(request(Some(8)), &supabase).await.unwrap();
+
+        assert_eq!(cents.base_value, 0.12);
+        // The tie rounds up rather than to the even digit
+        assert_eq!(satoshis.base_value, 0.12345679);
+        assert!(convert(request(Some(MAX_PRECISION + 1)), &supabase).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_price_is_price_not_found() {
+        let router = Router::new().route("/rest/v1/prices", get(|| async { Json(json!([])) }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        match convert(request(Some(8)), &supabase).await {
+            Err(Error::PriceNotFound { from, to }) => assert_eq!((from.as_str(), to.as_str()), ("USD", "BTC")),
+            other => panic!("expected PriceNotFound, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conversion_reports_age_of_price() {
+        let updated_at = (chrono::Utc::now() - chrono::Duration::hours(2)).to_rfc3339();
+        let router = Router::new().route("/rest/v1/prices", get({
+            let updated_at = updated_at.clone();
+            move || async move {
+                Json(json!([{
+                    "id": 1,
+                    "currency": "USD",
+                    "value": 0.00002,
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": updated_at
+                }]))
+            }
+        }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let result = convert(request(Some(8)), &supabase).await.unwrap();
+
+        assert_eq!(result.price_updated_at, updated_at);
+        let age = result.price_age_seconds.unwrap();
+        assert!((7_200..7_260).contains(&age), "unexpected price age: {}", age);
+    }
+
+    #[tokio::test]
+    async fn test_stale_preferred_source_falls_back_to_next() {
+        let router = Router::new().route("/rest/v1/prices", get(|| async {
+            let price = |id: i64, source: &str, value: f64, updated_at: String| json!({
+                "id": id,
+                "currency": "USD",
+                "value": value,
+                "source": source,
+                "createdAt": "2024-01-01T00:00:00Z",
+                "updatedAt": updated_at
+            });
+            Json(json!([
+                price(1, "coinbase", 0.00002, "2024-01-01T00:00:00Z".to_string()),
+                price(2, "kraken", 0.00003, chrono::Utc::now().to_rfc3339()),
+            ]))
+        }));
+        let sources = PriceSources {
+            priority: vec!["coinbase".to_string(), "kraken".to_string()],
+            ..PriceSources::default()
+        };
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service")
+            .with_price_sources(sources);
+
+        let result = convert(request(Some(8)), &supabase).await.unwrap();
+
+        assert_eq!(result.base_value, 0.00003);
+    }
+
+    #[tokio::test]
+    async fn test_convert_to_usd_is_parsed_and_cached() {
+        use axum::extract::Path;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let router = Router::new().route("/convert/:quote/to-USD", get({
+            let requests = requests.clone();
+            move |Path(quote): Path<String>| async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(quote, "1-FB");
+                Json(json!({
+                    "conversion": {
+                        "input": { "currency": "FB", "value": 1 },
+                        "output": { "currency": "USD", "value": 0.85 },
+                        "timestamp": "2024-01-01T00:00:00Z"
+                    }
+                }))
+            }
+        }));
+        let url = spawn_mock_supabase(router);
+
+        assert_eq!(fetch_convert_to_usd_from(&url, "FB").await.unwrap(), 0.85);
+        assert_eq!(fetch_convert_to_usd_from(&url, "fb").await.unwrap(), 0.85);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_round_value_half_up() {
+        assert_eq!(round_value(2.345, 2).unwrap(), 2.35);
+        assert_eq!(round_value(2.5, 0).unwrap(), 3.0);
+        assert_eq!(round_value(-2.5, 0).unwrap(), -3.0);
+    }
+}
@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Burst allowed when RATE_LIMIT_BURST is not set
+pub const DEFAULT_RATE_LIMIT_BURST: u32 = 20;
+
+/// Clients tracked by a RateLimiter before buckets that have refilled are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// How many requests a client may make: bursts of up to `burst`, refilled at `per_second`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// A token bucket for one client, holding up to `burst` tokens and spending one per request
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self::new_at(limit, Instant::now())
+    }
+
+    fn new_at(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket { limit, tokens: limit.burst as f64, refilled_at: now }
+    }
+
+    /// Spend a token, or return how long until one is available
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.limit.per_second))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.refilled_at = now;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.limit.burst as f64
+    }
+}
+
+/// Token buckets keyed by client, e.g. by IP address
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter { limit, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Spend one of `client`'s tokens, or return how long until it may make another request
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // A full bucket is the same as no bucket, so those are dropped to bound memory
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        buckets.entry(client.to_string())
+            .or_insert_with(|| TokenBucket::new_at(self.limit, now))
+            .try_acquire_at(now)
+    }
+}
+
+/// Milliseconds to wait before retrying, rounded up so a client retrying on time isn't throttled again
+pub fn retry_after_ms(retry_after: Duration) -> u64 {
+    retry_after.as_micros().div_ceil(1000) as u64
+}
+
+/// Whole seconds for the Retry-After header, rounded up and at least one
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after_ms(retry_after).div_ceil(1000).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_reports_time_until_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(RateLimit { burst: 2, per_second: 4.0 }, start);
+
+        assert!(bucket.try_acquire_at(start).is_ok());
+        assert!(bucket.try_acquire_at(start).is_ok());
+        assert_eq!(bucket.try_acquire_at(start), Err(Duration::from_millis(250)));
+
+        // Half a token has refilled, so half the wait remains
+        let later = start + Duration::from_millis(125);
+        assert_eq!(bucket.try_acquire_at(later), Err(Duration::from_millis(125)));
+        assert!(bucket.try_acquire_at(start + Duration::from_millis(250)).is_ok());
+    }
+}
@@ -6,49 +6,114 @@ use tokio_tungstenite::{
     accept_hdr_async,
     tungstenite::handshake::server::{Request, Response, ErrorResponse},
 };
-use futures::{StreamExt, SinkExt};
+use futures::{Sink, StreamExt, SinkExt};
+use futures::channel::mpsc::Receiver;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
 use uuid::Uuid;
 use serde_json::json;
 
 use crate::event_dispatcher::EventDispatcher;
 use crate::payment_options::create_payment_options;
-use crate::session::Session;
-use crate::types::Message;
+use crate::session::{record_sessions_reaped, BackpressurePolicy, Session, DEFAULT_MAX_SUBSCRIPTIONS, DEFAULT_SEND_BUFFER};
+use crate::types::{parse_message, Invoice, Message, PaymentOption, Subscription, DEFAULT_PRICES_PAGE_SIZE, MAX_PRICES_PAGE_SIZE};
 use crate::supabase::SupabaseClient;
-use crate::prices::{ConversionRequest, convert};
+use crate::prices::{ConversionRequest, PriceSources, convert};
 use crate::invoices;
+use crate::rate_limit::{retry_after_ms, RateLimit};
 use anyhow::Result;
 
+/// How often sessions left behind by missed cleanup are swept
+pub const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An invoice with its payment options, as sent to websocket clients
+fn invoice_snapshot((invoice, payment_options): (Invoice, Vec<PaymentOption>)) -> serde_json::Value {
+    json!({
+        "invoice": invoice,
+        "payment_options": payment_options
+    })
+}
+
 pub struct AnypayEventsServer {
     event_dispatcher: Arc<EventDispatcher>,
     sessions: Arc<RwLock<HashMap<Uuid, Session>>>,
     addr: String,
     supabase: Arc<SupabaseClient>,
+    send_buffer: usize,
+    backpressure: BackpressurePolicy,
+    max_subscriptions: usize,
+    rate_limit: Option<RateLimit>,
 }
 
 impl AnypayEventsServer {
     pub fn new(addr: &str, supabase_url: &str, supabase_anon_key: &str, supabase_service_role_key: &str) -> Self {
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let supabase = SupabaseClient::new(supabase_url, supabase_anon_key, supabase_service_role_key)
+            .with_event_dispatcher(event_dispatcher.clone());
+
         AnypayEventsServer {
-            event_dispatcher: Arc::new(EventDispatcher::new()),
+            event_dispatcher,
             sessions: Arc::new(RwLock::new(HashMap::new())),
             addr: addr.to_string(),
-            supabase: Arc::new(SupabaseClient::new(supabase_url, supabase_anon_key, supabase_service_role_key)),
+            supabase: Arc::new(supabase),
+            send_buffer: DEFAULT_SEND_BUFFER,
+            backpressure: BackpressurePolicy::Disconnect,
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            rate_limit: None,
         }
     }
 
+    /// Share `event_dispatcher` with the rest of the server, so the invoice events this server's
+    /// client publishes reach the same subscribers
+    pub fn with_event_dispatcher(mut self, event_dispatcher: Arc<EventDispatcher>) -> Self {
+        self.supabase = Arc::new(self.supabase.as_ref().clone().with_event_dispatcher(event_dispatcher.clone()));
+        self.event_dispatcher = event_dispatcher;
+        self
+    }
+
+    /// Set how many outbound messages each session buffers and what happens when a client falls behind
+    pub fn with_backpressure(mut self, send_buffer: usize, backpressure: BackpressurePolicy) -> Self {
+        self.send_buffer = send_buffer;
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Set how many subscriptions each session may hold before further subscribes are rejected
+    pub fn with_max_subscriptions(mut self, max_subscriptions: usize) -> Self {
+        self.max_subscriptions = max_subscriptions;
+        self
+    }
+
+    /// Prefer prices from sources in the order of `price_sources` when converting
+    pub fn with_price_sources(mut self, price_sources: PriceSources) -> Self {
+        self.supabase = Arc::new(self.supabase.as_ref().clone().with_price_sources(price_sources));
+        self
+    }
+
+    /// Limit the messages each session may send to `rate_limit`, or not at all when None
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
     pub async fn run(&self) -> Result<()> {
         let listener = TcpListener::bind(&self.addr).await?;
         tracing::info!("WebSocket server listening on: {}", self.addr);
 
+        self.spawn_session_sweeper(SESSION_SWEEP_INTERVAL);
+
         while let Ok((stream, addr)) = listener.accept().await {
             tracing::info!("New connection from: {}", addr);
             
             let event_dispatcher = self.event_dispatcher.clone();
             let sessions = self.sessions.clone();
             let supabase = self.supabase.clone();
+            let (send_buffer, backpressure, max_subscriptions, rate_limit) = (self.send_buffer, self.backpressure, self.max_subscriptions, self.rate_limit);
             
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(stream, event_dispatcher, sessions, supabase).await {
+                if let Err(e) = Self::handle_connection(stream, event_dispatcher, sessions, supabase, send_buffer, backpressure, max_subscriptions, rate_limit).await {
                     tracing::error!("Error handling connection: {}", e);
                 }
             });
@@ -57,37 +122,133 @@ impl AnypayEventsServer {
         Ok(())
     }
 
+    /// Remove sessions whose channel has closed, then any subscriptions still held by sessions that are
+    /// gone, returning how many sessions were removed
+    async fn sweep_sessions(sessions: &RwLock<HashMap<Uuid, Session>>, event_dispatcher: &EventDispatcher) -> usize {
+        let (live, reaped) = {
+            let mut sessions = sessions.write().await;
+            let before = sessions.len();
+            sessions.retain(|_, session| !session.is_closed());
+            let reaped = before - sessions.len();
+            if reaped > 0 {
+                record_sessions_reaped(reaped);
+            }
+            (sessions.keys().copied().collect::<HashSet<_>>(), reaped)
+        };
+
+        let dangling = event_dispatcher.retain_sessions(&live).await;
+        if reaped > 0 || dangling > 0 {
+            tracing::info!("Reaped {} closed sessions and {} dangling subscriptions", reaped, dangling);
+        }
+        reaped
+    }
+
+    /// Periodically sweep closed sessions the connection handlers failed to clean up
+    fn spawn_session_sweeper(&self, every: Duration) {
+        let sessions = self.sessions.clone();
+        let event_dispatcher = self.event_dispatcher.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                Self::sweep_sessions(&sessions, &event_dispatcher).await;
+            }
+        });
+    }
+
+    /// The error sent for a message over the session's rate limit, with when the next will be allowed
+    fn rate_limited(retry_after: Duration) -> serde_json::Value {
+        let retry_after_ms = retry_after_ms(retry_after);
+        json!({
+            "status": "error",
+            "message": format!("Rate limit exceeded, retry in {} ms", retry_after_ms),
+            "retry_after_ms": retry_after_ms
+        })
+    }
+
     async fn handle_message(
         message: Message,
-        session: &Session,
+        session: &mut Session,
         event_dispatcher: &Arc<EventDispatcher>,
         supabase: &Arc<SupabaseClient>,
     ) -> serde_json::Value {
         println!("message in handle message: {:?}", message);
+        match message {
+            Message::Batch { actions } => {
+                // parse_message bounds the batch size and rejects nested batches. Each action's
+                // result stands alone, so a failure is reported in its slot and the rest still run.
+                let mut results = Vec::with_capacity(actions.len());
+                for action in actions {
+                    results.push(Self::handle_action(action, session, event_dispatcher, supabase).await);
+                }
+                json!({
+                    "status": "success",
+                    "data": results
+                })
+            }
+            message => Self::handle_action(message, session, event_dispatcher, supabase).await,
+        }
+    }
+
+    async fn handle_action(
+        message: Message,
+        session: &mut Session,
+        event_dispatcher: &Arc<EventDispatcher>,
+        supabase: &Arc<SupabaseClient>,
+    ) -> serde_json::Value {
         match message {
             Message::Subscribe { sub_type, id } => {
+                let subscription = Subscription { sub_type: sub_type.clone(), id: id.clone() };
+                if session.exceeds_subscription_limit(&subscription) {
+                    return json!({
+                        "status": "error",
+                        "message": format!("Subscription limit of {} reached, unsubscribe before subscribing to {} {}", session.max_subscriptions, sub_type, id)
+                    });
+                }
+
                 event_dispatcher.subscribe(session.clone(), &sub_type, &id).await;
-                json!({
+                session.add_subscription(subscription);
+                let mut response = json!({
                     "status": "success",
                     "message": format!("Subscribed to {} {}", sub_type, id)
-                })
+                });
+
+                // Include the current state so the client needn't fetch it separately
+                if sub_type == "invoice" {
+                    response["data"] = match supabase.get_invoice(&id, true).await {
+                        Ok(Some(invoice)) => invoice_snapshot(invoice),
+                        Ok(None) => serde_json::Value::Null,
+                        Err(e) => {
+                            tracing::warn!("Failed to fetch invoice {} for subscription: {}", id, e);
+                            serde_json::Value::Null
+                        }
+                    };
+                }
+                response
             }
             Message::Unsubscribe { sub_type, id } => {
                 event_dispatcher.unsubscribe(session.clone(), &sub_type, &id).await;
+                session.remove_subscription(&Subscription { sub_type: sub_type.clone(), id: id.clone() });
                 json!({
                     "status": "success",
                     "message": format!("Unsubscribed from {} {}", sub_type, id)
                 })
             }
+            Message::UnsubscribeAll => {
+                let removed = event_dispatcher.unsubscribe_all(session.id).await;
+                session.subscriptions.clear();
+                json!({
+                    "status": "success",
+                    "message": format!("Unsubscribed from {} subscriptions", removed)
+                })
+            }
             Message::FetchInvoice { id } => {
                 tracing::info!("Fetching invoice with id: {}", id);
                 match supabase.get_invoice(&id, true).await {
                     Ok(Some(invoice)) => json!({
                         "status": "success",
-                        "data": {
-                            "invoice": invoice.0,
-                            "payment_options": invoice.1
-                        }
+                        "data": invoice_snapshot(invoice)
                     }),
                     Ok(None) => json!({
                         "status": "error",
@@ -99,7 +260,7 @@ impl AnypayEventsServer {
                     }),
                 }
             }
-            Message::CreateInvoice { amount, currency, webhook_url, redirect_url, memo } => {
+            Message::CreateInvoice { amount, currency, webhook_url, redirect_url, memo, accepted_currencies, crypto_denominated } => {
                 if let Some(account_id) = session.account_id {
                     println!("account_id in create invoice: {:?}", account_id);
                     match invoices::create_invoice(
@@ -109,7 +270,9 @@ impl AnypayEventsServer {
                         account_id,
                         webhook_url,
                         redirect_url,
-                        memo
+                        memo,
+                        accepted_currencies,
+                        crypto_denominated
                     ).await {
                         Ok(invoice) => json!({
                             "status": "success",
@@ -127,7 +290,8 @@ impl AnypayEventsServer {
                     })
                 }
             }
-            Message::ListPrices => {
+            // Without paging fields every price is sent, as before cursors existed
+            Message::ListPrices { limit: None, cursor: None } => {
                 tracing::info!("Listing all prices");
                 match supabase.list_prices().await {
                     Ok(prices) => json!({
@@ -140,11 +304,40 @@ impl AnypayEventsServer {
                     }),
                 }
             }
-            Message::ConvertPrice { quote_currency, base_currency, quote_value } => {
+            Message::ListPrices { limit, cursor } => {
+                let after = match cursor.as_deref().map(str::parse::<i64>).transpose() {
+                    Ok(after) => after,
+                    Err(_) => return json!({
+                        "status": "error",
+                        "message": format!("Invalid cursor: {}", cursor.unwrap_or_default())
+                    }),
+                };
+                let limit = limit.unwrap_or(DEFAULT_PRICES_PAGE_SIZE).clamp(1, MAX_PRICES_PAGE_SIZE);
+
+                match supabase.list_prices_page(limit, after).await {
+                    Ok((prices, has_more)) => {
+                        // The cursor is the id of the last price sent
+                        let next_cursor = prices.last()
+                            .filter(|_| has_more)
+                            .map(|price| price.id.to_string());
+                        json!({
+                            "status": "success",
+                            "data": prices,
+                            "next_cursor": next_cursor
+                        })
+                    }
+                    Err(e) => json!({
+                        "status": "error",
+                        "message": format!("Error fetching prices: {}", e)
+                    }),
+                }
+            }
+            Message::ConvertPrice { quote_currency, base_currency, quote_value, precision } => {
                 let req = ConversionRequest {
                     quote_currency,
                     base_currency,
                     quote_value,
+                    precision,
                 };
                 
                 match convert(req, supabase).await {
@@ -189,17 +382,70 @@ impl AnypayEventsServer {
                     "timestamp": chrono::Utc::now().timestamp()
                 })
             },
+            Message::Resume { token } => {
+                let (token, restored) = event_dispatcher.resume(session, token.as_deref()).await;
+                let subscriptions = restored.iter()
+                    .map(|subscription| json!({ "type": subscription.sub_type, "id": subscription.id }))
+                    .collect::<Vec<_>>();
+                json!({
+                    "status": "success",
+                    "data": {
+                        "token": token,
+                        "subscriptions": subscriptions
+                    }
+                })
+            }
+            Message::Batch { .. } => {
+                json!({
+                    "status": "error",
+                    "message": "Batches cannot be nested"
+                })
+            }
         }
     }
 
+    fn spawn_send_task<S>(
+        queue: Arc<std::sync::Mutex<Receiver<WsMessage>>>,
+        mut ws_sender: S,
+        is_connected: Arc<AtomicBool>,
+    ) -> tokio::task::JoinHandle<()>
+    where
+        S: Sink<WsMessage> + Unpin + Send + 'static,
+        S::Error: std::fmt::Display,
+    {
+        tokio::spawn(async move {
+            // The lock is only held while polling, so the session can drop queued messages in between
+            while let Some(message) = futures::future::poll_fn(|cx| queue.lock().unwrap().poll_next_unpin(cx)).await {
+                if !is_connected.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Err(e) = ws_sender.send(message).await {
+                    tracing::debug!("Connection closed by client: {}", e);
+                    break;
+                }
+            }
+
+            // The channel closes when the session is dropped or disconnected for backpressure
+            let _ = ws_sender.close().await;
+        })
+    }
+
     async fn handle_connection(
         stream: TcpStream,
         event_dispatcher: Arc<EventDispatcher>,
         sessions: Arc<RwLock<HashMap<Uuid, Session>>>,
         supabase: Arc<SupabaseClient>,
+        send_buffer: usize,
+        backpressure: BackpressurePolicy,
+        max_subscriptions: usize,
+        rate_limit: Option<RateLimit>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
-        let mut session = Session::new(Uuid::new_v4(), sender);
+        let (sender, receiver) = futures::channel::mpsc::channel(send_buffer);
+        let queue = Arc::new(std::sync::Mutex::new(receiver));
+        let mut session = Session::new(Uuid::new_v4(), sender, backpressure)
+            .with_queue(queue.clone())
+            .with_max_subscriptions(max_subscriptions)
+            .with_rate_limit(rate_limit);
         let supabase_clone = supabase.clone();
 
         let ws_stream = accept_hdr_async(stream, |req: &Request, res: Response| {
@@ -229,29 +475,16 @@ impl AnypayEventsServer {
             }
         }
 
-        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
-        session.sender = Some(sender).unwrap();
+        let (ws_sender, mut ws_receiver) = ws_stream.split();
 
         // Store the session
         sessions.write().await.insert(session.id, session.clone());
 
         // Create a flag to track connection state
-        let is_connected = Arc::new(std::sync::atomic::AtomicBool::new(true));
-        let is_connected_clone = is_connected.clone();
+        let is_connected = Arc::new(AtomicBool::new(true));
 
-        // Spawn a task to forward messages from the channel to the websocket
-        let _send_task = tokio::spawn(async move {
-            while let Some(message) = receiver.next().await {
-                if !is_connected_clone.load(std::sync::atomic::Ordering::SeqCst) {
-                    break;
-                }
-                if let Err(e) = ws_sender.send(message).await {
-                    tracing::debug!("Connection closed by client: {}", e);
-                    break;
-                }
-            }
-        });
+        // Forward messages sent through the session's channel to the websocket
+        let _send_task = Self::spawn_send_task(queue, ws_sender, is_connected.clone());
 
         // Handle incoming messages
         while let Some(msg) = ws_receiver.next().await {
@@ -259,18 +492,19 @@ impl AnypayEventsServer {
                 Ok(msg) => {
                     if let Ok(text) = msg.to_text() {
                         println!("text in handle connection: {:?}", text);
-                        let response = match serde_json::from_str::<Message>(text) {
-                            Ok(message) => {
+                        let response = match session.check_rate_limit().map(|()| parse_message(text)) {
+                            Err(retry_after) => Self::rate_limited(retry_after),
+                            Ok(Ok(message)) => {
                                 Self::handle_message(
                                     message,
-                                    &session,
+                                    &mut session,
                                     &event_dispatcher,
                                     &supabase,
                                 ).await
                             }
-                            Err(_) => json!({
+                            Ok(Err(e)) => json!({
                                 "status": "error",
-                                "message": "Invalid message format"
+                                "message": format!("Invalid message format: {}", e)
                             })
                         };
 
@@ -288,12 +522,289 @@ impl AnypayEventsServer {
         }
 
         // Mark connection as closed
-        is_connected.store(false, std::sync::atomic::Ordering::SeqCst);
+        is_connected.store(false, Ordering::SeqCst);
         
-        // Clean up session
+        // Clean up session and its subscriptions
         sessions.write().await.remove(&session.id);
+        event_dispatcher.remove_session(session.id).await;
         tracing::info!("Connection closed for session: {}", session.id);
         
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Query, routing::get, Json, Router};
+    use crate::supabase::tests::{invoice_row, mock_invoice, spawn_mock_supabase};
+
+    #[tokio::test]
+    async fn test_subscribe_response_includes_invoice_snapshot() {
+        let supabase = Arc::new(SupabaseClient::new(&spawn_mock_supabase(mock_invoice(invoice_row())), "anon", "service"));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, _receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let mut session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+
+        let message = Message::Subscribe { sub_type: "invoice".to_string(), id: "inv_1".to_string() };
+        let response = AnypayEventsServer::handle_message(message, &mut session, &event_dispatcher, &supabase).await;
+
+        assert_eq!(response["status"], "success");
+        assert_eq!(response["message"], "Subscribed to invoice inv_1");
+        assert_eq!(response["data"]["invoice"]["uid"], "inv_1");
+        assert_eq!(response["data"]["invoice"]["status"], "unpaid");
+        assert_eq!(response["data"]["payment_options"][0]["currency"], "BTC");
+        assert_eq!(response["data"]["payment_options"][0]["amount"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_session_messages_reach_websocket_sink() {
+        let (sender, receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+
+        // Stand in for the websocket sink with another channel
+        let (ws_sender, mut ws_sink) = futures::channel::mpsc::unbounded::<WsMessage>();
+        let queue = Arc::new(std::sync::Mutex::new(receiver));
+        let send_task = AnypayEventsServer::spawn_send_task(queue, ws_sender, Arc::new(AtomicBool::new(true)));
+
+        session.send(WsMessage::Text("hello".into())).unwrap();
+        assert_eq!(ws_sink.next().await, Some(WsMessage::Text("hello".into())));
+
+        drop(session);
+        send_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch_runs_actions_in_order() {
+        let supabase = Arc::new(SupabaseClient::new(&spawn_mock_supabase(mock_invoice(invoice_row())), "anon", "service"));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, _receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let mut session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+        session.set_account_id(1);
+
+        let message = parse_message(r#"{"action": "batch", "actions": [
+            {"action": "create_invoice", "amount": 500, "currency": "USD"},
+            {"action": "subscribe", "type": "invoice", "id": "inv_1"}
+        ]}"#).unwrap();
+        let response = AnypayEventsServer::handle_message(message, &mut session, &event_dispatcher, &supabase).await;
+
+        assert_eq!(response["status"], "success");
+        let results = response["data"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["status"], "success");
+        assert_eq!(results[0]["data"]["invoice"]["amount"], 500);
+        assert_eq!(results[1]["message"], "Subscribed to invoice inv_1");
+        assert!(event_dispatcher.get_subscribers(&crate::types::Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_1".to_string(),
+        }).await.contains(&session.id));
+    }
+
+    #[tokio::test]
+    async fn test_batch_failure_does_not_stop_later_actions() {
+        let supabase = Arc::new(SupabaseClient::new(&spawn_mock_supabase(mock_invoice(invoice_row())), "anon", "service"));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, _receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let mut session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+
+        // Without an account the invoice can't be created, but the ping after it still runs
+        let message = parse_message(r#"{"action": "batch", "actions": [
+            {"action": "create_invoice", "amount": 500, "currency": "USD"},
+            {"action": "ping"}
+        ]}"#).unwrap();
+        let response = AnypayEventsServer::handle_message(message, &mut session, &event_dispatcher, &supabase).await;
+
+        let results = response["data"].as_array().unwrap();
+        assert_eq!(results[0]["status"], "error");
+        assert_eq!(results[1]["type"], "pong");
+    }
+
+    /// Serve `count` prices with ids 1..=count, honouring the id cursor and limit PostgREST would
+    fn mock_prices(count: i64) -> Router {
+        Router::new().route("/rest/v1/prices", get(move |Query(params): Query<HashMap<String, String>>| async move {
+            let after = params.get("id")
+                .and_then(|id| id.strip_prefix("gt."))
+                .map_or(0, |id| id.parse::<i64>().unwrap());
+            let limit = params.get("limit").map_or(count as usize, |limit| limit.parse().unwrap());
+            let prices = (after + 1..=count)
+                .take(limit)
+                .map(|id| json!({
+                    "id": id,
+                    "currency": format!("C{}", id),
+                    "value": id as f64,
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": "2024-01-01T00:00:00Z"
+                }))
+                .collect::<Vec<_>>();
+            Json(json!(prices))
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_list_prices_pages_with_cursor() {
+        let supabase = Arc::new(SupabaseClient::new(&spawn_mock_supabase(mock_prices(250)), "anon", "service"));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, _receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let mut session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+
+        let mut ids = Vec::new();
+        let mut cursor: Option<String> = None;
+        let mut pages = 0;
+        loop {
+            let message = Message::ListPrices { limit: Some(100), cursor: cursor.clone() };
+            let response = AnypayEventsServer::handle_message(message, &mut session, &event_dispatcher, &supabase).await;
+            assert_eq!(response["status"], "success");
+            ids.extend(response["data"].as_array().unwrap().iter().map(|price| price["id"].as_i64().unwrap()));
+            pages += 1;
+
+            match response["next_cursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(ids, (1..=250).collect::<Vec<_>>());
+
+        // Without paging fields the whole table comes back, with no cursor
+        let message = parse_message(r#"{"action": "list_prices"}"#).unwrap();
+        let response = AnypayEventsServer::handle_message(message, &mut session, &event_dispatcher, &supabase).await;
+        assert_eq!(response["data"].as_array().unwrap().len(), 250);
+        assert!(response.get("next_cursor").is_none());
+
+        let message = Message::ListPrices { limit: None, cursor: Some("not-a-cursor".to_string()) };
+        let response = AnypayEventsServer::handle_message(message, &mut session, &event_dispatcher, &supabase).await;
+        assert_eq!(response["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_reaps_sessions_with_closed_channels() {
+        let sessions = RwLock::new(HashMap::new());
+        let event_dispatcher = EventDispatcher::new();
+        let reaped_before = crate::session::sessions_reaped();
+
+        let (sender, _receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let open = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+        // The send task ended without the connection handler removing the session
+        let (sender, receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let closed = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+        drop(receiver);
+        // A subscription whose session is no longer registered at all
+        let (sender, _gone_receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let gone = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+
+        for session in [&open, &closed] {
+            sessions.write().await.insert(session.id, session.clone());
+        }
+        for session in [&open, &closed, &gone] {
+            event_dispatcher.subscribe(session.clone(), "invoice", "inv_1").await;
+        }
+
+        let reaped = AnypayEventsServer::sweep_sessions(&sessions, &event_dispatcher).await;
+
+        assert_eq!(reaped, 1);
+        assert_eq!(sessions.read().await.keys().collect::<Vec<_>>(), vec![&open.id]);
+        let subscribers = event_dispatcher.get_subscribers(&crate::types::Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_1".to_string(),
+        }).await;
+        assert_eq!(subscribers, HashSet::from([open.id]));
+        assert!(crate::session::sessions_reaped() > reaped_before);
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_with_resume_token_restores_subscriptions() {
+        let supabase = Arc::new(SupabaseClient::new("http://127.0.0.1:1", "anon", "service"));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let connect = || {
+            let (sender, receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+            (Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect), receiver)
+        };
+
+        let (mut first, _receiver) = connect();
+        let response = AnypayEventsServer::handle_message(Message::Resume { token: None }, &mut first, &event_dispatcher, &supabase).await;
+        let token = response["data"]["token"].as_str().unwrap().to_string();
+        assert_eq!(response["data"]["subscriptions"], json!([]));
+        for id in ["acct_1", "acct_2"] {
+            let message = Message::Subscribe { sub_type: "account".to_string(), id: id.to_string() };
+            AnypayEventsServer::handle_message(message, &mut first, &event_dispatcher, &supabase).await;
+        }
+
+        // The network drops and the connection handler cleans up
+        event_dispatcher.remove_session(first.id).await;
+        assert!(event_dispatcher.subscriptions_for(first.id).await.is_empty());
+
+        let (mut second, _receiver) = connect();
+        let message = Message::Resume { token: Some(token.clone()) };
+        let response = AnypayEventsServer::handle_message(message, &mut second, &event_dispatcher, &supabase).await;
+
+        assert_eq!(response["status"], "success");
+        assert_eq!(response["data"]["token"], token);
+        assert_eq!(response["data"]["subscriptions"].as_array().unwrap().len(), 2);
+        let restored = event_dispatcher.subscriptions_for(second.id).await;
+        let expected = ["acct_1", "acct_2"].iter()
+            .map(|id| crate::types::Subscription { sub_type: "account".to_string(), id: id.to_string() })
+            .collect::<HashSet<_>>();
+        assert_eq!(restored, expected);
+
+        // A token restores once
+        let (mut third, _receiver) = connect();
+        let message = Message::Resume { token: Some(token.clone()) };
+        let response = AnypayEventsServer::handle_message(message, &mut third, &event_dispatcher, &supabase).await;
+        assert_ne!(response["data"]["token"], token);
+        assert_eq!(response["data"]["subscriptions"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_all_clears_the_sessions_subscriptions() {
+        let supabase = Arc::new(SupabaseClient::new("http://127.0.0.1:1", "anon", "service"));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let connect = || {
+            let (sender, receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+            (Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect), receiver)
+        };
+
+        let (mut leaving, _receiver) = connect();
+        let (mut staying, _receiver) = connect();
+        for (sub_type, id) in [("account", "acct_1"), ("address", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"), ("account", "acct_2")] {
+            let message = Message::Subscribe { sub_type: sub_type.to_string(), id: id.to_string() };
+            AnypayEventsServer::handle_message(message, &mut leaving, &event_dispatcher, &supabase).await;
+        }
+        let message = Message::Subscribe { sub_type: "account".to_string(), id: "acct_1".to_string() };
+        AnypayEventsServer::handle_message(message, &mut staying, &event_dispatcher, &supabase).await;
+        assert_eq!(leaving.subscriptions.len(), 3);
+
+        let response = AnypayEventsServer::handle_message(Message::UnsubscribeAll, &mut leaving, &event_dispatcher, &supabase).await;
+
+        assert_eq!(response["status"], "success");
+        assert_eq!(response["message"], "Unsubscribed from 3 subscriptions");
+        assert!(event_dispatcher.subscriptions_for(leaving.id).await.is_empty());
+        assert!(leaving.subscriptions.is_empty());
+        // Other sessions keep theirs
+        assert_eq!(event_dispatcher.subscriptions_for(staying.id).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_past_the_limit_is_rejected() {
+        let supabase = Arc::new(SupabaseClient::new("http://127.0.0.1:1", "anon", "service"));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, _receiver) = futures::channel::mpsc::channel(DEFAULT_SEND_BUFFER);
+        let mut session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect)
+            .with_max_subscriptions(3);
+        let subscribe = |id: &str| Message::Subscribe { sub_type: "account".to_string(), id: id.to_string() };
+
+        for id in ["acct_1", "acct_2", "acct_3"] {
+            let response = AnypayEventsServer::handle_message(subscribe(id), &mut session, &event_dispatcher, &supabase).await;
+            assert_eq!(response["status"], "success");
+        }
+
+        let response = AnypayEventsServer::handle_message(subscribe("acct_4"), &mut session, &event_dispatcher, &supabase).await;
+        assert_eq!(response["status"], "error");
+        assert!(response["message"].as_str().unwrap().contains("limit of 3"));
+        assert_eq!(session.subscription_count(), 3);
+        assert_eq!(event_dispatcher.subscriptions_for(session.id).await.len(), 3);
+
+        // Repeating a held subscription doesn't count against the limit
+        let response = AnypayEventsServer::handle_message(subscribe("acct_1"), &mut session, &event_dispatcher, &supabase).await;
+        assert_eq!(response["status"], "success");
+    }
+}
@@ -1,27 +1,111 @@
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio_tungstenite::tungstenite::Message as WsMessage;
-use futures::channel::mpsc::UnboundedSender;
+use futures::channel::mpsc::{Receiver, Sender};
+use serde::Deserialize;
 use uuid::Uuid;
 use crate::types::Subscription;
+use crate::rate_limit::{RateLimit, TokenBucket};
+
+/// Default number of outbound messages buffered per session before backpressure applies
+pub const DEFAULT_SEND_BUFFER: usize = 256;
+
+/// Default number of distinct subscriptions a session may hold at once
+pub const DEFAULT_MAX_SUBSCRIPTIONS: usize = 100;
+
+/// Sessions the sweeper has removed because their channel closed without a clean disconnect
+static SESSIONS_REAPED: AtomicU64 = AtomicU64::new(0);
+
+/// How many sessions the sweeper has removed since the process started
+pub fn sessions_reaped() -> u64 {
+    SESSIONS_REAPED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn record_sessions_reaped(count: usize) {
+    SESSIONS_REAPED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// What to do when a client isn't reading fast enough and its send buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackpressurePolicy {
+    /// Drop the oldest queued message to make room and keep the connection open
+    Drop,
+    /// Close the session's channel, which ends the send task and closes the websocket
+    Disconnect,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> anyhow::Result<Self> {
+        match policy.trim().to_lowercase().as_str() {
+            "drop" => Ok(BackpressurePolicy::Drop),
+            "disconnect" => Ok(BackpressurePolicy::Disconnect),
+            _ => Err(anyhow::anyhow!("Invalid backpressure policy: {} (expected drop or disconnect)", policy)),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Session {
     pub id: Uuid,
-    pub sender: UnboundedSender<WsMessage>,
+    // Shared so clones of the session don't each get their own guaranteed buffer slot
+    pub sender: Arc<Mutex<Sender<WsMessage>>>,
+    pub backpressure: BackpressurePolicy,
+    /// The receiving end of `sender`, shared with the send task so BackpressurePolicy::Drop can
+    /// discard the oldest queued message. Without it the message that didn't fit is dropped.
+    pub queue: Option<Arc<Mutex<Receiver<WsMessage>>>>,
     pub account_id: Option<i32>,
     pub auth_token: Option<String>,
     pub subscriptions: HashSet<Subscription>,
+    /// Most subscriptions the session may hold, so one client can't grow the dispatcher without bound
+    pub max_subscriptions: usize,
+    /// Tokens for the client's messages, when messages are rate limited
+    pub rate_limit: Option<TokenBucket>,
 }
 
 impl Session {
-    pub fn new(id: Uuid, sender: UnboundedSender<WsMessage>) -> Self {
+    pub fn new(id: Uuid, sender: Sender<WsMessage>, backpressure: BackpressurePolicy) -> Self {
         Session {
             id,
-            sender,
+            sender: Arc::new(Mutex::new(sender)),
+            backpressure,
+            queue: None,
             account_id: None,
             auth_token: None,
             subscriptions: HashSet::new(),
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            rate_limit: None,
+        }
+    }
+
+    /// Share the receiving end of the session's sender, so a full buffer drops its oldest message
+    pub fn with_queue(mut self, queue: Arc<Mutex<Receiver<WsMessage>>>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Allow the session `max_subscriptions` subscriptions instead of DEFAULT_MAX_SUBSCRIPTIONS
+    pub fn with_max_subscriptions(mut self, max_subscriptions: usize) -> Self {
+        self.max_subscriptions = max_subscriptions;
+        self
+    }
+
+    /// Limit the messages the client may send to `rate_limit`, or not at all when None
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit.map(TokenBucket::new);
+        self
+    }
+
+    /// Spend a token for a message from the client, or return how long until it may send another
+    pub fn check_rate_limit(&mut self) -> Result<(), std::time::Duration> {
+        match &mut self.rate_limit {
+            Some(bucket) => bucket.try_acquire(),
+            None => Ok(()),
         }
     }
 
@@ -34,7 +118,36 @@ impl Session {
     }
 
     pub fn send(&self, message: WsMessage) -> Result<(), Box<dyn std::error::Error>> {
-        Ok(self.sender.unbounded_send(message)?)
+        let mut sender = self.sender.lock().unwrap();
+        match sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_full() => match (self.backpressure, &self.queue) {
+                (BackpressurePolicy::Drop, Some(queue)) => {
+                    tracing::warn!("Send buffer full for session {}, dropping its oldest message", self.id);
+                    // Taking a message also frees the sender to queue again
+                    let _ = queue.lock().unwrap().try_next();
+                    match sender.try_send(e.into_inner()) {
+                        Err(e) if !e.is_full() => Err(e.into()),
+                        _ => Ok(()),
+                    }
+                }
+                (BackpressurePolicy::Drop, None) => {
+                    tracing::warn!("Send buffer full for session {}, dropping message", self.id);
+                    Ok(())
+                }
+                (BackpressurePolicy::Disconnect, _) => {
+                    tracing::warn!("Send buffer full for session {}, disconnecting", self.id);
+                    sender.close_channel();
+                    Err(format!("Send buffer full for session {}", self.id).into())
+                }
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Whether the session's outbound channel has been closed, e.g. by backpressure
+    pub fn is_closed(&self) -> bool {
+        self.sender.lock().unwrap().is_closed()
     }
 
     pub fn add_subscription(&mut self, subscription: Subscription) {
@@ -48,6 +161,15 @@ impl Session {
     pub fn has_subscription(&self, subscription: &Subscription) -> bool {
         self.subscriptions.contains(subscription)
     }
+
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// Whether adding `subscription` would take the session past its limit; repeating one it holds never does
+    pub fn exceeds_subscription_limit(&self, subscription: &Subscription) -> bool {
+        !self.has_subscription(subscription) && self.subscription_count() >= self.max_subscriptions
+    }
 }
 
 impl PartialEq for Session {
@@ -62,4 +184,51 @@ impl Hash for Session {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.id.hash(state);
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+        for i in 0..16 {
+            session.send(WsMessage::Text(format!("message {}", i).into()))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_buffer_disconnects_session() {
+        // Keep the receiver alive but never drain it, like a stuck client
+        let (sender, _receiver) = futures::channel::mpsc::channel(4);
+        let session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Disconnect);
+
+        assert!(fill(&session).is_err());
+        assert!(session.is_closed());
+    }
+
+    #[test]
+    fn test_full_buffer_drops_oldest_messages() {
+        let (sender, receiver) = futures::channel::mpsc::channel(4);
+        let queue = Arc::new(Mutex::new(receiver));
+        let session = Session::new(Uuid::new_v4(), sender, BackpressurePolicy::Drop)
+            .with_queue(queue.clone());
+
+        assert!(fill(&session).is_ok());
+        assert!(!session.is_closed());
+
+        let mut queued = Vec::new();
+        while let Ok(Some(WsMessage::Text(text))) = queue.lock().unwrap().try_next() {
+            queued.push(text.to_string());
+        }
+        assert_eq!(queued.last().map(String::as_str), Some("message 15"));
+        assert!(!queued.contains(&"message 0".to_string()));
+    }
+
+    #[test]
+    fn test_parse_backpressure_policy() {
+        assert_eq!("Disconnect".parse::<BackpressurePolicy>().unwrap(), BackpressurePolicy::Disconnect);
+        assert_eq!("drop".parse::<BackpressurePolicy>().unwrap(), BackpressurePolicy::Drop);
+        assert!("block".parse::<BackpressurePolicy>().is_err());
+    }
+}
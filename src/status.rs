@@ -0,0 +1,260 @@
+use chrono::{DateTime, Duration, Utc};
+use futures::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use crate::session::sessions_reaped;
+
+/// Chain subscriptions reported by the status endpoint, in display order
+pub const TRACKED_CHAINS: &[&str] = &["ETH", "POLYGON", "AVAX", "BNB", "XRPL", "BLOCKBOOK"];
+
+lazy_static! {
+    static ref CHAIN_STATUS: ChainStatusRegistry = ChainStatusRegistry::new();
+}
+
+/// Connectivity of a single chain subscription
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ChainStatus {
+    pub chain: String,
+    pub connected: bool,
+    pub last_block_height: Option<u64>,
+    /// When the last block or message was received
+    pub last_event_at: Option<DateTime<Utc>>,
+    /// When the subscription last connected
+    pub connected_at: Option<DateTime<Utc>>,
+}
+
+/// Longest gap between blocks before a connected chain is reported as stalled
+pub fn expected_block_interval(chain: &str) -> Duration {
+    match chain {
+        // Bitcoin blocks through Blockbook are ten minutes apart on average, but hour-long gaps happen
+        "BLOCKBOOK" => Duration::minutes(60),
+        "ETH" => Duration::minutes(2),
+        _ => Duration::minutes(1),
+    }
+}
+
+/// Live connectivity of every tracked chain, updated by the chain clients
+pub struct ChainStatusRegistry {
+    chains: RwLock<BTreeMap<String, ChainStatus>>,
+}
+
+impl ChainStatusRegistry {
+    pub fn new() -> Self {
+        let chains = TRACKED_CHAINS.iter()
+            .map(|chain| (chain.to_string(), ChainStatus { chain: chain.to_string(), ..Default::default() }))
+            .collect();
+
+        Self { chains: RwLock::new(chains) }
+    }
+
+    fn update(&self, chain: &str, apply: impl FnOnce(&mut ChainStatus)) {
+        let mut chains = self.chains.write().unwrap();
+        let status = chains.entry(chain.to_string())
+            .or_insert_with(|| ChainStatus { chain: chain.to_string(), ..Default::default() });
+        apply(status);
+    }
+
+    pub fn mark_connected(&self, chain: &str) {
+        self.update(chain, |status| {
+            status.connected = true;
+            status.connected_at = Some(Utc::now());
+        });
+    }
+
+    pub fn mark_disconnected(&self, chain: &str) {
+        self.update(chain, |status| status.connected = false);
+    }
+
+    /// Record a new block, which also shows the subscription is live
+    pub fn record_block(&self, chain: &str, height: u64) {
+        self.update(chain, |status| {
+            status.connected = true;
+            status.last_block_height = Some(height);
+            status.last_event_at = Some(Utc::now());
+        });
+    }
+
+    /// Record a message that carries no block height
+    pub fn record_event(&self, chain: &str) {
+        self.update(chain, |status| {
+            status.connected = true;
+            status.last_event_at = Some(Utc::now());
+        });
+    }
+
+    /// Record the height of every block from `blocks`, marking the chain disconnected when it ends
+    pub async fn track_blocks<S>(&self, chain: &str, blocks: S)
+    where
+        S: Stream<Item = u64>,
+    {
+        futures::pin_mut!(blocks);
+        while let Some(height) = blocks.next().await {
+            self.record_block(chain, height);
+        }
+        self.mark_disconnected(chain);
+    }
+
+    /// Connected chains that have gone longer than their expected block interval without an event
+    pub fn stalled_chains(&self, now: DateTime<Utc>) -> Vec<ChainStatus> {
+        self.snapshot()
+            .into_iter()
+            .filter(|status| status.connected)
+            .filter(|status| {
+                status.last_event_at.or(status.connected_at)
+                    .is_some_and(|since| now - since > expected_block_interval(&status.chain))
+            })
+            .collect()
+    }
+
+    /// Tracked chains first, in TRACKED_CHAINS order, then any others alphabetically
+    pub fn snapshot(&self) -> Vec<ChainStatus> {
+        let chains = self.chains.read().unwrap();
+        let tracked = TRACKED_CHAINS.iter().filter_map(|chain| chains.get(*chain));
+        let others = chains.values().filter(|status| !TRACKED_CHAINS.contains(&status.chain.as_str()));
+
+        tracked.chain(others).cloned().collect()
+    }
+}
+
+impl Default for ChainStatusRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The process-wide registry the chain clients report to
+pub fn chain_status() -> &'static ChainStatusRegistry {
+    &CHAIN_STATUS
+}
+
+/// Periodically warn about connected chains that have stopped receiving blocks
+pub fn start_block_watchdog(check_every: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(check_every);
+        loop {
+            interval.tick().await;
+            for status in chain_status().stalled_chains(Utc::now()) {
+                tracing::warn!(
+                    "No {} block received since {} (last height {:?})",
+                    status.chain,
+                    status.last_event_at.or(status.connected_at).map(|t| t.to_rfc3339()).unwrap_or_default(),
+                    status.last_block_height
+                );
+            }
+        }
+    });
+}
+
+/// Render chain statuses in the Prometheus text exposition format
+pub fn to_prometheus(statuses: &[ChainStatus]) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP anypay_chain_connected Whether the chain subscription is connected\n");
+    out.push_str("# TYPE anypay_chain_connected gauge\n");
+    for status in statuses {
+        out.push_str(&format!("anypay_chain_connected{{chain=\"{}\"}} {}\n", status.chain, status.connected as u8));
+    }
+
+    out.push_str("# HELP anypay_chain_last_block_height Height of the last block received\n");
+    out.push_str("# TYPE anypay_chain_last_block_height gauge\n");
+    for status in statuses {
+        if let Some(height) = status.last_block_height {
+            out.push_str(&format!("anypay_chain_last_block_height{{chain=\"{}\"}} {}\n", status.chain, height));
+        }
+    }
+
+    out.push_str("# HELP anypay_sessions_reaped_total Websocket sessions removed by the sweeper after their channel closed\n");
+    out.push_str("# TYPE anypay_sessions_reaped_total counter\n");
+    out.push_str(&format!("anypay_sessions_reaped_total {}\n", sessions_reaped()));
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_reflects_connected_and_disconnected_chains() {
+        let registry = ChainStatusRegistry::new();
+
+        registry.record_block("ETH", 19_000_000);
+        registry.mark_connected("XRPL");
+        registry.mark_disconnected("XRPL");
+
+        let statuses = registry.snapshot();
+        let chains = statuses.iter().map(|status| status.chain.as_str()).collect::<Vec<_>>();
+        assert_eq!(chains, TRACKED_CHAINS);
+
+        let eth = &statuses[0];
+        assert!(eth.connected);
+        assert_eq!(eth.last_block_height, Some(19_000_000));
+        assert!(eth.last_event_at.is_some());
+
+        let xrpl = statuses.iter().find(|status| status.chain == "XRPL").unwrap();
+        assert!(!xrpl.connected);
+        assert_eq!(xrpl.last_block_height, None);
+    }
+
+    async fn wait_for_height(registry: &ChainStatusRegistry, chain: &str, height: u64) {
+        for _ in 0..100 {
+            let current = registry.snapshot().into_iter().find(|status| status.chain == chain).unwrap();
+            if current.last_block_height == Some(height) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{} never reached height {}", chain, height);
+    }
+
+    #[tokio::test]
+    async fn test_tracked_height_advances_with_blocks() {
+        let registry = std::sync::Arc::new(ChainStatusRegistry::new());
+        let (blocks, received) = futures::channel::mpsc::unbounded();
+        let tracking = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.track_blocks("AVAX", received).await }
+        });
+
+        blocks.unbounded_send(100).unwrap();
+        wait_for_height(&registry, "AVAX", 100).await;
+        blocks.unbounded_send(101).unwrap();
+        wait_for_height(&registry, "AVAX", 101).await;
+
+        // The subscription ending leaves the last height but reports the chain as down
+        drop(blocks);
+        tracking.await.unwrap();
+        let avax = registry.snapshot().into_iter().find(|status| status.chain == "AVAX").unwrap();
+        assert!(!avax.connected);
+        assert_eq!(avax.last_block_height, Some(101));
+    }
+
+    #[test]
+    fn test_stalled_chains() {
+        let registry = ChainStatusRegistry::new();
+        registry.record_block("ETH", 1);
+        registry.record_block("BLOCKBOOK", 800_000);
+        registry.mark_connected("XRPL");
+
+        assert!(registry.stalled_chains(Utc::now()).is_empty());
+
+        let later = Utc::now() + Duration::minutes(5);
+        let stalled = registry.stalled_chains(later).into_iter().map(|status| status.chain).collect::<Vec<_>>();
+        assert_eq!(stalled, vec!["ETH", "XRPL"]);
+    }
+
+    #[test]
+    fn test_prometheus_output() {
+        let registry = ChainStatusRegistry::new();
+        registry.record_block("BNB", 42);
+
+        let text = to_prometheus(&registry.snapshot());
+
+        assert!(text.contains("anypay_chain_connected{chain=\"BNB\"} 1\n"));
+        assert!(text.contains("anypay_chain_connected{chain=\"ETH\"} 0\n"));
+        assert!(text.contains("anypay_chain_last_block_height{chain=\"BNB\"} 42\n"));
+        assert!(!text.contains("anypay_chain_last_block_height{chain=\"ETH\"}"));
+    }
+}
@@ -1,29 +1,123 @@
-use serde::{Deserialize, Serialize};
-use postgrest::Postgrest;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use postgrest::{Builder, Postgrest};
 use serde_json::{self, json, Value};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use std::sync::RwLock;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::time::{interval, Duration};
 use std::sync::Arc;
-use anyhow::{Result, anyhow};
+use std::future::Future;
+use anyhow::{Context, Result, anyhow};
 use reqwest;
 use crate::confirmations::{Payment, Confirmation};
-use crate::{payment::ConversionRequest, payment_options::create_payment_options, types::{Account, Address, Coin, CreateInvoiceRequest, Invoice, PaymentOption, Price}};
+use crate::error::Error;
+use crate::event_dispatcher::EventDispatcher;
+use crate::prices::PriceSources;
+use crate::{payment::ConversionRequest, payment_options::create_payment_options, types::{Account, Address, AddressIndex, Coin, CreateInvoiceRequest, Invoice, InvoiceEvent, InvoiceStatus, InvoiceTransition, PaymentOption, PaymentStatus, Price}};
 
 lazy_static! {
     static ref COIN_CACHE: RwLock<Option<HashMap<String, Coin>>> = RwLock::new(None);
+    /// Token coins keyed by chain and contract address, loaded with COIN_CACHE
+    static ref COIN_CONTRACT_CACHE: RwLock<HashMap<String, Coin>> = RwLock::new(HashMap::new());
     static ref PRICE_CACHE: RwLock<HashMap<String, Price>> = RwLock::new(HashMap::new());
 }
 
+/// Attempts made for read-only requests that fail with a 5xx or a timeout
+const MAX_READ_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled on each subsequent attempt
+const READ_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Read a response body, turning non-2xx statuses into an error carrying the status and body
+async fn response_text(response: reqwest::Response) -> Result<String> {
+    let status = response.status();
+    let text = response.text().await
+        .map_err(|e| anyhow!("Failed to read response: {}", e))?;
+
+    if !status.is_success() {
+        return Err(anyhow!("Supabase returned {}: {}", status, text));
+    }
+    Ok(text)
+}
+
+/// Send a read-only request, retrying transient failures (5xx, timeouts, refused connections)
+async fn send_with_retry<F, Fut>(mut request: F) -> Result<reqwest::Response>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = reqwest::Result<reqwest::Response>>,
+{
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Ok(response) if response.status().is_server_error() && attempt < MAX_READ_ATTEMPTS => {
+                tracing::warn!("Supabase returned {} (attempt {}/{}), retrying", response.status(), attempt, MAX_READ_ATTEMPTS);
+            }
+            Err(e) if (e.is_timeout() || e.is_connect()) && attempt < MAX_READ_ATTEMPTS => {
+                tracing::warn!("Supabase request failed (attempt {}/{}), retrying: {}", attempt, MAX_READ_ATTEMPTS, e);
+            }
+            Ok(response) => return Ok(response),
+            Err(e) => return Err(anyhow!("Supabase request failed: {}", e)),
+        }
+
+        tokio::time::sleep(READ_RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+        attempt += 1;
+    }
+}
+
+/// Send a read-only request with retries and return the body of a successful response
+async fn read_with_retry<F, Fut>(request: F) -> Result<String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = reqwest::Result<reqwest::Response>>,
+{
+    response_text(send_with_retry(request).await?).await
+}
+
+/// Key of a token coin in COIN_CONTRACT_CACHE; EVM addresses compare case-insensitively
+fn contract_key(chain: &str, contract_address: &str) -> String {
+    format!("{}:{}", chain, contract_address.to_lowercase())
+}
+
+/// Deserialize a Supabase response body, keeping the raw body in the error
+fn parse_json<T: DeserializeOwned>(text: &str) -> Result<T> {
+    serde_json::from_str(text)
+        .map_err(|e| anyhow!("Failed to parse Supabase response: {} (body: {})", e, text))
+}
+
+/// Execute a request once, check its status and deserialize the JSON body
+async fn execute_json<T: DeserializeOwned>(builder: Builder) -> Result<T> {
+    let response = builder.execute().await
+        .map_err(|e| anyhow!("Supabase request failed: {}", e))?;
+    parse_json(&response_text(response).await?)
+}
+
+/// Execute a read-only request with retries and deserialize the JSON body.
+/// Takes a function building the request, since a builder is consumed on each attempt.
+async fn query_json<T, F>(mut build: F) -> Result<T>
+where
+    T: DeserializeOwned,
+    F: FnMut() -> Builder,
+{
+    parse_json(&read_with_retry(|| build().execute()).await?)
+}
+
+/// Values looked up per `in.(...)` query; at 64 hex characters each, txids keep URLs under 8KB
+const TXID_LOOKUP_CHUNK: usize = 100;
+
+/// Unpaid invoices read per query by the expiry sweep
+const UNPAID_INVOICE_PAGE: usize = 500;
+
 #[derive(Clone)]
 pub struct SupabaseClient {
     client: Arc<Postgrest>,
     anon_key: String,
     service_role_key: String,
     base_url: String,
+    /// Notified of invoice status changes made through this client
+    event_dispatcher: Option<Arc<EventDispatcher>>,
+    /// Which source's price to use for pairs quoted by more than one
+    price_sources: PriceSources,
 }
 
 impl SupabaseClient {
@@ -43,10 +137,29 @@ impl SupabaseClient {
             client,
             anon_key: anon_key.to_string(),
             service_role_key: service_role_key.to_string(),
-            base_url: api_url,
+            // The raw requests' paths carry the /rest/v1 prefix themselves
+            base_url: api_url.trim_end_matches("/rest/v1").to_string(),
+            event_dispatcher: None,
+            price_sources: PriceSources::default(),
         }
     }
 
+    /// Prefer prices from sources in the order of `price_sources`, falling back past stale ones
+    pub fn with_price_sources(mut self, price_sources: PriceSources) -> Self {
+        self.price_sources = price_sources;
+        self
+    }
+
+    /// Publish an InvoiceEvent to `event_dispatcher` whenever this client changes an invoice's status
+    pub fn with_event_dispatcher(mut self, event_dispatcher: Arc<EventDispatcher>) -> Self {
+        self.event_dispatcher = Some(event_dispatcher);
+        self
+    }
+
+    pub fn event_dispatcher(&self) -> Option<&Arc<EventDispatcher>> {
+        self.event_dispatcher.as_ref()
+    }
+
     pub async fn get_invoice(&self, invoice_id: &str, use_service_role: bool) -> Result<Option<(Invoice, Vec<PaymentOption>)>> {
         let auth_key = if use_service_role {
             &self.service_role_key
@@ -57,40 +170,33 @@ impl SupabaseClient {
         tracing::info!("Fetching invoice with id: {}", invoice_id);
 
         // Get invoice
-        let response = self.client.as_ref()
+        let invoices: Vec<Invoice> = query_json(|| self.client.as_ref()
             .from("invoices")
             .select("*")
             .eq("uid", invoice_id)
-            .auth(self.service_role_key.to_string())
-            .execute()
+            .auth(self.service_role_key.to_string()))
             .await
             .map_err(|e| anyhow!("Failed to fetch invoice: {}", e))?;
 
-        tracing::info!("Invoice response: {:?}", response);
-
-        let response_text = response.text().await
-            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
-        tracing::info!("Invoice response text: {:?}", response_text);
-        let invoices: Vec<Invoice> = serde_json::from_str(&response_text)
-            .map_err(|e| anyhow!("Failed to parse invoice: {}", e))?;
-
         tracing::info!("Invoices: {:?}", invoices);
         
-        if let Some(invoice) = invoices.into_iter().next() {
+        if let Some(mut invoice) = invoices.into_iter().next() {
             // Get payment options
-            let response = self.client.as_ref()
+            let payment_options: Vec<PaymentOption> = query_json(|| self.client.as_ref()
                 .from("payment_options")
                 .select("*")
                 .eq("invoice_uid", invoice_id)
-                .auth(auth_key)
-                .execute()
+                .auth(auth_key))
                 .await
                 .map_err(|e| anyhow!("Failed to fetch payment options: {}", e))?;
 
-            let response_text = response.text().await
-                .map_err(|e| anyhow!("Failed to read response: {}", e))?;
-            let payment_options: Vec<PaymentOption> = serde_json::from_str(&response_text)
-                .map_err(|e| anyhow!("Failed to parse payment options: {}", e))?;
+            // Summarize against the stored options, since payments were made at their rates
+            match self.list_payments(invoice_id).await {
+                Ok(payments) => {
+                    invoice.summary = crate::invoices::summarize_payments(&invoice, &payment_options, &payments);
+                }
+                Err(e) => tracing::warn!("Failed to summarize payments for invoice {}: {}", invoice_id, e),
+            }
 
             // Get account for refreshing payment options
             let account = self.get_account(invoice.account_id).await?;
@@ -112,55 +218,62 @@ impl SupabaseClient {
 
     pub async fn create_invoice(
         &self,
-        amount: i64,
+        amount: Option<i64>,
         currency: &str,
         account_id: i64,
         webhook_url: Option<String>,
         redirect_url: Option<String>,
         memo: Option<String>,
+        accepted_currencies: Option<Vec<String>>,
+        required_fee_rate: Option<i64>,
+        crypto_denominated: bool,
+        op_return: Option<String>,
     ) -> Result<serde_json::Value> {
+        if let Some(data) = &op_return {
+            crate::payment::decode_op_return(data)?;
+        }
+
+        let account = self.get_account(account_id)
+            .await
+            .map_err(|e| anyhow!("Failed to get account: {}", e))?;
+
         let uid = format!("inv_{}", crate::payment::generate_uid());
+        let base_url = crate::uri::get_base_url(account.base_url.as_deref());
         let new_invoice = serde_json::json!([{
             "amount": amount,
             "currency": currency,
             "account_id": account_id,
-            "status": "unpaid",
+            "status": InvoiceStatus::Unpaid,
             "uid": uid.clone(),
             "webhook_url": webhook_url,
             "redirect_url": redirect_url,
             "memo": memo,
-            "uri": format!("pay:?r=https://api.anypayx.com/r/{}", crate::payment::generate_uid()),
+            "accepted_currencies": accepted_currencies,
+            "required_fee_rate": required_fee_rate,
+            "crypto_denominated": crypto_denominated,
+            "op_return": op_return,
+            "uri": crate::uri::compute_payment_request_uri(&base_url, &uid),
             "createdAt": Utc::now().to_rfc3339(),
             "updatedAt": Utc::now().to_rfc3339(),
         }]);
 
         tracing::info!("New invoice: {}", new_invoice);
 
-        let response = self.client.as_ref()
+        let invoices: Vec<Invoice> = execute_json(self.client.as_ref()
             .from("invoices")
             .insert(&serde_json::to_string(&new_invoice).map_err(|e| anyhow!("Failed to serialize invoice: {}", e))?)
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await
             .map_err(|e| anyhow!("Failed to create invoice: {}", e))?;
-
-        let response_text = response.text()
-            .await
-            .map_err(|e| anyhow!("Failed to get response text: {}", e))?;
-        tracing::info!("Create invoice response: {}", response_text);
-
-        let invoices: Vec<Invoice> = serde_json::from_str(&response_text)
-            .map_err(|e| anyhow!("Failed to parse invoice response: {}", e))?;
+        tracing::info!("Created invoices: {:?}", invoices);
         let invoice = invoices.into_iter().next()
             .ok_or_else(|| anyhow!("No invoice created"))?;
-        
-        // Get account and create payment options
-        let account = self.get_account(account_id)
-            .await
-            .map_err(|e| anyhow!("Failed to get account: {}", e))?;
+        // The invoices_record_creation trigger appends the invoice's first invoice_events row
+
+        // Context rather than a new error, so the cause stays typed for Error::from_anyhow
         let payment_options = create_payment_options(&account, &invoice, self)
             .await
-            .map_err(|e| anyhow!("Failed to create payment options: {}", e))?;
+            .context("Failed to create payment options")?;
 
         Ok(json!({
             "invoice": invoice,
@@ -169,53 +282,69 @@ impl SupabaseClient {
     }
 
     pub async fn list_prices(&self) -> Result<Vec<Price>> {
-        let response = self.client.as_ref()
+        query_json::<Vec<Price>, _>(|| self.client.as_ref()
             .from("prices")
             .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await
-            .map_err(|e| anyhow!("Failed to fetch prices: {}", e))?;
+            .map_err(|e| anyhow!("Failed to fetch prices: {}", e))
+    }
 
-        let text = response.text().await
-            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
+    /// Up to `limit` prices in id order, starting after the price with id `after`,
+    /// and whether more prices follow
+    pub async fn list_prices_page(&self, limit: usize, after: Option<i64>) -> Result<(Vec<Price>, bool)> {
+        // Fetch one extra row to tell whether this is the last page
+        let mut path = format!("/rest/v1/prices?select=*&order=id.asc&limit={}", limit + 1);
+        if let Some(after) = after {
+            path.push_str(&format!("&id=gt.{}", after));
+        }
 
-        let prices = serde_json::from_str::<Vec<Price>>(&text)
-            .map_err(|e| anyhow!("Failed to parse prices: {}", e))?;
-        Ok(prices)
+        let mut prices: Vec<Price> = parse_json(&self.get(&path).await
+            .map_err(|e| anyhow!("Failed to fetch prices: {}", e))?)?;
+        let has_more = prices.len() > limit;
+        prices.truncate(limit);
+        Ok((prices, has_more))
     }
 
     pub async fn get_account(&self, account_id: i64) -> Result<Account> {
-        let response = self.client.as_ref()
+        let accounts: Vec<Account> = query_json(|| self.client.as_ref()
             .from("accounts")
             .select("*")
             .eq("id", account_id.to_string())
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await
             .map_err(|e| anyhow!("Failed to fetch account: {}", e))?;
-
-        let text = response.text().await
-            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
-
-        let accounts: Vec<Account> = serde_json::from_str(&text)
-            .map_err(|e| anyhow!("Failed to parse account: {}", e))?;
         accounts.into_iter().next()
             .ok_or_else(|| anyhow!("Account not found"))
     }
 
+    /// The secret an account's webhooks are signed with, if it has set one
+    pub async fn get_webhook_secret(&self, account_id: i64) -> Result<Option<String>> {
+        #[derive(Deserialize)]
+        struct WebhookSecret {
+            webhook_secret: Option<String>,
+        }
+
+        let accounts: Vec<WebhookSecret> = query_json(|| self.client.as_ref()
+            .from("accounts")
+            .select("webhook_secret")
+            .eq("id", account_id.to_string())
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch webhook secret: {}", e))?;
+        let account = accounts.into_iter().next()
+            .ok_or_else(|| anyhow!("Account {} not found", account_id))?;
+
+        Ok(account.webhook_secret.filter(|secret| !secret.is_empty()))
+    }
+
     pub async fn list_available_addresses(&self, account: &Account) -> Result<Vec<Address>> {
-        let response_text = self.client.as_ref()
+        let addresses: Vec<Address> = query_json(|| self.client.as_ref()
             .from("addresses")
             .select("*")
-            .eq("account_id", account.id.to_string())
-            .execute()
-            .await?
-            .text()
+            .eq("account_id", account.id.to_string()))
             .await?;
 
-        let addresses: Vec<Address> = serde_json::from_str(&response_text)?;
-
         let mut available = Vec::new();
         for addr in addresses {
             let coin = self.get_coin(&addr.currency, &addr.chain).await.unwrap();
@@ -238,21 +367,25 @@ impl SupabaseClient {
         }
 
         // Load coins if cache is empty
-        let response = self.client.as_ref()
+        let coins: Vec<Coin> = query_json(|| self.client.as_ref()
             .from("coins")
             .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await?;
-
-        let response_text = response.text().await?;
-        tracing::info!("Loading coins from DB: {}", response_text);
-        let coins: Vec<Coin> = serde_json::from_str(&response_text)?;
+        tracing::info!("Loaded {} coins from DB", coins.len());
         
         let mut coin_map = HashMap::new();
         for coin in coins {
             coin_map.insert(format!("{}:{}", coin.currency, coin.chain), coin);
         }
+        #[cfg(feature = "eth")]
+        crate::plugin::register_coin_plugins(coin_map.values());
+
+        let contracts = coin_map.values()
+            .filter_map(|coin| coin.contract_address.as_deref()
+                .map(|contract_address| (contract_key(&coin.chain, contract_address), coin.clone())))
+            .collect();
+        *COIN_CONTRACT_CACHE.write().unwrap() = contracts;
         
         let mut cache = COIN_CACHE.write().unwrap();
         *cache = Some(coin_map);
@@ -261,15 +394,11 @@ impl SupabaseClient {
     }
 
     pub async fn get_coins(&self) -> Result<HashMap<String, Coin>> {
-        let response = self.client.as_ref()
+        let coins: Vec<Coin> = query_json(|| self.client.as_ref()
             .from("coins")
             .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await?;
-
-        let response_text = response.text().await?;
-        let coins: Vec<Coin> = serde_json::from_str(&response_text)?;
         
         // Convert to HashMap
         let mut coin_map = HashMap::new();
@@ -289,6 +418,15 @@ impl SupabaseClient {
             .cloned()))
     }
 
+    /// Find the token coin issued by `contract_address` on `chain`, to match incoming token transfers
+    pub async fn get_coin_by_contract(&self, chain: &str, contract_address: &str) -> Result<Option<Coin>> {
+        self.ensure_coins_loaded().await?;
+
+        Ok(COIN_CONTRACT_CACHE.read().unwrap()
+            .get(&contract_key(chain, contract_address))
+            .cloned())
+    }
+
     pub async fn refresh_coins(&self) -> Result<()> {
         // Force reload coins
         let mut cache = COIN_CACHE.write().unwrap();
@@ -299,21 +437,17 @@ impl SupabaseClient {
     }
 
     pub async fn create_payment_options(&self, options: &[PaymentOption]) -> Result<Vec<PaymentOption>> {
-        let response = self.client.as_ref()
+        let inserted: Vec<PaymentOption> = execute_json(self.client.as_ref()
             .from("payment_options")
             .insert(&serde_json::to_string(&serde_json::json!(options))?)
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await?;
+        tracing::info!("Created {} payment options", inserted.len());
 
-        let response_text = response.text().await?;
-        tracing::info!("Create payment options response: {}", response_text);
-        let inserted: Vec<PaymentOption> = serde_json::from_str(&response_text)?;
-        
         Ok(inserted)
     }
 
-    pub async fn start_price_updater(supabase: Arc<Self>) {
+    pub fn start_price_updater(supabase: Arc<Self>) {
         let mut interval = interval(Duration::from_secs(60)); // Every minute
 
         tokio::spawn(async move {
@@ -326,17 +460,98 @@ impl SupabaseClient {
         });
     }
 
+    /// Expire stale invoices every minute in the background. Each one publishes invoice.expired
+    /// through the client's event dispatcher, so set one with `with_event_dispatcher` first.
+    pub fn start_invoice_expiry_sweeper(supabase: Arc<Self>) {
+        let mut interval = interval(Duration::from_secs(60)); // Every minute
+
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                match supabase.expire_stale_invoices().await {
+                    Ok(expired) if !expired.is_empty() => {
+                        tracing::info!("Expired {} unpaid invoices", expired.len());
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("Failed to sweep expired invoices: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Mark unpaid invoices whose payment options have all expired (past the grace period) as expired,
+    /// reading the unpaid invoices UNPAID_INVOICE_PAGE at a time in id order
+    pub async fn expire_stale_invoices(&self) -> Result<Vec<String>> {
+        let mut expired = Vec::new();
+        let mut after = 0;
+        loop {
+            let invoices: Vec<Invoice> = query_json(|| self.client.as_ref()
+                .from("invoices")
+                .select("*")
+                .eq("status", InvoiceStatus::Unpaid.as_str())
+                .gt("id", after.to_string())
+                .order("id.asc")
+                .limit(UNPAID_INVOICE_PAGE)
+                .auth(&self.service_role_key))
+                .await
+                .map_err(|e| anyhow!("Failed to fetch unpaid invoices: {}", e))?;
+
+            let Some(last) = invoices.last() else {
+                break;
+            };
+            after = last.id;
+            let last_page = invoices.len() < UNPAID_INVOICE_PAGE;
+
+            expired.extend(self.expire_stale_page(invoices).await?);
+            if last_page {
+                break;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Expire those of one page of unpaid `invoices` whose payment options have all expired
+    async fn expire_stale_page(&self, invoices: Vec<Invoice>) -> Result<Vec<String>> {
+        let uids: Vec<&str> = invoices.iter().map(|invoice| invoice.uid.as_str()).collect();
+
+        // Read payment options directly, since get_invoice would refresh the expired ones
+        let mut options_by_invoice: HashMap<String, Vec<PaymentOption>> = HashMap::new();
+        for chunk in uids.chunks(TXID_LOOKUP_CHUNK) {
+            let payment_options: Vec<PaymentOption> = query_json(|| self.client.as_ref()
+                .from("payment_options")
+                .select("*")
+                .in_("invoice_uid", chunk.iter().copied())
+                .auth(&self.service_role_key))
+                .await
+                .map_err(|e| anyhow!("Failed to fetch payment options: {}", e))?;
+
+            for option in payment_options {
+                options_by_invoice.entry(option.invoice_uid.clone()).or_default().push(option);
+            }
+        }
+
+        let invoices = invoices.into_iter()
+            .map(|invoice| {
+                let options = options_by_invoice.remove(&invoice.uid).unwrap_or_default();
+                (invoice, options)
+            })
+            .collect();
+
+        Ok(crate::invoices::sweep_expired_invoices(
+            invoices,
+            Utc::now(),
+            chrono::Duration::minutes(crate::invoices::EXPIRY_GRACE_PERIOD_MINUTES),
+            |uid| async move { self.update_invoice_status_as(&uid, InvoiceStatus::Expired, crate::invoices::EXPIRY_ACTOR).await },
+        ).await)
+    }
+
     pub async fn refresh_prices(&self) -> Result<()> {
-        let response = self.client.as_ref()
+        let prices: Vec<Price> = query_json(|| self.client.as_ref()
             .from("prices")
             .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await?;
 
-        let response_text = response.text().await?;
-        let prices: Vec<Price> = serde_json::from_str(&response_text)?;
-
         // Update cache
         let mut cache = PRICE_CACHE.write().unwrap();
         for price in prices {
@@ -354,120 +569,378 @@ impl SupabaseClient {
             .cloned()
     }
 
+    /// The price of one `base_currency` in `currency`, from the source preferred by `price_sources`
     pub async fn find_price(&self, base_currency: &str, currency: &str) -> Result<Option<Price>> {
-        let response = self.client.as_ref()
+        let prices: Vec<Price> = query_json(|| self.client.as_ref()
             .from("prices")
             .select("*")
             .eq("base_currency", base_currency)
             .eq("currency", currency)
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key))
             .await?;
 
-        let response_text = response.text().await?;
-        let prices: Vec<Price> = serde_json::from_str(&response_text)?;
-        
-        Ok(prices.into_iter().next())
+        Ok(self.price_sources.select(prices, Utc::now()))
     }
 
-    pub async fn update_invoice_status(&self, uid: &str, status: &str) -> Result<()> {
-        self.client.as_ref()
-            .from("invoices")
-            .update(&serde_json::to_string(&json!({
-                "status": status
-            }))?)
-            .eq("uid", uid)
-            .execute()
-            .await?;
+    /// Set the status of invoice `uid`, recording the change as made by the service itself
+    pub async fn update_invoice_status(&self, uid: &str, status: InvoiceStatus) -> Result<()> {
+        self.update_invoice_status_as(uid, status, crate::invoices::SYSTEM_ACTOR).await
+    }
+
+    /// Set the status of invoice `uid`, and append the transition, attributed to `actor`, to `invoice_events`.
+    /// The `update_invoice_status` function does both in a single statement, so the history
+    /// records exactly the status each change replaced.
+    pub async fn update_invoice_status_as(&self, uid: &str, status: InvoiceStatus, actor: &str) -> Result<()> {
+        let params = json!({ "p_uid": uid, "p_status": status, "p_actor": actor });
+        let transitions: Vec<InvoiceTransition> = execute_json(self.client.as_ref()
+            .rpc("update_invoice_status", params.to_string())
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to update invoice {}: {}", uid, e))?;
+
+        if transitions.is_empty() {
+            return Err(anyhow!("Invoice {} not found", uid));
+        }
+
+        if let Some(event_dispatcher) = &self.event_dispatcher {
+            event_dispatcher.publish_invoice_event(InvoiceEvent { uid: uid.to_string(), status });
+        }
         Ok(())
     }
 
     pub async fn validate_api_key(&self, api_key: &str) -> Result<Option<i32>> {
-        println!("api_key: {:?}", api_key);
-        let response = self.client.as_ref()
+        let response = send_with_retry(|| self.client.as_ref()
             .from("access_tokens")
             .select("account_id")
             .eq("uid", api_key)
             .single()
-            .execute()
+            .execute())
             .await?;
 
-        println!("response: {:?}", response);
-            
-        let response_text = response.text().await?;
-        let data: Value = serde_json::from_str(&response_text)?;
+        // .single() responds 406 when no token matches
+        if response.status() == reqwest::StatusCode::NOT_ACCEPTABLE {
+            return Ok(None);
+        }
+
+        let data: Value = parse_json(&response_text(response).await?)?;
         
         Ok(data.get("account_id").and_then(|v| v.as_i64()).map(|id| id as i32))
     }
 
-    pub async fn cancel_invoice(&self, uid: &str, account_id: i32) -> Result<()> {
+    pub async fn cancel_invoice(&self, uid: &str, account_id: i32) -> crate::error::Result<()> {
         // First fetch invoice to check ownership
         println!("Cancelling invoice: {:?}", uid);
-        let (invoice, _) = self.get_invoice(uid, true).await?
-            .ok_or(anyhow!("Invoice not found"))?;
+        let (invoice, _) = self.get_invoice(uid, true).await
+            .map_err(Error::Db)?
+            .ok_or_else(|| Error::InvoiceNotFound(uid.to_string()))?;
 
         // Verify ownership
         if invoice.account_id as i32 != account_id {
-            return Err(anyhow!("Unauthorized to cancel this invoice"));
+            return Err(Error::Unauthorized("invoice belongs to another account".to_string()));
         }
 
         // Update status to cancelled
-        self.update_invoice_status(uid, "cancelled").await?;
+        self.update_invoice_status_as(uid, InvoiceStatus::Cancelled, &crate::invoices::account_actor(account_id as i64)).await
+            .map_err(Error::Db)?;
         
         Ok(())
     }
 
-    async fn get(&self, path: &str) -> Result<reqwest::Response> {
-        Ok(reqwest::Client::new()
+    async fn get(&self, path: &str) -> Result<String> {
+        let client = reqwest::Client::new();
+        read_with_retry(|| client
             .get(format!("{}{}", self.base_url, path))
             .header("apikey", &self.anon_key)
             .header("Authorization", format!("Bearer {}", self.service_role_key))
+            .send())
+            .await
+    }
+
+    async fn post(&self, path: &str, body: serde_json::Value, prefer: &str) -> Result<String> {
+        let response = reqwest::Client::new()
+            .post(format!("{}{}", self.base_url, path))
+            .header("apikey", &self.anon_key)
+            .header("Authorization", format!("Bearer {}", self.service_role_key))
+            .header("Prefer", prefer)
+            .json(&body)
             .send()
-            .await?)
+            .await?;
+        response_text(response).await
     }
 
-    async fn patch(&self, path: &str, body: serde_json::Value) -> Result<reqwest::Response> {
-        Ok(reqwest::Client::new()
+    async fn patch(&self, path: &str, body: serde_json::Value) -> Result<String> {
+        let response = reqwest::Client::new()
             .patch(format!("{}{}", self.base_url, path))
             .header("apikey", &self.anon_key)
             .header("Authorization", format!("Bearer {}", self.service_role_key))
             .json(&body)
             .send()
-            .await?)
+            .await?;
+        response_text(response).await
     }
 
     pub async fn get_unconfirmed_payment_by_txid(&self, txid: &str) -> Result<Option<Payment>> {
         let path = format!("/rest/v1/payments?txid=eq.{}&confirmation_hash=is.null", txid);
-        let response = self.get(&path).await?;
-        let payments: Vec<Payment> = response.json().await?;
+        let payments: Vec<Payment> = parse_json(&self.get(&path).await?)?;
         Ok(payments.into_iter().next())
     }
 
+    /// Unconfirmed payments for any of `txids`, with an `in.(...)` query per TXID_LOOKUP_CHUNK txids
+    /// so a full block doesn't exceed URL length limits
+    pub async fn get_unconfirmed_payments_by_txids(&self, txids: &[String]) -> Result<Vec<Payment>> {
+        let mut payments = Vec::new();
+        for chunk in txids.chunks(TXID_LOOKUP_CHUNK) {
+            // Hex txids need no quoting inside the list
+            let path = format!("/rest/v1/payments?txid=in.({})&confirmation_hash=is.null", chunk.join(","));
+            let found: Vec<Payment> = parse_json(&self.get(&path).await?)?;
+            payments.extend(found);
+        }
+        Ok(payments)
+    }
+
     pub async fn confirm_payment(&self, payment: Payment, confirmation: Confirmation) -> Result<Payment> {
         let path = format!("/rest/v1/payments?id=eq.{}", payment.id);
         let response = self.patch(&path, json!({
             "confirmation_hash": confirmation.confirmation_hash,
             "confirmation_height": confirmation.confirmation_height,
             "confirmation_date": confirmation.confirmation_date,
-            "status": "confirmed"
+            "status": PaymentStatus::Confirmed
         })).await?;
 
-        Ok(response.json().await?)
+        parse_json(&response)
     }
 
     pub async fn get_unconfirmed_payments(&self, chain: &str, currency: &str) -> Result<Vec<Payment>> {
         let path = format!("/rest/v1/payments?chain=eq.{}&currency=eq.{}&confirmation_hash=is.null", chain, currency);
-        let response = self.get(&path).await?;
-        Ok(response.json().await?)
+        parse_json(&self.get(&path).await?)
     }
 
     pub async fn get_payment_by_txid(&self, txid: &str) -> Result<Option<Payment>> {
         let path = format!("/rest/v1/payments?txid=eq.{}", txid);
-        let response = self.get(&path).await?;
-        let payments: Vec<Payment> = response.json().await?;
+        let payments: Vec<Payment> = parse_json(&self.get(&path).await?)?;
+        Ok(payments.into_iter().next())
+    }
+
+    /// Fetch the payment option an invoice offers for a chain and currency
+    pub async fn get_payment_option(&self, invoice_uid: &str, chain: &str, currency: &str) -> Result<Option<PaymentOption>> {
+        let options: Vec<PaymentOption> = query_json(|| self.client.as_ref()
+            .from("payment_options")
+            .select("*")
+            .eq("invoice_uid", invoice_uid)
+            .eq("chain", chain)
+            .eq("currency", currency)
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch payment option: {}", e))?;
+
+        Ok(options.into_iter().next())
+    }
+
+    /// Fetch the payment recorded for a transaction submitted against an invoice
+    pub async fn get_invoice_payment(&self, invoice_uid: &str, txid: &str) -> Result<Option<Payment>> {
+        let payments: Vec<Payment> = query_json(|| self.client.as_ref()
+            .from("payments")
+            .select("*")
+            .eq("invoice_uid", invoice_uid)
+            .eq("txid", txid)
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch payment {}: {}", txid, e))?;
+
         Ok(payments.into_iter().next())
     }
 
+    /// Record a submitted transaction as a pending payment so the block processors can confirm it.
+    /// Recording a txid the invoice already has returns the existing payment: the insert skips
+    /// conflicts on (invoice_uid, txid), so concurrent submissions can't record it twice.
+    pub async fn create_payment(
+        &self,
+        invoice_uid: &str,
+        chain: &str,
+        currency: &str,
+        txid: &str,
+        amount: i64,
+    ) -> Result<Payment> {
+        let new_payment = json!([{
+            "invoice_uid": invoice_uid,
+            "chain": chain,
+            "currency": currency,
+            "txid": txid,
+            "amount": amount,
+            "status": PaymentStatus::Pending,
+        }]);
+
+        let response = self.post("/rest/v1/payments?on_conflict=invoice_uid,txid", new_payment, "resolution=ignore-duplicates,return=representation").await
+            .map_err(|e| anyhow!("Failed to create payment: {}", e))?;
+        let payments: Vec<Payment> = parse_json(&response)?;
+
+        match payments.into_iter().next() {
+            Some(payment) => Ok(payment),
+            // Nothing is returned for a skipped conflict
+            None => self.get_invoice_payment(invoice_uid, txid).await?
+                .ok_or_else(|| anyhow!("No payment created for {}", txid)),
+        }
+    }
+
+    /// Addresses on `chain` that may still be paid or have a payment confirmed: those of unexpired
+    /// payment options, plus those of invoices with a payment awaiting confirmation
+    pub async fn get_watched_addresses(&self, chain: &str) -> Result<HashSet<String>> {
+        let now = Utc::now().to_rfc3339();
+        let open: Vec<PaymentOption> = query_json(|| self.client.as_ref()
+            .from("payment_options")
+            .select("*")
+            .eq("chain", chain)
+            .gt("expires", &now)
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch open payment options: {}", e))?;
+
+        let unconfirmed: Vec<Payment> = query_json(|| self.client.as_ref()
+            .from("payments")
+            .select("*")
+            .eq("chain", chain)
+            .is("confirmation_hash", "null")
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch unconfirmed payments: {}", e))?;
+
+        let uids: Vec<&str> = unconfirmed.iter().map(|payment| payment.invoice_uid.as_str()).collect();
+        let mut awaiting: Vec<PaymentOption> = Vec::new();
+        for chunk in uids.chunks(TXID_LOOKUP_CHUNK) {
+            let options: Vec<PaymentOption> = query_json(|| self.client.as_ref()
+                .from("payment_options")
+                .select("*")
+                .eq("chain", chain)
+                .in_("invoice_uid", chunk.iter().copied())
+                .auth(&self.service_role_key))
+                .await
+                .map_err(|e| anyhow!("Failed to fetch payment options awaiting confirmation: {}", e))?;
+            awaiting.extend(options);
+        }
+
+        Ok(open.into_iter().chain(awaiting).map(|option| option.address).collect())
+    }
+
+    /// Unexpired payment options of unpaid invoices that pay to any of `addresses`, with their invoices
+    pub async fn get_watched_payment_options(&self, addresses: &[String]) -> Result<Vec<(Invoice, PaymentOption)>> {
+        if addresses.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let mut options: Vec<PaymentOption> = Vec::new();
+        for chunk in addresses.chunks(TXID_LOOKUP_CHUNK) {
+            let found: Vec<PaymentOption> = query_json(|| self.client.as_ref()
+                .from("payment_options")
+                .select("*")
+                .in_("address", chunk.iter().map(String::as_str))
+                .gt("expires", &now)
+                .auth(&self.service_role_key))
+                .await
+                .map_err(|e| anyhow!("Failed to fetch watched payment options: {}", e))?;
+            options.extend(found);
+        }
+
+        let uids: Vec<&str> = options.iter().map(|option| option.invoice_uid.as_str()).collect();
+        let mut invoices: Vec<Invoice> = Vec::new();
+        for chunk in uids.chunks(TXID_LOOKUP_CHUNK) {
+            let found: Vec<Invoice> = query_json(|| self.client.as_ref()
+                .from("invoices")
+                .select("*")
+                .in_("uid", chunk.iter().copied())
+                .eq("status", InvoiceStatus::Unpaid.as_str())
+                .auth(&self.service_role_key))
+                .await
+                .map_err(|e| anyhow!("Failed to fetch invoices: {}", e))?;
+            invoices.extend(found);
+        }
+
+        Ok(options.into_iter()
+            .filter_map(|option| invoices.iter()
+                .find(|invoice| invoice.uid == option.invoice_uid)
+                .map(|invoice| (invoice.clone(), option)))
+            .collect())
+    }
+
+    /// List every payment recorded against an invoice, oldest first
+    pub async fn list_payments(&self, invoice_uid: &str) -> Result<Vec<Payment>> {
+        query_json(|| self.client.as_ref()
+            .from("payments")
+            .select("*")
+            .eq("invoice_uid", invoice_uid)
+            .order("id.asc")
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch payments for invoice {}: {}", invoice_uid, e))
+    }
+
+    /// Fetch the account-level extended public key an account configured for a chain
+    pub async fn get_account_xpub(&self, account_id: i64, chain: &str) -> Result<Option<String>> {
+        #[derive(Deserialize)]
+        struct AccountXpub {
+            xpub: String,
+        }
+
+        let xpubs: Vec<AccountXpub> = query_json(|| self.client.as_ref()
+            .from("account_xpubs")
+            .select("xpub")
+            .eq("account_id", account_id.to_string())
+            .eq("chain", chain)
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch xpub: {}", e))?;
+
+        Ok(xpubs.into_iter().next().map(|row| row.xpub))
+    }
+
+    /// Atomically claim the next derivation index for an account and chain.
+    /// The `claim_address_index` function increments `next_index` in a single statement,
+    /// creating the row at 0, and returns the row as it was before the increment.
+    pub async fn claim_address_index(&self, account_id: i64, chain: &str) -> Result<AddressIndex> {
+        let params = json!({ "p_account_id": account_id, "p_chain": chain });
+        let indexes: Vec<AddressIndex> = execute_json(self.client.as_ref()
+            .rpc("claim_address_index", params.to_string())
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to claim address index: {}", e))?;
+
+        indexes.into_iter().next()
+            .ok_or_else(|| anyhow!("No address index claimed for account {} on {}", account_id, chain))
+    }
+
+    /// Record that the address derived at `index` for an invoice's account has been paid,
+    /// which moves the gap limit window forward
+    pub async fn mark_address_index_used(&self, invoice_uid: &str, chain: &str, index: i64) -> Result<()> {
+        #[derive(Deserialize)]
+        struct InvoiceAccount {
+            account_id: i64,
+        }
+
+        let invoices: Vec<InvoiceAccount> = query_json(|| self.client.as_ref()
+            .from("invoices")
+            .select("account_id")
+            .eq("uid", invoice_uid)
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to fetch invoice: {}", e))?;
+        let account_id = invoices.into_iter().next()
+            .ok_or_else(|| anyhow!("Invoice {} not found", invoice_uid))?
+            .account_id;
+
+        // Only touch last_used_index, so a concurrent claim's increment of next_index isn't lost
+        let _: Vec<AddressIndex> = execute_json(self.client.as_ref()
+            .from("address_indexes")
+            .update(json!({ "last_used_index": index }).to_string())
+            .eq("account_id", account_id.to_string())
+            .eq("chain", chain)
+            .or(format!("last_used_index.is.null,last_used_index.lt.{}", index))
+            .auth(&self.service_role_key))
+            .await
+            .map_err(|e| anyhow!("Failed to mark address index {} used: {}", index, e))?;
+
+        Ok(())
+    }
+
     pub async fn update_payment(
         &self,
         id: i32,
@@ -480,10 +953,10 @@ impl SupabaseClient {
             "confirmation_hash": confirmation_hash,
             "confirmation_height": confirmation_height,
             "confirmation_date": confirmation_date,
-            "status": "confirmed"
+            "status": PaymentStatus::Confirmed
         })).await?;
 
-        Ok(response.json().await?)
+        parse_json(&response)
     }
 }
 
@@ -514,14 +987,354 @@ pub async fn convert(
         converted
     );
 
-    // Apply precision if specified
-    /*let result = if let Some(p) = precision {
-        let factor = 10f64.powi(p);
-        (converted * factor).round() / factor
-    } else {
-        converted
+    // Round to the requested precision, else the target coin's
+    let precision = match precision {
+        Some(precision) => Some(precision),
+        None => supabase.get_coin(to_currency, to_currency).await.ok().flatten().and_then(|coin| coin.precision),
     };
 
-    Ok(result)*/
-    Ok(converted)
-}
\ No newline at end of file
+    match precision {
+        Some(precision) => crate::prices::round_value(converted, precision),
+        None => Ok(converted),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::{extract::Query, http::StatusCode, routing::get, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serve a stand-in for the Supabase REST API and return its base URL
+    pub(crate) fn spawn_mock_supabase(router: Router) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service());
+        tokio::spawn(server);
+        url
+    }
+
+    /// Claim address indexes from `next_index` as the `claim_address_index` function does,
+    /// incrementing it under the lock the way the database does in a single statement
+    pub(crate) fn mock_claim_address_index(next_index: std::sync::Arc<std::sync::Mutex<i64>>) -> Router {
+        Router::new().route("/rest/v1/rpc/claim_address_index", axum::routing::post(move |Json(params): Json<Value>| async move {
+            let mut next_index = next_index.lock().unwrap();
+            let row = json!([{
+                "account_id": params["p_account_id"],
+                "chain": params["p_chain"],
+                "next_index": *next_index,
+                "last_used_index": null
+            }]);
+            *next_index += 1;
+            Json(row)
+        }))
+    }
+
+    /// Serve stand-ins for the block explorers plugins estimate fees and broadcast through, and
+    /// point MEMPOOL_API_URL and WHATSONCHAIN_API_URL at them. Plugins read those for every
+    /// request, so one server is shared by every test in the process, on its own runtime so it
+    /// outlives any one test's.
+    pub(crate) fn mock_chain_apis() -> &'static str {
+        static URL: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        URL.get_or_init(|| {
+            let txid = |txhex: &str| hex::decode(txhex.trim()).ok()
+                .and_then(|bytes| bitcoin::consensus::deserialize::<bitcoin::Transaction>(&bytes).ok())
+                .map(|tx| tx.txid().to_string());
+            let router = Router::new()
+                .route("/v1/fees/recommended", get(|| async {
+                    Json(json!({ "fastestFee": 20, "halfHourFee": 12, "hourFee": 8, "economyFee": 4, "minimumFee": 1 }))
+                }))
+                // mempool.space takes the hex as the body and answers with the txid
+                .route("/tx", axum::routing::post(move |txhex: String| async move {
+                    txid(&txhex).ok_or((StatusCode::BAD_REQUEST, "invalid transaction".to_string()))
+                }))
+                // WhatsOnChain takes {"txhex"} and answers with the txid as a JSON string
+                .route("/tx/raw", axum::routing::post(move |Json(body): Json<Value>| async move {
+                    txid(body["txhex"].as_str().unwrap_or_default())
+                        .map(Json)
+                        .ok_or((StatusCode::BAD_REQUEST, "invalid transaction".to_string()))
+                }));
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            std::thread::spawn(move || {
+                tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                    axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service()).await.unwrap();
+                });
+            });
+            std::env::set_var("MEMPOOL_API_URL", &url);
+            std::env::set_var("WHATSONCHAIN_API_URL", &url);
+            url
+        })
+    }
+
+    /// Address `btc_option_row` pays to
+    pub(crate) const BTC_ADDRESS: &str = "bc1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqq9e75rs";
+
+    /// An unpaid 1000 USD invoice inv_1 of account 1, as PostgREST returns it
+    pub(crate) fn invoice_row() -> Value {
+        json!({
+            "id": 1,
+            "uid": "inv_1",
+            "amount": 1000,
+            "currency": "USD",
+            "status": "unpaid",
+            "account_id": 1,
+            "complete": false,
+            "webhook_url": null,
+            "redirect_url": null,
+            "memo": null,
+            "uri": "pay:?r=https://api.anypayx.com/r/inv_1",
+            "createdAt": "2024-01-01T00:00:00Z",
+            "updatedAt": "2024-01-01T00:00:00Z"
+        })
+    }
+
+    /// inv_1's BTC payment option, 1000 sats to BTC_ADDRESS that haven't expired
+    pub(crate) fn btc_option_row() -> Value {
+        json!({
+            "invoice_uid": "inv_1",
+            "currency": "BTC",
+            "chain": "BTC",
+            "amount": 1000,
+            "address": BTC_ADDRESS,
+            "outputs": [{ "address": BTC_ADDRESS, "amount": 1000 }],
+            "uri": "bitcoin:?r=https://api.anypayx.com/r/inv_1",
+            "fee": 0,
+            "createdAt": "2024-01-01T00:00:00Z",
+            "updatedAt": "2024-01-01T00:00:00Z",
+            "expires": "2099-01-01T00:00:00Z"
+        })
+    }
+
+    /// Mock the tables read by SupabaseClient::get_invoice for the single invoice `invoice`, with
+    /// the option of `btc_option_row`, accepting new invoices for an account without addresses
+    pub(crate) fn mock_invoice(invoice: Value) -> Router {
+        Router::new()
+            .route("/rest/v1/invoices", get(move || async move { Json(json!([invoice])) })
+                .post(|Json(mut rows): Json<Value>| async move {
+                    for row in rows.as_array_mut().unwrap() {
+                        row["id"] = json!(2);
+                    }
+                    (StatusCode::CREATED, Json(rows))
+                }))
+            .route("/rest/v1/addresses", get(|| async { Json(json!([])) }))
+            .route("/rest/v1/payment_options", get(|| async { Json(json!([btc_option_row()])) })
+                .post(|Json(rows): Json<Value>| async move { (StatusCode::CREATED, Json(rows)) }))
+            .route("/rest/v1/accounts", get(|| async { Json(json!([{ "id": 1, "denomination": "USD" }])) }))
+            .route("/rest/v1/payments", get(|| async { Json(json!([])) }))
+    }
+
+    /// The rows the `update_invoice_status` function returns for `params`, for an invoice that was unpaid
+    pub(crate) fn invoice_transition_rows(params: &Value) -> Value {
+        json!([{
+            "invoice_uid": params["p_uid"],
+            "old_status": "unpaid",
+            "new_status": params["p_status"],
+            "actor": params["p_actor"],
+            "created_at": Utc::now().to_rfc3339(),
+        }])
+    }
+
+    /// Mock /rest/v1/prices that answers with `status` for the first `failures` requests
+    fn flaky_prices(failures: usize, status: StatusCode, calls: Arc<AtomicUsize>) -> Router {
+        Router::new().route("/rest/v1/prices", get(move || async move {
+            if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                (status, Json(json!({ "message": "upstream unavailable" })))
+            } else {
+                (StatusCode::OK, Json(json!([{
+                    "id": 1,
+                    "currency": "BTC",
+                    "value": 50000.0,
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": "2024-01-01T00:00:00Z"
+                }])))
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_read_retries_after_transient_503() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = spawn_mock_supabase(flaky_prices(1, StatusCode::SERVICE_UNAVAILABLE, calls.clone()));
+        let supabase = SupabaseClient::new(&url, "anon", "service");
+
+        let prices = supabase.list_prices().await.unwrap();
+
+        assert_eq!(prices.len(), 1);
+        assert_eq!(prices[0].currency, "BTC");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_surfaces_persistent_500() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = spawn_mock_supabase(flaky_prices(usize::MAX, StatusCode::INTERNAL_SERVER_ERROR, calls.clone()));
+        let supabase = SupabaseClient::new(&url, "anon", "service");
+
+        let err = supabase.list_prices().await.unwrap_err().to_string();
+
+        assert!(err.contains("500"), "unexpected error: {}", err);
+        assert!(err.contains("upstream unavailable"), "unexpected error: {}", err);
+        assert_eq!(calls.load(Ordering::SeqCst), MAX_READ_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn test_non_json_body_error_includes_body() {
+        let router = Router::new().route("/rest/v1/accounts", get(|| async {
+            "<html>upstream proxy error</html>"
+        }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let err = supabase.get_account(1).await.unwrap_err().to_string();
+
+        assert!(err.contains("Failed to parse Supabase response"), "unexpected error: {}", err);
+        assert!(err.contains("<html>upstream proxy error</html>"), "unexpected error: {}", err);
+    }
+
+    fn payment_row(id: i32, txid: &str, invoice_uid: &str, status: &str) -> Value {
+        json!({
+            "id": id,
+            "txid": txid,
+            "chain": "BTC",
+            "currency": "BTC",
+            "status": status,
+            "invoice_uid": invoice_uid,
+            "confirmation_hash": null,
+            "confirmation_height": null,
+            "confirmation_date": null
+        })
+    }
+
+    #[tokio::test]
+    async fn test_list_payments_for_invoice() {
+        let router = Router::new().route("/rest/v1/payments", get(|Query(params): Query<HashMap<String, String>>| async move {
+            let rows = vec![
+                payment_row(1, "aa11", "inv_1", "failed"),
+                payment_row(2, "bb22", "inv_1", "pending"),
+                payment_row(3, "cc33", "inv_2", "confirmed"),
+            ];
+            let invoice_uid = params.get("invoice_uid").cloned().unwrap_or_default();
+            Json(Value::Array(rows.into_iter()
+                .filter(|row| format!("eq.{}", row["invoice_uid"].as_str().unwrap()) == invoice_uid)
+                .collect()))
+        }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let payments = supabase.list_payments("inv_1").await.unwrap();
+
+        assert_eq!(payments.len(), 2);
+        assert!(payments.iter().all(|payment| payment.invoice_uid == "inv_1"));
+        assert_eq!(payments.iter().map(|payment| payment.txid.as_str()).collect::<Vec<_>>(), vec!["aa11", "bb22"]);
+        assert!(supabase.list_payments("inv_3").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recording_a_payment_twice_returns_the_existing_one() {
+        let recorded = Arc::new(std::sync::Mutex::new(Vec::<Value>::new()));
+        let router = Router::new().route("/rest/v1/payments", get({
+            let recorded = recorded.clone();
+            move || async move { Json(Value::Array(recorded.lock().unwrap().clone())) }
+        }).post({
+            let recorded = recorded.clone();
+            move |Query(params): Query<HashMap<String, String>>, headers: axum::http::HeaderMap, Json(rows): Json<Vec<Value>>| async move {
+                assert_eq!(params["on_conflict"], "invoice_uid,txid");
+                assert!(headers["prefer"].to_str().unwrap().contains("resolution=ignore-duplicates"));
+                let mut recorded = recorded.lock().unwrap();
+                let inserted = rows.into_iter()
+                    .filter(|row| !recorded.iter().any(|existing| existing["txid"] == row["txid"]))
+                    .map(|mut row| {
+                        row["id"] = json!(recorded.len() + 1);
+                        recorded.push(row.clone());
+                        row
+                    })
+                    .collect::<Vec<_>>();
+                (StatusCode::CREATED, Json(inserted))
+            }
+        }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        let first = supabase.create_payment("inv_1", "BTC", "BTC", "aa11", 20_000).await.unwrap();
+        let second = supabase.create_payment("inv_1", "BTC", "BTC", "aa11", 20_000).await.unwrap();
+
+        assert_eq!(first.id, second.id);
+        assert_eq!(recorded.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_invoices_are_published() {
+        let old = (Utc::now() - chrono::Duration::hours(2)).to_rfc3339();
+        let expires = (Utc::now() - chrono::Duration::hours(1)).to_rfc3339();
+        let invoices = json!([{
+            "id": 1, "uid": "inv_old", "amount": 1000, "currency": "USD", "status": "unpaid",
+            "account_id": 1, "complete": false, "webhook_url": null, "redirect_url": null, "memo": null,
+            "uri": "pay:?r=https://api.anypayx.com/r/inv_old", "createdAt": old, "updatedAt": old,
+        }]);
+        let options = json!([{
+            "invoice_uid": "inv_old", "currency": "BTC", "chain": "BTC", "amount": 1000,
+            "address": "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", "outputs": [], "uri": "", "fee": 0,
+            "createdAt": old, "updatedAt": old, "expires": expires,
+        }]);
+        let router = Router::new()
+            .route("/rest/v1/invoices", get(move || async move { Json(invoices) }))
+            .route("/rest/v1/rpc/update_invoice_status", axum::routing::post(|Json(params): Json<Value>| async move {
+                Json(invoice_transition_rows(&params))
+            }))
+            .route("/rest/v1/payment_options", get(move || async move { Json(options) }));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service")
+            .with_event_dispatcher(event_dispatcher.clone());
+        let mut events = event_dispatcher.subscribe_events();
+
+        assert_eq!(supabase.expire_stale_invoices().await.unwrap(), vec!["inv_old".to_string()]);
+
+        let event = events.try_recv().expect("no event published");
+        assert_eq!(event.topic, "invoice.expired");
+        assert_eq!(event.payload, json!({ "uid": "inv_old", "status": "expired" }));
+    }
+
+    #[tokio::test]
+    async fn test_expiry_sweep_looks_up_payment_options_in_chunks() {
+        let created = Utc::now().to_rfc3339();
+        let invoices = Value::Array((1..=150).map(|id| json!({
+            "id": id, "uid": format!("inv_{}", id), "amount": 1000, "currency": "USD", "status": "unpaid",
+            "account_id": 1, "complete": false, "webhook_url": null, "redirect_url": null, "memo": null,
+            "uri": "", "createdAt": created, "updatedAt": created,
+        })).collect());
+        let lookups = Arc::new(std::sync::Mutex::new(Vec::<usize>::new()));
+        let router = Router::new()
+            .route("/rest/v1/invoices", get(move || async move { Json(invoices) }))
+            .route("/rest/v1/payment_options", get({
+                let lookups = lookups.clone();
+                move |Query(params): Query<HashMap<String, String>>| async move {
+                    lookups.lock().unwrap().push(params["invoice_uid"].split(',').count());
+                    Json(json!([]))
+                }
+            }));
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service");
+
+        // The invoices were just created, so none is expired
+        assert!(supabase.expire_stale_invoices().await.unwrap().is_empty());
+        assert_eq!(*lookups.lock().unwrap(), vec![TXID_LOOKUP_CHUNK, 150 - TXID_LOOKUP_CHUNK]);
+    }
+
+    #[tokio::test]
+    async fn test_status_change_is_published_to_invoice_stream() {
+        use futures::StreamExt;
+
+        let router = Router::new().route("/rest/v1/rpc/update_invoice_status", axum::routing::post(|Json(params): Json<Value>| async move {
+            Json(invoice_transition_rows(&params))
+        }));
+        let event_dispatcher = Arc::new(EventDispatcher::new());
+        let supabase = SupabaseClient::new(&spawn_mock_supabase(router), "anon", "service")
+            .with_event_dispatcher(event_dispatcher.clone());
+        let mut events = Box::pin(event_dispatcher.subscribe_invoice("inv_1"));
+
+        supabase.update_invoice_status("inv_1", InvoiceStatus::Paid).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.next()).await
+            .expect("no event published")
+            .unwrap();
+        assert_eq!(event, InvoiceEvent { uid: "inv_1".to_string(), status: InvoiceStatus::Paid });
+    }
+}
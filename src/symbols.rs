@@ -0,0 +1,34 @@
+/// Legacy chain names, mapped to the canonical chain. Canonical chains are the ones the coins
+/// table, payment options and plugins use, and that cards report from `Card::chain`.
+pub const CHAIN_ALIASES: &[(&str, &str)] = &[
+    ("XRPL", "XRP"),
+    ("MATIC", "POLYGON"),
+    ("BSC", "BNB"),
+];
+
+/// The canonical spelling of a chain, e.g. "XRP" for "xrpl"
+pub fn normalize_chain(chain: &str) -> String {
+    let chain = chain.trim().to_uppercase();
+    CHAIN_ALIASES.iter()
+        .find(|(alias, _)| *alias == chain)
+        .map_or(chain, |(_, canonical)| canonical.to_string())
+}
+
+/// The canonical (chain, currency) pair for inputs in any of the conventions in use, so card
+/// and plugin lookups agree on what they are given
+pub fn normalize(chain: &str, currency: &str) -> (String, String) {
+    (normalize_chain(chain), currency.trim().to_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_aliases_and_case() {
+        assert_eq!(normalize("XRPL", "XRP"), ("XRP".to_string(), "XRP".to_string()));
+        assert_eq!(normalize("xrp", " xrp "), ("XRP".to_string(), "XRP".to_string()));
+        assert_eq!(normalize("MATIC", "MATIC"), ("POLYGON".to_string(), "MATIC".to_string()));
+        assert_eq!(normalize("ETH", "USDC"), ("ETH".to_string(), "USDC".to_string()));
+    }
+}
@@ -17,13 +17,17 @@ pub enum Message {
         sub_type: String,
         id: String,
     },
+    /// Drop every subscription the session holds, e.g. when its user logs out
+    #[serde(rename = "unsubscribe_all")]
+    UnsubscribeAll,
     #[serde(rename = "fetch_invoice")]
     FetchInvoice {
         id: String,
     },
     #[serde(rename = "create_invoice")]
     CreateInvoice {        
-        amount: i64,
+        /// Null for a donation invoice, where the payer chooses the amount
+        amount: Option<i64>,
         currency: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         webhook_url: Option<String>,
@@ -31,15 +35,27 @@ pub enum Message {
         redirect_url: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         memo: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        accepted_currencies: Option<Vec<String>>,
+        #[serde(default)]
+        crypto_denominated: bool,
     },
+    /// List prices, a page of `limit` at a time starting after `cursor` when either is set
     #[serde(rename = "list_prices")]
-    ListPrices,
+    ListPrices {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+    },
     #[serde(rename = "convert_price")]
     ConvertPrice {
         quote_currency: String,
         base_currency: String,
         #[serde(deserialize_with = "deserialize_number_from_string")]
         quote_value: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        precision: Option<i32>,
     },
     #[serde(rename = "cancel_invoice")]
     CancelInvoice {
@@ -47,6 +63,108 @@ pub enum Message {
     },
     #[serde(rename = "ping")]
     Ping,
+    /// Opt in to keeping this session's subscriptions across a reconnect, restoring those parked under
+    /// `token` when a previous session presents one
+    #[serde(rename = "resume")]
+    Resume {
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Run several actions in order, answering with one result per action. Not atomic: each
+    /// action succeeds or fails on its own, without stopping or undoing the others.
+    #[serde(rename = "batch")]
+    Batch {
+        actions: Vec<Message>,
+    },
+}
+
+/// Every `action` accepted by `Message`, used to tell unknown actions apart from malformed ones
+pub const MESSAGE_ACTIONS: &[&str] = &[
+    "subscribe",
+    "unsubscribe",
+    "unsubscribe_all",
+    "fetch_invoice",
+    "create_invoice",
+    "list_prices",
+    "convert_price",
+    "cancel_invoice",
+    "ping",
+    "resume",
+    "batch",
+];
+
+/// Most actions a single `batch` message may carry
+pub const MAX_BATCH_ACTIONS: usize = 20;
+
+/// Prices per `list_prices` page when a cursor is sent without a limit
+pub const DEFAULT_PRICES_PAGE_SIZE: usize = 100;
+
+/// Largest `list_prices` page a client may ask for
+pub const MAX_PRICES_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageError {
+    InvalidJson(String),
+    NotAnObject,
+    MissingAction,
+    UnknownAction(String),
+    InvalidFields { action: String, detail: String },
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::InvalidJson(detail) => write!(f, "Invalid JSON: {}", detail),
+            MessageError::NotAnObject => write!(f, "Message must be a JSON object"),
+            MessageError::MissingAction => write!(f, "Missing required field `action`"),
+            MessageError::UnknownAction(action) => write!(
+                f,
+                "Unknown action `{}`, expected one of: {}",
+                action,
+                MESSAGE_ACTIONS.join(", ")
+            ),
+            MessageError::InvalidFields { action, detail } => write!(f, "Invalid `{}` message: {}", action, detail),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// Parse an incoming websocket message, describing what was wrong with it on failure
+pub fn parse_message(text: &str) -> Result<Message, MessageError> {
+    let value: serde_json::Value = serde_json::from_str(text)
+        .map_err(|e| MessageError::InvalidJson(e.to_string()))?;
+
+    let action = match value.as_object() {
+        None => return Err(MessageError::NotAnObject),
+        Some(object) => match object.get("action") {
+            None | Some(serde_json::Value::Null) => return Err(MessageError::MissingAction),
+            Some(serde_json::Value::String(action)) => action.clone(),
+            Some(other) => return Err(MessageError::UnknownAction(other.to_string())),
+        },
+    };
+
+    if !MESSAGE_ACTIONS.contains(&action.as_str()) {
+        return Err(MessageError::UnknownAction(action));
+    }
+
+    // serde's messages here name the missing or mistyped field without echoing internals
+    let message = serde_json::from_value(value)
+        .map_err(|e| MessageError::InvalidFields { action: action.clone(), detail: e.to_string() })?;
+
+    if let Message::Batch { actions } = &message {
+        if actions.len() > MAX_BATCH_ACTIONS {
+            return Err(MessageError::InvalidFields {
+                action,
+                detail: format!("{} actions exceeds the limit of {}", actions.len(), MAX_BATCH_ACTIONS),
+            });
+        }
+        if actions.iter().any(|action| matches!(action, Message::Batch { .. })) {
+            return Err(MessageError::InvalidFields { action, detail: "batches cannot be nested".to_string() });
+        }
+    }
+
+    Ok(message)
 }
 
 fn deserialize_number_from_string<'de, D>(deserializer: D) -> Result<f64, D::Error>
@@ -98,13 +216,110 @@ pub struct CreateInvoiceRequest {
     pub memo: Option<String>,
 }
 
+/// Where an invoice is in its lifecycle, as stored in `invoices.status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvoiceStatus {
+    Unpaid,
+    #[serde(alias = "confirmed")]
+    Paid,
+    Underpaid,
+    Expired,
+    Cancelled,
+    /// A status this build doesn't know about yet
+    #[serde(other)]
+    Unknown,
+}
+
+impl InvoiceStatus {
+    /// The stored string, for filtering queries by status
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InvoiceStatus::Unpaid => "unpaid",
+            InvoiceStatus::Paid => "paid",
+            InvoiceStatus::Underpaid => "underpaid",
+            InvoiceStatus::Expired => "expired",
+            InvoiceStatus::Cancelled => "cancelled",
+            InvoiceStatus::Unknown => "unknown",
+        }
+    }
+
+    /// Whether the invoice has been paid, whether or not the payment is confirmed yet
+    pub fn is_paid(&self) -> bool {
+        matches!(self, InvoiceStatus::Paid)
+    }
+}
+
+impl std::fmt::Display for InvoiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A change to an invoice, delivered to the streams from `EventDispatcher::subscribe_invoice`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InvoiceEvent {
+    pub uid: String,
+    pub status: InvoiceStatus,
+}
+
+/// An event for consumers outside the process, such as the AMQP exchange, keyed by its topic
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PublishedEvent {
+    pub topic: String,
+    pub payload: serde_json::Value,
+}
+
+/// A row of the append-only `invoice_events` table, recording one change of an invoice's status
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InvoiceTransition {
+    pub invoice_uid: String,
+    /// None when the row records the invoice's creation
+    pub old_status: Option<InvoiceStatus>,
+    pub new_status: InvoiceStatus,
+    /// Who made the change, e.g. "account:42" or "system:expiry"
+    pub actor: String,
+    pub created_at: String,
+}
+
+/// Where a payment is in its lifecycle, as stored in `payments.status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentStatus {
+    /// Seen in the mempool but not recorded, only reported in payment.seen events
+    Unconfirmed,
+    /// Recorded and waiting to be mined
+    Pending,
+    Confirmed,
+    Failed,
+}
+
+impl PaymentStatus {
+    /// The stored string, for filtering queries by status
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Unconfirmed => "unconfirmed",
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Confirmed => "confirmed",
+            PaymentStatus::Failed => "failed",
+        }
+    }
+}
+
+impl std::fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Invoice {
     pub id: i64,
     pub uid: String,
-    pub amount: i64,
+    /// None for donation invoices, which accept whatever amount the payer sends
+    pub amount: Option<i64>,
     pub currency: String,
-    pub status: String,
+    pub status: InvoiceStatus,
     pub account_id: i64,
     pub complete: Option<bool>,
     pub webhook_url: Option<String>,
@@ -113,6 +328,29 @@ pub struct Invoice {
     pub uri: String,
     pub createdAt: String,
     pub updatedAt: String,
+    /// Currencies the invoice may be paid in; every currency the account has an address for when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted_currencies: Option<Vec<String>>,
+    /// Minimum fee rate wallets must pay, overriding the coin's required_fee_rate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_fee_rate: Option<i64>,
+    /// The amount is fixed in base units of `currency`, a crypto, rather than converted from fiat
+    #[serde(default)]
+    pub crypto_denominated: bool,
+    /// Hex-encoded data payments must carry in an OP_RETURN output, on chains that have them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op_return: Option<String>,
+    /// Computed from the invoice's payments when it is fetched, never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<InvoiceSummary>,
+}
+
+/// Amounts paid towards an invoice, denominated in the invoice currency
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InvoiceSummary {
+    pub total_expected: i64,
+    pub total_received: i64,
+    pub outstanding: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -120,6 +358,9 @@ pub struct Price {
     pub id: i64,
     pub currency: String,
     pub value: f64,
+    /// The feed the price came from, when the pair is quoted by more than one
+    #[serde(default)]
+    pub source: Option<String>,
     #[serde(rename = "createdAt")]
     pub created_at: String,
     #[serde(rename = "updatedAt")]
@@ -170,18 +411,33 @@ pub struct PaymentOption {
     #[serde(rename = "updatedAt")]
     pub updated_at: String,
     pub expires: String,
+    /// Index of the address on the account's xpub, when it was derived rather than configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derivation_index: Option<i64>,
+    /// Brand color of the coin, for checkout buttons
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Hex-encoded data the payment must carry in an OP_RETURN output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op_return: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Output {
     pub address: String,
     pub amount: i64,
+    /// Hex-encoded output script to pay instead of the address's, for scripts with no address form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
     pub id: i64,
     pub denomination: Option<String>,
+    /// Base URL of a white-label merchant's payment domain, used in place of BASE_URL
+    #[serde(default)]
+    pub base_url: Option<String>,
     // ... other fields ...
 }
 
@@ -193,6 +449,18 @@ pub struct Address {
     // ... other fields
 }
 
+/// Derivation progress for an account's extended public key on a chain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressIndex {
+    pub account_id: i64,
+    pub chain: String,
+    /// Counter of addresses handed out so far
+    pub next_index: i64,
+    /// Highest index that has received a payment
+    #[serde(default)]
+    pub last_used_index: Option<i64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coin {
     pub id: i64,
@@ -212,4 +480,103 @@ pub struct Coin {
     pub supported: bool,
     pub required_fee_rate: Option<i64>,
     pub color: Option<String>,
-}
\ No newline at end of file
+    /// Smallest payable amount in base units; defaults to the network's dust limit
+    #[serde(default)]
+    pub min_amount: Option<i64>,
+    /// Token contract, for coins issued on another coin's chain
+    #[serde(default)]
+    pub contract_address: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message_malformed_json() {
+        assert!(matches!(parse_message("{\"action\": "), Err(MessageError::InvalidJson(_))));
+        assert_eq!(parse_message("[1, 2]").unwrap_err(), MessageError::NotAnObject);
+    }
+
+    #[test]
+    fn test_parse_message_missing_action() {
+        let err = parse_message(r#"{"id": "inv_123"}"#).unwrap_err();
+        assert_eq!(err, MessageError::MissingAction);
+        assert_eq!(err.to_string(), "Missing required field `action`");
+    }
+
+    #[test]
+    fn test_parse_message_unknown_action() {
+        let err = parse_message(r#"{"action": "refund"}"#).unwrap_err();
+        assert_eq!(err, MessageError::UnknownAction("refund".to_string()));
+        assert!(err.to_string().contains("expected one of: subscribe"));
+    }
+
+    #[test]
+    fn test_parse_message_invalid_fields() {
+        let err = parse_message(r#"{"action": "fetch_invoice"}"#).unwrap_err();
+        match err {
+            MessageError::InvalidFields { action, detail } => {
+                assert_eq!(action, "fetch_invoice");
+                assert!(detail.contains("missing field `id`"), "{}", detail);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_message_batch_limits() {
+        let ping = r#"{"action": "ping"}"#;
+        let batch = |count: usize| format!(r#"{{"action": "batch", "actions": [{}]}}"#, vec![ping; count].join(","));
+
+        assert!(matches!(parse_message(&batch(MAX_BATCH_ACTIONS)), Ok(Message::Batch { actions }) if actions.len() == MAX_BATCH_ACTIONS));
+        assert!(matches!(
+            parse_message(&batch(MAX_BATCH_ACTIONS + 1)),
+            Err(MessageError::InvalidFields { action, .. }) if action == "batch"
+        ));
+        assert!(matches!(
+            parse_message(r#"{"action": "batch", "actions": [{"action": "batch", "actions": []}]}"#),
+            Err(MessageError::InvalidFields { detail, .. }) if detail == "batches cannot be nested"
+        ));
+    }
+
+    #[test]
+    fn test_parse_message_valid() {
+        assert!(matches!(parse_message(r#"{"action": "ping"}"#), Ok(Message::Ping)));
+        assert!(matches!(
+            parse_message(r#"{"action": "fetch_invoice", "id": "inv_123"}"#),
+            Ok(Message::FetchInvoice { id }) if id == "inv_123"
+        ));
+    }
+
+    #[test]
+    fn test_statuses_round_trip_through_their_wire_strings() {
+        let invoice_statuses = [
+            (InvoiceStatus::Unpaid, "unpaid"),
+            (InvoiceStatus::Paid, "paid"),
+            (InvoiceStatus::Underpaid, "underpaid"),
+            (InvoiceStatus::Expired, "expired"),
+            (InvoiceStatus::Cancelled, "cancelled"),
+        ];
+        for (status, wire) in invoice_statuses {
+            assert_eq!(serde_json::to_value(status).unwrap(), wire);
+            assert_eq!(serde_json::from_value::<InvoiceStatus>(wire.into()).unwrap(), status);
+            assert_eq!(status.as_str(), wire);
+        }
+
+        let payment_statuses = [
+            (PaymentStatus::Unconfirmed, "unconfirmed"),
+            (PaymentStatus::Pending, "pending"),
+            (PaymentStatus::Confirmed, "confirmed"),
+            (PaymentStatus::Failed, "failed"),
+        ];
+        for (status, wire) in payment_statuses {
+            assert_eq!(serde_json::to_value(status).unwrap(), wire);
+            assert_eq!(serde_json::from_value::<PaymentStatus>(wire.into()).unwrap(), status);
+            assert_eq!(status.as_str(), wire);
+        }
+
+        assert_eq!(serde_json::from_value::<InvoiceStatus>("confirmed".into()).unwrap(), InvoiceStatus::Paid);
+        assert_eq!(serde_json::from_value::<InvoiceStatus>("settled".into()).unwrap(), InvoiceStatus::Unknown);
+    }
+}
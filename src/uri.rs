@@ -1,14 +1,197 @@
+use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Longest memo passed on to wallets, in characters
+pub const MAX_MEMO_LENGTH: usize = 100;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InvoiceUriParams {
     pub currency: String,
     pub uid: String,
+    pub memo: Option<String>,
+    /// Payment protocol host, from `get_base_url`
+    pub base_url: String,
+}
+
+/// Collapse whitespace and drop control characters from an invoice memo, truncated to MAX_MEMO_LENGTH
+pub fn sanitize_memo(memo: &str) -> Option<String> {
+    let cleaned = memo.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_MEMO_LENGTH)
+        .collect::<String>();
+
+    let cleaned = cleaned.trim();
+    (!cleaned.is_empty()).then(|| cleaned.to_string())
+}
+
+/// Payment protocol host invoices point at when their account has no domain of its own
+pub const DEFAULT_BASE_URL: &str = "https://api.anypayx.com";
+
+/// The account's own base URL when configured, otherwise BASE_URL, without a trailing slash
+pub fn get_base_url(account_base_url: Option<&str>) -> String {
+    let base_url = account_base_url
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| std::env::var("BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string()));
+
+    base_url.trim_end_matches('/').to_string()
+}
+
+/// Payment protocol URI wallets fetch the invoice's payment request from
+pub fn compute_payment_request_uri(base_url: &str, uid: &str) -> String {
+    format!("pay:?r={}/r/{}", base_url, uid)
 }
 
 pub fn compute_invoice_uri(params: &InvoiceUriParams) -> String {
     // Format: anypay:{currency}_{uid}
-    format!("anypay:{}_{}", params.currency.to_lowercase(), params.uid)
+    let uri = format!("anypay:{}_{}", params.currency.to_lowercase(), params.uid);
+
+    match encoded_memo(params) {
+        Some(message) => format!("{}?message={}", uri, message),
+        None => uri,
+    }
+}
+
+/// Render a coin's uri_template, replacing {address}, {amount}, {uid}, {currency} and {memo}.
+/// The amount is in whole coin units and the memo is percent-encoded (each empty when unset)
+pub fn render_uri_template(template: &str, params: &InvoiceUriParams, address: &str, amount: Option<f64>) -> String {
+    template
+        .replace("{address}", address)
+        .replace("{amount}", &amount.map(|amount| amount.to_string()).unwrap_or_default())
+        .replace("{uid}", &params.uid)
+        .replace("{currency}", &params.currency.to_lowercase())
+        .replace("{memo}", &encoded_memo(params).unwrap_or_default())
+}
+
+/// BIP21 scheme for currencies whose wallets take the address and amount straight from the URI
+pub fn bip21_scheme(currency: &str) -> Option<&'static str> {
+    match currency.to_uppercase().as_str() {
+        "BTC" => Some("bitcoin"),
+        "BCH" => Some("bitcoincash"),
+        "LTC" => Some("litecoin"),
+        "DOGE" => Some("dogecoin"),
+        "DASH" => Some("dash"),
+        "ZEC" => Some("zcash"),
+        _ => None,
+    }
+}
+
+/// BIP21 URI paying `amount` whole coins to `address`, with the memo as its message.
+/// Without an amount the wallet asks the payer how much to send.
+pub fn compute_bip21_uri(scheme: &str, params: &InvoiceUriParams, address: &str, amount: Option<f64>) -> String {
+    let query = amount.map(|amount| format!("amount={}", amount))
+        .into_iter()
+        .chain(encoded_memo(params).map(|message| format!("message={}", message)))
+        .collect::<Vec<_>>();
+
+    if query.is_empty() {
+        format!("{}:{}", scheme, address)
+    } else {
+        format!("{}:{}?{}", scheme, address, query.join("&"))
+    }
+}
+
+/// Scannable payment URI for an option: the coin's uri_template when it has one, a BIP21 URI
+/// for currencies that support it, otherwise a payment protocol (BIP70-style) request URI.
+/// BIP21 can only pay a single address, so options with more than one output (e.g. a separate
+/// fee output) use the payment request as well.
+/// Options without an amount, on donation invoices, fall back to the bare address instead,
+/// since a payment request would fix the amount.
+pub fn compute_payment_uri(template: Option<&str>, params: &InvoiceUriParams, address: &str, amount: Option<f64>, outputs: usize) -> String {
+    if let Some(template) = template.map(str::trim).filter(|t| !t.is_empty()) {
+        return render_uri_template(template, params, address, amount);
+    }
+
+    match (bip21_scheme(&params.currency), amount) {
+        (Some(scheme), None) => compute_bip21_uri(scheme, params, address, None),
+        (Some(scheme), Some(_)) if outputs == 1 => compute_bip21_uri(scheme, params, address, amount),
+        (_, Some(_)) => compute_payment_request_uri(&params.base_url, &params.uid),
+        (None, None) => address.to_string(),
+    }
+}
+
+/// An invoice reference decoded from a payment URI
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PaymentUri {
+    pub uid: String,
+    /// Host serving the invoice, e.g. "https://api.anypayx.com", when the URI names one
+    pub base_url: Option<String>,
+}
+
+/// Decode any of the forms invoices are shared in: a payment request URI (`pay:?r=https://host/r/{uid}`),
+/// an invoice or payment request URL (`https://host/i/{uid}`, `https://host/r/{uid}`), an invoice
+/// URI (`anypay:btc_{uid}`) or a bare uid
+pub fn parse_payment_uri(input: &str) -> Result<PaymentUri> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(anyhow!("Empty payment URI"));
+    }
+
+    let Ok(url) = Url::parse(input) else {
+        return Ok(PaymentUri { uid: validate_uid(input)?, base_url: None });
+    };
+
+    match url.scheme() {
+        "pay" => {
+            let request_url = url.query_pairs()
+                .find(|(key, _)| key == "r")
+                .ok_or_else(|| anyhow!("Invalid payment URI {}: missing 'r' parameter", input))?
+                .1
+                .to_string();
+            let request_url = Url::parse(&request_url)
+                .map_err(|e| anyhow!("Invalid payment request URL {}: {}", request_url, e))?;
+            parse_invoice_url(&request_url)
+        }
+        "http" | "https" => parse_invoice_url(&url),
+        "anypay" => {
+            // anypay:{currency}_{uid}, as compute_invoice_uri writes it
+            let (_, uid) = url.path().split_once('_')
+                .ok_or_else(|| anyhow!("Invalid invoice URI {}: expected anypay:{{currency}}_{{uid}}", input))?;
+            Ok(PaymentUri { uid: validate_uid(uid)?, base_url: None })
+        }
+        scheme => Err(anyhow!("Unsupported payment URI scheme '{}' in {}", scheme, input)),
+    }
+}
+
+/// The uid and host of an invoice (/i/{uid}) or payment request (/r/{uid}) URL
+fn parse_invoice_url(url: &Url) -> Result<PaymentUri> {
+    let segments = url.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let uid = match segments.as_slice() {
+        ["i" | "r" | "invoices", uid] => *uid,
+        _ => return Err(anyhow!("Invalid invoice URL {}: expected /i/{{uid}} or /r/{{uid}}", url)),
+    };
+    let host = url.host_str()
+        .ok_or_else(|| anyhow!("Invalid invoice URL {}: missing host", url))?;
+    let base_url = match url.port() {
+        Some(port) => format!("{}://{}:{}", url.scheme(), host, port),
+        None => format!("{}://{}", url.scheme(), host),
+    };
+
+    Ok(PaymentUri { uid: validate_uid(uid)?, base_url: Some(base_url) })
+}
+
+fn validate_uid(uid: &str) -> Result<String> {
+    if uid.is_empty() || !uid.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(anyhow!("Invalid invoice uid '{}'", uid));
+    }
+    Ok(uid.to_string())
+}
+
+// BIP21 message, percent-encoded (form encoding uses '+' for spaces, which BIP21 doesn't)
+fn encoded_memo(params: &InvoiceUriParams) -> Option<String> {
+    params.memo.as_deref().and_then(sanitize_memo).map(|memo| {
+        url::form_urlencoded::byte_serialize(memo.as_bytes())
+            .collect::<String>()
+            .replace('+', "%20")
+    })
 }
 
 #[cfg(test)]
@@ -20,9 +203,150 @@ mod tests {
         let params = InvoiceUriParams {
             currency: "BTC".to_string(),
             uid: "inv_123".to_string(),
+            memo: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
         };
 
         let uri = compute_invoice_uri(&params);
         assert_eq!(uri, "anypay:btc_inv_123");
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_invoice_uri_includes_escaped_memo() {
+        let params = InvoiceUriParams {
+            currency: "BTC".to_string(),
+            uid: "inv_123".to_string(),
+            memo: Some("Coffee & cake\nfor 2+1".to_string()),
+            base_url: DEFAULT_BASE_URL.to_string(),
+        };
+
+        let uri = compute_invoice_uri(&params);
+        assert_eq!(uri, "anypay:btc_inv_123?message=Coffee%20%26%20cake%20for%202%2B1");
+    }
+
+    #[test]
+    fn test_render_uri_template() {
+        let params = InvoiceUriParams {
+            currency: "BTC".to_string(),
+            uid: "inv_123".to_string(),
+            memo: Some("Order 42".to_string()),
+            base_url: DEFAULT_BASE_URL.to_string(),
+        };
+
+        let uri = render_uri_template(
+            "bitcoin:{address}?amount={amount}&label={uid}&message={memo}",
+            &params,
+            "bc1qp5wfcq48h6d63wyy9qz0awtpfqwwv4sma86mhz",
+            Some(0.0005),
+        );
+        assert_eq!(
+            uri,
+            "bitcoin:bc1qp5wfcq48h6d63wyy9qz0awtpfqwwv4sma86mhz?amount=0.0005&label=inv_123&message=Order%2042"
+        );
+    }
+
+    #[test]
+    fn test_payment_uri_falls_back_without_template() {
+        let params = |currency: &str| InvoiceUriParams {
+            currency: currency.to_string(),
+            uid: "inv_123".to_string(),
+            memo: Some("Order 42".to_string()),
+            base_url: "https://pay.shop-a.com".to_string(),
+        };
+
+        assert_eq!(compute_payment_uri(None, &params("BTC"), "addr", Some(0.0005), 1), "bitcoin:addr?amount=0.0005&message=Order%2042");
+        assert_eq!(compute_payment_uri(Some("  "), &params("DOGE"), "addr", Some(12.5), 1), "dogecoin:addr?amount=12.5&message=Order%2042");
+        assert_eq!(compute_payment_uri(None, &params("BSV"), "addr", Some(1.0), 1), "pay:?r=https://pay.shop-a.com/r/inv_123");
+        assert_eq!(compute_payment_uri(Some("xrp:{address}"), &params("XRP"), "addr", Some(1.0), 1), "xrp:addr");
+    }
+
+    #[test]
+    fn test_payment_uri_with_fee_output_uses_payment_request() {
+        let params = InvoiceUriParams {
+            currency: "BTC".to_string(),
+            uid: "inv_123".to_string(),
+            memo: None,
+            base_url: "https://pay.shop-a.com".to_string(),
+        };
+
+        // A BIP21 URI would pay the whole amount to the merchant and leave the fee output unpaid
+        assert_eq!(compute_payment_uri(None, &params, "addr", Some(0.0005), 2), "pay:?r=https://pay.shop-a.com/r/inv_123");
+        assert_eq!(compute_payment_uri(None, &params, "addr", None, 1), "bitcoin:addr");
+    }
+
+    #[test]
+    fn test_donation_uris_carry_only_the_address() {
+        let params = |currency: &str, memo: Option<&str>| InvoiceUriParams {
+            currency: currency.to_string(),
+            uid: "inv_123".to_string(),
+            memo: memo.map(str::to_string),
+            base_url: DEFAULT_BASE_URL.to_string(),
+        };
+
+        assert_eq!(compute_payment_uri(None, &params("BTC", None), "addr", None, 1), "bitcoin:addr");
+        assert_eq!(compute_payment_uri(None, &params("BTC", Some("Tip jar")), "addr", None, 1), "bitcoin:addr?message=Tip%20jar");
+        assert_eq!(compute_payment_uri(None, &params("BSV", None), "addr", None, 1), "addr");
+    }
+
+    #[test]
+    fn test_sanitize_memo() {
+        assert_eq!(sanitize_memo("  Order\t#42\u{7}  "), Some("Order #42".to_string()));
+        assert_eq!(sanitize_memo(" \n "), None);
+        assert_eq!(sanitize_memo(&"x".repeat(500)).unwrap().len(), MAX_MEMO_LENGTH);
+    }
+
+    #[test]
+    fn test_parse_payment_uri_forms() {
+        let hosted = |uid: &str, base_url: &str| PaymentUri { uid: uid.to_string(), base_url: Some(base_url.to_string()) };
+        let bare = |uid: &str| PaymentUri { uid: uid.to_string(), base_url: None };
+
+        assert_eq!(parse_payment_uri("pay:?r=https://api.anypayx.com/r/inv_123").unwrap(), hosted("inv_123", "https://api.anypayx.com"));
+        assert_eq!(parse_payment_uri("https://anypayx.com/i/inv_123").unwrap(), hosted("inv_123", "https://anypayx.com"));
+        assert_eq!(parse_payment_uri("http://localhost:8000/r/inv_123/").unwrap(), hosted("inv_123", "http://localhost:8000"));
+        assert_eq!(parse_payment_uri("anypay:btc_inv_123?message=Order%2042").unwrap(), bare("inv_123"));
+        assert_eq!(parse_payment_uri(" inv_123 ").unwrap(), bare("inv_123"));
+
+        // The URIs this module writes decode back to their invoice
+        let payment_request = compute_payment_request_uri("https://pay.shop-a.com", "inv_9");
+        assert_eq!(parse_payment_uri(&payment_request).unwrap(), hosted("inv_9", "https://pay.shop-a.com"));
+    }
+
+    #[test]
+    fn test_parse_payment_uri_ignores_trailing_slash_and_query() {
+        for input in [
+            "https://anypayx.com/i/abc/",
+            "https://anypayx.com/i/abc?utm_source=email",
+            "https://api.anypayx.com/r/abc",
+            "pay:?r=https://api.anypayx.com/r/abc",
+            "pay:?r=https%3A%2F%2Fapi.anypayx.com%2Fr%2Fabc%2F",
+            "abc",
+        ] {
+            assert_eq!(parse_payment_uri(input).unwrap().uid, "abc", "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_payment_uri_rejects_malformed_input() {
+        for input in [
+            "",
+            "pay:?x=https://api.anypayx.com/r/inv_123",
+            "pay:?r=not a url",
+            "https://anypayx.com/",
+            "https://anypayx.com/i/inv_123/extra",
+            "https://anypayx.com/pricing",
+            "anypay:btc_",
+            "bitcoin:bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            "inv 123",
+        ] {
+            assert!(parse_payment_uri(input).is_err(), "{:?} should be rejected", input);
+        }
+    }
+
+    #[test]
+    fn test_payment_request_uri_on_account_domain() {
+        let base_url = get_base_url(Some("https://pay.shop-a.com/"));
+
+        assert_eq!(base_url, "https://pay.shop-a.com");
+        assert_eq!(compute_payment_request_uri(&base_url, "inv_1"), "pay:?r=https://pay.shop-a.com/r/inv_1");
+    }
+}
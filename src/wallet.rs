@@ -10,18 +10,171 @@ use bitcoin::{
     witness::Witness,
     address::Payload,
     consensus::encode::serialize_hex,
+    script::{Builder, PushBytes},
 };
-use bip32::{Mnemonic, XPrv, XPub, DerivationPath};
-use rand_core::OsRng;
+use bip32::{XPrv, XPub, DerivationPath};
+use bip39::{Language, Mnemonic};
 use anyhow::{Result, anyhow};
+use std::collections::HashMap;
 use std::str::FromStr;
-use url::Url;
 use crate::client::{AnypayClient, Utxo};
 use crate::cards;
-use serde::Deserialize;
+use crate::plugin::get_plugin;
+
+/// Derive the scriptPubKey for a P2PKH, P2SH, P2WPKH/P2WSH or P2TR address on the given network
+pub fn script_pubkey_for_address(address: &str, network: Network) -> Result<ScriptBuf> {
+    let address = BtcAddress::from_str(address)
+        .map_err(|e| anyhow!("Invalid address {}: {}", address, e))?
+        .require_network(network)
+        .map_err(|e| anyhow!("Address network mismatch: {}", e))?;
+
+    Ok(address.payload().script_pubkey())
+}
+
+/// Finalize each signed input of `psbt`, building its scriptSig and witness from its partial
+/// signature for P2WPKH, P2SH-P2WPKH and P2PKH outputs. Inputs that are already final are left alone.
+pub fn finalize_psbt(psbt: &mut Psbt) -> Result<()> {
+    for i in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[i];
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+
+        let vout = psbt.unsigned_tx.input[i].previous_output.vout as usize;
+        let spent = input.witness_utxo.as_ref()
+            .or_else(|| input.non_witness_utxo.as_ref().and_then(|tx| tx.output.get(vout)))
+            .map(|utxo| utxo.script_pubkey.clone())
+            .ok_or_else(|| anyhow!("Input {} is missing the output it spends", i))?;
+        let (public_key, signature) = match input.partial_sigs.len() {
+            0 => return Err(anyhow!("Input {} is not signed", i)),
+            1 => input.partial_sigs.iter().next().map(|(key, sig)| (*key, *sig)).unwrap(),
+            n => return Err(anyhow!("Input {} has {} signatures; only single-key inputs can be finalized", i, n)),
+        };
+
+        let input = &mut psbt.inputs[i];
+        if spent.is_p2wpkh() {
+            input.final_script_witness = Some(Witness::p2wpkh(&signature, &public_key.inner));
+        } else if spent.is_p2sh() && input.redeem_script.as_ref().is_some_and(|redeem| redeem.is_p2wpkh()) {
+            let redeem_script = input.redeem_script.take().unwrap();
+            let redeem_push: &PushBytes = redeem_script.as_bytes().try_into()
+                .map_err(|e| anyhow!("Failed to push redeem script: {}", e))?;
+            input.final_script_sig = Some(Builder::new().push_slice(redeem_push).into_script());
+            input.final_script_witness = Some(Witness::p2wpkh(&signature, &public_key.inner));
+        } else if spent.is_p2pkh() {
+            let sig_bytes = signature.to_vec();
+            let sig_push: &PushBytes = sig_bytes.as_slice().try_into()
+                .map_err(|e| anyhow!("Failed to push signature: {}", e))?;
+            input.final_script_sig = Some(Builder::new().push_slice(sig_push).push_key(&public_key).into_script());
+        } else {
+            return Err(anyhow!("Input {} spends a {} script, which can't be finalized", i, spent.to_asm_string()));
+        }
+
+        // Per BIP174, the finalizer clears everything the final scripts replace
+        input.partial_sigs.clear();
+        input.sighash_type = None;
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation.clear();
+    }
+
+    Ok(())
+}
+
+/// Extract the signed transaction from `psbt`, which `finalize_psbt` must have completed, so a
+/// partly signed PSBT is reported rather than producing a transaction nodes reject
+pub fn extract_finalized_tx(psbt: Psbt) -> Result<Transaction> {
+    if let Some(i) = psbt.inputs.iter().position(|input| input.final_script_sig.is_none() && input.final_script_witness.is_none()) {
+        return Err(anyhow!("Input {} is not finalized; sign and finalize every input before extracting the transaction", i));
+    }
+
+    psbt.extract_tx()
+        .map_err(|e| anyhow!("Failed to extract transaction: {}", e))
+}
+
+/// A stage of `Wallet::pay_invoice_with`, reported as it completes
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaymentStage {
+    /// UTXOs were fetched for every source address
+    UtxosFetched { count: usize, total: Amount },
+    /// The unsigned transaction was built, sending any change to `change`
+    TransactionBuilt { inputs: usize, outputs: usize, fee: Amount, change: Option<String> },
+    /// Every input was signed and the transaction checked against the invoice
+    Signed { txid: String, tx_hex: String },
+    /// The transaction was submitted for the invoice
+    Submitted { txid: String },
+}
+
+impl std::fmt::Display for PaymentStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentStage::UtxosFetched { count, total } => write!(f, "Fetched {} UTXOs holding {} sats", count, total.to_sat()),
+            PaymentStage::TransactionBuilt { inputs, outputs, fee, change } => {
+                write!(f, "Built transaction with {} inputs and {} outputs, paying a {} sat fee", inputs, outputs, fee.to_sat())?;
+                match change {
+                    Some(change) => write!(f, " with change to {}", change),
+                    None => Ok(()),
+                }
+            }
+            PaymentStage::Signed { txid, tx_hex } => write!(f, "Signed transaction {}: {}", txid, tx_hex),
+            PaymentStage::Submitted { txid } => write!(f, "Submitted payment {}", txid),
+        }
+    }
+}
+
+/// A stage of `Wallet::bump_fee`, reported as it completes
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeeBumpStage {
+    /// The transaction to replace was fetched; nodes enforcing BIP125 may reject the replacement
+    /// of one that isn't `replaceable`
+    Fetched { txid: String, replaceable: bool },
+    /// The replacement paying `fee` was signed and is being broadcast
+    Signed { txid: String, fee: Amount },
+}
+
+impl std::fmt::Display for FeeBumpStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeeBumpStage::Fetched { txid, replaceable: true } => write!(f, "Fetched transaction {}", txid),
+            FeeBumpStage::Fetched { txid, replaceable: false } => {
+                write!(f, "Warning: {} doesn't signal replace-by-fee, so nodes enforcing BIP125 may reject the replacement", txid)
+            }
+            FeeBumpStage::Signed { txid, fee } => write!(f, "Broadcasting replacement {} paying {} sats in fees", txid, fee.to_sat()),
+        }
+    }
+}
+
+pub use crate::payment::MAX_OP_RETURN_BYTES;
+
+/// A zero-value output carrying `data` after OP_RETURN
+pub fn op_return_output(data: &[u8]) -> Result<TxOut> {
+    if data.len() > MAX_OP_RETURN_BYTES {
+        return Err(anyhow!("OP_RETURN data is {} bytes; at most {} are relayed", data.len(), MAX_OP_RETURN_BYTES));
+    }
+    let push: &PushBytes = data.try_into()
+        .map_err(|e| anyhow!("Failed to push OP_RETURN data: {}", e))?;
+
+    Ok(TxOut {
+        value: Amount::ZERO,
+        script_pubkey: Builder::new().push_opcode(bitcoin::opcodes::all::OP_RETURN).push_slice(push).into_script(),
+    })
+}
+
+/// Most change addresses scanned for history before a payment; change is handed out in order,
+/// so a wallet only reaches this after that many payments with change
+pub const MAX_CHANGE_CARDS: u32 = 1000;
+
+/// Version, locktime, input and output counts, and the segwit marker and flag, in vbytes
+const TX_OVERHEAD_VSIZE: u64 = 11;
+
+/// A P2WPKH input: outpoint, empty script sig and sequence, plus its discounted signature and key
+const P2WPKH_INPUT_VSIZE: u64 = 68;
+
+/// Smallest output relayed by default policy; change can't be reduced below it to bump a fee
+pub const DUST_LIMIT_SATS: u64 = 546;
 
 pub struct Wallet {
     mnemonic: Mnemonic,
+    seed_phrase: String,
     master_key: XPrv,
 }
 
@@ -36,29 +189,62 @@ pub struct BitcoinCard {
 }
 
 impl Wallet {
-    /// Create a new wallet from an existing seed phrase
+    /// Create a new wallet from an existing seed phrase, detecting its wordlist language
     pub fn from_seed_phrase(seed_phrase: &str) -> Result<Self> {
-        let mnemonic = Mnemonic::new(seed_phrase, Default::default())
-            .map_err(|e| anyhow!("Invalid seed phrase: {}", e))?;
+        Self::from_seed_phrase_in(seed_phrase, None)
+    }
+
+    /// Create a new wallet from an existing seed phrase in the given wordlist language
+    pub fn from_seed_phrase_in(seed_phrase: &str, language: Option<Language>) -> Result<Self> {
+        let mnemonic = cards::parse_mnemonic(seed_phrase, language)?;
         
         let seed = mnemonic.to_seed("");
         let master_key = XPrv::new(&seed)
             .map_err(|e| anyhow!("Failed to derive master key: {}", e))?;
 
         Ok(Self {
+            seed_phrase: mnemonic.to_string(),
             mnemonic,
             master_key,
         })
     }
 
-    /// Generate a new wallet with a random seed phrase
+    /// Create a watch-only wallet from an account-level xpub (m/44'/0'/account').
+    /// It can derive addresses and check balances, but never sign.
+    pub fn from_xpub(xpub: &str) -> Result<WatchOnlyWallet> {
+        // Parse eagerly so a bad xpub fails here rather than on first use
+        XPub::from_str(xpub)
+            .map_err(|e| anyhow!("Invalid xpub: {}", e))?;
+
+        Ok(WatchOnlyWallet {
+            xpub: xpub.to_string(),
+        })
+    }
+
+    /// Generate a new wallet with a random 24-word seed phrase
     pub fn new() -> Result<Self> {
-        let mnemonic = Mnemonic::random(&mut OsRng, Default::default());
+        Self::generate(24)
+    }
+
+    /// Generate a new wallet with a random seed phrase of 12, 15, 18, 21 or 24 words
+    pub fn generate(word_count: usize) -> Result<Self> {
+        Self::generate_in(Language::English, word_count)
+    }
+
+    /// Generate a new wallet with a random seed phrase from the given wordlist language
+    pub fn generate_in(language: Language, word_count: usize) -> Result<Self> {
+        if ![12, 15, 18, 21, 24].contains(&word_count) {
+            return Err(anyhow!("Invalid word count: {} (expected 12, 15, 18, 21 or 24)", word_count));
+        }
+
+        let mnemonic = Mnemonic::generate_in(language, word_count)
+            .map_err(|e| anyhow!("Failed to generate seed phrase: {}", e))?;
         let seed = mnemonic.to_seed("");
         let master_key = XPrv::new(&seed)
             .map_err(|e| anyhow!("Failed to derive master key: {}", e))?;
 
         Ok(Self {
+            seed_phrase: mnemonic.to_string(),
             mnemonic,
             master_key,
         })
@@ -66,47 +252,40 @@ impl Wallet {
 
     /// Get the seed phrase
     pub fn seed_phrase(&self) -> &str {
-        self.mnemonic.phrase()
+        &self.seed_phrase
+    }
+
+    /// Get the wordlist language of the seed phrase
+    pub fn language(&self) -> Language {
+        self.mnemonic.language()
     }
 
     /// Create a new card for a specific chain and currency
     pub fn create_card(&self, chain: &str, currency: &str, network: Network, account: u32) -> Result<Box<dyn cards::Card>> {
-        cards::create_card(chain, currency, network, account, self.seed_phrase())
-    }
-
-    pub fn parse_invoice_identifier(invoice: &str) -> Result<String> {
-        if let Ok(url) = Url::parse(invoice) {
-            if url.scheme() == "pay" {
-                // Handle pay:?r=... URLs
-                let r_param = url.query_pairs()
-                    .find(|(key, _)| key == "r")
-                    .ok_or_else(|| anyhow!("Invalid payment URL: missing 'r' parameter"))?
-                    .1;
-                return Self::extract_uid_from_url(&r_param.to_string());
-            } else {
-                // Handle https://anypayx.com/i/{uid}
-                return Self::extract_uid_from_url(invoice);
-            }
-        }
-        // Assume it's just a UID
-        Ok(invoice.to_string())
+        cards::create_card(chain, currency, network, account, self.seed_phrase(), Some(self.language()))
     }
 
-    fn extract_uid_from_url(url: &str) -> Result<String> {
-        let parts: Vec<&str> = url.split('/').collect();
-        parts.last()
-            .ok_or_else(|| anyhow!("Invalid URL format"))
-            .map(|s| s.to_string())
+    pub async fn fetch_invoice_details(uid: &str, api_key: &str) -> Result<InvoiceDetails> {
+        Self::fetch_invoice_details_with(&AnypayClient::new(api_key), uid).await
     }
 
-    pub async fn fetch_invoice_details(uid: &str, api_key: &str) -> Result<InvoiceDetails> {
-        let client = AnypayClient::new(api_key);
+    /// Fetch the outputs invoice `uid` asks for through `client`, e.g. one pointed at the
+    /// invoice's own API with `with_api_url`
+    pub async fn fetch_invoice_details_with(client: &AnypayClient, uid: &str) -> Result<InvoiceDetails> {
         let invoice = client.get_invoice(uid).await?;
         
         let mut outputs = Vec::new();
+        let mut data_outputs = Vec::new();
         for opt in &invoice.payment_options {
             let currency = opt.currency.clone();
             for inst in &opt.instructions {
+                if let Some(op_return) = &inst.op_return {
+                    data_outputs.push(DataOutput {
+                        data: hex::decode(op_return)
+                            .map_err(|e| anyhow!("Invalid OP_RETURN data for {}: {}", currency, e))?,
+                        currency: currency.clone(),
+                    });
+                }
                 for out in &inst.outputs {
                     let amount = if currency == "BTC" {
                         out.amount  // Keep as satoshis for BTC
@@ -115,6 +294,7 @@ impl Wallet {
                     };
                     outputs.push(PaymentOutput {
                         address: out.address.clone(),
+                        script: out.script.clone(),
                         amount,
                         currency: currency.clone(),
                     });
@@ -125,6 +305,7 @@ impl Wallet {
         Ok(InvoiceDetails {
             uid: invoice.uid,
             outputs,
+            data_outputs,
         })
     }
 
@@ -167,57 +348,41 @@ impl Wallet {
         Ok(selected)
     }
 
-    pub async fn pay_invoice(card: &Box<dyn cards::Card>, invoice: &InvoiceDetails) -> Result<()> {
-        // Handle both BTC and FB payments
-        let outputs = invoice.outputs.iter()
-            .filter(|output| output.currency == card.currency())
-            .collect::<Vec<_>>();
+    /// Fee rate in sat/vbyte for a payment, from the chain plugin's estimate or a flat
+    /// 10 sat/vbyte when it can't provide one
+    async fn estimate_fee_rate(card: &Box<dyn cards::Card>) -> u64 {
+        let fallback = 10;
 
-        if outputs.is_empty() {
-            return Err(anyhow!("No {} payment options found for this invoice", card.currency()));
+        let Some(plugin) = get_plugin(card.chain(), card.currency()) else {
+            return fallback;
+        };
+        match plugin.estimate_fee_rate().await {
+            Ok(rate) if rate > 0 => rate as u64,
+            Ok(_) => fallback,
+            Err(e) => {
+                tracing::warn!("Fee estimation failed, using a flat {} sat/vbyte: {}", fallback, e);
+                fallback
+            }
         }
+    }
 
-        let api_key = std::env::var("ANYPAY_API_KEY")
-            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
-        
-        let client = AnypayClient::new(&api_key);
+    /// Estimated virtual size of a transaction spending `inputs` P2WPKH outputs to `outputs`
+    pub fn estimated_vsize(inputs: usize, outputs: &[TxOut]) -> u64 {
+        // Value, script length (one byte for any standard script) and script
+        let outputs_size: usize = outputs.iter()
+            .map(|output| 8 + 1 + output.script_pubkey.len())
+            .sum();
+        TX_OVERHEAD_VSIZE + P2WPKH_INPUT_VSIZE * inputs as u64 + outputs_size as u64
+    }
 
-        // 1. Fetch UTXOs for the source address
-        println!("Fetching UTXOs...");
-        
+    /// UTXOs at `card`'s address, from the Fractal API for FB and the standard mempool API otherwise
+    async fn fetch_utxos(client: &AnypayClient, card: &Box<dyn cards::Card>) -> Result<Vec<Utxo>> {
         // Special handling for Fractal Bitcoin (FB) UTXOs
-        let utxos = if card.chain() == "FB" {
-            // Use the Fractal API to get UTXOs
-            #[derive(Deserialize)]
-            struct FractalUtxo {
-                txid: String,
-                vout: u32,
-                value: u64,
-                status: FractalUtxoStatus,
-            }
-            
-            #[derive(Deserialize)]
-            struct FractalUtxoStatus {
-                confirmed: bool,
-                block_height: Option<u32>,
-                block_time: Option<u64>,
-            }
-            
-            println!("Fetching UTXOs from Fractal API for {}", card.address());
-            let response = reqwest::Client::new()
-                .get(&format!("https://mempool.fractalbitcoin.io/api/v1/address/{}/utxo", card.address()))
-                .send()
-                .await?;
-                
-            if !response.status().is_success() {
-                let error = response.text().await?;
-                return Err(anyhow!("Failed to fetch UTXOs from Fractal API: {}", error));
-            }
-            
-            let fractal_utxos = response.json::<Vec<FractalUtxo>>().await?;
-            
+        if card.chain() == "FB" {
+            let fractal_utxos = crate::client::get_fractal_utxos(card.address()).await?;
+
             // Convert fractal UTXOs to our standard format
-            fractal_utxos.into_iter()
+            return Ok(fractal_utxos.into_iter()
                 .map(|u| {
                     Utxo {
                         txid: u.txid,
@@ -227,25 +392,283 @@ impl Wallet {
                         script_pub_key: String::new(),
                     }
                 })
-                .collect()
-        } else {
-            // For regular BTC, use the standard mempool API
-            client.get_utxos(card.address()).await?
-        };
+                .collect());
+        }
+
+        client.get_utxos(card.address()).await
+    }
+
+    /// The change cards of `card` that have been used, and the first that hasn't, found by walking
+    /// its internal chain (.../1/0, .../1/1, ...) until an address the mempool API has no transactions for.
+    /// Gives up after MAX_CHANGE_CARDS used addresses rather than querying the API without end.
+    async fn scan_change_cards(card: &Box<dyn cards::Card>, mempool_api_url: &str) -> Result<(Vec<Box<dyn cards::Card>>, Box<dyn cards::Card>)> {
+        let mut used = Vec::new();
+        for index in 0..MAX_CHANGE_CARDS {
+            let change = card.change_card(index)?;
+            if !crate::client::address_has_history(mempool_api_url, change.address()).await? {
+                return Ok((used, change));
+            }
+            used.push(change);
+        }
+        Err(anyhow!("All of the first {} change addresses of {} have been used", MAX_CHANGE_CARDS, card.address()))
+    }
+
+    /// Check that `tx` pays every one of `outputs` at least its amount, so a payment that wouldn't
+    /// settle the invoice is never submitted. Outputs to the same script are totalled.
+    pub fn verify_pays_outputs(tx: &Transaction, outputs: &[&PaymentOutput], network: Network) -> Result<()> {
+        let mut required: Vec<(&str, ScriptBuf, Amount)> = Vec::new();
+        for output in outputs {
+            let script = output.script_pubkey(network)?;
+            match required.iter_mut().find(|(_, required_script, _)| *required_script == script) {
+                Some((_, _, amount)) => *amount += Amount::from_sat(output.amount),
+                None => required.push((output.destination(), script, Amount::from_sat(output.amount))),
+            }
+        }
+
+        for (destination, script, amount) in required {
+            let paid = tx.output.iter()
+                .filter(|output| output.script_pubkey == script)
+                .map(|output| output.value)
+                .sum::<Amount>();
+            if paid < amount {
+                return Err(anyhow!("Transaction pays {} sats to {} but the invoice requires {} sats; not submitting it",
+                    paid.to_sat(), destination, amount.to_sat()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unsigned inputs spending `utxos`, signalling replace-by-fee (BIP125) when `rbf` is set
+    pub fn unsigned_inputs(utxos: &[Utxo], rbf: bool) -> Result<Vec<TxIn>> {
+        let sequence = if rbf { Sequence::ENABLE_RBF_NO_LOCKTIME } else { Sequence::MAX };
+
+        utxos.iter()
+            .map(|utxo| {
+                let outpoint = OutPoint::from_str(&format!("{}:{}", utxo.txid, utxo.vout))
+                    .map_err(|_| anyhow!("Invalid UTXO txid: {}", utxo.txid))?;
+                Ok(TxIn {
+                    previous_output: outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence,
+                    witness: Witness::default(),
+                })
+            })
+            .collect()
+    }
+
+    /// A copy of `tx` paying `new_fee`, spending the same inputs with the increase taken from
+    /// its change, the output paying one of `change_scripts`. The copy is unsigned and signals
+    /// replace-by-fee so it can be bumped again.
+    pub fn rebuild_with_fee(tx: &Transaction, prevouts: &[TxOut], change_scripts: &[ScriptBuf], new_fee: Amount) -> Result<Transaction> {
+        if prevouts.len() != tx.input.len() {
+            return Err(anyhow!("Expected {} previous outputs, got {}", tx.input.len(), prevouts.len()));
+        }
+
+        let total_input = prevouts.iter().map(|prevout| prevout.value).sum::<Amount>();
+        let total_output = tx.output.iter().map(|output| output.value).sum::<Amount>();
+        let current_fee = total_input.checked_sub(total_output)
+            .ok_or_else(|| anyhow!("Transaction spends more than its inputs"))?;
+        if new_fee <= current_fee {
+            return Err(anyhow!("New fee of {} sats must exceed the current fee of {} sats", new_fee.to_sat(), current_fee.to_sat()));
+        }
+        let increase = new_fee - current_fee;
+
+        let mut replacement = tx.clone();
+        let change = replacement.output.iter_mut()
+            .find(|output| change_scripts.contains(&output.script_pubkey))
+            .ok_or_else(|| anyhow!("Transaction has no change output to take a higher fee from"))?;
+        change.value = change.value.checked_sub(increase)
+            .filter(|value| *value >= Amount::from_sat(DUST_LIMIT_SATS))
+            .ok_or_else(|| anyhow!("Change of {} sats can't cover a fee increase of {} sats", change.value.to_sat(), increase.to_sat()))?;
+
+        for input in &mut replacement.input {
+            input.script_sig = ScriptBuf::new();
+            input.witness = Witness::default();
+            input.sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+        }
+
+        Ok(replacement)
+    }
+
+    /// Replace the unconfirmed transaction `txid`, paid from `cards` or their change addresses,
+    /// with one paying `fee_rate` sat/vbyte, broadcasting it and returning its txid. Each stage
+    /// is reported to `progress`.
+    pub async fn bump_fee(
+        cards: &[Box<dyn cards::Card>],
+        txid: &str,
+        fee_rate: u64,
+        progress: &(dyn Fn(&FeeBumpStage) + Sync),
+    ) -> Result<String> {
+        let card = cards.first()
+            .ok_or_else(|| anyhow!("No cards to sign with"))?;
+
+        let original = crate::client::get_transaction(card.chain(), txid).await?;
+        if original.confirmed {
+            return Err(anyhow!("Transaction {} is already confirmed", txid));
+        }
+        progress(&FeeBumpStage::Fetched { txid: txid.to_string(), replaceable: original.tx.is_explicitly_rbf() });
+
+        // The inputs and change of a payment belong to the cards or to their used change addresses
+        let mut used_change = Vec::new();
+        for source in cards {
+            used_change.extend(Self::scan_change_cards(source, crate::client::default_mempool_api_url(source.chain())).await?.0);
+        }
+        let signers = cards.iter().chain(&used_change).collect::<Vec<_>>();
+        let change_scripts = signers.iter()
+            .map(|signer| script_pubkey_for_address(signer.address(), signer.network()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let new_fee = Amount::from_sat(fee_rate * original.tx.vsize() as u64);
+        let replacement = Self::rebuild_with_fee(&original.tx, &original.prevouts, &change_scripts, new_fee)?;
+
+        let mut psbt = Psbt::from_unsigned_tx(replacement)?;
+        for (input, prevout) in psbt.inputs.iter_mut().zip(original.prevouts) {
+            input.witness_utxo = Some(prevout);
+        }
+        Self::sign_with_cards(signers, &mut psbt)?;
+        finalize_psbt(&mut psbt)?;
+        let final_tx = extract_finalized_tx(psbt)?;
+
+        progress(&FeeBumpStage::Signed { txid: final_tx.txid().to_string(), fee: new_fee });
+        crate::client::broadcast_transaction(card.chain(), &serialize_hex(&final_tx)).await
+    }
+
+    /// An output paying `amount` of change to `change_card`'s address
+    pub fn change_output(change_card: &Box<dyn cards::Card>, amount: Amount) -> Result<TxOut> {
+        Ok(TxOut {
+            value: amount,
+            script_pubkey: script_pubkey_for_address(change_card.address(), change_card.network())
+                .map_err(|e| anyhow!("Invalid change address: {}", e))?,
+        })
+    }
+
+    /// Sign `psbt` with each of `cards`, each signing the inputs that spend its own address,
+    /// failing if any input is left that none of them could sign
+    pub fn sign_with_cards<'a>(cards: impl IntoIterator<Item = &'a Box<dyn cards::Card>>, psbt: &mut Psbt) -> Result<()> {
+        for card in cards {
+            card.sign_transaction(psbt)?;
+        }
+
+        let unsigned = psbt.inputs.iter().position(|input| {
+            input.partial_sigs.is_empty() && input.final_script_sig.is_none() && input.final_script_witness.is_none()
+        });
+        match unsigned {
+            Some(i) => Err(anyhow!("Input {} spends an address none of the cards can sign for", i)),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn pay_invoice(card: &Box<dyn cards::Card>, invoice: &InvoiceDetails) -> Result<String> {
+        Self::pay_invoice_from(std::slice::from_ref(card), invoice, false).await
+    }
+
+    /// Pay an invoice with UTXOs held by any of `cards`, e.g. one chain derived at several account
+    /// indices, logging each stage. With `rbf` the inputs signal replace-by-fee, so a stuck
+    /// payment can later be bumped with `bump_fee`. Returns the payment's txid.
+    pub async fn pay_invoice_from(cards: &[Box<dyn cards::Card>], invoice: &InvoiceDetails, rbf: bool) -> Result<String> {
+        let api_key = std::env::var("ANYPAY_API_KEY")
+            .map_err(|_| anyhow!("ANYPAY_API_KEY environment variable not set"))?;
+        let client = AnypayClient::new(&api_key);
+
+        Self::pay_invoice_with(&client, cards, invoice, rbf, &|stage: &PaymentStage| tracing::info!("{}", stage)).await
+    }
+
+    /// Pay an invoice through `client` with UTXOs held by any of `cards`, reporting each stage to `progress`.
+    /// Each input is signed by the card whose address it spends; change returns to the first card.
+    pub async fn pay_invoice_with(
+        client: &AnypayClient,
+        cards: &[Box<dyn cards::Card>],
+        invoice: &InvoiceDetails,
+        rbf: bool,
+        progress: &(dyn Fn(&PaymentStage) + Sync),
+    ) -> Result<String> {
+        let card = cards.first()
+            .ok_or_else(|| anyhow!("No cards to pay with"))?;
+        if let Some(other) = cards.iter().find(|other| {
+            other.chain() != card.chain() || other.currency() != card.currency() || other.network() != card.network()
+        }) {
+            return Err(anyhow!("Cannot combine {}/{} and {}/{} inputs in one payment",
+                card.chain(), card.currency(), other.chain(), other.currency()));
+        }
+
+        // Handle both BTC and FB payments
+        let outputs = invoice.outputs.iter()
+            .filter(|output| output.currency == card.currency())
+            .collect::<Vec<_>>();
+
+        if outputs.is_empty() {
+            return Err(anyhow!("No {} payment options found for this invoice", card.currency()));
+        }
+
+        // Standard transactions carry at most one OP_RETURN output
+        let data_outputs = invoice.data_outputs.iter()
+            .filter(|data_output| data_output.currency == card.currency())
+            .collect::<Vec<_>>();
+        if data_outputs.len() > 1 {
+            return Err(anyhow!("Invoice asks for {} OP_RETURN outputs in {}; at most one is relayed", data_outputs.len(), card.currency()));
+        }
+
+        // Change goes to a fresh internal-chain address of the first card rather than back to its
+        // receive address. Change from earlier payments sits at the used ones, so those are spent from too.
+        let mut used_change = Vec::new();
+        let mut change_card = None;
+        for source in cards {
+            let (used, next) = Self::scan_change_cards(source, client.mempool_api_url(source.chain())).await?;
+            used_change.extend(used);
+            change_card.get_or_insert(next);
+        }
+        let change_card = change_card.ok_or_else(|| anyhow!("No cards to pay with"))?;
+
+        // 1. Fetch UTXOs for each source address, remembering which card can spend each one
+        let mut utxos = Vec::new();
+        let mut owners = HashMap::new();
+        for source in cards.iter().chain(&used_change) {
+            for utxo in Self::fetch_utxos(client, source).await? {
+                if owners.insert((utxo.txid.clone(), utxo.vout), source).is_none() {
+                    utxos.push(utxo);
+                }
+            }
+        }
+        progress(&PaymentStage::UtxosFetched {
+            count: utxos.len(),
+            total: utxos.iter().map(|utxo| Amount::from_btc(utxo.amount).unwrap_or(Amount::ZERO)).sum(),
+        });
         
-        // 2. Calculate total required amount (including estimated fee)
-        let fee_rate = 10.0; // sats/vbyte
+        // 2. Build the payment outputs, then any OP_RETURN data the invoice asks for
         let total_output_amount = Amount::from_sat(
             outputs.iter()
                 .map(|output| output.amount)
                 .sum()
         );
-        let estimated_size = 200; // Rough estimate for a typical transaction
-        let fee_amount = Amount::from_sat((fee_rate * estimated_size as f64) as u64);
-        let total_required = total_output_amount + fee_amount;
+        let mut tx_outputs = Vec::new();
+        for output in &outputs {
+            tx_outputs.push(TxOut {
+                value: Amount::from_sat(output.amount),
+                script_pubkey: output.script_pubkey(card.network())?,
+            });
+        }
+        for data_output in &data_outputs {
+            tx_outputs.push(op_return_output(&data_output.data)?);
+        }
 
-        // 3. Select UTXOs
-        let selected_utxos = Self::select_utxos(&utxos, total_required)?;
+        // 3. Select UTXOs, sizing the fee for the inputs selected and a change output. Each
+        // input raises the fee, so select again until the selection covers its own fee.
+        let fee_rate = Self::estimate_fee_rate(card).await;
+        let sized_outputs = tx_outputs.iter().cloned()
+            .chain(std::iter::once(Self::change_output(&change_card, Amount::ZERO)?))
+            .collect::<Vec<_>>();
+        let fee_for = |inputs: usize| Amount::from_sat(fee_rate * Self::estimated_vsize(inputs, &sized_outputs));
+        let mut selected_utxos = Self::select_utxos(&utxos, total_output_amount + fee_for(1))?;
+        loop {
+            let reselected = Self::select_utxos(&utxos, total_output_amount + fee_for(selected_utxos.len()))?;
+            let grew = reselected.len() > selected_utxos.len();
+            selected_utxos = reselected;
+            if !grew {
+                break;
+            }
+        }
+        let fee_amount = fee_for(selected_utxos.len());
         let total_input = selected_utxos.iter()
             .map(|utxo| Amount::from_btc(utxo.amount).unwrap_or(Amount::ZERO))
             .sum::<Amount>();
@@ -254,88 +677,59 @@ impl Wallet {
         let mut tx_builder = Transaction {
             version: Version(2),
             lock_time: LockTime::ZERO,
-            input: vec![],
-            output: vec![],
+            input: Self::unsigned_inputs(&selected_utxos, rbf)?,
+            output: tx_outputs,
         };
 
-        // Add inputs
-        for utxo in &selected_utxos {
-            let outpoint = OutPoint::from_str(&format!("{}:{}", utxo.txid, utxo.vout))
-                .map_err(|_| anyhow!("Invalid UTXO txid: {}", utxo.txid))?;
-            tx_builder.input.push(TxIn {
-                previous_output: outpoint,
-                script_sig: ScriptBuf::new(),
-                sequence: Sequence::MAX,
-                witness: Witness::default(),
-            });
-        }
-
-        // Add all payment outputs
-        for output in outputs {
-            println!("\nProcessing output address: {}", output.address);
-            println!("Output amount: {} sats", output.amount);
-            
-            let recipient_address = BtcAddress::from_str(&output.address)
-                .map_err(|e| anyhow!("Invalid recipient address {}: {}", output.address, e))?;
-            
-            let network_address = recipient_address
-                .require_network(card.network())
-                .map_err(|e| anyhow!("Address network mismatch for {}: {}", output.address, e))?;
-            
-            tx_builder.output.push(TxOut {
-                value: Amount::from_sat(output.amount),
-                script_pubkey: network_address.payload().script_pubkey(),
-            });
-        }
-
         // Add change output if needed
         let change_amount = total_input - total_output_amount - fee_amount;
         if change_amount > Amount::ZERO {
-            let change_address = BtcAddress::from_str(card.address())
-                .map_err(|e| anyhow!("Invalid change address: {}", e))?;
-            
-            tx_builder.output.push(TxOut {
-                value: change_amount,
-                script_pubkey: change_address.payload().script_pubkey(),
-            });
+            tx_builder.output.push(Self::change_output(&change_card, change_amount)?);
         }
+        progress(&PaymentStage::TransactionBuilt {
+            inputs: tx_builder.input.len(),
+            outputs: tx_builder.output.len(),
+            fee: fee_amount,
+            change: (change_amount > Amount::ZERO).then(|| change_card.address().to_string()),
+        });
 
         // 5. Sign transaction
         let mut psbt = Psbt::from_unsigned_tx(tx_builder)?;
         
-        // Add UTXO information
+        // Add UTXO information, deriving the script from the owning card's address when the API omits it
         for (i, utxo) in selected_utxos.iter().enumerate() {
-            let script = ScriptBuf::from_hex(&utxo.script_pub_key)
-                .map_err(|_| anyhow!("Invalid script: {}", utxo.script_pub_key))?;
+            let owner = owners[&(utxo.txid.clone(), utxo.vout)];
+            let script = if utxo.script_pub_key.is_empty() {
+                script_pubkey_for_address(owner.address(), owner.network())?
+            } else {
+                ScriptBuf::from_hex(&utxo.script_pub_key)
+                    .map_err(|_| anyhow!("Invalid script: {}", utxo.script_pub_key))?
+            };
             psbt.inputs[i].witness_utxo = Some(TxOut {
                 value: Amount::from_btc(utxo.amount)?,
                 script_pubkey: script,
             });
         }
 
-        // Sign with the card's private key
-        card.sign_transaction(&mut psbt)?;
+        // Sign each input with the private key of the card that owns it
+        Self::sign_with_cards(cards.iter().chain(&used_change), &mut psbt)?;
 
-        // Extract final transaction
-        let final_tx = psbt.extract_tx()?;
+        // Build the final scripts from the signatures, then extract the transaction
+        finalize_psbt(&mut psbt)?;
+        let final_tx = extract_finalized_tx(psbt)?;
         
         // Verify all outputs are present with correct amounts
-        println!("\nVerifying transaction outputs:");
-        for (i, output) in final_tx.output.iter().enumerate() {
-            println!("Output {}: {} sats", i, output.value.to_sat());
-            println!("Script: {}", output.script_pubkey.to_hex_string());
-        }
+        Self::verify_pays_outputs(&final_tx, &outputs, card.network())?;
 
+        let txid = final_tx.txid().to_string();
         let tx_hex = serialize_hex(&final_tx);
-        println!("\nTransaction hex: {}", tx_hex);
+        progress(&PaymentStage::Signed { txid: txid.clone(), tx_hex: tx_hex.clone() });
 
         // 6. Submit payment
-        println!("Submitting payment...");
         client.submit_payment(&invoice.uid, card.chain(), card.currency(), &tx_hex).await?;
+        progress(&PaymentStage::Submitted { txid: txid.clone() });
 
-        println!("Payment submitted successfully!");
-
-        Ok(())
+        Ok(txid)
     }
 }
 
@@ -389,15 +783,488 @@ impl std::fmt::Display for BitcoinCard {
     }
 }
 
+pub struct WatchOnlyWallet {
+    xpub: String,
+}
+
+impl WatchOnlyWallet {
+    /// Create a watch-only card; only BTC is supported since the xpub is BTC account-level.
+    /// The xpub already fixes the account, so `xpub_account` is only the index it was exported
+    /// at (m/44'/0'/xpub_account'), recorded in the card's derivation path.
+    pub fn create_card(&self, chain: &str, currency: &str, network: Network, xpub_account: u32) -> Result<Box<dyn cards::Card>> {
+        match (chain, currency) {
+            #[cfg(feature = "btc")]
+            ("BTC", "BTC") => Ok(Box::new(cards::watch::WatchOnlyCard::new(network, xpub_account, &self.xpub)?)),
+            _ => Err(anyhow!("Watch-only cards are not supported for {}/{}", chain, currency))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct InvoiceDetails {
     pub uid: String,
     pub outputs: Vec<PaymentOutput>,
+    /// OP_RETURN outputs the payment must include
+    pub data_outputs: Vec<DataOutput>,
+}
+
+/// Data an invoice requires a payment in `currency` to carry in an OP_RETURN output
+#[derive(Debug, Clone)]
+pub struct DataOutput {
+    pub data: Vec<u8>,
+    pub currency: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct PaymentOutput {
     pub address: String,
+    /// Hex-encoded output script to pay instead of the address, for scripts with no address form
+    pub script: Option<String>,
     pub amount: u64,  // Store as satoshis for BTC, regular amount for others
     pub currency: String,
-} 
\ No newline at end of file
+}
+
+impl PaymentOutput {
+    /// The output script this pays: its raw script if it has one, otherwise its address's
+    pub fn script_pubkey(&self, network: Network) -> Result<ScriptBuf> {
+        match &self.script {
+            Some(script) => ScriptBuf::from_hex(script)
+                .map_err(|e| anyhow!("Invalid output script {}: {}", script, e)),
+            None => script_pubkey_for_address(&self.address, network)
+                .map_err(|e| anyhow!("Invalid recipient address {}: {}", self.address, e)),
+        }
+    }
+
+    /// The script or address this pays, for messages
+    fn destination(&self) -> &str {
+        self.script.as_deref().unwrap_or(&self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::supabase::tests::spawn_mock_supabase;
+
+    #[test]
+    fn test_generate_word_counts() {
+        let wallet = Wallet::generate(24).unwrap();
+        assert_eq!(wallet.seed_phrase().split_whitespace().count(), 24);
+
+        let wallet = Wallet::generate(12).unwrap();
+        assert_eq!(wallet.seed_phrase().split_whitespace().count(), 12);
+    }
+
+    #[test]
+    fn test_generate_rejects_invalid_word_count() {
+        assert!(Wallet::generate(13).is_err());
+    }
+
+    #[test]
+    fn test_spanish_seed_phrase_round_trip() {
+        let generated = Wallet::generate_in(Language::Spanish, 12).unwrap();
+        let wallet = Wallet::from_seed_phrase(generated.seed_phrase()).unwrap();
+
+        assert_eq!(wallet.language(), Language::Spanish);
+        assert_eq!(wallet.seed_phrase(), generated.seed_phrase());
+    }
+
+    #[test]
+    fn test_script_pubkey_for_address() {
+        let cases = [
+            ("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", "76a91477bff20c60e522dfaa3350c39b030a5d004e839a88ac"),
+            ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", "a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87"),
+            ("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+            ("bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297", "5120a37c3903c8d0db6512e2b40b0dffa05e5a3ab73603ce8c9c4b7771e5412328f9"),
+        ];
+
+        for (address, script_hex) in cases {
+            let script = script_pubkey_for_address(address, Network::Bitcoin).unwrap();
+            assert_eq!(script.to_hex_string(), script_hex, "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_signs_inputs_from_several_accounts() {
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let cards = [0, 1].into_iter()
+            .map(|account| wallet.create_card("BTC", "BTC", Network::Bitcoin, account).unwrap())
+            .collect::<Vec<_>>();
+        let scripts = cards.iter()
+            .map(|card| script_pubkey_for_address(card.address(), Network::Bitcoin).unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(scripts[0], scripts[1]);
+
+        // One input from each account, listed second account first
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: (0..2).map(|vout| TxIn {
+                previous_output: OutPoint { txid: bitcoin::Txid::from_str(&"11".repeat(32)).unwrap(), vout },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }).collect(),
+            output: vec![TxOut { value: Amount::from_sat(90_000), script_pubkey: scripts[0].clone() }],
+        };
+        let unsigned_psbt = || {
+            let mut psbt = Psbt::from_unsigned_tx(tx.clone()).unwrap();
+            for (input, script) in psbt.inputs.iter_mut().zip(scripts.iter().rev()) {
+                input.witness_utxo = Some(TxOut { value: Amount::from_sat(50_000), script_pubkey: script.clone() });
+            }
+            psbt
+        };
+
+        let mut psbt = unsigned_psbt();
+        Wallet::sign_with_cards(&cards, &mut psbt).unwrap();
+
+        for (i, input) in psbt.inputs.iter().enumerate() {
+            assert_eq!(input.partial_sigs.len(), 1, "input {}", i);
+            let signer = input.partial_sigs.keys().next().unwrap();
+            let signer_script = ScriptBuf::new_p2wpkh(&signer.wpubkey_hash().unwrap());
+            assert_eq!(signer_script, input.witness_utxo.as_ref().unwrap().script_pubkey, "input {}", i);
+        }
+
+        // Without the second account's card, its input can't be signed
+        assert!(Wallet::sign_with_cards(&cards[..1], &mut unsigned_psbt()).is_err());
+    }
+
+    #[test]
+    fn test_change_goes_to_internal_chain() {
+        let seed_phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let wallet = Wallet::from_seed_phrase(seed_phrase).unwrap();
+        let card = wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap();
+
+        let change_card = card.change_card(0).unwrap();
+        assert_eq!(change_card.derivation_path(), "m/44'/0'/0'/1/0");
+        assert_ne!(change_card.address(), card.address());
+        assert_ne!(card.get_change_address(1).unwrap(), change_card.address());
+
+        // The change address is the P2WPKH address of the m/44'/0'/0'/1/0 key
+        let seed = cards::parse_mnemonic(seed_phrase, None).unwrap().to_seed("");
+        let xpriv = XPrv::derive_from_path(seed, &DerivationPath::from_str("m/44'/0'/0'/1/0").unwrap()).unwrap();
+        let public_key = bitcoin::PublicKey::from_slice(&xpriv.public_key().to_bytes()).unwrap();
+        let expected = BtcAddress::p2wpkh(&public_key, Network::Bitcoin).unwrap();
+
+        let output = Wallet::change_output(&change_card, Amount::from_sat(1_000)).unwrap();
+        assert_eq!(output.value, Amount::from_sat(1_000));
+        assert_eq!(output.script_pubkey, expected.script_pubkey());
+        assert_ne!(output.script_pubkey, script_pubkey_for_address(card.address(), Network::Bitcoin).unwrap());
+    }
+
+    fn utxo(vout: u32, amount: f64) -> Utxo {
+        Utxo { txid: "11".repeat(32), vout, amount, confirmations: 0, script_pub_key: String::new() }
+    }
+
+    #[test]
+    fn test_rbf_inputs_signal_replaceability() {
+        let utxos = [utxo(0, 0.001), utxo(1, 0.002)];
+
+        for input in Wallet::unsigned_inputs(&utxos, true).unwrap() {
+            assert_eq!(input.sequence, Sequence(0xfffffffd));
+            assert!(input.sequence.is_rbf());
+        }
+        for input in Wallet::unsigned_inputs(&utxos, false).unwrap() {
+            assert_eq!(input.sequence, Sequence::MAX);
+        }
+    }
+
+    #[test]
+    fn test_bump_fee_takes_increase_from_change() {
+        let payee = script_pubkey_for_address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", Network::Bitcoin).unwrap();
+        let change = script_pubkey_for_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", Network::Bitcoin).unwrap();
+        let prevouts = [TxOut { value: Amount::from_sat(100_000), script_pubkey: change.clone() }];
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: Wallet::unsigned_inputs(&[utxo(0, 0.001)], true).unwrap(),
+            output: vec![
+                TxOut { value: Amount::from_sat(60_000), script_pubkey: payee.clone() },
+                TxOut { value: Amount::from_sat(39_000), script_pubkey: change.clone() },
+            ],
+        };
+        let fee = |tx: &Transaction| Amount::from_sat(100_000) - tx.output.iter().map(|output| output.value).sum::<Amount>();
+
+        let bumped = Wallet::rebuild_with_fee(&tx, &prevouts, &[change.clone()], Amount::from_sat(3_000)).unwrap();
+
+        assert_eq!(fee(&tx), Amount::from_sat(1_000));
+        assert_eq!(fee(&bumped), Amount::from_sat(3_000));
+        assert_eq!(bumped.input.iter().map(|input| input.previous_output).collect::<Vec<_>>(),
+            tx.input.iter().map(|input| input.previous_output).collect::<Vec<_>>());
+        assert_eq!(bumped.output[0], tx.output[0]);
+        assert_eq!(bumped.output[1].value, Amount::from_sat(37_000));
+        assert!(bumped.is_explicitly_rbf());
+
+        // The fee must go up, and change can't fall below dust
+        assert!(Wallet::rebuild_with_fee(&tx, &prevouts, &[change.clone()], Amount::from_sat(1_000)).is_err());
+        assert!(Wallet::rebuild_with_fee(&tx, &prevouts, &[change], Amount::from_sat(39_000)).is_err());
+    }
+
+    #[test]
+    fn test_only_finalized_psbts_are_extracted() {
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let cards = vec![wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap()];
+        let script = script_pubkey_for_address(cards[0].address(), Network::Bitcoin).unwrap();
+
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: Wallet::unsigned_inputs(&[utxo(0, 0.0005)], false).unwrap(),
+            output: vec![TxOut { value: Amount::from_sat(49_000), script_pubkey: script.clone() }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: Amount::from_sat(50_000), script_pubkey: script.clone() });
+        Wallet::sign_with_cards(&cards, &mut psbt).unwrap();
+
+        let error = extract_finalized_tx(psbt.clone()).unwrap_err();
+        assert!(error.to_string().contains("Input 0 is not finalized"), "{}", error);
+
+        finalize_psbt(&mut psbt).unwrap();
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+        let final_tx = extract_finalized_tx(psbt).unwrap();
+        let witness = &final_tx.input[0].witness;
+        assert_eq!(witness.len(), 2);
+        let public_key = bitcoin::PublicKey::from_slice(witness.nth(1).unwrap()).unwrap();
+        assert_eq!(ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash().unwrap()), script);
+        assert!(final_tx.input[0].script_sig.is_empty());
+    }
+
+    #[test]
+    fn test_finalize_rejects_unsigned_inputs() {
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: Wallet::unsigned_inputs(&[utxo(0, 0.0005)], false).unwrap(),
+            output: vec![],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(50_000),
+            script_pubkey: script_pubkey_for_address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", Network::Bitcoin).unwrap(),
+        });
+
+        assert!(finalize_psbt(&mut psbt).unwrap_err().to_string().contains("Input 0 is not signed"));
+    }
+
+    #[test]
+    fn test_transaction_missing_an_invoice_output_is_refused() {
+        let outputs = [
+            PaymentOutput { address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(), script: None, amount: 40_000, currency: "BTC".to_string() },
+            PaymentOutput { address: "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy".to_string(), script: None, amount: 1_000, currency: "BTC".to_string() },
+        ];
+        let outputs = outputs.iter().collect::<Vec<_>>();
+        let pay = |address: &str, sats: u64| TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: script_pubkey_for_address(address, Network::Bitcoin).unwrap(),
+        };
+        let tx = |output: Vec<TxOut>| Transaction { version: Version(2), lock_time: LockTime::ZERO, input: vec![], output };
+
+        let complete = tx(vec![pay(&outputs[0].address, 40_000), pay(&outputs[1].address, 1_000)]);
+        Wallet::verify_pays_outputs(&complete, &outputs, Network::Bitcoin).unwrap();
+
+        let missing_fee_output = tx(vec![pay(&outputs[0].address, 40_000)]);
+        let error = Wallet::verify_pays_outputs(&missing_fee_output, &outputs, Network::Bitcoin).unwrap_err();
+        assert!(error.to_string().contains("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"), "{}", error);
+
+        let underpaid = tx(vec![pay(&outputs[0].address, 39_999), pay(&outputs[1].address, 1_000)]);
+        assert!(Wallet::verify_pays_outputs(&underpaid, &outputs, Network::Bitcoin).is_err());
+    }
+
+    type Submitted = std::sync::Arc<std::sync::Mutex<Vec<serde_json::Value>>>;
+
+    /// Serve the mempool and payment protocol endpoints a payment uses: one 0.01 BTC UTXO at every
+    /// address, no history at any address, and a payment endpoint recording what is submitted to it
+    fn spawn_mock_payment_api() -> (String, Submitted) {
+        use axum::{routing::{get, post}, Json, Router};
+        use serde_json::{json, Value};
+
+        let submitted = Submitted::default();
+        let router = Router::new()
+            .route("/address/:address", get(|| async {
+                Json(json!({ "chain_stats": { "tx_count": 0 }, "mempool_stats": { "tx_count": 0 } }))
+            }))
+            .route("/address/:address/utxo", get(|| async {
+                Json(json!([{
+                    "txid": "11".repeat(32),
+                    "vout": 0,
+                    "value": 1_000_000,
+                    "status": { "confirmed": true, "block_height": 90, "block_time": null }
+                }]))
+            }))
+            .route("/blocks/tip/height", get(|| async { "100" }))
+            .route("/r/:uid", post({
+                let submitted = submitted.clone();
+                move |Json(payment): Json<Value>| async move {
+                    submitted.lock().unwrap().push(payment);
+                    Json(json!({}))
+                }
+            }));
+        let url = spawn_mock_supabase(router);
+
+        (url, submitted)
+    }
+
+    fn btc_invoice(data_outputs: Vec<DataOutput>) -> InvoiceDetails {
+        InvoiceDetails {
+            uid: "inv_123".to_string(),
+            outputs: vec![PaymentOutput {
+                address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+                script: None,
+                amount: 40_000,
+                currency: "BTC".to_string(),
+            }],
+            data_outputs,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_payment_reports_each_stage() {
+        use serde_json::json;
+        use std::sync::Mutex;
+
+        let (url, submitted) = spawn_mock_payment_api();
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let cards = vec![wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap()];
+        let invoice = btc_invoice(vec![]);
+        let client = AnypayClient::new("test").with_api_url(&url).with_mempool_api_url(&url);
+
+        let stages = Mutex::new(Vec::new());
+        let txid = Wallet::pay_invoice_with(&client, &cards, &invoice, false, &|stage: &PaymentStage| {
+            stages.lock().unwrap().push(stage.clone());
+        }).await.unwrap();
+
+        let stages = stages.into_inner().unwrap();
+        assert_eq!(stages.len(), 4, "{:?}", stages);
+        assert_eq!(stages[0], PaymentStage::UtxosFetched { count: 1, total: Amount::from_sat(1_000_000) });
+        let PaymentStage::TransactionBuilt { inputs, outputs, change, .. } = &stages[1] else {
+            panic!("expected the transaction to be built, got {:?}", stages[1]);
+        };
+        assert_eq!((*inputs, *outputs), (1, 2));
+        assert_eq!(change.as_deref(), Some(cards[0].get_change_address(0).unwrap().as_str()));
+        let PaymentStage::Signed { txid: signed_txid, tx_hex } = &stages[2] else {
+            panic!("expected the transaction to be signed, got {:?}", stages[2]);
+        };
+        assert_eq!(signed_txid, &txid);
+        assert_eq!(stages[3], PaymentStage::Submitted { txid: txid.clone() });
+
+        let submitted = submitted.lock().unwrap();
+        assert_eq!(submitted.len(), 1);
+        assert_eq!(submitted[0]["transactions"][0]["tx"], json!(tx_hex));
+    }
+
+    #[tokio::test]
+    async fn test_payment_carries_invoice_op_return() {
+        let (url, submitted) = spawn_mock_payment_api();
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let cards = vec![wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap()];
+        let invoice = btc_invoice(vec![
+            DataOutput { data: b"anypay:inv_123".to_vec(), currency: "BTC".to_string() },
+            DataOutput { data: b"not for this chain".to_vec(), currency: "BSV".to_string() },
+        ]);
+        let client = AnypayClient::new("test").with_api_url(&url).with_mempool_api_url(&url);
+
+        Wallet::pay_invoice_with(&client, &cards, &invoice, false, &|_: &PaymentStage| {}).await.unwrap();
+
+        let tx_hex = submitted.lock().unwrap()[0]["transactions"][0]["tx"].as_str().unwrap().to_string();
+        let tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(tx_hex).unwrap()).unwrap();
+        let data_outputs = tx.output.iter().filter(|output| output.script_pubkey.is_op_return()).collect::<Vec<_>>();
+        assert_eq!(data_outputs.len(), 1);
+        assert_eq!(data_outputs[0].value, Amount::ZERO);
+
+        let mut expected = vec![0x6a, 14];
+        expected.extend(b"anypay:inv_123");
+        assert_eq!(data_outputs[0].script_pubkey.as_bytes(), expected.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_payment_to_raw_output_script() {
+        let (url, submitted) = spawn_mock_payment_api();
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let cards = vec![wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap()];
+        // A bare pay-to-pubkey script, which has no address form
+        let p2pk = "210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac";
+        let invoice = InvoiceDetails {
+            uid: "inv_123".to_string(),
+            outputs: vec![PaymentOutput {
+                address: String::new(),
+                script: Some(p2pk.to_string()),
+                amount: 40_000,
+                currency: "BTC".to_string(),
+            }],
+            data_outputs: vec![],
+        };
+        let client = AnypayClient::new("test").with_api_url(&url).with_mempool_api_url(&url);
+
+        Wallet::pay_invoice_with(&client, &cards, &invoice, false, &|_: &PaymentStage| {}).await.unwrap();
+
+        let tx_hex = submitted.lock().unwrap()[0]["transactions"][0]["tx"].as_str().unwrap().to_string();
+        let tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(tx_hex).unwrap()).unwrap();
+        let script = ScriptBuf::from_hex(p2pk).unwrap();
+        let paid = tx.output.iter().filter(|output| output.script_pubkey == script).collect::<Vec<_>>();
+        assert_eq!(paid.len(), 1);
+        assert_eq!(paid[0].value, Amount::from_sat(40_000));
+    }
+
+    #[tokio::test]
+    async fn test_payment_fee_is_sized_for_the_selected_inputs() {
+        // The chain API mock reports a half-hour rate of 12 sat/vbyte
+        crate::supabase::tests::mock_chain_apis();
+        let (url, submitted) = spawn_mock_payment_api();
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let cards = vec![wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap()];
+        let client = AnypayClient::new("test").with_api_url(&url).with_mempool_api_url(&url);
+
+        Wallet::pay_invoice_with(&client, &cards, &btc_invoice(vec![]), false, &|_: &PaymentStage| {}).await.unwrap();
+
+        let tx_hex = submitted.lock().unwrap()[0]["transactions"][0]["tx"].as_str().unwrap().to_string();
+        let tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(tx_hex).unwrap()).unwrap();
+        let paid_out: Amount = tx.output.iter().map(|output| output.value).sum();
+        let estimated = Wallet::estimated_vsize(tx.input.len(), &tx.output);
+
+        // One 0.01 BTC input, paying 12 sat/vbyte on the size of the transaction actually built
+        assert_eq!(Amount::from_sat(1_000_000) - paid_out, Amount::from_sat(12 * estimated));
+        assert!((tx.vsize() as i64 - estimated as i64).abs() <= 1, "estimated {} vbytes, signed {}", estimated, tx.vsize());
+    }
+
+    #[tokio::test]
+    async fn test_change_scan_stops_at_the_limit() {
+        use axum::{routing::get, Json, Router};
+
+        // Every change address has been used
+        let router = Router::new().route("/address/:address", get(|| async {
+            Json(serde_json::json!({ "chain_stats": { "tx_count": 1 }, "mempool_stats": { "tx_count": 0 } }))
+        }));
+        let url = spawn_mock_supabase(router);
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let card = wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap();
+
+        let error = Wallet::scan_change_cards(&card, &url).await.err().unwrap();
+        assert!(error.to_string().contains(&format!("first {} change addresses", MAX_CHANGE_CARDS)), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_payment_rejects_two_op_returns_for_one_currency() {
+        let wallet = Wallet::from_seed_phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap();
+        let cards = vec![wallet.create_card("BTC", "BTC", Network::Bitcoin, 0).unwrap()];
+        let invoice = btc_invoice(vec![
+            DataOutput { data: b"first".to_vec(), currency: "BTC".to_string() },
+            DataOutput { data: b"second".to_vec(), currency: "BTC".to_string() },
+        ]);
+        // Rejected before anything is fetched, so no API is needed
+        let client = AnypayClient::new("test").with_api_url("http://127.0.0.1:9").with_mempool_api_url("http://127.0.0.1:9");
+
+        let error = Wallet::pay_invoice_with(&client, &cards, &invoice, false, &|_: &PaymentStage| {}).await.unwrap_err();
+        assert!(error.to_string().contains("2 OP_RETURN outputs"), "{}", error);
+    }
+
+    #[test]
+    fn test_op_return_data_is_limited_to_80_bytes() {
+        assert!(op_return_output(&[7; MAX_OP_RETURN_BYTES]).is_ok());
+        assert!(op_return_output(&[7; MAX_OP_RETURN_BYTES + 1]).is_err());
+    }
+
+    #[test]
+    fn test_script_pubkey_for_address_rejects_wrong_network() {
+        assert!(script_pubkey_for_address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", Network::Testnet).is_err());
+    }
+}
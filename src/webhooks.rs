@@ -0,0 +1,225 @@
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use url::Url;
+use crate::error::Result;
+use crate::types::{InvoiceStatus, PaymentStatus};
+use crate::confirmations::{ConfirmationInfo, InvoiceInfo, PaymentConfirmedEvent, PaymentConfirmedPayload, PaymentInfo};
+
+/// Header carrying the hex HMAC-SHA256 of the request body, keyed with the account's webhook secret
+pub const SIGNATURE_HEADER: &str = "X-Anypay-Signature";
+
+/// How long a receiver has to respond before delivery is treated as failed
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hex HMAC-SHA256 of `body`, which receivers recompute to check a webhook came from us
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// A payment.confirmed event with placeholder values, for merchants testing their receiver
+pub fn sample_payment_confirmed(account_id: i32) -> PaymentConfirmedEvent {
+    PaymentConfirmedEvent {
+        topic: "payment.confirmed".to_string(),
+        payload: PaymentConfirmedPayload {
+            account_id: Some(account_id.to_string()),
+            app_id: None,
+            payment: PaymentInfo {
+                chain: "BTC".to_string(),
+                currency: "BTC".to_string(),
+                txid: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+                status: PaymentStatus::Confirmed,
+            },
+            invoice: InvoiceInfo {
+                uid: "inv_test".to_string(),
+                status: InvoiceStatus::Paid,
+            },
+            confirmation: ConfirmationInfo {
+                hash: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+                height: 0,
+            },
+        },
+    }
+}
+
+/// Whether `ip` is routable on the public internet, rather than loopback, private, link-local
+/// (which includes cloud metadata endpoints like 169.254.169.254) or otherwise reserved
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space (RFC 6598) and 0.0.0.0/8
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                // Reserved for future use (240.0.0.0/4)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local (fc00::/7) and link-local (fe80::/10)
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// A webhook URL whose host has been resolved and found to be public. Requests go to the
+/// addresses that were checked, so the host can't be re-pointed at an internal one in between.
+pub struct WebhookTarget {
+    url: Url,
+    addrs: Vec<SocketAddr>,
+}
+
+impl WebhookTarget {
+    /// Parse `url` and resolve its host, failing unless it is http(s) and every address is public
+    pub async fn resolve(url: &str) -> Result<Self> {
+        let url = Url::parse(url).map_err(|e| anyhow!("Invalid webhook URL {}: {}", url, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("Webhook URL {} must be http or https", url).into());
+        }
+        let host = url.host_str().ok_or_else(|| anyhow!("Webhook URL {} has no host", url))?;
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        // Bracketed IPv6 literals resolve without the brackets
+        let addrs = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port)).await
+            .map_err(|e| anyhow!("Failed to resolve webhook host {}: {}", host, e))?
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(anyhow!("Webhook host {} has no addresses", host).into());
+        }
+        if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+            return Err(anyhow!("Webhook host {} resolves to non-public address {}", host, addr.ip()).into());
+        }
+
+        Ok(WebhookTarget { url, addrs })
+    }
+
+    /// POST `payload` as JSON, signed when the account has a secret, and return the receiver's status.
+    /// Redirects are returned as they are rather than followed, since they could point anywhere.
+    pub async fn send<T: Serialize>(&self, secret: Option<&str>, payload: &T) -> Result<reqwest::StatusCode> {
+        let client = webhook_client()
+            .resolve_to_addrs(self.url.host_str().unwrap_or_default(), &self.addrs)
+            .build()?;
+        post_signed(&client, self.url.as_str(), secret, payload).await
+    }
+}
+
+fn webhook_client() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+}
+
+async fn post_signed<T: Serialize>(client: &reqwest::Client, url: &str, secret: Option<&str>, payload: &T) -> Result<reqwest::StatusCode> {
+    let body = serde_json::to_vec(payload).map_err(anyhow::Error::from)?;
+
+    let mut request = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(secret) = secret {
+        request = request.header(SIGNATURE_HEADER, sign_payload(secret, &body));
+    }
+
+    let response = request.body(body).send().await
+        .map_err(|e| anyhow!("Failed to deliver webhook to {}: {}", url, e))?;
+    Ok(response.status())
+}
+
+/// POST `payload` as JSON to `url`, signed when the account has a secret, and return the receiver's
+/// status. URLs resolving to loopback, private or link-local addresses are refused.
+pub async fn send_webhook<T: Serialize>(url: &str, secret: Option<&str>, payload: &T) -> Result<reqwest::StatusCode> {
+    WebhookTarget::resolve(url).await?.send(secret, payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_payload() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign_payload("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_internal_addresses_are_not_public() {
+        for ip in ["127.0.0.1", "10.0.0.1", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1", "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} should not be public", ip);
+        }
+        for ip in ["8.8.8.8", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refuses_internal_webhook_urls() {
+        for url in ["http://127.0.0.1:8080/hooks", "http://localhost/hooks", "http://169.254.169.254/latest/meta-data", "http://[::1]/hooks", "ftp://example.com/hooks"] {
+            assert!(WebhookTarget::resolve(url).await.is_err(), "{} should be refused", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sample_is_signed_with_account_secret() {
+        use axum::{body::Bytes, http::{HeaderMap, StatusCode}, routing::post, Router};
+        use std::sync::{Arc, Mutex};
+
+        let received = Arc::new(Mutex::new(None));
+        let receiver = Router::new().route("/hooks", post({
+            let received = received.clone();
+            move |headers: HeaderMap, body: Bytes| async move {
+                let signature = headers.get(SIGNATURE_HEADER)
+                    .map(|value| value.to_str().unwrap().to_string());
+                *received.lock().unwrap() = Some((signature, body));
+                StatusCode::ACCEPTED
+            }
+        }));
+        let url = format!("{}/hooks", crate::supabase::tests::spawn_mock_supabase(receiver));
+
+        let client = webhook_client().build().unwrap();
+        let status = post_signed(&client, &url, Some("whsec_test"), &sample_payment_confirmed(42)).await.unwrap();
+        assert_eq!(status, reqwest::StatusCode::ACCEPTED);
+
+        let (signature, payload) = received.lock().unwrap().take().expect("receiver was not called");
+        assert_eq!(signature.as_deref(), Some(sign_payload("whsec_test", &payload).as_str()));
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload["topic"], "payment.confirmed");
+        assert_eq!(payload["payload"]["account_id"], "42");
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_not_followed() {
+        use axum::{http::{header::LOCATION, StatusCode}, routing::post, Router};
+
+        let receiver = Router::new().route("/hooks", post(|| async {
+            (StatusCode::TEMPORARY_REDIRECT, [(LOCATION, "http://169.254.169.254/latest/meta-data")])
+        }));
+        let url = format!("{}/hooks", crate::supabase::tests::spawn_mock_supabase(receiver));
+
+        let client = webhook_client().build().unwrap();
+        let status = post_signed(&client, &url, None, &serde_json::json!({})).await.unwrap();
+        assert_eq!(status, reqwest::StatusCode::TEMPORARY_REDIRECT);
+    }
+}
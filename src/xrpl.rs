@@ -3,6 +3,7 @@ use xrpl::asynch::clients::{
 };
 use xrpl::models::requests::subscribe::{StreamParameter, Subscribe};
 use tracing::info;
+use crate::status::chain_status;
 
 pub struct XRPLClient {}
 
@@ -12,6 +13,12 @@ impl XRPLClient {
     }
 
     pub async fn run_with_url(&mut self, url: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let result = self.listen(url).await;
+        chain_status().mark_disconnected("XRPL");
+        result
+    }
+
+    async fn listen(&mut self, url: &str) -> Result<(), Box<dyn std::error::Error>> {
         info!("Connecting to XRP Ledger at {}", url);
         let mut client: AsyncWebSocketClient<SingleExecutorMutex, WebSocketOpen> = 
             AsyncWebSocketClient::open(url.parse()?).await?;
@@ -25,10 +32,12 @@ impl XRPLClient {
 
         client.xrpl_send(subscribe.into()).await?;
         info!("Subscribed to XRPL streams");
+        chain_status().mark_connected("XRPL");
 
         loop {
             if let Some(msg) = client.xrpl_receive().await? {
                 //info!("XRPL Event: {:#?}", msg);
+                chain_status().record_event("XRPL");
             }
         }
     }
@@ -0,0 +1,33 @@
+-- Extended public keys accounts derive a fresh payment address per invoice from
+create table if not exists account_xpubs (
+    id bigserial primary key,
+    account_id bigint not null references accounts (id) on delete cascade,
+    chain text not null,
+    xpub text not null,
+    created_at timestamptz not null default now(),
+    unique (account_id, chain)
+);
+
+-- Derivation counter per account and chain
+create table if not exists address_indexes (
+    account_id bigint not null references accounts (id) on delete cascade,
+    chain text not null,
+    -- Counter of addresses handed out so far
+    next_index bigint not null default 0,
+    -- Highest index that has received a payment
+    last_used_index bigint,
+    primary key (account_id, chain)
+);
+
+-- Claim the next derivation index in a single statement so concurrent invoices never share
+-- an address. Creates the row at 0 and returns it as it was before the increment.
+create or replace function claim_address_index(p_account_id bigint, p_chain text)
+returns setof address_indexes
+language sql
+as $$
+    insert into address_indexes as ai (account_id, chain, next_index)
+    values (p_account_id, p_chain, 1)
+    on conflict (account_id, chain)
+        do update set next_index = ai.next_index + 1
+    returning ai.account_id, ai.chain, ai.next_index - 1, ai.last_used_index;
+$$;
@@ -0,0 +1,3 @@
+-- A transaction is recorded once per invoice; payments are inserted with
+-- on_conflict=invoice_uid,txid so concurrent submissions don't race
+create unique index if not exists payments_invoice_uid_txid_key on payments (invoice_uid, txid);
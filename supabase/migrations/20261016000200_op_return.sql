@@ -0,0 +1,4 @@
+-- Hex-encoded data payments must carry in an OP_RETURN output, set per invoice and
+-- copied onto its bitcoin-family payment options
+alter table invoices add column if not exists op_return text;
+alter table payment_options add column if not exists op_return text;
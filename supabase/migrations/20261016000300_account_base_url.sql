@@ -0,0 +1,2 @@
+-- Domain a white-label account's invoice and payment URLs point at; BASE_URL when null
+alter table accounts add column if not exists base_url text;
@@ -0,0 +1,50 @@
+-- Append-only history of invoice status changes
+create table if not exists invoice_events (
+    id bigserial primary key,
+    invoice_uid text not null,
+    -- Null for the row recording the invoice's creation
+    old_status text,
+    new_status text not null,
+    -- Who made the change, e.g. 'account:42' or 'system:expiry'
+    actor text not null,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists invoice_events_invoice_uid_idx on invoice_events (invoice_uid);
+
+-- Record each invoice's creation as made by the account that created it
+create or replace function record_invoice_creation()
+returns trigger
+language plpgsql
+as $$
+begin
+    insert into invoice_events (invoice_uid, old_status, new_status, actor)
+    values (new.uid, null, new.status, 'account:' || new.account_id);
+    return new;
+end;
+$$;
+
+drop trigger if exists invoices_record_creation on invoices;
+create trigger invoices_record_creation
+    after insert on invoices
+    for each row execute function record_invoice_creation();
+
+-- Set an invoice's status and append the transition in a single statement, so the history
+-- can't miss a change or disagree with the status it replaced. Returns the appended row,
+-- or no rows when the invoice doesn't exist.
+create or replace function update_invoice_status(p_uid text, p_status text, p_actor text)
+returns setof invoice_events
+language sql
+as $$
+    with old as (
+        select uid, status from invoices where uid = p_uid for update
+    ), updated as (
+        update invoices set status = p_status
+        from old
+        where invoices.uid = old.uid
+        returning invoices.uid, old.status as old_status
+    )
+    insert into invoice_events (invoice_uid, old_status, new_status, actor)
+    select updated.uid, updated.old_status, p_status, p_actor from updated
+    returning *;
+$$;
@@ -0,0 +1,2 @@
+-- Brand color of the option's coin, copied from coins.color for checkout buttons
+alter table payment_options add column if not exists color text;
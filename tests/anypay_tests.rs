@@ -0,0 +1,142 @@
+mod common;
+
+use anypay::{Anypay, Error, InvoiceOptions};
+use anypay::payment::{submit_payment, PaymentSubmission, SubmittedTransaction};
+use anypay::types::{InvoiceEvent, InvoiceStatus};
+use bitcoin::{absolute::LockTime, transaction::Version, Address, Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+use std::str::FromStr;
+use futures::StreamExt;
+use serde_json::json;
+use common::{mock_chain_apis, MockSupabase, ACCOUNT_ID, ADDRESSES};
+
+#[tokio::test]
+async fn test_create_invoice_through_facade() {
+    let backend = MockSupabase::spawn();
+    let anypay = Anypay::new(&backend.url, "anon", "service");
+
+    let options = InvoiceOptions { memo: Some("Embedded checkout".to_string()), ..Default::default() };
+    let (invoice, payment_options) = anypay.create_invoice(ACCOUNT_ID, 1000, "USD", options)
+        .await
+        .expect("Failed to create invoice");
+
+    assert!(invoice.uid.starts_with("inv_"));
+    assert_eq!((invoice.amount, invoice.currency.as_str(), invoice.status), (Some(1000), "USD", InvoiceStatus::Unpaid));
+    assert_eq!(invoice.memo.as_deref(), Some("Embedded checkout"));
+    assert_eq!(backend.invoices.lock().unwrap().len(), 1, "Invoice should be stored");
+
+    assert_eq!(payment_options.len(), ADDRESSES.len());
+    assert!(payment_options.iter().all(|option| option.invoice_uid == invoice.uid));
+
+    let (fetched, fetched_options) = anypay.get_invoice(&invoice.uid).await.expect("Invoice should be found");
+    assert_eq!(fetched.uid, invoice.uid);
+    assert_eq!(fetched_options.len(), ADDRESSES.len());
+}
+
+#[tokio::test]
+async fn test_facade_errors_are_typed() {
+    let backend = MockSupabase::spawn();
+    let anypay = Anypay::new(&backend.url, "anon", "service");
+
+    assert!(matches!(anypay.get_invoice("inv_missing").await, Err(Error::InvoiceNotFound(uid)) if uid == "inv_missing"));
+    assert!(matches!(anypay.convert(10.0, "USD", "DOGE").await, Err(Error::PriceNotFound { .. })));
+
+    let (invoice, _) = anypay.create_invoice(ACCOUNT_ID, 1000, "USD", InvoiceOptions::default()).await.unwrap();
+    let other_account = ACCOUNT_ID as i32 + 1;
+    assert!(matches!(anypay.cancel_invoice(&invoice.uid, other_account).await, Err(Error::Unauthorized(_))));
+}
+
+#[tokio::test]
+async fn test_convert_through_facade() {
+    let backend = MockSupabase::spawn();
+    let anypay = Anypay::new(&backend.url, "anon", "service");
+
+    // $1000 at $50,000/BTC
+    let conversion = anypay.convert(1000.0, "USD", "BTC").await.unwrap();
+    assert_eq!(conversion.base_value, 0.02);
+}
+
+#[tokio::test]
+async fn test_invoice_stream_reports_cancellation() {
+    let backend = MockSupabase::spawn();
+    let anypay = Anypay::new(&backend.url, "anon", "service");
+    let (invoice, _) = anypay.create_invoice(ACCOUNT_ID, 1000, "USD", InvoiceOptions::default()).await.unwrap();
+
+    let mut events = Box::pin(anypay.subscribe_invoice(&invoice.uid));
+    anypay.cancel_invoice(&invoice.uid, ACCOUNT_ID as i32).await.unwrap();
+
+    let event = tokio::time::timeout(std::time::Duration::from_secs(1), events.next()).await
+        .expect("no event published")
+        .unwrap();
+    assert_eq!(event, InvoiceEvent { uid: invoice.uid.clone(), status: InvoiceStatus::Cancelled });
+}
+
+#[tokio::test]
+async fn test_cancellation_is_recorded_as_lifecycle_event() {
+    let backend = MockSupabase::spawn();
+    let anypay = Anypay::new(&backend.url, "anon", "service");
+    let (invoice, _) = anypay.create_invoice(ACCOUNT_ID, 1000, "USD", InvoiceOptions::default()).await.unwrap();
+
+    anypay.cancel_invoice(&invoice.uid, ACCOUNT_ID as i32).await.unwrap();
+
+    let events = backend.invoice_events.lock().unwrap().clone();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["old_status"], json!(null));
+    assert_eq!(events[0]["new_status"], json!("unpaid"));
+    let cancelled = &events[1];
+    assert_eq!(cancelled["invoice_uid"], json!(invoice.uid));
+    assert_eq!(cancelled["old_status"], json!("unpaid"));
+    assert_eq!(cancelled["new_status"], json!("cancelled"));
+    assert_eq!(cancelled["actor"], json!(format!("account:{}", ACCOUNT_ID)));
+    assert!(chrono::DateTime::parse_from_rfc3339(cancelled["created_at"].as_str().unwrap()).is_ok());
+}
+
+/// A transaction with a single output paying `sats` to `address`
+fn paying(address: &str, sats: u64) -> String {
+    let address = Address::from_str(address).unwrap().require_network(Network::Bitcoin).unwrap();
+    let tx = Transaction {
+        version: Version::ONE,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut { value: Amount::from_sat(sats), script_pubkey: address.script_pubkey() }],
+    };
+    bitcoin::consensus::encode::serialize_hex(&tx)
+}
+
+#[tokio::test]
+async fn test_donation_invoice_accepts_any_amount() {
+    mock_chain_apis();
+    let backend = MockSupabase::spawn();
+    let anypay = Anypay::new(&backend.url, "anon", "service");
+
+    let (invoice, payment_options) = anypay.create_donation_invoice(ACCOUNT_ID, "USD", InvoiceOptions::default())
+        .await
+        .expect("Failed to create donation invoice");
+    assert_eq!(invoice.amount, None);
+    assert_eq!(payment_options.len(), ADDRESSES.len());
+
+    // Options fix no amount, so their URIs carry just the address
+    let btc = payment_options.iter().find(|option| option.currency == "BTC").unwrap();
+    assert_eq!((btc.amount, btc.uri.clone()), (0, format!("bitcoin:{}", btc.address)));
+    let bsv = payment_options.iter().find(|option| option.currency == "BSV").unwrap();
+    assert_eq!((bsv.amount, bsv.uri.as_str()), (0, bsv.address.as_str()));
+
+    let submission = |sats| PaymentSubmission {
+        chain: "BSV".to_string(),
+        currency: "BSV".to_string(),
+        transactions: vec![SubmittedTransaction { tx: paying(&bsv.address, sats), txid: None, txkey: None }],
+    };
+
+    // Paying nothing is still rejected
+    assert!(submit_payment(anypay.supabase(), &invoice.uid, &submission(0)).await.is_err());
+
+    let payments = submit_payment(anypay.supabase(), &invoice.uid, &submission(123_456)).await
+        .expect("Any positive amount should be accepted");
+    assert_eq!(payments.len(), 1);
+    assert_eq!(payments[0].amount, Some(123_456));
+    assert_eq!(backend.payments.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,209 @@
+//! A local stand-in for the Supabase REST API, so integration tests run without credentials or network
+// Each test binary uses a different part of the mock
+#![allow(dead_code)]
+
+use axum::{extract::Query, http::StatusCode, routing::{get, post}, Json, Router};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[path = "../../src/fixtures.rs"]
+mod fixtures;
+pub use fixtures::{coin_row, usd_price};
+
+/// Addresses the mock account accepts payments at
+pub const ADDRESSES: &[(&str, &str)] = &[
+    ("BTC", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+    ("BSV", "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2"),
+    ("ETH", "0x4B7115aD9623A528f1845eaf85D166dE1E869BFB"),
+];
+
+/// Whether `row` passes each of the PostgREST `column=eq.value` filters given for `columns`
+fn matches_filters(row: &Value, params: &HashMap<String, String>, columns: &[&str]) -> bool {
+    columns.iter().all(|column| match params.get(*column).and_then(|filter| filter.strip_prefix("eq.")) {
+        Some(value) => row[*column].as_str() == Some(value),
+        None => true,
+    })
+}
+
+/// Account the mock backend serves
+pub const ACCOUNT_ID: i64 = 1;
+
+/// A running mock backend
+pub struct MockSupabase {
+    pub url: String,
+    /// Invoice rows inserted through the API
+    pub invoices: Arc<Mutex<Vec<Value>>>,
+    /// Payment option rows inserted through the API
+    pub payment_options: Arc<Mutex<Vec<Value>>>,
+    /// Payment rows inserted through the API
+    pub payments: Arc<Mutex<Vec<Value>>>,
+    /// Invoice lifecycle rows, recorded as the database's trigger and update_invoice_status function would
+    pub invoice_events: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockSupabase {
+    /// Serve the PostgREST tables read and written when creating invoices and their payment options,
+    /// and recording their status changes,
+    /// for account ACCOUNT_ID with an address for each of ADDRESSES and no xpubs
+    pub fn spawn() -> Self {
+        let invoices = Arc::new(Mutex::new(Vec::new()));
+        let payment_options = Arc::new(Mutex::new(Vec::new()));
+        let payments = Arc::new(Mutex::new(Vec::new()));
+        let invoice_events = Arc::new(Mutex::new(Vec::new()));
+
+        let addresses = ADDRESSES.iter()
+            .map(|(currency, value)| json!({ "chain": currency, "currency": currency, "value": value }))
+            .collect::<Vec<_>>();
+        let coins = ADDRESSES.iter()
+            .enumerate()
+            .map(|(id, (chain, _))| coin_row(id as i64 + 1, chain, None))
+            .collect::<Vec<_>>();
+
+        let router = Router::new()
+            .route("/rest/v1/accounts", get(|| async {
+                Json(json!([{ "id": ACCOUNT_ID, "denomination": "USD", "base_url": null }]))
+            }))
+            .route("/rest/v1/invoices", get({
+                let invoices = invoices.clone();
+                move |Query(params): Query<HashMap<String, String>>| async move {
+                    let uid = params.get("uid").and_then(|uid| uid.strip_prefix("eq.")).map(str::to_string);
+                    let rows = invoices.lock().unwrap().iter()
+                        .filter(|row| uid.is_none() || row["uid"].as_str() == uid.as_deref())
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    Json(json!(rows))
+                }
+            }).post({
+                let invoices = invoices.clone();
+                let invoice_events = invoice_events.clone();
+                move |Json(rows): Json<Value>| async move {
+                    let mut stored = invoices.lock().unwrap();
+                    let mut created = Vec::new();
+                    for mut row in rows.as_array().cloned().unwrap_or_default() {
+                        row["id"] = json!(stored.len() + 1);
+                        // As the invoices_record_creation trigger does
+                        invoice_events.lock().unwrap().push(json!({
+                            "invoice_uid": row["uid"],
+                            "old_status": null,
+                            "new_status": row["status"],
+                            "actor": format!("account:{}", row["account_id"]),
+                            "created_at": chrono::Utc::now().to_rfc3339(),
+                        }));
+                        stored.push(row.clone());
+                        created.push(row);
+                    }
+                    (StatusCode::CREATED, Json(json!(created)))
+                }
+            }))
+            .route("/rest/v1/rpc/update_invoice_status", post({
+                let invoices = invoices.clone();
+                let invoice_events = invoice_events.clone();
+                move |Json(params): Json<Value>| async move {
+                    let mut stored = invoices.lock().unwrap();
+                    let mut transitions = Vec::new();
+                    for row in stored.iter_mut().filter(|row| row["uid"] == params["p_uid"]) {
+                        let transition = json!({
+                            "invoice_uid": row["uid"],
+                            "old_status": row["status"],
+                            "new_status": params["p_status"],
+                            "actor": params["p_actor"],
+                            "created_at": chrono::Utc::now().to_rfc3339(),
+                        });
+                        row["status"] = params["p_status"].clone();
+                        invoice_events.lock().unwrap().push(transition.clone());
+                        transitions.push(transition);
+                    }
+                    Json(json!(transitions))
+                }
+            }))
+            .route("/rest/v1/payments", get({
+                let payments = payments.clone();
+                move |Query(params): Query<HashMap<String, String>>| async move {
+                    let rows = payments.lock().unwrap().iter()
+                        .filter(|row| matches_filters(row, &params, &["invoice_uid", "txid"]))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    Json(json!(rows))
+                }
+            }).post({
+                let payments = payments.clone();
+                move |Json(rows): Json<Value>| async move {
+                    let mut stored = payments.lock().unwrap();
+                    let mut created = Vec::new();
+                    for mut row in rows.as_array().cloned().unwrap_or_default() {
+                        row["id"] = json!(stored.len() + 1);
+                        stored.push(row.clone());
+                        created.push(row);
+                    }
+                    (StatusCode::CREATED, Json(json!(created)))
+                }
+            }))
+            .route("/rest/v1/addresses", get(move || async move { Json(json!(addresses)) }))
+            .route("/rest/v1/coins", get(move || async move { Json(json!(coins)) }))
+            .route("/rest/v1/account_xpubs", get(|| async { Json(json!([])) }))
+            .route("/rest/v1/prices", get(|Query(params): Query<HashMap<String, String>>| async move {
+                let base_currency = params.get("base_currency").and_then(|base| base.strip_prefix("eq."));
+                let quote_currency = params.get("currency").and_then(|quote| quote.strip_prefix("eq."));
+                let Some(value) = base_currency.and_then(usd_price).filter(|_| quote_currency == Some("USD")) else {
+                    return Json(json!([]));
+                };
+                Json(json!([{
+                    "id": 1,
+                    "currency": "USD",
+                    "value": value,
+                    "createdAt": "2024-01-01T00:00:00Z",
+                    "updatedAt": "2024-01-01T00:00:00Z"
+                }]))
+            }))
+            .route("/rest/v1/payment_options", get({
+                let payment_options = payment_options.clone();
+                move |Query(params): Query<HashMap<String, String>>| async move {
+                    let rows = payment_options.lock().unwrap().iter()
+                        .filter(|row| matches_filters(row, &params, &["invoice_uid", "chain", "currency"]))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    Json(json!(rows))
+                }
+            }).post({
+                let payment_options = payment_options.clone();
+                move |Json(rows): Json<Value>| async move {
+                    payment_options.lock().unwrap().extend(rows.as_array().cloned().unwrap_or_default());
+                    (StatusCode::CREATED, Json(rows))
+                }
+            }));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service()));
+
+        MockSupabase { url, invoices, payment_options, payments, invoice_events }
+    }
+}
+
+/// Serve a stand-in for WhatsOnChain's broadcast endpoint and point WHATSONCHAIN_API_URL at it.
+/// The BSV plugin reads it for every broadcast, so one server on its own runtime is shared by
+/// every test in the binary.
+pub fn mock_chain_apis() -> &'static str {
+    static URL: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    URL.get_or_init(|| {
+        let router = Router::new().route("/tx/raw", post(|Json(body): Json<Value>| async move {
+            let tx = hex::decode(body["txhex"].as_str().unwrap_or_default()).ok()
+                .and_then(|bytes| bitcoin::consensus::deserialize::<bitcoin::Transaction>(&bytes).ok());
+            match tx {
+                Some(tx) => Ok(Json(json!(tx.txid().to_string()))),
+                None => Err(StatusCode::BAD_REQUEST),
+            }
+        }));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service()).await.unwrap();
+            });
+        });
+        std::env::set_var("WHATSONCHAIN_API_URL", &url);
+        url
+    })
+}
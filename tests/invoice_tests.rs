@@ -1,28 +1,26 @@
-use anypay_websockets::{
+mod common;
+
+use anypay::{
     supabase::SupabaseClient,
-    types::{Account, Invoice, PaymentOption},
+    types::{Account, Invoice, InvoiceStatus, PaymentOption},
     payment_options::create_payment_options,
     payment_options::update_expired_payment_options,
 };
-use std::env;
-use dotenv::dotenv;
+use common::{MockSupabase, ADDRESSES};
 
-fn setup_supabase() -> SupabaseClient {
-    dotenv().ok();
-    let url = env::var("SUPABASE_URL").expect("SUPABASE_URL must be set");
-    let anon_key = env::var("SUPABASE_ANON_KEY").expect("SUPABASE_ANON_KEY must be set");
-    let service_role_key = env::var("SUPABASE_SERVICE_ROLE_KEY").expect("SUPABASE_SERVICE_ROLE_KEY must be set");
-    
-    SupabaseClient::new(&url, &anon_key, &service_role_key)
+fn setup_supabase() -> (MockSupabase, SupabaseClient) {
+    let backend = MockSupabase::spawn();
+    let supabase = SupabaseClient::new(&backend.url, "anon", "service");
+    (backend, supabase)
 }
 
 fn create_test_invoice() -> Invoice {
     Invoice {
         id: 1,
         uid: format!("inv_{}", uuid::Uuid::new_v4()),
-        amount: 100000, // $1000.00
+        amount: Some(1000), // $1000
         currency: "USD".to_string(),
-        status: "unpaid".to_string(),
+        status: InvoiceStatus::Unpaid,
         account_id: 1,
         complete: Some(false),
         webhook_url: Some("https://example.com/webhook".to_string()),
@@ -31,6 +29,11 @@ fn create_test_invoice() -> Invoice {
         uri: format!("pay:?r=https://api.anypayx.com/r/{}", uuid::Uuid::new_v4()),
         createdAt: chrono::Utc::now().to_rfc3339(),
         updatedAt: chrono::Utc::now().to_rfc3339(),
+        accepted_currencies: None,
+        required_fee_rate: None,
+        crypto_denominated: false,
+        op_return: None,
+        summary: None,
     }
 }
 
@@ -38,12 +41,13 @@ fn create_test_account() -> Account {
     Account {
         id: 1,
         denomination: Some("USD".to_string()),
+        base_url: None,
     }
 }
 
 #[tokio::test]
 async fn test_create_payment_options() {
-    let supabase = setup_supabase();
+    let (backend, supabase) = setup_supabase();
     let invoice = create_test_invoice();
     let account = create_test_account();
     
@@ -51,11 +55,16 @@ async fn test_create_payment_options() {
         .await
         .expect("Failed to create payment options");
     
-    assert!(!payment_options.is_empty(), "Should have created at least one payment option");
+    assert_eq!(payment_options.len(), ADDRESSES.len(), "Should have created an option per address");
     
     for option in &payment_options {
         verify_payment_option(option, &invoice);
     }
+
+    // $1000 at $50,000/BTC
+    let btc = payment_options.iter().find(|option| option.currency == "BTC").unwrap();
+    assert_eq!(btc.amount, 2_000_000);
+    assert_eq!(backend.payment_options.lock().unwrap().len(), ADDRESSES.len(), "Options should be stored");
 }
 
 fn verify_payment_option(option: &PaymentOption, invoice: &Invoice) {
@@ -93,7 +102,7 @@ fn verify_payment_option(option: &PaymentOption, invoice: &Invoice) {
 
 #[tokio::test]
 async fn test_payment_option_expiry() {
-    let supabase = setup_supabase();
+    let (_backend, supabase) = setup_supabase();
     let invoice = create_test_invoice();
     let account = create_test_account();
     